@@ -3,8 +3,6 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use bindgen;
-
 use std::env;
 use std::path::PathBuf;
 
@@ -14,6 +12,7 @@ fn main() {
         let bindings = bindgen::Builder::default()
             .header("src/wrapper-windows.h")
             .whitelist_function("NCryptSignHash")
+            .whitelist_function("NCryptDecrypt")
             .generate()
             .expect("Unable to generate bindings");
         let out_path = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR unset?"));
@@ -0,0 +1,689 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use pkcs11::types::*;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+use crate::error::Error;
+use crate::util::*;
+use crate::verify::VerifyingKey;
+
+/// A `Backend` is a source of certificates with corresponding private keys (e.g. the macOS
+/// keychain or a Windows certificate store). The `Manager` uses a `Backend` to enumerate the
+/// objects it exposes in a slot and to perform operations with the private keys of those objects.
+pub trait Backend {
+    /// Returns the label of the token this backend's certificates and keys are exposed on. Each
+    /// backend is exposed as its own token, so the label should say where they come from.
+    fn token_label(&self) -> String {
+        String::from("OS Client Cert Token")
+    }
+
+    /// Returns the serial number of the token this backend's certificates and keys are exposed on,
+    /// if it has one. Otherwise, the serial number is derived from the ID of the token's slot.
+    fn token_serial_number(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the manufacturer of the token this backend's certificates and keys are exposed on,
+    /// if it isn't the default.
+    fn token_manufacturer(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the directories this backend's certificates and keys come from, if they come only
+    /// from files in directories. These directories are watched for changes (where possible) rather
+    /// than periodically asking the backend for its identities.
+    fn watched_directories(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    /// Enumerates the certificates for which this backend has a corresponding private key. Each
+    /// certificate is returned along with its key.
+    fn list_identities(&mut self) -> Vec<(Cert, Key)>;
+
+    /// Returns the length of the signature that would be produced by signing the given data with
+    /// the given key. `key` must have been returned by `list_identities`.
+    fn get_signature_length(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<usize, Error>;
+
+    /// Signs the given data (which is the hash of the message being signed, or, for RSA PKCS #1
+    /// v1.5, a DigestInfo) with the given key. `key` must have been returned by `list_identities`.
+    fn sign(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<Vec<u8>, Error>;
+
+    /// Signs the given message (rather than a hash of it) with the given key, hashing it with the
+    /// given algorithm (e.g. `CKM_SHA256`) as part of the signature scheme. RSA keys sign with
+    /// PKCS #1 v1.5 padding and EC keys sign with ECDSA. This is for backends that can't sign
+    /// precomputed hashes (e.g. ssh-agent). The default implementation fails, in which case the
+    /// message has to be hashed and signed with `sign` instead.
+    fn sign_message(
+        &self,
+        _key: &Key,
+        _message: &[u8],
+        _hash_algorithm: CK_MECHANISM_TYPE,
+    ) -> Result<Vec<u8>, Error> {
+        Err(Error::MechanismInvalid)
+    }
+
+    /// Decrypts the given data, which was encrypted with the public key of the given RSA key. If
+    /// `params` is `None`, the data was padded with PKCS #1 v1.5. Otherwise, it was padded with
+    /// OAEP with the given params. `key` must have been returned by `list_identities`. The default
+    /// implementation fails, for backends that can't decrypt.
+    fn decrypt(
+        &self,
+        _key: &Key,
+        _data: &[u8],
+        _params: &Option<OaepParams>,
+    ) -> Result<Vec<u8>, Error> {
+        Err(Error::MechanismInvalid)
+    }
+
+    /// Returns whether or not the given key can sign precomputed hashes with `sign`. If not, it can
+    /// only sign whole messages with `sign_message`. `key` must have been returned by
+    /// `list_identities`.
+    fn signs_hashes(&self, _key: &Key) -> bool {
+        true
+    }
+
+    /// Returns whether or not this backend's keys are kept in and used by hardware (e.g. a smart
+    /// card), so that signing with them is performed by hardware.
+    fn is_hardware(&self) -> bool {
+        false
+    }
+
+    /// Returns whether or not this backend has certificates or keys that can't be used until the
+    /// user logs in.
+    fn login_required(&self) -> bool {
+        false
+    }
+
+    /// Attempts to unlock this backend's locked certificates and keys with the given PIN. Fails
+    /// (with `Error::PinIncorrect`, unless something else went wrong) if nothing could be unlocked
+    /// with it. Anything unlocked will be returned by subsequent calls to `list_identities`.
+    fn login(&mut self, _pin: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Forgets any PIN given to `login`. Anything that was unlocked with it will not be returned by
+    /// subsequent calls to `list_identities`.
+    fn logout(&mut self) {}
+
+    /// Gives the PIN for the next signature with the given key (and PSS params, if any), which has
+    /// `CKA_ALWAYS_AUTHENTICATE` set. The `Manager` only calls this for a sign operation that has
+    /// been started with such a key. Fails with `Error::PinIncorrect` if the PIN is wrong. The
+    /// default implementation fails, for backends whose keys never need this.
+    fn login_context_specific(
+        &mut self,
+        _key: &Key,
+        _params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+        _pin: &str,
+    ) -> Result<(), Error> {
+        Err(Error::General)
+    }
+}
+
+/// The params of RSA OAEP decryption. The mask generation function is always MGF1 with the same
+/// hash algorithm, and the label is always empty.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OaepParams {
+    /// The hash algorithm (`CKM_SHA_1` or `CKM_SHA256`).
+    pub hash_algorithm: CK_MECHANISM_TYPE,
+}
+
+/// Represents a certificate for which there exists a corresponding private key.
+pub struct Cert {
+    /// PKCS #11 object class. Will be `CKO_CERTIFICATE`.
+    class: Vec<u8>,
+    /// Whether or not this is on a token. Will be `CK_TRUE`.
+    token: Vec<u8>,
+    /// An identifier unique to this certificate. Must be the same as the ID for the private key.
+    id: Vec<u8>,
+    /// The bytes of a human-readable label for this certificate.
+    label: Vec<u8>,
+    /// The DER bytes of the certificate.
+    value: Vec<u8>,
+    /// The DER bytes of the issuer distinguished name of the certificate.
+    issuer: Vec<u8>,
+    /// The DER bytes of the serial number of the certificate.
+    serial_number: Vec<u8>,
+    /// The DER bytes of the subject distinguished name of the certificate.
+    subject: Vec<u8>,
+}
+
+impl Cert {
+    /// Creates a new `Cert` given the DER bytes of the certificate and the values of its various
+    /// attributes, as determined by the backend. The ID of the certificate is the SHA-256 hash of
+    /// its DER bytes.
+    pub fn new(
+        value: Vec<u8>,
+        label: Vec<u8>,
+        issuer: Vec<u8>,
+        serial_number: Vec<u8>,
+        subject: Vec<u8>,
+    ) -> Result<Cert, ()> {
+        let id = Sha256::digest(&value).to_vec();
+        Ok(Cert {
+            class: serialize_uint(CKO_CERTIFICATE)?,
+            token: serialize_uint(CK_TRUE)?,
+            id,
+            label,
+            value,
+            issuer,
+            serial_number,
+            subject,
+        })
+    }
+
+    fn class(&self) -> &[u8] {
+        &self.class
+    }
+
+    fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    fn label(&self) -> &[u8] {
+        &self.label
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    fn issuer(&self) -> &[u8] {
+        &self.issuer
+    }
+
+    fn serial_number(&self) -> &[u8] {
+        &self.serial_number
+    }
+
+    fn subject(&self) -> &[u8] {
+        &self.subject
+    }
+
+    fn matches(&self, attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)]) -> bool {
+        for (attr_type, attr_value) in attrs {
+            let comparison = match *attr_type {
+                CKA_CLASS => self.class(),
+                CKA_TOKEN => self.token(),
+                CKA_LABEL => self.label(),
+                CKA_ID => self.id(),
+                CKA_VALUE => self.value(),
+                CKA_ISSUER => self.issuer(),
+                CKA_SERIAL_NUMBER => self.serial_number(),
+                CKA_SUBJECT => self.subject(),
+                _ => return false,
+            };
+            if attr_value.as_slice() != comparison {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn get_attribute(&self, attribute: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
+        let result = match attribute {
+            CKA_CLASS => self.class(),
+            CKA_TOKEN => self.token(),
+            CKA_LABEL => self.label(),
+            CKA_ID => self.id(),
+            CKA_VALUE => self.value(),
+            CKA_ISSUER => self.issuer(),
+            CKA_SERIAL_NUMBER => self.serial_number(),
+            CKA_SUBJECT => self.subject(),
+            _ => return None,
+        };
+        Some(result)
+    }
+}
+
+/// A helper enum to identify a private key's type. We support EC and RSA. For EC keys, the value is
+/// the width in bytes of each coordinate of a point on the key's curve.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyType {
+    EC(usize),
+    RSA,
+}
+
+/// Represents a private key for which there exists a corresponding certificate. The backend that
+/// created the key is responsible for actually using it.
+pub struct Key {
+    /// PKCS #11 object class. Will be `CKO_PRIVATE_KEY`.
+    class: Vec<u8>,
+    /// Whether or not this is on a token. Will be `CK_TRUE`.
+    token: Vec<u8>,
+    /// An identifier unique to this key. Must be the same as the ID for the certificate.
+    id: Vec<u8>,
+    /// Whether or not this key is "private" (can it be exported?). Will be CK_TRUE (it can't be
+    /// exported).
+    private: Vec<u8>,
+    /// Whether or not the secret parts of this key can be revealed. Will be CK_TRUE (they can't).
+    sensitive: Vec<u8>,
+    /// Whether or not this key can be wrapped to be exported. Will be CK_FALSE.
+    extractable: Vec<u8>,
+    /// Whether or not this key can be used to decrypt. Will be CK_TRUE for RSA keys whose
+    /// certificate allows key encipherment, and CK_FALSE otherwise.
+    decrypt: Vec<u8>,
+    /// Whether or not the user has to give the PIN every time this key is used. Will be CK_FALSE
+    /// unless the backend sets it with `set_always_authenticate`.
+    always_authenticate: Vec<u8>,
+    /// PKCS #11 key type. Will be `CKK_EC` for EC, and `CKK_RSA` for RSA.
+    key_type: Vec<u8>,
+    /// If this is an RSA key, this is the value of the modulus as an unsigned integer.
+    modulus: Option<Vec<u8>>,
+    /// If this is an EC key, this is the DER bytes of the OID identifying the curve the key is on.
+    ec_params: Option<Vec<u8>>,
+    /// An enum identifying this key's type. The `Manager` uses this to check that the key can be
+    /// used with a mechanism, and the OS backends use it to sign (software keys know their own
+    /// type).
+    key_type_enum: KeyType,
+    /// Whether or not this key can be used to decrypt, as `decrypt` says.
+    can_decrypt: bool,
+    /// Whether or not the user has to give the PIN every time this key is used, as
+    /// `always_authenticate` says.
+    needs_context_specific_login: bool,
+}
+
+impl Key {
+    /// Creates a new `Key` corresponding to the certificate with the given DER bytes. `modulus`
+    /// must be set for RSA keys and `ec_params` must be set for EC keys.
+    pub fn new(
+        cert_der: &[u8],
+        key_type_enum: KeyType,
+        modulus: Option<Vec<u8>>,
+        ec_params: Option<Vec<u8>>,
+    ) -> Result<Key, ()> {
+        let id = Sha256::digest(cert_der).to_vec();
+        // Keys are still usable for signing if the certificate's extensions can't be read.
+        let can_decrypt =
+            key_type_enum == KeyType::RSA && allows_key_encipherment(cert_der).unwrap_or(false);
+        let key_type_attribute = match key_type_enum {
+            KeyType::EC(_) => {
+                if ec_params.is_none() {
+                    return Err(());
+                }
+                CKK_EC
+            }
+            KeyType::RSA => {
+                if modulus.is_none() {
+                    return Err(());
+                }
+                CKK_RSA
+            }
+        };
+        Ok(Key {
+            class: serialize_uint(CKO_PRIVATE_KEY)?,
+            token: serialize_uint(CK_TRUE)?,
+            id,
+            private: serialize_uint(CK_TRUE)?,
+            sensitive: serialize_uint(CK_TRUE)?,
+            extractable: serialize_uint(CK_FALSE)?,
+            decrypt: serialize_uint(if can_decrypt { CK_TRUE } else { CK_FALSE })?,
+            always_authenticate: serialize_uint(CK_FALSE)?,
+            key_type: serialize_uint(key_type_attribute)?,
+            modulus,
+            ec_params,
+            key_type_enum,
+            can_decrypt,
+            needs_context_specific_login: false,
+        })
+    }
+
+    fn class(&self) -> &[u8] {
+        &self.class
+    }
+
+    fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    fn private(&self) -> &[u8] {
+        &self.private
+    }
+
+    fn sensitive(&self) -> &[u8] {
+        &self.sensitive
+    }
+
+    fn extractable(&self) -> &[u8] {
+        &self.extractable
+    }
+
+    fn decrypt(&self) -> &[u8] {
+        &self.decrypt
+    }
+
+    /// Returns whether or not this key can be used to decrypt (i.e. whether its `CKA_DECRYPT` is
+    /// `CK_TRUE`).
+    pub fn can_decrypt(&self) -> bool {
+        self.can_decrypt
+    }
+
+    fn always_authenticate(&self) -> &[u8] {
+        &self.always_authenticate
+    }
+
+    /// Returns whether or not the user has to log in with `CKU_CONTEXT_SPECIFIC` every time this
+    /// key is used to sign (i.e. whether its `CKA_ALWAYS_AUTHENTICATE` is `CK_TRUE`).
+    pub fn needs_context_specific_login(&self) -> bool {
+        self.needs_context_specific_login
+    }
+
+    /// Marks this key as one the user has to give the PIN for every time it is used (e.g. because
+    /// the key on the smart card it comes from is like that).
+    pub fn set_always_authenticate(&mut self, always_authenticate: bool) -> Result<(), ()> {
+        self.always_authenticate = serialize_uint(if always_authenticate {
+            CK_TRUE
+        } else {
+            CK_FALSE
+        })?;
+        self.needs_context_specific_login = always_authenticate;
+        Ok(())
+    }
+
+    fn key_type(&self) -> &[u8] {
+        &self.key_type
+    }
+
+    pub fn key_type_enum(&self) -> KeyType {
+        self.key_type_enum
+    }
+
+    /// Returns the size of this key in bits: the length of the modulus for RSA keys, and the size
+    /// of the field of the key's curve for EC keys.
+    pub fn key_size(&self) -> usize {
+        match self.key_type_enum {
+            KeyType::RSA => {
+                // The modulus may have leading zero bytes.
+                let modulus = self.modulus().unwrap_or_default();
+                match modulus.iter().position(|byte| *byte != 0) {
+                    Some(start) => {
+                        (modulus.len() - start) * 8 - modulus[start].leading_zeros() as usize
+                    }
+                    None => 0,
+                }
+            }
+            // secp521r1 is the only supported curve with a field size that isn't a multiple of 8.
+            KeyType::EC(66) => 521,
+            KeyType::EC(coordinate_width) => coordinate_width * 8,
+        }
+    }
+
+    pub fn modulus(&self) -> Option<&[u8]> {
+        match &self.modulus {
+            Some(modulus) => Some(modulus.as_slice()),
+            None => None,
+        }
+    }
+
+    pub fn ec_params(&self) -> Option<&[u8]> {
+        match &self.ec_params {
+            Some(ec_params) => Some(ec_params.as_slice()),
+            None => None,
+        }
+    }
+
+    fn matches(&self, attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)]) -> bool {
+        for (attr_type, attr_value) in attrs {
+            let comparison = match *attr_type {
+                CKA_CLASS => self.class(),
+                CKA_TOKEN => self.token(),
+                CKA_ID => self.id(),
+                CKA_PRIVATE => self.private(),
+                CKA_SENSITIVE => self.sensitive(),
+                CKA_EXTRACTABLE => self.extractable(),
+                CKA_DECRYPT => self.decrypt(),
+                CKA_ALWAYS_AUTHENTICATE => self.always_authenticate(),
+                CKA_KEY_TYPE => self.key_type(),
+                CKA_MODULUS => {
+                    if let Some(modulus) = self.modulus() {
+                        modulus
+                    } else {
+                        return false;
+                    }
+                }
+                CKA_EC_PARAMS => {
+                    if let Some(ec_params) = self.ec_params() {
+                        ec_params
+                    } else {
+                        return false;
+                    }
+                }
+                _ => return false,
+            };
+            if attr_value.as_slice() != comparison {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn get_attribute(&self, attribute: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
+        match attribute {
+            CKA_CLASS => Some(self.class()),
+            CKA_TOKEN => Some(self.token()),
+            CKA_ID => Some(self.id()),
+            CKA_PRIVATE => Some(self.private()),
+            CKA_SENSITIVE => Some(self.sensitive()),
+            CKA_EXTRACTABLE => Some(self.extractable()),
+            CKA_DECRYPT => Some(self.decrypt()),
+            CKA_ALWAYS_AUTHENTICATE => Some(self.always_authenticate()),
+            CKA_KEY_TYPE => Some(self.key_type()),
+            CKA_MODULUS => self.modulus(),
+            CKA_EC_PARAMS => self.ec_params(),
+            _ => None,
+        }
+    }
+}
+
+/// Represents the public key of a certificate for which there exists a corresponding private key.
+/// Some applications (e.g. OpenSSH) find keys by their public key objects rather than by their
+/// certificates. The attributes all come from the certificate.
+pub struct PublicKey {
+    /// PKCS #11 object class. Will be `CKO_PUBLIC_KEY`.
+    class: Vec<u8>,
+    /// Whether or not this is on a token. Will be `CK_TRUE`.
+    token: Vec<u8>,
+    /// An identifier unique to this key. Will be the same as the ID for the certificate.
+    id: Vec<u8>,
+    /// The label of the certificate.
+    label: Vec<u8>,
+    /// Whether or not this key is "private". Will be CK_FALSE.
+    private: Vec<u8>,
+    /// The DER bytes of the subject distinguished name of the certificate.
+    subject: Vec<u8>,
+    /// PKCS #11 key type. Will be `CKK_EC` for EC, and `CKK_RSA` for RSA.
+    key_type: Vec<u8>,
+    /// If this is an RSA key, this is the value of the modulus as an unsigned integer.
+    modulus: Option<Vec<u8>>,
+    /// If this is an RSA key, this is the value of the public exponent as an unsigned integer.
+    public_exponent: Option<Vec<u8>>,
+    /// If this is an EC key, this is the DER bytes of the OID identifying the curve the key is on.
+    ec_params: Option<Vec<u8>>,
+    /// If this is an EC key, this is the DER bytes of an OCTET STRING containing the point that is
+    /// the key.
+    ec_point: Option<Vec<u8>>,
+    /// Whether or not this key can be used to verify signatures. Will be CK_TRUE for RSA keys and
+    /// EC keys on the curves signatures can be verified with.
+    verify: Vec<u8>,
+    /// What signatures are verified with, if this key can be used to verify them.
+    verifying_key: Option<VerifyingKey>,
+}
+
+impl PublicKey {
+    /// Creates a new `PublicKey` from the subject public key info of the given certificate.
+    pub fn new(cert: &Cert) -> Result<PublicKey, ()> {
+        let public_key_info = read_public_key_info(cert.value())?;
+        let verifying_key = VerifyingKey::new(&public_key_info).ok();
+        let (key_type, modulus, public_exponent, ec_params, ec_point) = match public_key_info {
+            PublicKeyInfo::Rsa {
+                modulus,
+                public_exponent,
+            } => (
+                CKK_RSA,
+                Some(modulus.to_vec()),
+                Some(public_exponent.to_vec()),
+                None,
+                None,
+            ),
+            PublicKeyInfo::Ec { ec_params, point } => (
+                CKK_EC,
+                None,
+                None,
+                Some(ec_params.to_vec()),
+                Some(encode_octet_string(point)?),
+            ),
+        };
+        Ok(PublicKey {
+            class: serialize_uint(CKO_PUBLIC_KEY)?,
+            token: serialize_uint(CK_TRUE)?,
+            id: cert.id().to_vec(),
+            label: cert.label().to_vec(),
+            private: serialize_uint(CK_FALSE)?,
+            subject: cert.subject().to_vec(),
+            key_type: serialize_uint(key_type)?,
+            modulus,
+            public_exponent,
+            ec_params,
+            ec_point,
+            verify: serialize_uint(if verifying_key.is_some() {
+                CK_TRUE
+            } else {
+                CK_FALSE
+            })?,
+            verifying_key,
+        })
+    }
+
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// Returns the PKCS #11 type of this key.
+    pub fn key_type(&self) -> CK_KEY_TYPE {
+        if self.modulus.is_some() {
+            CKK_RSA
+        } else {
+            CKK_EC
+        }
+    }
+
+    /// Returns what signatures are verified with, if this key can be used to verify them (i.e.
+    /// whether its `CKA_VERIFY` is `CK_TRUE`).
+    pub fn verifying_key(&self) -> Option<&VerifyingKey> {
+        self.verifying_key.as_ref()
+    }
+
+    fn matches(&self, attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)]) -> bool {
+        attrs
+            .iter()
+            .all(|(attr_type, attr_value)| self.get_attribute(*attr_type) == Some(attr_value))
+    }
+
+    fn get_attribute(&self, attribute: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
+        match attribute {
+            CKA_CLASS => Some(&self.class),
+            CKA_TOKEN => Some(&self.token),
+            CKA_ID => Some(&self.id),
+            CKA_LABEL => Some(&self.label),
+            CKA_PRIVATE => Some(&self.private),
+            CKA_SUBJECT => Some(&self.subject),
+            CKA_KEY_TYPE => Some(&self.key_type),
+            CKA_MODULUS => self.modulus.as_deref(),
+            CKA_PUBLIC_EXPONENT => self.public_exponent.as_deref(),
+            CKA_EC_PARAMS => self.ec_params.as_deref(),
+            CKA_EC_POINT => self.ec_point.as_deref(),
+            CKA_VERIFY => Some(&self.verify),
+            _ => None,
+        }
+    }
+}
+
+/// A helper enum that represents the three types of PKCS #11 objects we support: certificates,
+/// private keys, and public keys.
+pub enum Object {
+    Cert(Cert),
+    Key(Key),
+    PublicKey(PublicKey),
+}
+
+impl Object {
+    pub fn matches(&self, attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)]) -> bool {
+        match self {
+            Object::Cert(cert) => cert.matches(attrs),
+            Object::Key(key) => key.matches(attrs),
+            Object::PublicKey(public_key) => public_key.matches(attrs),
+        }
+    }
+
+    pub fn get_attribute(&self, attribute: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
+        match self {
+            Object::Cert(cert) => cert.get_attribute(attribute),
+            Object::Key(key) => key.get_attribute(attribute),
+            Object::PublicKey(public_key) => public_key.get_attribute(attribute),
+        }
+    }
+
+    /// Returns whether or not the given attribute is one whose value must not be revealed. Keys
+    /// are sensitive, so their secret parts are never available (even if a backend could get them).
+    pub fn is_sensitive_attribute(&self, attribute: CK_ATTRIBUTE_TYPE) -> bool {
+        match self {
+            Object::Cert(_) | Object::PublicKey(_) => false,
+            Object::Key(_) => SENSITIVE_KEY_ATTRIBUTES.contains(&attribute),
+        }
+    }
+}
+
+/// The attributes of private keys that hold their secret parts.
+const SENSITIVE_KEY_ATTRIBUTES: &[CK_ATTRIBUTE_TYPE] = &[
+    CKA_VALUE,
+    CKA_PRIVATE_EXPONENT,
+    CKA_PRIME_1,
+    CKA_PRIME_2,
+    CKA_EXPONENT_1,
+    CKA_EXPONENT_2,
+    CKA_COEFFICIENT,
+];
+
+pub const SUPPORTED_ATTRIBUTES: &[CK_ATTRIBUTE_TYPE] = &[
+    CKA_CLASS,
+    CKA_TOKEN,
+    CKA_LABEL,
+    CKA_ID,
+    CKA_VALUE,
+    CKA_ISSUER,
+    CKA_SERIAL_NUMBER,
+    CKA_SUBJECT,
+    CKA_PRIVATE,
+    CKA_SENSITIVE,
+    CKA_EXTRACTABLE,
+    CKA_DECRYPT,
+    CKA_VERIFY,
+    CKA_ALWAYS_AUTHENTICATE,
+    CKA_KEY_TYPE,
+    CKA_MODULUS,
+    CKA_PUBLIC_EXPONENT,
+    CKA_EC_PARAMS,
+    CKA_EC_POINT,
+];
@@ -0,0 +1,184 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use pkcs11::types::*;
+use std::path::PathBuf;
+
+use crate::backend::*;
+use crate::config::{Filter, TokenConfig};
+use crate::error::Error;
+
+/// A backend that applies the configuration of another backend: the identities it exposes are
+/// filtered and the information about its token is overridden. Everything else is forwarded to the
+/// other backend.
+pub struct ConfiguredBackend {
+    backend: Box<dyn Backend>,
+    token: TokenConfig,
+    filter: Filter,
+}
+
+impl ConfiguredBackend {
+    pub fn new(backend: Box<dyn Backend>, token: TokenConfig, filter: Filter) -> ConfiguredBackend {
+        ConfiguredBackend {
+            backend,
+            token,
+            filter,
+        }
+    }
+}
+
+impl Backend for ConfiguredBackend {
+    fn token_label(&self) -> String {
+        match &self.token.label {
+            Some(label) => label.clone(),
+            None => self.backend.token_label(),
+        }
+    }
+
+    fn token_serial_number(&self) -> Option<String> {
+        self.token
+            .serial_number
+            .clone()
+            .or_else(|| self.backend.token_serial_number())
+    }
+
+    fn token_manufacturer(&self) -> Option<String> {
+        self.token
+            .manufacturer
+            .clone()
+            .or_else(|| self.backend.token_manufacturer())
+    }
+
+    fn watched_directories(&self) -> Vec<PathBuf> {
+        self.backend.watched_directories()
+    }
+
+    fn list_identities(&mut self) -> Vec<(Cert, Key)> {
+        let filter = &self.filter;
+        self.backend
+            .list_identities()
+            .into_iter()
+            .filter(|(cert, key)| filter.allows(cert, key))
+            .collect()
+    }
+
+    fn get_signature_length(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<usize, Error> {
+        self.backend.get_signature_length(key, data, params)
+    }
+
+    fn sign(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<Vec<u8>, Error> {
+        self.backend.sign(key, data, params)
+    }
+
+    fn sign_message(
+        &self,
+        key: &Key,
+        message: &[u8],
+        hash_algorithm: CK_MECHANISM_TYPE,
+    ) -> Result<Vec<u8>, Error> {
+        self.backend.sign_message(key, message, hash_algorithm)
+    }
+
+    fn decrypt(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<OaepParams>,
+    ) -> Result<Vec<u8>, Error> {
+        self.backend.decrypt(key, data, params)
+    }
+
+    fn signs_hashes(&self, key: &Key) -> bool {
+        self.backend.signs_hashes(key)
+    }
+
+    fn is_hardware(&self) -> bool {
+        self.backend.is_hardware()
+    }
+
+    fn login_required(&self) -> bool {
+        self.backend.login_required()
+    }
+
+    fn login(&mut self, pin: &str) -> Result<(), Error> {
+        self.backend.login(pin)
+    }
+
+    fn logout(&mut self) {
+        self.backend.logout()
+    }
+
+    fn login_context_specific(
+        &mut self,
+        key: &Key,
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+        pin: &str,
+    ) -> Result<(), Error> {
+        self.backend.login_context_specific(key, params, pin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_mock::*;
+    use crate::config::KeyKind;
+
+    #[test]
+    fn test_token_info() {
+        let backend = ConfiguredBackend::new(
+            Box::new(MockBackend::new(MockStore::new())),
+            TokenConfig::default(),
+            Filter::default(),
+        );
+        assert_eq!(backend.token_label(), "mock token");
+        assert_eq!(backend.token_serial_number(), None);
+        assert_eq!(backend.token_manufacturer(), None);
+        let backend = ConfiguredBackend::new(
+            Box::new(MockBackend::new(MockStore::new())),
+            TokenConfig {
+                label: Some(String::from("label")),
+                serial_number: Some(String::from("serial")),
+                manufacturer: Some(String::from("manufacturer")),
+            },
+            Filter::default(),
+        );
+        assert_eq!(backend.token_label(), "label");
+        assert_eq!(backend.token_serial_number().as_deref(), Some("serial"));
+        assert_eq!(
+            backend.token_manufacturer().as_deref(),
+            Some("manufacturer")
+        );
+    }
+
+    #[test]
+    fn test_filtered_identities() {
+        let store = MockStore::with_fixtures();
+        let mut backend = ConfiguredBackend::new(
+            Box::new(MockBackend::new(store)),
+            TokenConfig::default(),
+            Filter {
+                key_types: Some(vec![KeyKind::Ec]),
+                ..Default::default()
+            },
+        );
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 2);
+        for (_, key) in &identities {
+            assert!(key.ec_params().is_some());
+            assert!(backend.sign(key, &[0; 32], &None).is_ok());
+        }
+    }
+}
@@ -0,0 +1,1018 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use pkcs11::types::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::backend::*;
+use crate::error::Error;
+use crate::pbe;
+use crate::pkcs12;
+use crate::software_key::SoftwareKey;
+use crate::util::pkcs::read_encrypted_private_key_info;
+use crate::util::*;
+
+/// The directory (relative to the user's home directory) searched for certificates and keys by
+/// default.
+const USER_DIRECTORY: &str = ".pki/osclientcerts";
+/// The system-wide directory searched for certificates and keys by default.
+const SYSTEM_DIRECTORY: &str = "/etc/pki/osclientcerts";
+/// The label of the token the user's directory is exposed on.
+const USER_TOKEN_LABEL: &str = "User Certificate Files";
+/// The label of the token the system-wide directory is exposed on.
+const SYSTEM_TOKEN_LABEL: &str = "System Certificate Files";
+
+/// A certificate found in a file, along with the label it will be exposed with.
+pub struct FileCert {
+    pub der: Vec<u8>,
+    pub label: Vec<u8>,
+}
+
+/// Returns the directories searched for certificates and keys by default:
+/// `~/.pki/osclientcerts/` and `/etc/pki/osclientcerts/`.
+pub fn default_directories() -> Vec<PathBuf> {
+    let mut directories = Vec::with_capacity(2);
+    if let Some(user_directory) = user_directory() {
+        directories.push(user_directory);
+    }
+    directories.push(PathBuf::from(SYSTEM_DIRECTORY));
+    directories
+}
+
+/// Returns `~/.pki/osclientcerts/`, if the user's home directory is known.
+fn user_directory() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(USER_DIRECTORY))
+}
+
+/// Returns the certificates in the files in the given directories (ignoring any keys). This is for
+/// backends that have keys but not the certificates that go with them.
+pub fn find_certificates(directories: &[PathBuf]) -> Vec<FileCert> {
+    scan_directories(directories, None).certs
+}
+
+/// A password-protected private key found in a file. These are only decrypted once the user has
+/// logged in (with the password as the PIN), and then only when they're needed.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum EncryptedKey {
+    /// The DER bytes of a PKCS #8 EncryptedPrivateKeyInfo.
+    Pkcs8(Vec<u8>),
+    /// The contents of a PEM block with OpenSSL's legacy encryption headers, along with the block's
+    /// tag (which determines the format of the decrypted key) and the value of its DEK-Info header.
+    LegacyPem {
+        tag: String,
+        dek_info: String,
+        ciphertext: Vec<u8>,
+    },
+}
+
+impl EncryptedKey {
+    fn decrypt(&self, password: &str) -> Result<SoftwareKey, ()> {
+        match self {
+            EncryptedKey::Pkcs8(der) => SoftwareKey::from_encrypted_pkcs8_der(der, password),
+            EncryptedKey::LegacyPem {
+                tag,
+                dek_info,
+                ciphertext,
+            } => {
+                let der = pbe::decrypt_legacy_pem(dek_info, password, ciphertext)?;
+                match tag.as_str() {
+                    "RSA PRIVATE KEY" => SoftwareKey::from_pkcs1_der(&der),
+                    "EC PRIVATE KEY" => SoftwareKey::from_sec1_der(&der),
+                    _ => SoftwareKey::from_der(&der),
+                }
+            }
+        }
+    }
+}
+
+/// Decrypts the given key (found in the file with the given path) with the given PIN.
+fn decrypt_key(path: &Path, encrypted_key: &EncryptedKey, pin: &str) -> Option<SoftwareKey> {
+    match encrypted_key.decrypt(pin) {
+        Ok(key) => Some(key),
+        Err(()) => {
+            debug!("couldn't decrypt key in '{}'", path.display());
+            None
+        }
+    }
+}
+
+/// Creates the key object for a certificate whose private key hasn't been decrypted yet (so its
+/// attributes come from the certificate's public key).
+fn new_undecrypted_key(cert_der: &[u8]) -> Result<Key, ()> {
+    match read_public_key_info(cert_der)? {
+        PublicKeyInfo::Rsa { modulus, .. } => {
+            Key::new(cert_der, KeyType::RSA, Some(modulus.to_vec()), None)
+        }
+        PublicKeyInfo::Ec { ec_params, .. } => Key::new(
+            cert_der,
+            KeyType::EC(get_ec_coordinate_width(ec_params)?),
+            None,
+            Some(ec_params.to_vec()),
+        ),
+    }
+}
+
+/// Everything found while scanning the directories of a `FileBackend`.
+#[derive(Default)]
+struct ScanResults {
+    certs: Vec<FileCert>,
+    keys: Vec<SoftwareKey>,
+    /// Encrypted keys, along with the files they were found in.
+    encrypted_keys: Vec<(PathBuf, EncryptedKey)>,
+    /// Files that couldn't be completely decoded because they need a password that hasn't been
+    /// given (or because the password that has been given isn't the right one).
+    locked_files: Vec<PathBuf>,
+}
+
+/// A backend that finds certificates and private keys in files in a list of directories. Files
+/// may be PEM (in which case they may contain any number of certificates and keys) or DER (in which
+/// case they contain exactly one certificate or key). Supported key formats are unencrypted
+/// PKCS #8, PKCS #1 ("traditional" RSA), and SEC1 (EC). Keys are paired with certificates by public
+/// key, so certificates and keys can be in the same file or in different files. Signing is done in
+/// software.
+/// PKCS #12 files (with the extension .p12 or .pfx) and encrypted keys (PKCS #8
+/// EncryptedPrivateKeyInfo or PEM with OpenSSL's legacy "Proc-Type: 4,ENCRYPTED" headers) are also
+/// supported. Those that are encrypted stay locked until the user logs in with their password as
+/// the PIN. Because decrypting a key can be expensive (particularly with scrypt), logging in only
+/// decrypts as much as it takes to check the PIN, and other keys are decrypted the first time
+/// they're used. Until then, there is no telling which certificate an encrypted key goes with, so
+/// any certificate that no other key goes with is assumed to be the one.
+pub struct FileBackend {
+    /// The label of the token this backend is exposed on.
+    label: String,
+    /// The directories to search, in order.
+    directories: Vec<PathBuf>,
+    /// A map of key identifiers to the keys they identify.
+    keys: BTreeMap<Vec<u8>, SoftwareKey>,
+    /// A map of key identifiers to the subject public key infos of certificates that are assumed
+    /// to go with one of the encrypted keys that hasn't been decrypted yet.
+    undecrypted_keys: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// The PIN the user logged in with, if any. This is used as the password of PKCS #12 files.
+    pin: Option<String>,
+    /// The files that were locked as of the last scan.
+    locked_files: Vec<PathBuf>,
+    /// The encrypted keys found in the last scan, along with the files they were found in.
+    encrypted_keys: Vec<(PathBuf, EncryptedKey)>,
+    /// The results of decrypting encrypted keys with the PIN (`None` if the PIN doesn't decrypt
+    /// the key), so that each key is decrypted at most once after logging in.
+    decrypted_keys: RefCell<BTreeMap<EncryptedKey, Option<SoftwareKey>>>,
+}
+
+impl FileBackend {
+    /// Creates a `FileBackend` for each of the directories searched by default, so that the user's
+    /// `~/.pki/osclientcerts/` and the system-wide `/etc/pki/osclientcerts/` are separate tokens.
+    pub fn new_defaults() -> Vec<FileBackend> {
+        let mut backends = Vec::with_capacity(2);
+        if let Some(user_directory) = user_directory() {
+            backends.push(FileBackend::with_directories(
+                USER_TOKEN_LABEL,
+                vec![user_directory],
+            ));
+        }
+        backends.push(FileBackend::with_directories(
+            SYSTEM_TOKEN_LABEL,
+            vec![PathBuf::from(SYSTEM_DIRECTORY)],
+        ));
+        backends
+    }
+
+    /// Creates a `FileBackend` that searches the given directories and is exposed on a token with
+    /// the given label.
+    pub fn with_directories(label: &str, directories: Vec<PathBuf>) -> FileBackend {
+        FileBackend {
+            label: label.to_owned(),
+            directories,
+            keys: BTreeMap::new(),
+            undecrypted_keys: BTreeMap::new(),
+            pin: None,
+            locked_files: Vec::new(),
+            encrypted_keys: Vec::new(),
+            decrypted_keys: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Calls `f` with the software key for the given key, decrypting it first if it hasn't been
+    /// yet. Fails if the key has gone away or if no encrypted key turns out to go with it.
+    fn with_software_key<T, F>(&self, key: &Key, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&SoftwareKey) -> Result<T, Error>,
+    {
+        if let Some(software_key) = self.keys.get(key.id()) {
+            return f(software_key);
+        }
+        let subject_public_key_info = self
+            .undecrypted_keys
+            .get(key.id())
+            .ok_or(Error::KeyHandleInvalid)?;
+        match self.find_encrypted_key(subject_public_key_info) {
+            Some(software_key) => f(&software_key),
+            None => {
+                error!("no encrypted key goes with the certificate");
+                Err(Error::BackendFailure)
+            }
+        }
+    }
+
+    /// Decrypts the encrypted keys from the last scan (those that haven't been already) until one
+    /// with the given public key turns up.
+    fn find_encrypted_key(&self, subject_public_key_info: &[u8]) -> Option<SoftwareKey> {
+        let pin = self.pin.as_deref()?;
+        let mut decrypted_keys = self.decrypted_keys.borrow_mut();
+        for (path, encrypted_key) in &self.encrypted_keys {
+            let key = decrypted_keys
+                .entry(encrypted_key.clone())
+                .or_insert_with(|| decrypt_key(path, encrypted_key, pin));
+            if let Some(key) = key {
+                if key.subject_public_key_info().ok()?.as_slice() == subject_public_key_info {
+                    return Some(key.clone());
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Returns whether the given PIN unlocks the given file: whether it's the password of the file (if
+/// it's PKCS #12) or whether it decrypts the first encrypted key in it. Any key decrypted is added
+/// to `decrypted_keys`.
+fn unlocks(
+    path: &Path,
+    pin: &str,
+    decrypted_keys: &mut BTreeMap<EncryptedKey, Option<SoftwareKey>>,
+) -> bool {
+    let mut results = ScanResults::default();
+    read_file(path, Some(pin), &mut results);
+    if results.locked_files.iter().any(|locked| locked == path) {
+        return false;
+    }
+    match results.encrypted_keys.into_iter().next() {
+        Some((path, encrypted_key)) => {
+            let key = decrypt_key(&path, &encrypted_key, pin);
+            let unlocked = key.is_some();
+            decrypted_keys.insert(encrypted_key, key);
+            unlocked
+        }
+        None => true,
+    }
+}
+
+/// Reads the certificates and keys in the given file, adding them to `results`. Anything that
+/// can't be decoded is skipped.
+fn read_file(path: &Path, pin: Option<&str>, results: &mut ScanResults) {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            warn!("couldn't read '{}': {}", path.display(), e);
+            return;
+        }
+    };
+    let label = match path.file_stem() {
+        Some(file_stem) => file_stem.to_string_lossy().as_bytes().to_vec(),
+        None => return,
+    };
+    let is_pkcs12 = match path.extension() {
+        Some(extension) => {
+            extension.eq_ignore_ascii_case("p12") || extension.eq_ignore_ascii_case("pfx")
+        }
+        None => false,
+    };
+    if is_pkcs12 {
+        read_pkcs12_file(path, &contents, label, pin, results);
+    } else if contents.windows(11).any(|window| window == b"-----BEGIN ") {
+        let blocks = match pem::parse_many(&contents) {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                warn!("couldn't decode PEM in '{}': {}", path.display(), e);
+                return;
+            }
+        };
+        for block in blocks {
+            match block.tag() {
+                "CERTIFICATE" => {
+                    if read_certificate_fields(block.contents()).is_ok() {
+                        results.certs.push(FileCert {
+                            der: block.into_contents(),
+                            label: label.clone(),
+                        });
+                    } else {
+                        warn!("malformed certificate in '{}'", path.display());
+                    }
+                }
+                "ENCRYPTED PRIVATE KEY" => results.encrypted_keys.push((
+                    path.to_path_buf(),
+                    EncryptedKey::Pkcs8(block.into_contents()),
+                )),
+                "RSA PRIVATE KEY" | "EC PRIVATE KEY"
+                    if block
+                        .headers()
+                        .get("Proc-Type")
+                        .is_some_and(|proc_type| proc_type.ends_with("ENCRYPTED")) =>
+                {
+                    let dek_info = match block.headers().get("DEK-Info") {
+                        Some(dek_info) => dek_info.to_owned(),
+                        None => {
+                            warn!("encrypted key without DEK-Info in '{}'", path.display());
+                            continue;
+                        }
+                    };
+                    results.encrypted_keys.push((
+                        path.to_path_buf(),
+                        EncryptedKey::LegacyPem {
+                            tag: block.tag().to_owned(),
+                            dek_info,
+                            ciphertext: block.into_contents(),
+                        },
+                    ));
+                }
+                "PRIVATE KEY" => results
+                    .keys
+                    .extend(SoftwareKey::from_pkcs8_der(block.contents())),
+                "RSA PRIVATE KEY" => results
+                    .keys
+                    .extend(SoftwareKey::from_pkcs1_der(block.contents())),
+                "EC PRIVATE KEY" => results
+                    .keys
+                    .extend(SoftwareKey::from_sec1_der(block.contents())),
+                tag => debug!("ignoring '{}' in '{}'", tag, path.display()),
+            }
+        }
+    } else if read_certificate_fields(&contents).is_ok() {
+        results.certs.push(FileCert {
+            der: contents,
+            label,
+        });
+    } else if read_encrypted_private_key_info(&contents).is_ok() {
+        results
+            .encrypted_keys
+            .push((path.to_path_buf(), EncryptedKey::Pkcs8(contents)));
+    } else {
+        results.keys.extend(SoftwareKey::from_der(&contents));
+    }
+}
+
+/// Reads all of the files in the given directories. Encrypted keys aren't decrypted.
+fn scan_directories(directories: &[PathBuf], pin: Option<&str>) -> ScanResults {
+    let mut results = ScanResults::default();
+    for directory in directories {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("couldn't read directory '{}': {}", directory.display(), e);
+                continue;
+            }
+        };
+        // Sort the paths so that objects are found in a consistent order.
+        let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+        paths.sort();
+        for path in paths {
+            if path.is_file() {
+                read_file(&path, pin, &mut results);
+            }
+        }
+    }
+    results
+}
+
+/// Reads the certificates and keys in the given PKCS #12 file, adding them to `results`. If the
+/// file needs a password and `pin` isn't it, the file is noted as being locked. Certificates are
+/// labeled with their friendly name if they have one and `label` otherwise.
+fn read_pkcs12_file(
+    path: &Path,
+    contents: &[u8],
+    label: Vec<u8>,
+    pin: Option<&str>,
+    results: &mut ScanResults,
+) {
+    let needs_password = match pkcs12::needs_password(contents) {
+        Ok(needs_password) => needs_password,
+        Err(()) => {
+            warn!("malformed PKCS #12 file '{}'", path.display());
+            return;
+        }
+    };
+    let password = if needs_password {
+        match pin {
+            Some(pin) => Some(pin),
+            None => {
+                results.locked_files.push(path.to_path_buf());
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    let pkcs12_contents = match pkcs12::decode(contents, password) {
+        Ok(pkcs12_contents) => pkcs12_contents,
+        Err(()) => {
+            if needs_password {
+                debug!("couldn't unlock '{}'", path.display());
+                results.locked_files.push(path.to_path_buf());
+            } else {
+                warn!("couldn't decode PKCS #12 file '{}'", path.display());
+            }
+            return;
+        }
+    };
+    for (der, friendly_name) in pkcs12_contents.certs {
+        let label = match friendly_name {
+            Some(friendly_name) => friendly_name.into_bytes(),
+            None => label.clone(),
+        };
+        results.certs.push(FileCert { der, label });
+    }
+    results.keys.extend(pkcs12_contents.keys);
+}
+
+impl Backend for FileBackend {
+    fn token_label(&self) -> String {
+        self.label.clone()
+    }
+
+    fn watched_directories(&self) -> Vec<PathBuf> {
+        self.directories.clone()
+    }
+
+    fn list_identities(&mut self) -> Vec<(Cert, Key)> {
+        let mut results = scan_directories(&self.directories, self.pin.as_deref());
+        let decrypted_keys = self.decrypted_keys.get_mut();
+        // Forget about decrypted keys that have gone away.
+        let encrypted_keys = &results.encrypted_keys;
+        decrypted_keys
+            .retain(|encrypted_key, _| encrypted_keys.iter().any(|(_, key)| key == encrypted_key));
+        let mut any_undecrypted = false;
+        for (path, encrypted_key) in &results.encrypted_keys {
+            match decrypted_keys.get(encrypted_key) {
+                Some(Some(key)) => results.keys.push(key.clone()),
+                None if self.pin.is_some() => any_undecrypted = true,
+                // Either the PIN doesn't decrypt the key or there isn't a PIN yet.
+                _ => results.locked_files.push(path.clone()),
+            }
+        }
+        self.encrypted_keys = results.encrypted_keys;
+        results.locked_files.dedup();
+        self.locked_files = results.locked_files;
+        let mut public_keys = Vec::with_capacity(results.keys.len());
+        for key in results.keys {
+            if let Ok(subject_public_key_info) = key.subject_public_key_info() {
+                public_keys.push((subject_public_key_info, key));
+            }
+        }
+
+        let mut identities = Vec::new();
+        self.keys.clear();
+        self.undecrypted_keys.clear();
+        for cert in results.certs {
+            let fields = match read_certificate_fields(&cert.der) {
+                Ok(fields) => fields,
+                Err(()) => continue,
+            };
+            let software_key = public_keys
+                .iter()
+                .find(|(spki, _)| spki.as_slice() == fields.subject_public_key_info)
+                .map(|(_, software_key)| software_key);
+            let key = match software_key {
+                Some(software_key) => software_key.new_key(&cert.der),
+                None if any_undecrypted => new_undecrypted_key(&cert.der),
+                None => {
+                    debug!("no key found for certificate");
+                    continue;
+                }
+            };
+            let key = match key {
+                Ok(key) => key,
+                Err(()) => continue,
+            };
+            let cert = match Cert::new(
+                cert.der.clone(),
+                cert.label,
+                fields.issuer.to_vec(),
+                fields.serial_number.to_vec(),
+                fields.subject.to_vec(),
+            ) {
+                Ok(cert) => cert,
+                Err(()) => continue,
+            };
+            match software_key {
+                Some(software_key) => {
+                    self.keys.insert(key.id().to_vec(), software_key.clone());
+                }
+                None => {
+                    self.undecrypted_keys
+                        .insert(key.id().to_vec(), fields.subject_public_key_info.to_vec());
+                }
+            }
+            identities.push((cert, key));
+        }
+        identities
+    }
+
+    fn get_signature_length(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<usize, Error> {
+        self.with_software_key(key, |software_key| {
+            software_key.get_signature_length(data, params)
+        })
+    }
+
+    fn sign(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<Vec<u8>, Error> {
+        self.with_software_key(key, |software_key| software_key.sign(data, params))
+    }
+
+    fn decrypt(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<OaepParams>,
+    ) -> Result<Vec<u8>, Error> {
+        self.with_software_key(key, |software_key| software_key.decrypt(data, params))
+    }
+
+    fn login_required(&self) -> bool {
+        !self.locked_files.is_empty()
+    }
+
+    /// Succeeds if the PIN unlocks at least one of the files that were locked as of the last scan
+    /// (or if there weren't any). Files are tried in turn until one is unlocked, so with the right
+    /// PIN, usually only one key is decrypted. The PIN is remembered so that it can be used to
+    /// unlock files on subsequent scans and to decrypt the other keys when they're used.
+    fn login(&mut self, pin: &str) -> Result<(), Error> {
+        let mut decrypted_keys = BTreeMap::new();
+        if !self.locked_files.is_empty()
+            && !self
+                .locked_files
+                .iter()
+                .any(|path| unlocks(path, pin, &mut decrypted_keys))
+        {
+            return Err(Error::PinIncorrect);
+        }
+        self.pin = Some(pin.to_owned());
+        *self.decrypted_keys.get_mut() = decrypted_keys;
+        Ok(())
+    }
+
+    fn logout(&mut self) {
+        self.pin = None;
+        self.undecrypted_keys.clear();
+        self.decrypted_keys.get_mut().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_mock::*;
+    use p256::ecdsa::signature::hazmat::PrehashVerifier;
+    use rsa::pkcs1::EncodeRsaPrivateKey;
+    use rsa::pkcs8::DecodePrivateKey;
+    use rsa::traits::PublicKeyParts;
+    use sha2::Digest;
+    use std::path::Path;
+
+    fn to_pem(tag: &str, der: &[u8]) -> String {
+        pem::encode(&pem::Pem::new(tag, der))
+    }
+
+    fn write_file<P: AsRef<Path>, C: AsRef<[u8]>>(directory: P, name: &str, contents: C) {
+        fs::write(directory.as_ref().join(name), contents).unwrap();
+    }
+
+    fn list_identities(directories: &[&Path]) -> Vec<(Cert, Key)> {
+        let directories = directories.iter().map(|d| d.to_path_buf()).collect();
+        FileBackend::with_directories("test", directories).list_identities()
+    }
+
+    #[test]
+    fn test_pem_cert_and_pkcs8_key_in_one_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let contents = to_pem("CERTIFICATE", RSA_CERT) + &to_pem("PRIVATE KEY", RSA_KEY);
+        write_file(&directory, "client.pem", contents);
+        let identities = list_identities(&[directory.path()]);
+        assert_eq!(identities.len(), 1);
+        let (cert, key) = &identities[0];
+        assert_eq!(cert.id(), id_of(RSA_CERT).as_slice());
+        assert_eq!(key.id(), cert.id());
+        assert!(key.modulus().is_some());
+        let object = Object::Cert(identities.into_iter().next().unwrap().0);
+        assert_eq!(object.get_attribute(CKA_LABEL), Some(&b"client"[..]));
+        assert_eq!(object.get_attribute(CKA_VALUE), Some(RSA_CERT));
+    }
+
+    #[test]
+    fn test_der_files() {
+        let directory = tempfile::tempdir().unwrap();
+        write_file(&directory, "p256.crt", P256_CERT);
+        write_file(&directory, "p256.key", P256_KEY);
+        let identities = list_identities(&[directory.path()]);
+        assert_eq!(identities.len(), 1);
+        let (cert, key) = &identities[0];
+        assert_eq!(cert.id(), id_of(P256_CERT).as_slice());
+        assert_eq!(key.ec_params(), Some(OID_BYTES_SECP256R1));
+    }
+
+    #[test]
+    fn test_traditional_rsa_key() {
+        let directory = tempfile::tempdir().unwrap();
+        let key = rsa::RsaPrivateKey::from_pkcs8_der(RSA_KEY).unwrap();
+        let pkcs1 = key.to_pkcs1_der().unwrap();
+        write_file(&directory, "rsa.crt", to_pem("CERTIFICATE", RSA_CERT));
+        write_file(
+            &directory,
+            "rsa.key",
+            to_pem("RSA PRIVATE KEY", pkcs1.as_bytes()),
+        );
+        write_file(&directory, "rsa-der.key", pkcs1.as_bytes());
+        let identities = list_identities(&[directory.path()]);
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].0.id(), id_of(RSA_CERT).as_slice());
+    }
+
+    #[test]
+    fn test_sec1_keys() {
+        let directory = tempfile::tempdir().unwrap();
+        let p256_key = p256::SecretKey::from_pkcs8_der(P256_KEY).unwrap();
+        let p384_key = p384::SecretKey::from_pkcs8_der(P384_KEY).unwrap();
+        // `openssl ecparam -genkey` outputs the curve parameters before the key.
+        let p256_pem = to_pem("EC PARAMETERS", OID_BYTES_SECP256R1)
+            + &to_pem("EC PRIVATE KEY", &p256_key.to_sec1_der().unwrap());
+        write_file(&directory, "p256.key", p256_pem);
+        write_file(&directory, "p256.crt", to_pem("CERTIFICATE", P256_CERT));
+        write_file(&directory, "p384.key", p384_key.to_sec1_der().unwrap());
+        write_file(&directory, "p384.crt", P384_CERT);
+        let identities = list_identities(&[directory.path()]);
+        assert_eq!(identities.len(), 2);
+        assert_eq!(identities[0].0.id(), id_of(P256_CERT).as_slice());
+        assert_eq!(identities[0].1.ec_params(), Some(OID_BYTES_SECP256R1));
+        assert_eq!(identities[1].0.id(), id_of(P384_CERT).as_slice());
+        assert_eq!(identities[1].1.ec_params(), Some(OID_BYTES_SECP384R1));
+    }
+
+    #[test]
+    fn test_keys_are_paired_by_public_key() {
+        let directory = tempfile::tempdir().unwrap();
+        // All the keys are in one file and all the certificates are in another, in different orders.
+        let keys = to_pem("PRIVATE KEY", P384_KEY)
+            + &to_pem("PRIVATE KEY", RSA_KEY)
+            + &to_pem("PRIVATE KEY", P256_KEY);
+        let certs = to_pem("CERTIFICATE", RSA_CERT)
+            + &to_pem("CERTIFICATE", P256_CERT)
+            + &to_pem("CERTIFICATE", P384_CERT);
+        write_file(&directory, "keys.pem", keys);
+        write_file(&directory, "certs.pem", certs);
+        let mut backend =
+            FileBackend::with_directories("test", vec![directory.path().to_path_buf()]);
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 3);
+        for (cert, key) in &identities {
+            assert_eq!(key.id(), cert.id());
+        }
+        // The key for the secp384r1 certificate is the secp384r1 key.
+        let (_, key) = identities
+            .iter()
+            .find(|(cert, _)| cert.id() == id_of(P384_CERT).as_slice())
+            .unwrap();
+        let hash = sha2::Sha384::digest(b"data");
+        let signature = backend.sign(key, &hash, &None).unwrap();
+        assert_eq!(backend.get_signature_length(key, &hash, &None), Ok(96));
+        let signing_key = p384::ecdsa::SigningKey::from_pkcs8_der(P384_KEY).unwrap();
+        let signature = p384::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(signing_key
+            .verifying_key()
+            .verify_prehash(&hash, &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_rsa_decryption_is_oaep_only() {
+        let directory = tempfile::tempdir().unwrap();
+        write_file(&directory, "rsa.crt", RSA_CERT);
+        write_file(&directory, "rsa.key", RSA_KEY);
+        let mut backend =
+            FileBackend::with_directories("test", vec![directory.path().to_path_buf()]);
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 1);
+        let key = &identities[0].1;
+        let public_key = rsa::RsaPrivateKey::from_pkcs8_der(RSA_KEY)
+            .unwrap()
+            .to_public_key();
+        let mut rng = rsa::rand_core::OsRng;
+        let encrypted = public_key
+            .encrypt(&mut rng, rsa::Oaep::new::<sha2::Sha256>(), b"secret")
+            .unwrap();
+        let oaep_params = Some(OaepParams {
+            hash_algorithm: CKM_SHA256,
+        });
+        assert_eq!(
+            backend.decrypt(key, &encrypted, &oaep_params),
+            Ok(b"secret".to_vec())
+        );
+        // PKCS #1 v1.5 decryption with the `rsa` crate isn't constant-time (RUSTSEC-2023-0071).
+        let encrypted = public_key
+            .encrypt(&mut rng, rsa::Pkcs1v15Encrypt, b"secret")
+            .unwrap();
+        assert_eq!(
+            backend.decrypt(key, &encrypted, &None),
+            Err(Error::MechanismInvalid)
+        );
+    }
+
+    #[test]
+    fn test_unpaired_objects_are_ignored() {
+        let directory = tempfile::tempdir().unwrap();
+        write_file(&directory, "rsa.crt", RSA_CERT);
+        write_file(&directory, "p256.key", P256_KEY);
+        assert!(list_identities(&[directory.path()]).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_files_are_ignored() {
+        let directory = tempfile::tempdir().unwrap();
+        write_file(&directory, "empty", b"");
+        write_file(&directory, "garbage", b"\x30\x03\x02\x01\x00");
+        write_file(&directory, "README", b"these are my certificates");
+        write_file(&directory, "bad.pem", "-----BEGIN CERTIFICATE-----\n!!!\n");
+        write_file(
+            &directory,
+            "bad-cert.pem",
+            to_pem("CERTIFICATE", b"not a certificate"),
+        );
+        write_file(
+            &directory,
+            "bad-key.pem",
+            to_pem("PRIVATE KEY", b"not a key"),
+        );
+        fs::create_dir(directory.path().join("subdirectory")).unwrap();
+        write_file(directory.path().join("subdirectory"), "p256.crt", P256_CERT);
+        write_file(&directory, "p256.crt", P256_CERT);
+        write_file(&directory, "p256.key", P256_KEY);
+        let identities = list_identities(&[directory.path()]);
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].0.id(), id_of(P256_CERT).as_slice());
+    }
+
+    #[test]
+    fn test_multiple_directories() {
+        let user_directory = tempfile::tempdir().unwrap();
+        let system_directory = tempfile::tempdir().unwrap();
+        let missing_directory = user_directory.path().join("missing");
+        write_file(&user_directory, "rsa.pem", to_pem("CERTIFICATE", RSA_CERT));
+        write_file(&system_directory, "rsa.key", RSA_KEY);
+        write_file(&system_directory, "p256.crt", P256_CERT);
+        write_file(&system_directory, "p256.key", P256_KEY);
+        let identities = list_identities(&[
+            missing_directory.as_path(),
+            user_directory.path(),
+            system_directory.path(),
+        ]);
+        assert_eq!(identities.len(), 2);
+        assert_eq!(identities[0].0.id(), id_of(RSA_CERT).as_slice());
+        assert_eq!(identities[1].0.id(), id_of(P256_CERT).as_slice());
+    }
+
+    #[test]
+    fn test_rescan() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut backend =
+            FileBackend::with_directories("test", vec![directory.path().to_path_buf()]);
+        assert!(backend.list_identities().is_empty());
+        write_file(&directory, "p256.crt", P256_CERT);
+        write_file(&directory, "p256.key", P256_KEY);
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 1);
+        let (_, key) = &identities[0];
+        assert!(backend.sign(key, &[0; 32], &None).is_ok());
+        fs::remove_file(directory.path().join("p256.key")).unwrap();
+        assert!(backend.list_identities().is_empty());
+        // The key is no longer available.
+        assert!(backend.sign(key, &[0; 32], &None).is_err());
+    }
+
+    #[test]
+    fn test_pkcs12_file_requires_login() {
+        let directory = tempfile::tempdir().unwrap();
+        write_file(&directory, "rsa.p12", include_bytes!("../test/rsa-aes.p12"));
+        let mut backend =
+            FileBackend::with_directories("test", vec![directory.path().to_path_buf()]);
+        assert!(backend.list_identities().is_empty());
+        assert!(backend.login_required());
+        assert!(backend.login("wrong").is_err());
+        assert!(backend.list_identities().is_empty());
+        assert!(backend.login("password").is_ok());
+        let identities = backend.list_identities();
+        assert!(!backend.login_required());
+        assert_eq!(identities.len(), 1);
+        let (cert, key) = &identities[0];
+        assert_eq!(cert.id(), id_of(RSA_CERT).as_slice());
+        assert!(backend.sign(key, &[0; 51], &None).is_ok());
+        let object = Object::Cert(identities.into_iter().next().unwrap().0);
+        assert_eq!(object.get_attribute(CKA_LABEL), Some(&b"Test RSA"[..]));
+        backend.logout();
+        assert!(backend.list_identities().is_empty());
+        assert!(backend.login_required());
+    }
+
+    #[test]
+    fn test_unencrypted_pkcs12_file() {
+        let directory = tempfile::tempdir().unwrap();
+        write_file(
+            &directory,
+            "p256.PFX",
+            include_bytes!("../test/p256-unencrypted.p12"),
+        );
+        let mut backend =
+            FileBackend::with_directories("test", vec![directory.path().to_path_buf()]);
+        let identities = backend.list_identities();
+        assert!(!backend.login_required());
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].0.id(), id_of(P256_CERT).as_slice());
+        // With nothing to unlock, any PIN is accepted.
+        assert!(backend.login("anything").is_ok());
+    }
+
+    #[test]
+    fn test_pkcs12_key_with_separate_cert() {
+        let directory = tempfile::tempdir().unwrap();
+        write_file(
+            &directory,
+            "p384.p12",
+            include_bytes!("../test/p384-3des.p12"),
+        );
+        let mut backend =
+            FileBackend::with_directories("test", vec![directory.path().to_path_buf()]);
+        // The certificate isn't encrypted, but without the key, there is no identity.
+        assert!(backend.list_identities().is_empty());
+        assert!(backend.login_required());
+        assert!(backend.login("password").is_ok());
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].0.id(), id_of(P384_CERT).as_slice());
+        let object = Object::Cert(identities.into_iter().next().unwrap().0);
+        assert_eq!(object.get_attribute(CKA_LABEL), Some(&b"p384"[..]));
+    }
+
+    /// Writes the given certificate and encrypted key to a new directory and checks that the
+    /// identity is only available after logging in with the right password.
+    fn check_encrypted_key(cert_der: &[u8], key_file_name: &str, key_contents: &[u8]) {
+        let directory = tempfile::tempdir().unwrap();
+        write_file(&directory, "cert.der", cert_der);
+        write_file(&directory, key_file_name, key_contents);
+        let mut backend =
+            FileBackend::with_directories("test", vec![directory.path().to_path_buf()]);
+        assert!(backend.list_identities().is_empty());
+        assert!(backend.login_required());
+        assert!(backend.login("wrong").is_err());
+        assert!(backend.login("password").is_ok());
+        let identities = backend.list_identities();
+        assert!(!backend.login_required());
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].0.id(), id_of(cert_der).as_slice());
+        assert!(backend.sign(&identities[0].1, &[0; 32], &None).is_ok());
+        backend.logout();
+        assert!(backend.list_identities().is_empty());
+        assert!(backend.login_required());
+    }
+
+    #[test]
+    fn test_pbes2_pbkdf2_aes_cbc_key() {
+        check_encrypted_key(
+            P256_CERT,
+            "p256.pem",
+            include_bytes!("../test/p256-key-pbes2.pem"),
+        );
+    }
+
+    #[test]
+    fn test_pbes2_scrypt_key() {
+        check_encrypted_key(
+            RSA_CERT,
+            "rsa.pem",
+            include_bytes!("../test/rsa-key-scrypt.pem"),
+        );
+    }
+
+    #[test]
+    fn test_pbes2_aes_gcm_key() {
+        check_encrypted_key(
+            P384_CERT,
+            "p384.pem",
+            include_bytes!("../test/p384-key-gcm.pem"),
+        );
+    }
+
+    #[test]
+    fn test_encrypted_der_key() {
+        let pem = pem::parse(&include_bytes!("../test/p256-key-pbes2.pem")[..]).unwrap();
+        check_encrypted_key(P256_CERT, "p256.key", pem.contents());
+    }
+
+    #[test]
+    fn test_legacy_encrypted_pem_keys() {
+        check_encrypted_key(
+            RSA_CERT,
+            "rsa.key",
+            include_bytes!("../test/rsa-key-legacy.pem"),
+        );
+        check_encrypted_key(
+            P384_CERT,
+            "p384.key",
+            include_bytes!("../test/p384-key-legacy.pem"),
+        );
+    }
+
+    #[test]
+    fn test_keys_are_decrypted_once() {
+        let directory = tempfile::tempdir().unwrap();
+        write_file(&directory, "p256.crt", P256_CERT);
+        write_file(
+            &directory,
+            "p256.key",
+            include_bytes!("../test/p256-key-pbes2.pem"),
+        );
+        let mut backend =
+            FileBackend::with_directories("test", vec![directory.path().to_path_buf()]);
+        backend.list_identities();
+        assert!(backend.decrypted_keys.borrow().is_empty());
+        assert!(backend.login("password").is_ok());
+        assert_eq!(backend.decrypted_keys.borrow().len(), 1);
+        assert_eq!(backend.list_identities().len(), 1);
+        assert_eq!(backend.list_identities().len(), 1);
+        assert_eq!(backend.decrypted_keys.borrow().len(), 1);
+        // Once the file is gone, so is the decrypted key.
+        fs::remove_file(directory.path().join("p256.key")).unwrap();
+        assert!(backend.list_identities().is_empty());
+        assert!(backend.decrypted_keys.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_keys_are_decrypted_when_first_used() {
+        let directory = tempfile::tempdir().unwrap();
+        write_file(&directory, "p256.crt", P256_CERT);
+        write_file(&directory, "p384.crt", P384_CERT);
+        write_file(&directory, "rsa.crt", RSA_CERT);
+        write_file(
+            &directory,
+            "p256.key",
+            include_bytes!("../test/p256-key-pbes2.pem"),
+        );
+        write_file(
+            &directory,
+            "rsa.key",
+            include_bytes!("../test/rsa-key-scrypt.pem"),
+        );
+        let mut backend =
+            FileBackend::with_directories("test", vec![directory.path().to_path_buf()]);
+        assert!(backend.list_identities().is_empty());
+        // Logging in only decrypts the key it takes to check the PIN.
+        assert!(backend.login("password").is_ok());
+        assert_eq!(backend.decrypted_keys.borrow().len(), 1);
+        // Until the RSA key is decrypted, it could go with either of the other certificates (there
+        // is no key for the P-384 one).
+        let identities = backend.list_identities();
+        assert!(!backend.login_required());
+        assert_eq!(identities.len(), 3);
+        let find_key = |cert_der| {
+            identities
+                .iter()
+                .find(|(cert, _)| cert.id() == id_of(cert_der).as_slice())
+                .map(|(_, key)| key)
+                .unwrap()
+        };
+        let rsa_key = find_key(RSA_CERT);
+        let rsa_public_key = rsa::RsaPrivateKey::from_pkcs8_der(RSA_KEY)
+            .unwrap()
+            .to_public_key();
+        assert_eq!(
+            rsa_key.modulus(),
+            Some(rsa_public_key.n().to_bytes_be().as_slice())
+        );
+        assert_eq!(
+            backend.sign(find_key(P384_CERT), &[0; 48], &None),
+            Err(Error::BackendFailure)
+        );
+        assert_eq!(backend.decrypted_keys.borrow().len(), 2);
+        assert!(backend.sign(rsa_key, &[0; 32], &None).is_ok());
+        assert_eq!(backend.decrypted_keys.borrow().len(), 2);
+        // Now that every key has been decrypted, the P-384 certificate is known to have no key.
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 2);
+        assert!(identities
+            .iter()
+            .all(|(cert, _)| cert.id() != id_of(P384_CERT).as_slice()));
+        backend.logout();
+        assert!(backend.decrypted_keys.borrow().is_empty());
+        assert!(backend.list_identities().is_empty());
+        assert!(backend.login_required());
+    }
+}
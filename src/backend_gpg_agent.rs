@@ -0,0 +1,1172 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use pkcs11::types::*;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPublicKey;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::backend::*;
+use crate::backend_file::{default_directories, find_certificates};
+use crate::error::Error;
+use crate::util::pkcs::*;
+use crate::util::*;
+
+/// The environment variable that overrides the location of GnuPG's home directory.
+const GNUPGHOME: &str = "GNUPGHOME";
+/// The name of the agent's socket in GnuPG's home directory (if gpgconf doesn't say otherwise).
+const AGENT_SOCKET_NAME: &str = "S.gpg-agent";
+/// The name of the keybox gpgsm stores certificates in, in GnuPG's home directory.
+const KEYBOX_NAME: &str = "pubring.kbx";
+
+/// How long to wait for the agent to respond. The agent may ask the user for the passphrase of a
+/// key before signing with it, so this is fairly generous.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(300);
+/// The largest amount of data we'll accept in a response from the agent.
+const MAX_RESPONSE_LENGTH: usize = 64 * 1024;
+/// How deeply nested the S-expressions sent by the agent may be.
+const MAX_SEXP_DEPTH: usize = 8;
+
+// Hash algorithm identifiers from libgcrypt, as used by SETHASH.
+const GCRY_MD_SHA1: u8 = 2;
+const GCRY_MD_SHA256: u8 = 8;
+const GCRY_MD_SHA384: u8 = 9;
+const GCRY_MD_SHA512: u8 = 10;
+
+/// The GnuPG error codes that mean that the user cancelled a prompt.
+const GPG_ERR_CANCELED: u32 = 99;
+const GPG_ERR_FULLY_CANCELED: u32 = 198;
+
+/// The type of keybox blobs that hold X.509 certificates.
+const KEYBOX_BLOB_TYPE_X509: u8 = 3;
+/// The keybox blob flag marking blobs that aren't meant to be used (e.g. certificates gpgsm has
+/// only looked up temporarily).
+const KEYBOX_BLOB_FLAG_EPHEMERAL: u16 = 2;
+
+// The parameters of the P-256 and P-384 curves, in the form libgcrypt uses to compute keygrips.
+const P256_P: &[u8] = &[
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+const P256_A: &[u8] = &[
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfc,
+];
+const P256_B: &[u8] = &[
+    0x5a, 0xc6, 0x35, 0xd8, 0xaa, 0x3a, 0x93, 0xe7, 0xb3, 0xeb, 0xbd, 0x55, 0x76, 0x98, 0x86, 0xbc,
+    0x65, 0x1d, 0x06, 0xb0, 0xcc, 0x53, 0xb0, 0xf6, 0x3b, 0xce, 0x3c, 0x3e, 0x27, 0xd2, 0x60, 0x4b,
+];
+const P256_G: &[u8] = &[
+    0x04, 0x6b, 0x17, 0xd1, 0xf2, 0xe1, 0x2c, 0x42, 0x47, 0xf8, 0xbc, 0xe6, 0xe5, 0x63, 0xa4, 0x40,
+    0xf2, 0x77, 0x03, 0x7d, 0x81, 0x2d, 0xeb, 0x33, 0xa0, 0xf4, 0xa1, 0x39, 0x45, 0xd8, 0x98, 0xc2,
+    0x96, 0x4f, 0xe3, 0x42, 0xe2, 0xfe, 0x1a, 0x7f, 0x9b, 0x8e, 0xe7, 0xeb, 0x4a, 0x7c, 0x0f, 0x9e,
+    0x16, 0x2b, 0xce, 0x33, 0x57, 0x6b, 0x31, 0x5e, 0xce, 0xcb, 0xb6, 0x40, 0x68, 0x37, 0xbf, 0x51,
+    0xf5,
+];
+const P256_N: &[u8] = &[
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xbc, 0xe6, 0xfa, 0xad, 0xa7, 0x17, 0x9e, 0x84, 0xf3, 0xb9, 0xca, 0xc2, 0xfc, 0x63, 0x25, 0x51,
+];
+const P384_P: &[u8] = &[
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff,
+];
+const P384_A: &[u8] = &[
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xfc,
+];
+const P384_B: &[u8] = &[
+    0xb3, 0x31, 0x2f, 0xa7, 0xe2, 0x3e, 0xe7, 0xe4, 0x98, 0x8e, 0x05, 0x6b, 0xe3, 0xf8, 0x2d, 0x19,
+    0x18, 0x1d, 0x9c, 0x6e, 0xfe, 0x81, 0x41, 0x12, 0x03, 0x14, 0x08, 0x8f, 0x50, 0x13, 0x87, 0x5a,
+    0xc6, 0x56, 0x39, 0x8d, 0x8a, 0x2e, 0xd1, 0x9d, 0x2a, 0x85, 0xc8, 0xed, 0xd3, 0xec, 0x2a, 0xef,
+];
+const P384_G: &[u8] = &[
+    0x04, 0xaa, 0x87, 0xca, 0x22, 0xbe, 0x8b, 0x05, 0x37, 0x8e, 0xb1, 0xc7, 0x1e, 0xf3, 0x20, 0xad,
+    0x74, 0x6e, 0x1d, 0x3b, 0x62, 0x8b, 0xa7, 0x9b, 0x98, 0x59, 0xf7, 0x41, 0xe0, 0x82, 0x54, 0x2a,
+    0x38, 0x55, 0x02, 0xf2, 0x5d, 0xbf, 0x55, 0x29, 0x6c, 0x3a, 0x54, 0x5e, 0x38, 0x72, 0x76, 0x0a,
+    0xb7, 0x36, 0x17, 0xde, 0x4a, 0x96, 0x26, 0x2c, 0x6f, 0x5d, 0x9e, 0x98, 0xbf, 0x92, 0x92, 0xdc,
+    0x29, 0xf8, 0xf4, 0x1d, 0xbd, 0x28, 0x9a, 0x14, 0x7c, 0xe9, 0xda, 0x31, 0x13, 0xb5, 0xf0, 0xb8,
+    0xc0, 0x0a, 0x60, 0xb1, 0xce, 0x1d, 0x7e, 0x81, 0x9d, 0x7a, 0x43, 0x1d, 0x7c, 0x90, 0xea, 0x0e,
+    0x5f,
+];
+const P384_N: &[u8] = &[
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc7, 0x63, 0x4d, 0x81, 0xf4, 0x37, 0x2d, 0xdf,
+    0x58, 0x1a, 0x0d, 0xb2, 0x48, 0xb0, 0xa7, 0x7a, 0xec, 0xec, 0x19, 0x6a, 0xcc, 0xc5, 0x29, 0x73,
+];
+
+/// Computes the keygrip of an ECC key: the SHA-1 hash of the parameters of its curve and its public
+/// point, each encoded like an element of an S-expression. The cofactor isn't included.
+fn ecc_keygrip(curve_parameters: &[(&str, &[u8])], public_point: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for (name, value) in curve_parameters
+        .iter()
+        .chain(std::iter::once(&("q", public_point)))
+    {
+        hasher.update(format!("(1:{}{}:", name, value.len()).as_bytes());
+        hasher.update(value);
+        hasher.update(b")");
+    }
+    hasher.finalize().into()
+}
+
+/// The types of keys supported by this backend. For RSA, the value is the length of the modulus in
+/// bytes. For ECDSA, the value is the width in bytes of each coordinate of a point on the key's
+/// curve.
+#[derive(Clone, Copy)]
+enum GpgKeyType {
+    Rsa(usize),
+    Ecdsa(usize),
+}
+
+/// A key the agent has.
+struct GpgKey {
+    /// The keygrip of the key, in hexadecimal. This is how keys are identified to the agent.
+    keygrip: String,
+    key_type: GpgKeyType,
+    /// For RSA keys, the modulus. For ECDSA keys, the DER encoding of the OID of the curve.
+    modulus_or_ec_params: Vec<u8>,
+    /// The label of the key's certificate, which is shown to the user if the agent asks for the
+    /// key's passphrase. This is empty until the certificate is paired with the key.
+    label: Vec<u8>,
+}
+
+impl GpgKey {
+    /// Creates a `GpgKey` for the given DER-encoded SubjectPublicKeyInfo. RSA keys and ECDSA keys on
+    /// the P-256 and P-384 curves are supported.
+    fn new(subject_public_key_info: &[u8]) -> Result<GpgKey, ()> {
+        let (keygrip, key_type, modulus_or_ec_params) = if let Ok(public_key) =
+            RsaPublicKey::from_public_key_der(subject_public_key_info)
+        {
+            // The keygrip of an RSA key is the SHA-1 hash of its modulus as a signed
+            // big-endian integer.
+            let modulus = public_key.n().to_bytes_be();
+            let mut hasher = Sha1::new();
+            if modulus.first().is_some_and(|byte| byte & 0x80 != 0) {
+                hasher.update([0]);
+            }
+            hasher.update(&modulus);
+            let keygrip: [u8; 20] = hasher.finalize().into();
+            (keygrip, GpgKeyType::Rsa(public_key.size()), modulus)
+        } else if let Ok(public_key) = p256::PublicKey::from_public_key_der(subject_public_key_info)
+        {
+            let keygrip = ecc_keygrip(
+                &[
+                    ("p", P256_P),
+                    ("a", P256_A),
+                    ("b", P256_B),
+                    ("g", P256_G),
+                    ("n", P256_N),
+                ],
+                public_key.to_encoded_point(false).as_bytes(),
+            );
+            (keygrip, GpgKeyType::Ecdsa(32), OID_BYTES_SECP256R1.to_vec())
+        } else if let Ok(public_key) = p384::PublicKey::from_public_key_der(subject_public_key_info)
+        {
+            let keygrip = ecc_keygrip(
+                &[
+                    ("p", P384_P),
+                    ("a", P384_A),
+                    ("b", P384_B),
+                    ("g", P384_G),
+                    ("n", P384_N),
+                ],
+                public_key.to_encoded_point(false).as_bytes(),
+            );
+            (keygrip, GpgKeyType::Ecdsa(48), OID_BYTES_SECP384R1.to_vec())
+        } else {
+            return Err(());
+        };
+        Ok(GpgKey {
+            keygrip: keygrip.iter().map(|byte| format!("{:02X}", byte)).collect(),
+            key_type,
+            modulus_or_ec_params,
+            label: Vec::new(),
+        })
+    }
+
+    fn new_key(&self, cert_der: &[u8]) -> Result<Key, ()> {
+        match self.key_type {
+            GpgKeyType::Rsa(_) => Key::new(
+                cert_der,
+                KeyType::RSA,
+                Some(self.modulus_or_ec_params.clone()),
+                None,
+            ),
+            GpgKeyType::Ecdsa(coordinate_width) => Key::new(
+                cert_der,
+                KeyType::EC(coordinate_width),
+                None,
+                Some(self.modulus_or_ec_params.clone()),
+            ),
+        }
+    }
+}
+
+/// Returns the DER encodings of the X.509 certificates in the given gpgsm keybox.
+/// Each blob in a keybox starts with its length (a 32-bit big-endian integer, including the length
+/// itself) and its type. X.509 blobs go on to have a version, some flags, and the offset and length
+/// of the certificate within the blob.
+fn read_keybox_certificates(keybox: &[u8]) -> Result<Vec<Vec<u8>>, ()> {
+    let read_u32 = |bytes: &[u8], offset: usize| -> Result<usize, ()> {
+        let bytes = bytes.get(offset..offset + 4).ok_or(())?;
+        usize::try_from(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .map_err(|_| ())
+    };
+    let mut certs = Vec::new();
+    let mut rest = keybox;
+    while !rest.is_empty() {
+        let length = read_u32(rest, 0)?;
+        if length < 5 || length > rest.len() {
+            return Err(());
+        }
+        let (blob, remainder) = rest.split_at(length);
+        rest = remainder;
+        if blob[4] != KEYBOX_BLOB_TYPE_X509 {
+            continue;
+        }
+        let flags = u16::from_be_bytes([*blob.get(6).ok_or(())?, *blob.get(7).ok_or(())?]);
+        if flags & KEYBOX_BLOB_FLAG_EPHEMERAL != 0 {
+            continue;
+        }
+        let cert_offset = read_u32(blob, 8)?;
+        let cert_length = read_u32(blob, 12)?;
+        let cert_end = cert_offset.checked_add(cert_length).ok_or(())?;
+        certs.push(blob.get(cert_offset..cert_end).ok_or(())?.to_vec());
+    }
+    Ok(certs)
+}
+
+/// A canonical S-expression, which is how the agent encodes signatures.
+#[derive(Debug, PartialEq)]
+enum Sexp<'a> {
+    Atom(&'a [u8]),
+    List(Vec<Sexp<'a>>),
+}
+
+impl<'a> Sexp<'a> {
+    /// Parses a complete canonical S-expression.
+    fn parse(data: &'a [u8]) -> Result<Sexp<'a>, ()> {
+        let (sexp, rest) = Sexp::parse_prefix(data, 0)?;
+        if !rest.is_empty() {
+            return Err(());
+        }
+        Ok(sexp)
+    }
+
+    /// Parses the S-expression at the start of `data`, returning it and what follows it.
+    fn parse_prefix(data: &'a [u8], depth: usize) -> Result<(Sexp<'a>, &'a [u8]), ()> {
+        match data.first() {
+            Some(b'(') => {
+                if depth >= MAX_SEXP_DEPTH {
+                    return Err(());
+                }
+                let mut elements = Vec::new();
+                let mut rest = &data[1..];
+                loop {
+                    if let Some(remainder) = rest.strip_prefix(b")") {
+                        return Ok((Sexp::List(elements), remainder));
+                    }
+                    let (element, remainder) = Sexp::parse_prefix(rest, depth + 1)?;
+                    elements.push(element);
+                    rest = remainder;
+                }
+            }
+            Some(b'1'..=b'9') => {
+                let digits = data.iter().take_while(|byte| byte.is_ascii_digit()).count();
+                let length: usize = std::str::from_utf8(&data[..digits])
+                    .map_err(|_| ())?
+                    .parse()
+                    .map_err(|_| ())?;
+                let rest = data[digits..].strip_prefix(b":").ok_or(())?;
+                if rest.len() < length {
+                    return Err(());
+                }
+                let (atom, rest) = rest.split_at(length);
+                Ok((Sexp::Atom(atom), rest))
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Searches this S-expression for a list of the form `(name value)` and returns `value`.
+    fn find_value(&self, name: &[u8]) -> Option<&'a [u8]> {
+        match self {
+            Sexp::Atom(_) => None,
+            Sexp::List(elements) => match elements.as_slice() {
+                [Sexp::Atom(element_name), Sexp::Atom(value)] if *element_name == name => {
+                    Some(value)
+                }
+                _ => elements.iter().find_map(|element| element.find_value(name)),
+            },
+        }
+    }
+}
+
+/// Strips any leading zeroes from the given big-endian integer and 0-pads it to `length` bytes.
+fn pad_integer(integer: &[u8], length: usize, output: &mut Vec<u8>) -> Result<(), ()> {
+    let leading_zeroes = integer.iter().take_while(|byte| **byte == 0).count();
+    let integer = &integer[leading_zeroes..];
+    if integer.len() > length {
+        return Err(());
+    }
+    output.resize(output.len() + length - integer.len(), 0);
+    output.extend_from_slice(integer);
+    Ok(())
+}
+
+/// Converts a signature S-expression from gpg-agent to the form PKCS #11 uses: the value of s,
+/// padded to the length of the modulus, for RSA, and the concatenation of r and s, each padded to
+/// the width of a coordinate, for ECDSA.
+fn read_signature(signature: &[u8], key_type: GpgKeyType) -> Result<Vec<u8>, ()> {
+    let signature = Sexp::parse(signature).map_err(|_| {
+        error!("malformed signature from gpg-agent");
+    })?;
+    let mut signature_value = Vec::new();
+    match key_type {
+        GpgKeyType::Rsa(modulus_length) => {
+            pad_integer(
+                signature.find_value(b"s").ok_or(())?,
+                modulus_length,
+                &mut signature_value,
+            )?;
+        }
+        GpgKeyType::Ecdsa(coordinate_width) => {
+            pad_integer(
+                signature.find_value(b"r").ok_or(())?,
+                coordinate_width,
+                &mut signature_value,
+            )?;
+            pad_integer(
+                signature.find_value(b"s").ok_or(())?,
+                coordinate_width,
+                &mut signature_value,
+            )?;
+        }
+    }
+    Ok(signature_value)
+}
+
+/// Percent-escapes a string for use as the argument of an Assuan command. If `plus_for_space` is
+/// true, spaces are replaced with '+' (and '+' is escaped), as some commands expect.
+fn assuan_escape(string: &[u8], plus_for_space: bool) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for byte in string {
+        match byte {
+            b' ' if plus_for_space => escaped.push('+'),
+            b'+' if plus_for_space => escaped.push_str("%2B"),
+            b'%' | 0x00..=0x1f | 0x7f..=0xff => escaped.push_str(&format!("%{:02X}", byte)),
+            _ => escaped.push(char::from(*byte)),
+        }
+    }
+    escaped
+}
+
+/// Decodes a percent-escaped string sent by the agent (or by gpgconf).
+fn assuan_unescape(string: &[u8]) -> Result<Vec<u8>, ()> {
+    let mut unescaped = Vec::with_capacity(string.len());
+    let mut bytes = string.iter();
+    while let Some(byte) = bytes.next() {
+        if *byte == b'%' {
+            let hex = [*bytes.next().ok_or(())?, *bytes.next().ok_or(())?];
+            let hex = std::str::from_utf8(&hex).map_err(|_| ())?;
+            unescaped.push(u8::from_str_radix(hex, 16).map_err(|_| ())?);
+        } else {
+            unescaped.push(*byte);
+        }
+    }
+    Ok(unescaped)
+}
+
+/// The outcome of an Assuan command: either the data the agent sent before "OK", or the error the
+/// agent sent.
+enum AssuanResponse {
+    Ok(Vec<u8>),
+    Err(String),
+}
+
+/// A connection to the agent, which speaks the Assuan protocol: the client sends commands one line
+/// at a time, and the server responds with any number of data ("D"), status ("S"), comment ("#"),
+/// and inquiry ("INQUIRE") lines, followed by "OK" or "ERR".
+struct AssuanConnection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl AssuanConnection {
+    /// Connects to the agent at the given socket and waits for its greeting.
+    fn connect(socket_path: &Path) -> Result<AssuanConnection, ()> {
+        let stream = UnixStream::connect(socket_path).map_err(|e| {
+            error!(
+                "couldn't connect to gpg-agent at '{}': {}",
+                socket_path.display(),
+                e
+            )
+        })?;
+        stream
+            .set_read_timeout(Some(RESPONSE_TIMEOUT))
+            .map_err(|_| ())?;
+        let mut connection = AssuanConnection {
+            reader: BufReader::new(stream.try_clone().map_err(|_| ())?),
+            writer: stream,
+        };
+        match connection.read_response()? {
+            AssuanResponse::Ok(_) => Ok(connection),
+            AssuanResponse::Err(e) => {
+                error!("gpg-agent refused the connection: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), ()> {
+        self.writer
+            .write_all(format!("{}\n", line).as_bytes())
+            .map_err(|e| error!("couldn't write to gpg-agent: {}", e))
+    }
+
+    fn read_response(&mut self) -> Result<AssuanResponse, ()> {
+        let mut data = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            self.reader
+                .read_until(b'\n', &mut line)
+                .map_err(|e| error!("couldn't read from gpg-agent: {}", e))?;
+            if line.pop() != Some(b'\n') {
+                error!("gpg-agent closed the connection");
+                return Err(());
+            }
+            if line == b"OK" || line.starts_with(b"OK ") {
+                return Ok(AssuanResponse::Ok(data));
+            } else if let Some(error) = line.strip_prefix(b"ERR ") {
+                return Ok(AssuanResponse::Err(
+                    String::from_utf8_lossy(error).into_owned(),
+                ));
+            } else if let Some(line_data) = line.strip_prefix(b"D ") {
+                data.extend_from_slice(&assuan_unescape(line_data)?);
+                if data.len() > MAX_RESPONSE_LENGTH {
+                    error!("response from gpg-agent is too long");
+                    return Err(());
+                }
+            } else if line.starts_with(b"INQUIRE ") {
+                // The agent only expects an answer to PINENTRY_LAUNCHED, which is a notification.
+                // Anything else it asks for isn't something we have.
+                if line.starts_with(b"INQUIRE PINENTRY_LAUNCHED") {
+                    self.write_line("END")?;
+                } else {
+                    self.write_line("CAN")?;
+                }
+            } else if !(line == b"S" || line.starts_with(b"S ") || line.starts_with(b"#")) {
+                error!("unexpected response from gpg-agent");
+                return Err(());
+            }
+        }
+    }
+
+    /// Sends the given command and returns the agent's response.
+    fn transact(&mut self, command: &str) -> Result<AssuanResponse, ()> {
+        self.write_line(command)?;
+        self.read_response()
+    }
+
+    /// Sends the given command and returns the data the agent sent, if it succeeded.
+    fn command(&mut self, command: &str) -> Result<Vec<u8>, ()> {
+        match self.transact(command)? {
+            AssuanResponse::Ok(data) => Ok(data),
+            AssuanResponse::Err(e) => {
+                let name = command.split(' ').next().unwrap_or_default();
+                error!("gpg-agent {} failed: {}", name, e);
+                Err(())
+            }
+        }
+    }
+}
+
+/// Returns whether or not the given Assuan error (e.g. "83886179 Operation cancelled <Pinentry>")
+/// means that the user cancelled the agent's prompt. The code of a GnuPG error is in its low 16 bits.
+fn is_cancellation(error: &str) -> bool {
+    match error
+        .split(' ')
+        .next()
+        .and_then(|code| code.parse::<u32>().ok())
+    {
+        Some(code) => matches!(code & 0xffff, GPG_ERR_CANCELED | GPG_ERR_FULLY_CANCELED),
+        None => false,
+    }
+}
+
+/// Asks gpgconf where GnuPG's home directory and the agent's socket are. If gpgconf isn't
+/// available, they're assumed to be `$GNUPGHOME` (or `~/.gnupg`) and the `S.gpg-agent` socket in it.
+fn find_gnupg_directories() -> Option<(PathBuf, PathBuf)> {
+    if let Ok(output) = Command::new("gpgconf")
+        .arg("--list-dirs")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+    {
+        let mut homedir = None;
+        let mut socket_path = None;
+        for line in output.stdout.split(|byte| *byte == b'\n') {
+            let (name, value) = match line.iter().position(|byte| *byte == b':') {
+                Some(index) => (&line[..index], &line[index + 1..]),
+                None => continue,
+            };
+            let value = match assuan_unescape(value).map(String::from_utf8) {
+                Ok(Ok(value)) => PathBuf::from(value),
+                _ => continue,
+            };
+            match name {
+                b"homedir" => homedir = Some(value),
+                b"agent-socket" => socket_path = Some(value),
+                _ => {}
+            }
+        }
+        if let (true, Some(homedir), Some(socket_path)) =
+            (output.status.success(), homedir, socket_path)
+        {
+            return Some((homedir, socket_path));
+        }
+    }
+    let homedir = match std::env::var_os(GNUPGHOME) {
+        Some(homedir) => PathBuf::from(homedir),
+        None => Path::new(&std::env::var_os("HOME")?).join(".gnupg"),
+    };
+    let socket_path = homedir.join(AGENT_SOCKET_NAME);
+    Some((homedir, socket_path))
+}
+
+/// A backend that uses keys held by gpg-agent, which is where GnuPG (including gpgsm, its S/MIME
+/// tool) keeps secret keys. The agent identifies keys by their keygrip (a hash of the public key),
+/// so certificates are found in gpgsm's keybox and in the directories the `FileBackend` uses, and
+/// each one whose keygrip the agent has is exposed.
+/// The agent may ask the user for a key's passphrase (via pinentry) when signing with it.
+pub struct GpgAgentBackend {
+    /// The path of the agent's socket.
+    socket_path: PathBuf,
+    /// The directories to search for certificates.
+    directories: Vec<PathBuf>,
+    /// The path of gpgsm's keybox, if it should be searched for certificates.
+    keybox_path: Option<PathBuf>,
+    /// A map of key identifiers to the keys they identify.
+    keys: BTreeMap<Vec<u8>, GpgKey>,
+}
+
+impl GpgAgentBackend {
+    /// Creates a `GpgAgentBackend` that uses the user's gpg-agent, with certificates from gpgsm's
+    /// keybox and the default directories, if GnuPG's home directory exists.
+    pub fn new() -> Option<GpgAgentBackend> {
+        GpgAgentBackend::with_options(None, None, None)
+    }
+
+    /// Creates a `GpgAgentBackend` that uses the agent with the given socket, with certificates from
+    /// the given keybox and directories. Anything that isn't given is found as in `new` (if GnuPG's
+    /// home directory exists).
+    pub fn with_options(
+        socket_path: Option<PathBuf>,
+        keybox_path: Option<PathBuf>,
+        directories: Option<Vec<PathBuf>>,
+    ) -> Option<GpgAgentBackend> {
+        let directories = directories.unwrap_or_else(default_directories);
+        if let (Some(socket_path), Some(keybox_path)) = (&socket_path, &keybox_path) {
+            return Some(GpgAgentBackend::with_socket_path(
+                socket_path.clone(),
+                directories,
+                Some(keybox_path.clone()),
+            ));
+        }
+        let (homedir, default_socket_path) = find_gnupg_directories()?;
+        if !homedir.is_dir() {
+            return None;
+        }
+        Some(GpgAgentBackend::with_socket_path(
+            socket_path.unwrap_or(default_socket_path),
+            directories,
+            Some(keybox_path.unwrap_or_else(|| homedir.join(KEYBOX_NAME))),
+        ))
+    }
+
+    /// Creates a `GpgAgentBackend` that uses the agent with the given socket and certificates from
+    /// the given directories and keybox.
+    pub fn with_socket_path(
+        socket_path: PathBuf,
+        directories: Vec<PathBuf>,
+        keybox_path: Option<PathBuf>,
+    ) -> GpgAgentBackend {
+        GpgAgentBackend {
+            socket_path,
+            directories,
+            keybox_path,
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the certificates in the keybox and the directories, along with their labels.
+    /// Certificates in the keybox are labeled with the keygrip of their key.
+    fn find_certificates(&self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        let mut certs = Vec::new();
+        if let Some(keybox_path) = &self.keybox_path {
+            match std::fs::read(keybox_path) {
+                Ok(keybox) => match read_keybox_certificates(&keybox) {
+                    Ok(keybox_certs) => {
+                        certs.extend(keybox_certs.into_iter().map(|der| (der, None)))
+                    }
+                    Err(()) => warn!("malformed keybox '{}'", keybox_path.display()),
+                },
+                Err(e) => debug!("couldn't read '{}': {}", keybox_path.display(), e),
+            }
+        }
+        certs.extend(
+            find_certificates(&self.directories)
+                .into_iter()
+                .map(|cert| (cert.der, Some(cert.label))),
+        );
+        certs
+    }
+
+    /// Connects to the agent and tells it which key to sign with and what to sign (the hash, in
+    /// hexadecimal, with the given libgcrypt hash algorithm), so that PKSIGN can be sent next.
+    fn prepare_signature(
+        &self,
+        gpg_key: &GpgKey,
+        hash_algorithm: u8,
+        hash: &str,
+    ) -> Result<AssuanConnection, ()> {
+        let mut connection = AssuanConnection::connect(&self.socket_path)?;
+        connection.command(&format!("SIGKEY {}", gpg_key.keygrip))?;
+        let description = format!(
+            "Please enter the passphrase to unlock the key for the certificate \"{}\".",
+            String::from_utf8_lossy(&gpg_key.label)
+        );
+        connection.command(&format!(
+            "SETKEYDESC {}",
+            assuan_escape(description.as_bytes(), true)
+        ))?;
+        connection.command(&format!("SETHASH {} {}", hash_algorithm, hash))?;
+        Ok(connection)
+    }
+}
+
+impl Backend for GpgAgentBackend {
+    fn token_label(&self) -> String {
+        String::from("GnuPG Agent")
+    }
+
+    fn list_identities(&mut self) -> Vec<(Cert, Key)> {
+        self.keys.clear();
+        let certs = self.find_certificates();
+        if certs.is_empty() {
+            return Vec::new();
+        }
+        let mut connection = match AssuanConnection::connect(&self.socket_path) {
+            Ok(connection) => connection,
+            Err(()) => return Vec::new(),
+        };
+        let mut seen_certs = BTreeSet::new();
+        let mut identities = Vec::new();
+        for (cert_der, label) in certs {
+            if !seen_certs.insert(cert_der.clone()) {
+                continue;
+            }
+            let fields = match read_certificate_fields(&cert_der) {
+                Ok(fields) => fields,
+                Err(()) => continue,
+            };
+            let mut gpg_key = match GpgKey::new(fields.subject_public_key_info) {
+                Ok(gpg_key) => gpg_key,
+                Err(()) => continue,
+            };
+            match connection.transact(&format!("HAVEKEY {}", gpg_key.keygrip)) {
+                Ok(AssuanResponse::Ok(_)) => {}
+                Ok(AssuanResponse::Err(_)) => continue,
+                Err(()) => break,
+            }
+            gpg_key.label = label.unwrap_or_else(|| gpg_key.keygrip.clone().into_bytes());
+            let key = match gpg_key.new_key(&cert_der) {
+                Ok(key) => key,
+                Err(()) => continue,
+            };
+            let cert = match Cert::new(
+                cert_der.clone(),
+                gpg_key.label.clone(),
+                fields.issuer.to_vec(),
+                fields.serial_number.to_vec(),
+                fields.subject.to_vec(),
+            ) {
+                Ok(cert) => cert,
+                Err(()) => continue,
+            };
+            self.keys.insert(key.id().to_vec(), gpg_key);
+            identities.push((cert, key));
+        }
+        identities
+    }
+
+    fn get_signature_length(
+        &self,
+        key: &Key,
+        _data: &[u8],
+        _params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<usize, Error> {
+        match self
+            .keys
+            .get(key.id())
+            .ok_or(Error::KeyHandleInvalid)?
+            .key_type
+        {
+            GpgKeyType::Rsa(modulus_length) => Ok(modulus_length),
+            GpgKeyType::Ecdsa(coordinate_width) => Ok(2 * coordinate_width),
+        }
+    }
+
+    /// Asks the agent to sign the given hash. For RSA keys, `data` is a DigestInfo, which the agent
+    /// reconstructs from the hash and its algorithm. RSA-PSS isn't supported. For ECDSA keys, the
+    /// hash algorithm is inferred from the length of `data`.
+    fn sign(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<Vec<u8>, Error> {
+        let gpg_key = self.keys.get(key.id()).ok_or(Error::KeyHandleInvalid)?;
+        if params.is_some() {
+            error!("gpg-agent can't sign with RSA-PSS");
+            return Err(Error::MechanismInvalid);
+        }
+        let (hash_algorithm, hash) = match gpg_key.key_type {
+            GpgKeyType::Rsa(_) => {
+                let (algorithm, hash) = read_digest_info(data).map_err(|()| Error::DataInvalid)?;
+                let hash_algorithm = match algorithm {
+                    OID_BYTES_SHA1 => GCRY_MD_SHA1,
+                    OID_BYTES_SHA256 => GCRY_MD_SHA256,
+                    OID_BYTES_SHA384 => GCRY_MD_SHA384,
+                    OID_BYTES_SHA512 => GCRY_MD_SHA512,
+                    _ => {
+                        error!("unsupported hash algorithm for gpg-agent");
+                        return Err(Error::DataInvalid);
+                    }
+                };
+                (hash_algorithm, hash)
+            }
+            GpgKeyType::Ecdsa(_) => {
+                let hash_algorithm = match data.len() {
+                    20 => GCRY_MD_SHA1,
+                    32 => GCRY_MD_SHA256,
+                    48 => GCRY_MD_SHA384,
+                    64 => GCRY_MD_SHA512,
+                    _ => {
+                        error!("unsupported hash length for gpg-agent: {}", data.len());
+                        return Err(Error::DataLenRange);
+                    }
+                };
+                (hash_algorithm, data)
+            }
+        };
+        let hash: String = hash.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let mut connection = self
+            .prepare_signature(gpg_key, hash_algorithm, &hash)
+            .map_err(|()| Error::BackendFailure)?;
+        let response = connection
+            .transact("PKSIGN")
+            .map_err(|()| Error::BackendFailure)?;
+        let signature = match response {
+            AssuanResponse::Ok(signature) => signature,
+            AssuanResponse::Err(e) if is_cancellation(&e) => {
+                debug!("gpg-agent PKSIGN was cancelled: {}", e);
+                return Err(Error::Cancelled);
+            }
+            AssuanResponse::Err(e) => {
+                error!("gpg-agent PKSIGN failed: {}", e);
+                return Err(Error::BackendFailure);
+            }
+        };
+        read_signature(&signature, gpg_key.key_type).map_err(|()| Error::BackendFailure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_mock::*;
+    use crate::digest::encode_digest_info;
+    use crate::software_key::SoftwareKey;
+    use p256::ecdsa::signature::hazmat::PrehashVerifier;
+    use rsa::pkcs8::DecodePrivateKey;
+    use rsa::traits::PrivateKeyParts;
+    use rsa::{Pkcs1v15Sign, RsaPrivateKey};
+    use sha2::{Sha256, Sha384, Sha512};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    /// Appends the given bytes to `output` as an atom of a canonical S-expression.
+    fn write_atom(output: &mut Vec<u8>, bytes: &[u8]) {
+        output.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+        output.extend_from_slice(bytes);
+    }
+
+    /// Appends a `(name value)` list to `output`, where `value` is an unsigned big-endian integer
+    /// encoded the way libgcrypt expects (as a signed integer with no unnecessary leading zeroes).
+    fn write_integer(output: &mut Vec<u8>, name: &str, value: &[u8]) {
+        let leading_zeroes = value.iter().take_while(|byte| **byte == 0).count();
+        let mut integer = Vec::new();
+        if value
+            .get(leading_zeroes)
+            .is_some_and(|byte| byte & 0x80 != 0)
+        {
+            integer.push(0);
+        }
+        integer.extend_from_slice(&value[leading_zeroes..]);
+        output.push(b'(');
+        write_atom(output, name.as_bytes());
+        write_atom(output, &integer);
+        output.push(b')');
+    }
+
+    /// Converts a PKCS #8 private key to the (unprotected) S-expression gpg-agent stores it as.
+    fn to_gpg_private_key(pkcs8: &[u8]) -> Vec<u8> {
+        let mut sexp = b"(11:private-key".to_vec();
+        if let Ok(key) = RsaPrivateKey::from_pkcs8_der(pkcs8) {
+            // libgcrypt's p and q are the other way around from PKCS #1's, so that its u (the
+            // inverse of p mod q) is PKCS #1's coefficient (the inverse of q mod p).
+            sexp.extend_from_slice(b"(3:rsa");
+            write_integer(&mut sexp, "n", &key.n().to_bytes_be());
+            write_integer(&mut sexp, "e", &key.e().to_bytes_be());
+            write_integer(&mut sexp, "d", &key.d().to_bytes_be());
+            write_integer(&mut sexp, "p", &key.primes()[1].to_bytes_be());
+            write_integer(&mut sexp, "q", &key.primes()[0].to_bytes_be());
+            let coefficient = key.crt_coefficient().unwrap();
+            write_integer(&mut sexp, "u", &coefficient.to_bytes_be());
+        } else {
+            let (curve, public_point, secret) =
+                if let Ok(key) = p256::SecretKey::from_pkcs8_der(pkcs8) {
+                    (
+                        "NIST P-256",
+                        key.public_key().to_encoded_point(false).as_bytes().to_vec(),
+                        key.to_bytes().to_vec(),
+                    )
+                } else {
+                    let key = p384::SecretKey::from_pkcs8_der(pkcs8).unwrap();
+                    (
+                        "NIST P-384",
+                        key.public_key().to_encoded_point(false).as_bytes().to_vec(),
+                        key.to_bytes().to_vec(),
+                    )
+                };
+            sexp.extend_from_slice(b"(3:ecc(5:curve");
+            write_atom(&mut sexp, curve.as_bytes());
+            sexp.extend_from_slice(b")(1:q");
+            write_atom(&mut sexp, &public_point);
+            sexp.push(b')');
+            write_integer(&mut sexp, "d", &secret);
+        }
+        sexp.extend_from_slice(b"))");
+        sexp
+    }
+
+    fn keygrip_of_key(pkcs8: &[u8]) -> String {
+        let key = SoftwareKey::from_pkcs8_der(pkcs8).unwrap();
+        GpgKey::new(&key.subject_public_key_info().unwrap())
+            .unwrap()
+            .keygrip
+    }
+
+    fn keygrip_of_cert(cert_der: &[u8]) -> String {
+        let fields = read_certificate_fields(cert_der).unwrap();
+        GpgKey::new(fields.subject_public_key_info).unwrap().keygrip
+    }
+
+    /// A gpg-agent run for the duration of a test with its own home directory, along with a
+    /// directory of certificates.
+    struct TestAgent {
+        homedir: TempDir,
+        cert_directory: TempDir,
+        socket_path: PathBuf,
+    }
+
+    impl TestAgent {
+        /// Starts a gpg-agent with the given PKCS #8 keys, writes the given certificates to a
+        /// directory, and imports the given keybox certificates with gpgsm. Panics if gpg-agent
+        /// (or gpgsm, if there are keybox certificates) isn't installed.
+        fn new(keys: &[&[u8]], certs: &[&[u8]], keybox_certs: &[&[u8]]) -> TestAgent {
+            let homedir = tempfile::tempdir().unwrap();
+            fs::set_permissions(homedir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+            let private_keys_directory = homedir.path().join("private-keys-v1.d");
+            fs::create_dir(&private_keys_directory).unwrap();
+            for key in keys {
+                fs::write(
+                    private_keys_directory.join(format!("{}.key", keygrip_of_key(key))),
+                    to_gpg_private_key(key),
+                )
+                .unwrap();
+            }
+            let status = Command::new("gpg-agent")
+                .arg("--homedir")
+                .arg(homedir.path())
+                .arg("--daemon")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .expect("couldn't run gpg-agent");
+            assert!(status.success());
+            let output = Command::new("gpgconf")
+                .arg("--homedir")
+                .arg(homedir.path())
+                .arg("--list-dirs")
+                .arg("agent-socket")
+                .output()
+                .unwrap();
+            let socket_path = assuan_unescape(output.stdout.trim_ascii_end()).unwrap();
+            let test_agent = TestAgent {
+                homedir,
+                cert_directory: tempfile::tempdir().unwrap(),
+                socket_path: PathBuf::from(String::from_utf8(socket_path).unwrap()),
+            };
+            for (index, cert) in certs.iter().enumerate() {
+                let cert_path = test_agent
+                    .cert_directory
+                    .path()
+                    .join(format!("{}.crt", index));
+                fs::write(cert_path, cert).unwrap();
+            }
+            for cert in keybox_certs {
+                let cert_path = test_agent.homedir.path().join("import.crt");
+                fs::write(&cert_path, cert).unwrap();
+                // gpgsm may complain about the test certificates (e.g. because the P-384 one is
+                // signed with a SHA-256 hash), but it imports them anyway.
+                Command::new("gpgsm")
+                    .arg("--homedir")
+                    .arg(test_agent.homedir.path())
+                    .arg("--batch")
+                    .arg("--import")
+                    .arg(&cert_path)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .expect("couldn't run gpgsm");
+            }
+            if !keybox_certs.is_empty() {
+                assert!(test_agent.homedir.path().join(KEYBOX_NAME).exists());
+            }
+            test_agent
+        }
+
+        fn backend(&self) -> GpgAgentBackend {
+            GpgAgentBackend::with_socket_path(
+                self.socket_path.clone(),
+                vec![self.cert_directory.path().to_path_buf()],
+                Some(self.homedir.path().join(KEYBOX_NAME)),
+            )
+        }
+    }
+
+    impl Drop for TestAgent {
+        fn drop(&mut self) {
+            let _ = Command::new("gpgconf")
+                .arg("--homedir")
+                .arg(self.homedir.path())
+                .arg("--kill")
+                .arg("gpg-agent")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+        }
+    }
+
+    fn find_identity<'a>(identities: &'a [(Cert, Key)], cert_der: &[u8]) -> &'a (Cert, Key) {
+        identities
+            .iter()
+            .find(|(cert, _)| cert.id() == id_of(cert_der).as_slice())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_keygrips() {
+        // These are the keygrips gpgsm computes for these certificates.
+        assert_eq!(
+            keygrip_of_cert(RSA_CERT),
+            "FCA4D3F7CD952BD4EB45FA540953D59EF329336A"
+        );
+        assert_eq!(
+            keygrip_of_cert(P256_CERT),
+            "97D3D6AC015AA999A881DD350A19A630E6E087F9"
+        );
+        assert_eq!(
+            keygrip_of_cert(P384_CERT),
+            "1B575128B14BDFEAEB9F5C795747C8250FF33A64"
+        );
+        assert_eq!(keygrip_of_key(P256_KEY), keygrip_of_cert(P256_CERT));
+    }
+
+    #[test]
+    fn test_read_keybox_certificates() {
+        let mut keybox = Vec::new();
+        // A header blob.
+        keybox.extend_from_slice(&[0, 0, 0, 32, 1, 1, 0, 0]);
+        keybox.extend_from_slice(b"KBXf");
+        keybox.resize(32, 0);
+        // An X.509 blob with the certificate after some other data.
+        keybox.extend_from_slice(&[0, 0, 0, 24, KEYBOX_BLOB_TYPE_X509, 1, 0, 0]);
+        keybox.extend_from_slice(&[0, 0, 0, 20, 0, 0, 0, 3, 0xaa, 0xbb, 0xcc, 0xdd]);
+        keybox.extend_from_slice(&[1, 2, 3, 0]);
+        // An ephemeral X.509 blob.
+        keybox.extend_from_slice(&[0, 0, 0, 19, KEYBOX_BLOB_TYPE_X509, 1, 0, 2]);
+        keybox.extend_from_slice(&[0, 0, 0, 16, 0, 0, 0, 3, 4, 5, 6]);
+        assert_eq!(read_keybox_certificates(&keybox), Ok(vec![vec![1, 2, 3]]));
+        assert!(read_keybox_certificates(&keybox[..keybox.len() - 1]).is_err());
+        // The certificate runs past the end of its blob.
+        keybox[32 + 15] = 5;
+        assert!(read_keybox_certificates(&keybox).is_err());
+    }
+
+    #[test]
+    fn test_sexp() {
+        let signature = b"(7:sig-val(5:ecdsa(1:r2:\x00\x80)(1:s1:\x01)))";
+        let sexp = Sexp::parse(signature).unwrap();
+        assert_eq!(sexp.find_value(b"r"), Some(&b"\x00\x80"[..]));
+        assert_eq!(sexp.find_value(b"s"), Some(&b"\x01"[..]));
+        assert_eq!(sexp.find_value(b"ecdsa"), None);
+        let mut signature_value = Vec::new();
+        pad_integer(sexp.find_value(b"r").unwrap(), 2, &mut signature_value).unwrap();
+        pad_integer(sexp.find_value(b"s").unwrap(), 2, &mut signature_value).unwrap();
+        assert_eq!(signature_value, [0x00, 0x80, 0x00, 0x01]);
+        assert!(pad_integer(&[1, 2, 3], 2, &mut signature_value).is_err());
+
+        assert!(Sexp::parse(b"(1:a").is_err());
+        assert!(Sexp::parse(b"(1:a))").is_err());
+        assert!(Sexp::parse(b"(2:a)").is_err());
+        assert!(Sexp::parse(b"(01:a)").is_err());
+        assert!(Sexp::parse(b"(((((((((())))))))))").is_err());
+    }
+
+    #[test]
+    fn test_assuan_escaping() {
+        assert_eq!(
+            assuan_escape("50% +1\nok é".as_bytes(), true),
+            "50%25+%2B1%0Aok+%C3%A9"
+        );
+        assert_eq!(assuan_escape(b"a b+c", false), "a b+c");
+        assert_eq!(assuan_unescape(b"a%25b%0A%3a"), Ok(b"a%b\n:".to_vec()));
+        assert!(assuan_unescape(b"%2").is_err());
+        assert!(assuan_unescape(b"%zz").is_err());
+    }
+
+    #[test]
+    fn test_identities_are_paired_with_certificates() {
+        let agent = TestAgent::new(
+            &[RSA_KEY, P256_KEY, P384_KEY],
+            &[RSA_CERT, P256_CERT],
+            &[P384_CERT, P256_CERT],
+        );
+        let mut backend = agent.backend();
+        let identities = backend.list_identities();
+        // The P-256 certificate is in both the directory and the keybox, but only appears once.
+        assert_eq!(identities.len(), 3);
+        for (cert, key) in &identities {
+            assert_eq!(cert.id(), key.id());
+        }
+        let (_, key) = find_identity(&identities, P384_CERT);
+        assert_eq!(key.ec_params(), Some(OID_BYTES_SECP384R1));
+        // Certificates from the keybox are labeled with their keygrip. Others are labeled with the
+        // name of their file.
+        for (cert, _) in identities {
+            let id = cert.id().to_vec();
+            let object = Object::Cert(cert);
+            let label = object.get_attribute(CKA_LABEL).unwrap();
+            if id == id_of(P384_CERT) {
+                assert_eq!(label, keygrip_of_cert(P384_CERT).as_bytes());
+            } else if id == id_of(RSA_CERT) {
+                assert_eq!(label, b"0");
+            }
+        }
+    }
+
+    #[test]
+    fn test_certificates_without_keys_are_skipped() {
+        let agent = TestAgent::new(&[P256_KEY], &[RSA_CERT, P256_CERT], &[]);
+        let mut backend = agent.backend();
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].0.id(), id_of(P256_CERT).as_slice());
+    }
+
+    #[test]
+    fn test_sign_rsa() {
+        let agent = TestAgent::new(&[RSA_KEY], &[RSA_CERT], &[]);
+        let mut backend = agent.backend();
+        let identities = backend.list_identities();
+        let (_, key) = find_identity(&identities, RSA_CERT);
+        let public_key = RsaPrivateKey::from_pkcs8_der(RSA_KEY)
+            .unwrap()
+            .to_public_key();
+        for (hash_algorithm, hash) in [
+            (CKM_SHA256, Sha256::digest(b"hello").to_vec()),
+            (CKM_SHA512, Sha512::digest(b"hello").to_vec()),
+        ] {
+            let digest_info = encode_digest_info(hash_algorithm, &hash).unwrap();
+            let signature = backend.sign(key, &digest_info, &None).unwrap();
+            assert_eq!(
+                backend.get_signature_length(key, &digest_info, &None),
+                Ok(signature.len())
+            );
+            assert!(public_key
+                .verify(Pkcs1v15Sign::new_unprefixed(), &digest_info, &signature)
+                .is_ok());
+        }
+        // A bare hash isn't a DigestInfo.
+        assert!(backend.sign(key, &Sha256::digest(b"hello"), &None).is_err());
+    }
+
+    #[test]
+    fn test_sign_ecdsa() {
+        let agent = TestAgent::new(&[P256_KEY, P384_KEY], &[P256_CERT, P384_CERT], &[]);
+        let mut backend = agent.backend();
+        let identities = backend.list_identities();
+
+        let (_, key) = find_identity(&identities, P256_CERT);
+        let hash = Sha256::digest(b"hello");
+        let signature = backend.sign(key, &hash, &None).unwrap();
+        assert_eq!(signature.len(), 64);
+        assert_eq!(backend.get_signature_length(key, &hash, &None), Ok(64));
+        let verifying_key = *p256::ecdsa::SigningKey::from_pkcs8_der(P256_KEY)
+            .unwrap()
+            .verifying_key();
+        let signature = p256::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(verifying_key.verify_prehash(&hash, &signature).is_ok());
+        assert!(backend.sign(key, &[0; 31], &None).is_err());
+
+        let (_, key) = find_identity(&identities, P384_CERT);
+        let hash = Sha384::digest(b"hello");
+        let signature = backend.sign(key, &hash, &None).unwrap();
+        assert_eq!(signature.len(), 96);
+        let verifying_key = *p384::ecdsa::SigningKey::from_pkcs8_der(P384_KEY)
+            .unwrap()
+            .verifying_key();
+        let signature = p384::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(verifying_key.verify_prehash(&hash, &signature).is_ok());
+    }
+
+    #[test]
+    fn test_no_agent() {
+        let directory = tempfile::tempdir().unwrap();
+        fs::write(directory.path().join("rsa.crt"), RSA_CERT).unwrap();
+        let mut backend = GpgAgentBackend::with_socket_path(
+            directory.path().join("S.gpg-agent"),
+            vec![directory.path().to_path_buf()],
+            None,
+        );
+        assert!(backend.list_identities().is_empty());
+    }
+}
@@ -0,0 +1,752 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use pkcs11::types::*;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPublicKey;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::path::PathBuf;
+
+use crate::backend::*;
+use crate::backend_file::{default_directories, find_certificates, FileCert};
+use crate::error::Error;
+use crate::util::pkcs::*;
+use crate::util::*;
+
+/// The type of the kernel's key serial numbers.
+type KeySerial = i32;
+
+// Special keyring identifiers and keyctl operations (see keyctl(2) and linux/keyctl.h).
+const KEY_SPEC_SESSION_KEYRING: KeySerial = -3;
+const KEY_SPEC_USER_KEYRING: KeySerial = -4;
+const KEYCTL_DESCRIBE: libc::c_long = 6;
+const KEYCTL_READ: libc::c_long = 11;
+const KEYCTL_PKEY_QUERY: libc::c_long = 24;
+const KEYCTL_PKEY_DECRYPT: libc::c_long = 26;
+const KEYCTL_PKEY_SIGN: libc::c_long = 27;
+
+/// The bit of `keyctl_pkey_query::supported_ops` that indicates a key can sign.
+const KEYCTL_SUPPORTS_SIGN: u32 = 0x04;
+
+/// How deeply nested keyrings will be searched.
+const MAX_KEYRING_DEPTH: usize = 4;
+
+/// The result of KEYCTL_PKEY_QUERY.
+#[repr(C)]
+#[derive(Default)]
+struct keyctl_pkey_query {
+    supported_ops: u32,
+    key_size: u32,
+    max_data_size: u16,
+    max_sig_size: u16,
+    max_enc_size: u16,
+    max_dec_size: u16,
+    __spare: [u32; 10],
+}
+
+/// The parameters of KEYCTL_PKEY_SIGN (and the other public key operations).
+#[repr(C)]
+#[derive(Default)]
+struct keyctl_pkey_params {
+    key_id: KeySerial,
+    in_len: u32,
+    out_len: u32,
+    __spare: [u32; 7],
+}
+
+/// Calls keyctl with the given operation and arguments, returning the (non-negative) result or the
+/// error that occurred.
+fn keyctl(
+    operation: libc::c_long,
+    arg2: libc::c_long,
+    arg3: libc::c_long,
+    arg4: libc::c_long,
+    arg5: libc::c_long,
+) -> Result<libc::c_long, std::io::Error> {
+    let result = unsafe { libc::syscall(libc::SYS_keyctl, operation, arg2, arg3, arg4, arg5) };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(result)
+}
+
+/// Calls keyctl with an operation that fills a buffer (KEYCTL_READ or KEYCTL_DESCRIBE) and returns
+/// the contents of the buffer. These operations return the size of the whole result, even if the
+/// buffer is too small, so this retries with a bigger buffer as necessary.
+fn keyctl_read_buffer(operation: libc::c_long, key: KeySerial) -> Result<Vec<u8>, std::io::Error> {
+    let mut buffer = Vec::new();
+    loop {
+        let length = keyctl(
+            operation,
+            libc::c_long::from(key),
+            buffer.as_mut_ptr() as libc::c_long,
+            buffer.len() as libc::c_long,
+            0,
+        )? as usize;
+        if length <= buffer.len() {
+            buffer.truncate(length);
+            return Ok(buffer);
+        }
+        buffer.resize(length, 0);
+    }
+}
+
+/// Returns the keys in the given keyring.
+fn read_keyring(keyring: KeySerial) -> Result<Vec<KeySerial>, std::io::Error> {
+    Ok(keyctl_read_buffer(KEYCTL_READ, keyring)?
+        .chunks_exact(4)
+        .map(|serial| KeySerial::from_ne_bytes([serial[0], serial[1], serial[2], serial[3]]))
+        .collect())
+}
+
+/// Returns the type and description of the given key. The kernel describes keys as
+/// "type;uid;gid;perm;description".
+fn describe_key(key: KeySerial) -> Result<(String, String), ()> {
+    let mut description = keyctl_read_buffer(KEYCTL_DESCRIBE, key).map_err(|_| ())?;
+    if description.last() == Some(&0) {
+        description.pop();
+    }
+    let description = String::from_utf8(description).map_err(|_| ())?;
+    let mut fields = description.splitn(5, ';');
+    let key_type = fields.next().ok_or(())?.to_string();
+    let description = fields.nth(3).ok_or(())?.to_string();
+    Ok((key_type, description))
+}
+
+/// Asks the kernel about the given asymmetric key.
+fn query_key(key: KeySerial) -> Result<keyctl_pkey_query, std::io::Error> {
+    let info = CString::default();
+    let mut query = keyctl_pkey_query::default();
+    keyctl(
+        KEYCTL_PKEY_QUERY,
+        libc::c_long::from(key),
+        0,
+        info.as_ptr() as libc::c_long,
+        &mut query as *mut keyctl_pkey_query as libc::c_long,
+    )?;
+    Ok(query)
+}
+
+/// Returns the serial numbers, descriptions, and sizes (in bits) of the asymmetric keys that can
+/// sign in the given keyrings (and the keyrings they contain).
+fn find_signing_keys(keyrings: &[KeySerial]) -> Vec<(KeySerial, String, u32)> {
+    let mut keys = Vec::new();
+    let mut seen = BTreeSet::new();
+    let mut to_search: Vec<(KeySerial, usize)> =
+        keyrings.iter().map(|keyring| (*keyring, 0)).collect();
+    while let Some((keyring, depth)) = to_search.pop() {
+        let serials = match read_keyring(keyring) {
+            Ok(serials) => serials,
+            Err(e) => {
+                debug!("couldn't read keyring {}: {}", keyring, e);
+                continue;
+            }
+        };
+        for serial in serials {
+            if !seen.insert(serial) {
+                continue;
+            }
+            let (key_type, description) = match describe_key(serial) {
+                Ok(key_type_and_description) => key_type_and_description,
+                Err(()) => continue,
+            };
+            match key_type.as_str() {
+                "keyring" if depth < MAX_KEYRING_DEPTH => to_search.push((serial, depth + 1)),
+                "asymmetric" => match query_key(serial) {
+                    Ok(query) if query.supported_ops & KEYCTL_SUPPORTS_SIGN != 0 => {
+                        keys.push((serial, description, query.key_size))
+                    }
+                    Ok(_) => debug!("ignoring key '{}', which can't sign", description),
+                    Err(e) => debug!("couldn't query key '{}': {}", description, e),
+                },
+                _ => {}
+            }
+        }
+    }
+    keys
+}
+
+/// Returns whether or not the given certificate is the one described by `description`. Keys the
+/// kernel creates from X.509 certificates are described as "subject: identifier", where the
+/// identifier is the hex encoding of the certificate's subject key identifier (or of its serial
+/// number, if it has no subject key identifier). Otherwise, a certificate is paired with a key if
+/// its label (i.e. the name of its file) is the key's description.
+fn certificate_matches_description(cert: &FileCert, description: &str) -> bool {
+    if cert.label == description.as_bytes() {
+        return true;
+    }
+    let identifier = match description.rsplit_once(": ") {
+        Some((_, identifier)) => identifier,
+        None => return false,
+    };
+    let to_hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+    match read_subject_key_identifier(&cert.der) {
+        Ok(Some(key_identifier)) => identifier.eq_ignore_ascii_case(&to_hex(key_identifier)),
+        Ok(None) => match read_certificate_fields(&cert.der) {
+            // The serial number is the complete DER encoding of the INTEGER, so skip the tag and
+            // length (the kernel only uses this for serial numbers shorter than 128 bytes).
+            Ok(fields) => {
+                fields.serial_number.len() > 2
+                    && identifier.eq_ignore_ascii_case(&to_hex(&fields.serial_number[2..]))
+            }
+            Err(()) => false,
+        },
+        Err(()) => false,
+    }
+}
+
+/// The types of keys supported by this backend. For RSA, the value is the length of the modulus in
+/// bytes. For ECDSA, the value is the width in bytes of each coordinate of a point on the key's
+/// curve.
+#[derive(Clone, Copy)]
+enum KeyringKeyType {
+    Rsa(usize),
+    Ecdsa(usize),
+}
+
+/// A key in the kernel's keyrings.
+struct KeyringKey {
+    serial: KeySerial,
+    key_type: KeyringKeyType,
+    /// For RSA keys, the modulus. For ECDSA keys, the DER encoding of the OID of the curve.
+    modulus_or_ec_params: Vec<u8>,
+}
+
+impl KeyringKey {
+    /// Creates a `KeyringKey` for the key with the given serial number, which has the public key in
+    /// the given DER-encoded SubjectPublicKeyInfo. RSA keys and ECDSA keys on the P-256 and P-384
+    /// curves are supported.
+    fn new(serial: KeySerial, subject_public_key_info: &[u8]) -> Result<KeyringKey, ()> {
+        let (key_type, modulus_or_ec_params) =
+            if let Ok(public_key) = RsaPublicKey::from_public_key_der(subject_public_key_info) {
+                (
+                    KeyringKeyType::Rsa(public_key.size()),
+                    public_key.n().to_bytes_be(),
+                )
+            } else if p256::PublicKey::from_public_key_der(subject_public_key_info).is_ok() {
+                (KeyringKeyType::Ecdsa(32), OID_BYTES_SECP256R1.to_vec())
+            } else if p384::PublicKey::from_public_key_der(subject_public_key_info).is_ok() {
+                (KeyringKeyType::Ecdsa(48), OID_BYTES_SECP384R1.to_vec())
+            } else {
+                return Err(());
+            };
+        Ok(KeyringKey {
+            serial,
+            key_type,
+            modulus_or_ec_params,
+        })
+    }
+
+    /// Returns the size of the key in bits, as the kernel reports it.
+    fn size_in_bits(&self) -> usize {
+        match self.key_type {
+            KeyringKeyType::Rsa(modulus_length) => 8 * modulus_length,
+            KeyringKeyType::Ecdsa(coordinate_width) => 8 * coordinate_width,
+        }
+    }
+
+    fn new_key(&self, cert_der: &[u8]) -> Result<Key, ()> {
+        match self.key_type {
+            KeyringKeyType::Rsa(_) => Key::new(
+                cert_der,
+                KeyType::RSA,
+                Some(self.modulus_or_ec_params.clone()),
+                None,
+            ),
+            KeyringKeyType::Ecdsa(coordinate_width) => Key::new(
+                cert_der,
+                KeyType::EC(coordinate_width),
+                None,
+                Some(self.modulus_or_ec_params.clone()),
+            ),
+        }
+    }
+}
+
+/// Returns the name the kernel uses for the hash algorithm with the given DER-encoded OID.
+fn hash_algorithm_name(algorithm: &[u8]) -> Result<&'static str, ()> {
+    match algorithm {
+        OID_BYTES_SHA1 => Ok("sha1"),
+        OID_BYTES_SHA256 => Ok("sha256"),
+        OID_BYTES_SHA384 => Ok("sha384"),
+        OID_BYTES_SHA512 => Ok("sha512"),
+        _ => Err(()),
+    }
+}
+
+/// A backend that uses asymmetric keys in the Linux kernel's keyrings (by default, the session and
+/// user keyrings). Signing is done by the kernel, so private keys never enter this process.
+/// The kernel doesn't keep certificates, so certificates are found in the directories the
+/// `FileBackend` uses and paired with keys by their description (see
+/// `certificate_matches_description`).
+pub struct KeyringBackend {
+    /// The keyrings to search for keys.
+    keyrings: Vec<KeySerial>,
+    /// The directories to search for certificates.
+    directories: Vec<PathBuf>,
+    /// A map of key identifiers to the keys they identify.
+    keys: BTreeMap<Vec<u8>, KeyringKey>,
+}
+
+impl KeyringBackend {
+    /// Creates a `KeyringBackend` that uses keys in the session and user keyrings, with
+    /// certificates from the default directories.
+    pub fn new() -> KeyringBackend {
+        KeyringBackend::with_directories(default_directories())
+    }
+
+    /// Creates a `KeyringBackend` that uses keys in the session and user keyrings, with
+    /// certificates from the given directories.
+    pub fn with_directories(directories: Vec<PathBuf>) -> KeyringBackend {
+        KeyringBackend::with_keyrings(
+            vec![KEY_SPEC_SESSION_KEYRING, KEY_SPEC_USER_KEYRING],
+            directories,
+        )
+    }
+
+    /// Creates a `KeyringBackend` that uses keys in the given keyrings, with certificates from the
+    /// given directories.
+    pub fn with_keyrings(keyrings: Vec<KeySerial>, directories: Vec<PathBuf>) -> KeyringBackend {
+        KeyringBackend {
+            keyrings,
+            directories,
+            keys: BTreeMap::new(),
+        }
+    }
+}
+
+impl Backend for KeyringBackend {
+    fn token_label(&self) -> String {
+        String::from("Kernel Keyring")
+    }
+
+    fn list_identities(&mut self) -> Vec<(Cert, Key)> {
+        self.keys.clear();
+        let signing_keys = find_signing_keys(&self.keyrings);
+        if signing_keys.is_empty() {
+            return Vec::new();
+        }
+        let mut identities = Vec::new();
+        for cert in find_certificates(&self.directories) {
+            let fields = match read_certificate_fields(&cert.der) {
+                Ok(fields) => fields,
+                Err(()) => continue,
+            };
+            let keyring_key = match signing_keys.iter().find_map(|(serial, description, size)| {
+                if !certificate_matches_description(&cert, description) {
+                    return None;
+                }
+                let keyring_key = KeyringKey::new(*serial, fields.subject_public_key_info).ok()?;
+                if usize::try_from(*size).ok()? != keyring_key.size_in_bits() {
+                    return None;
+                }
+                Some(keyring_key)
+            }) {
+                Some(keyring_key) => keyring_key,
+                None => continue,
+            };
+            let key = match keyring_key.new_key(&cert.der) {
+                Ok(key) => key,
+                Err(()) => continue,
+            };
+            let cert = match Cert::new(
+                cert.der.clone(),
+                cert.label,
+                fields.issuer.to_vec(),
+                fields.serial_number.to_vec(),
+                fields.subject.to_vec(),
+            ) {
+                Ok(cert) => cert,
+                Err(()) => continue,
+            };
+            self.keys.insert(key.id().to_vec(), keyring_key);
+            identities.push((cert, key));
+        }
+        identities
+    }
+
+    fn get_signature_length(
+        &self,
+        key: &Key,
+        _data: &[u8],
+        _params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<usize, Error> {
+        match self
+            .keys
+            .get(key.id())
+            .ok_or(Error::KeyHandleInvalid)?
+            .key_type
+        {
+            KeyringKeyType::Rsa(modulus_length) => Ok(modulus_length),
+            KeyringKeyType::Ecdsa(coordinate_width) => Ok(2 * coordinate_width),
+        }
+    }
+
+    /// Asks the kernel to sign the given hash. For RSA keys, `data` is a DigestInfo, which the kernel
+    /// reconstructs from the hash and its algorithm. RSA-PSS isn't supported. For ECDSA keys, the
+    /// hash algorithm is inferred from the length of `data`.
+    fn sign(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<Vec<u8>, Error> {
+        let keyring_key = self.keys.get(key.id()).ok_or(Error::KeyHandleInvalid)?;
+        if params.is_some() {
+            error!("the kernel can't sign with RSA-PSS");
+            return Err(Error::MechanismInvalid);
+        }
+        let (info, hash, signature_length) = match keyring_key.key_type {
+            KeyringKeyType::Rsa(modulus_length) => {
+                let (algorithm, hash) = read_digest_info(data).map_err(|()| Error::DataInvalid)?;
+                let info = format!(
+                    "enc=pkcs1 hash={}",
+                    hash_algorithm_name(algorithm).map_err(|()| Error::DataInvalid)?
+                );
+                (info, hash, modulus_length)
+            }
+            KeyringKeyType::Ecdsa(coordinate_width) => {
+                let hash_algorithm = match data.len() {
+                    20 => "sha1",
+                    32 => "sha256",
+                    48 => "sha384",
+                    64 => "sha512",
+                    _ => {
+                        error!("unsupported hash length for ECDSA: {}", data.len());
+                        return Err(Error::DataLenRange);
+                    }
+                };
+                // The signature is a DER-encoded Ecdsa-Sig-Value, which is at most 9 bytes longer
+                // than the two integers it holds.
+                let info = format!("enc=x962 hash={}", hash_algorithm);
+                (info, data, 2 * coordinate_width + 9)
+            }
+        };
+        let info = CString::new(info).map_err(|_| Error::General)?;
+        let mut signature = vec![0; signature_length];
+        let params = keyctl_pkey_params {
+            key_id: keyring_key.serial,
+            in_len: u32::try_from(hash.len()).map_err(|_| Error::DataLenRange)?,
+            out_len: u32::try_from(signature.len()).map_err(|_| Error::General)?,
+            ..Default::default()
+        };
+        let length = keyctl(
+            KEYCTL_PKEY_SIGN,
+            &params as *const keyctl_pkey_params as libc::c_long,
+            info.as_ptr() as libc::c_long,
+            hash.as_ptr() as libc::c_long,
+            signature.as_mut_ptr() as libc::c_long,
+        )
+        .map_err(|e| {
+            error!("keyctl_pkey_sign failed: {}", e);
+            Error::BackendFailure
+        })?;
+        signature.truncate(usize::try_from(length).map_err(|_| Error::BackendFailure)?);
+        match keyring_key.key_type {
+            KeyringKeyType::Rsa(modulus_length) => {
+                if signature.len() != modulus_length {
+                    return Err(Error::BackendFailure);
+                }
+                Ok(signature)
+            }
+            KeyringKeyType::Ecdsa(coordinate_width) => {
+                let (r, s) = read_ec_sig_point(&signature).map_err(|()| Error::BackendFailure)?;
+                if r.len() > coordinate_width || s.len() > coordinate_width {
+                    return Err(Error::BackendFailure);
+                }
+                let mut signature_value = Vec::with_capacity(2 * coordinate_width);
+                signature_value.resize(coordinate_width - r.len(), 0);
+                signature_value.extend_from_slice(r);
+                signature_value.resize(2 * coordinate_width - s.len(), 0);
+                signature_value.extend_from_slice(s);
+                Ok(signature_value)
+            }
+        }
+    }
+
+    /// Asks the kernel to decrypt the given data. Only PKCS #1 v1.5 padding is supported.
+    fn decrypt(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<OaepParams>,
+    ) -> Result<Vec<u8>, Error> {
+        let keyring_key = self.keys.get(key.id()).ok_or(Error::KeyHandleInvalid)?;
+        let modulus_length = match keyring_key.key_type {
+            KeyringKeyType::Rsa(modulus_length) => modulus_length,
+            KeyringKeyType::Ecdsa(_) => return Err(Error::KeyTypeInconsistent),
+        };
+        if params.is_some() {
+            error!("the kernel can't decrypt with RSA-OAEP");
+            return Err(Error::MechanismInvalid);
+        }
+        if data.len() != modulus_length {
+            return Err(Error::EncryptedDataLenRange);
+        }
+        let info = CString::new("enc=pkcs1").map_err(|_| Error::General)?;
+        let mut decrypted = vec![0; modulus_length];
+        let params = keyctl_pkey_params {
+            key_id: keyring_key.serial,
+            in_len: u32::try_from(data.len()).map_err(|_| Error::EncryptedDataLenRange)?,
+            out_len: u32::try_from(decrypted.len()).map_err(|_| Error::General)?,
+            ..Default::default()
+        };
+        let length = keyctl(
+            KEYCTL_PKEY_DECRYPT,
+            &params as *const keyctl_pkey_params as libc::c_long,
+            info.as_ptr() as libc::c_long,
+            data.as_ptr() as libc::c_long,
+            decrypted.as_mut_ptr() as libc::c_long,
+        )
+        .map_err(|e| {
+            error!("keyctl_pkey_decrypt failed: {}", e);
+            // The kernel fails with EBADMSG if the padding is wrong.
+            if e.raw_os_error() == Some(libc::EBADMSG) {
+                Error::EncryptedDataInvalid
+            } else {
+                Error::BackendFailure
+            }
+        })?;
+        decrypted.truncate(usize::try_from(length).map_err(|_| Error::BackendFailure)?);
+        Ok(decrypted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_mock::*;
+    use crate::digest::encode_digest_info;
+    use p256::ecdsa::signature::hazmat::PrehashVerifier;
+    use rsa::pkcs8::DecodePrivateKey;
+    use rsa::{Pkcs1v15Encrypt, Pkcs1v15Sign, RsaPrivateKey};
+    use sha2::{Digest, Sha256, Sha384};
+    use std::fs;
+
+    const KEY_SPEC_THREAD_KEYRING: KeySerial = -1;
+    const KEYCTL_UNLINK: libc::c_long = 9;
+
+    fn add_key(
+        key_type: &str,
+        description: &str,
+        payload: &[u8],
+        keyring: KeySerial,
+    ) -> Result<KeySerial, std::io::Error> {
+        let key_type = CString::new(key_type).unwrap();
+        let description = CString::new(description).unwrap();
+        let payload_pointer = if payload.is_empty() {
+            std::ptr::null()
+        } else {
+            payload.as_ptr()
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_add_key,
+                key_type.as_ptr(),
+                description.as_ptr(),
+                payload_pointer,
+                payload.len(),
+                keyring,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(result as KeySerial)
+    }
+
+    /// A keyring created for the duration of a test (in the keyring of the test's thread), along
+    /// with a directory of certificates.
+    struct TestKeyring {
+        keyring: KeySerial,
+        directory: tempfile::TempDir,
+    }
+
+    impl TestKeyring {
+        fn new() -> TestKeyring {
+            TestKeyring {
+                keyring: add_key(
+                    "keyring",
+                    "osclientcerts-test",
+                    &[],
+                    KEY_SPEC_THREAD_KEYRING,
+                )
+                .unwrap(),
+                directory: tempfile::tempdir().unwrap(),
+            }
+        }
+
+        /// Adds the given PKCS #8 private key to the keyring. Panics if the kernel can't parse
+        /// private keys (i.e. it wasn't built with CONFIG_PKCS8_PRIVATE_KEY_PARSER).
+        fn add_private_key(&self, description: &str, key: &[u8]) {
+            if let Err(e) = add_key("asymmetric", description, key, self.keyring) {
+                panic!(
+                    "couldn't add private key ({}) - does the kernel have \
+                     CONFIG_PKCS8_PRIVATE_KEY_PARSER?",
+                    e
+                );
+            }
+        }
+
+        fn write_cert(&self, name: &str, cert: &[u8]) {
+            fs::write(self.directory.path().join(name), cert).unwrap();
+        }
+
+        fn backend(&self) -> KeyringBackend {
+            KeyringBackend::with_keyrings(
+                vec![self.keyring],
+                vec![self.directory.path().to_path_buf()],
+            )
+        }
+    }
+
+    impl Drop for TestKeyring {
+        fn drop(&mut self) {
+            let _ = keyctl(
+                KEYCTL_UNLINK,
+                libc::c_long::from(self.keyring),
+                libc::c_long::from(KEY_SPEC_THREAD_KEYRING),
+                0,
+                0,
+            );
+        }
+    }
+
+    #[test]
+    fn test_public_keys_are_ignored() {
+        let test_keyring = TestKeyring::new();
+        // Adding a certificate to a keyring creates a key that can only verify.
+        let serial = add_key("asymmetric", "", RSA_CERT, test_keyring.keyring).unwrap();
+        let nested_keyring = add_key("keyring", "nested", &[], test_keyring.keyring).unwrap();
+        add_key("asymmetric", "", RSA_CERT, nested_keyring).unwrap();
+        assert_eq!(read_keyring(test_keyring.keyring).unwrap().len(), 2);
+        assert_eq!(
+            describe_key(serial),
+            Ok((
+                "asymmetric".to_string(),
+                "Test RSA Client: b161bae6dfa952932b367a9d950c203124b5cc7f".to_string()
+            ))
+        );
+        let query = query_key(serial).unwrap();
+        assert_eq!(query.key_size, 2048);
+        assert_eq!(query.supported_ops & KEYCTL_SUPPORTS_SIGN, 0);
+        assert!(find_signing_keys(&[test_keyring.keyring]).is_empty());
+        test_keyring.write_cert("rsa.crt", RSA_CERT);
+        assert!(test_keyring.backend().list_identities().is_empty());
+    }
+
+    #[test]
+    fn test_certificate_matches_description() {
+        let rsa_cert = FileCert {
+            der: RSA_CERT.to_vec(),
+            label: b"client".to_vec(),
+        };
+        assert!(certificate_matches_description(&rsa_cert, "client"));
+        assert!(certificate_matches_description(
+            &rsa_cert,
+            "Test RSA Client: b161bae6dfa952932b367a9d950c203124b5cc7f"
+        ));
+        assert!(certificate_matches_description(
+            &rsa_cert,
+            "Some other name: B161BAE6DFA952932B367A9D950C203124B5CC7F"
+        ));
+        assert!(!certificate_matches_description(&rsa_cert, "client2"));
+        assert!(!certificate_matches_description(
+            &rsa_cert,
+            "Test RSA Client: 443f1edc2faaea05f243115587cc789a09f0cc4b"
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs a kernel built with CONFIG_PKCS8_PRIVATE_KEY_PARSER"]
+    fn test_sign_rsa() {
+        let test_keyring = TestKeyring::new();
+        test_keyring.add_private_key("client", RSA_KEY);
+        test_keyring.write_cert("client.crt", RSA_CERT);
+        test_keyring.write_cert("other.crt", P256_CERT);
+        let mut backend = test_keyring.backend();
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 1);
+        let (_, key) = &identities[0];
+        let digest_info = encode_digest_info(CKM_SHA256, &Sha256::digest(b"hello")).unwrap();
+        let signature = backend.sign(key, &digest_info, &None).unwrap();
+        assert_eq!(
+            backend.get_signature_length(key, &digest_info, &None),
+            Ok(signature.len())
+        );
+        let public_key = RsaPrivateKey::from_pkcs8_der(RSA_KEY)
+            .unwrap()
+            .to_public_key();
+        assert!(public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), &digest_info, &signature)
+            .is_ok());
+    }
+
+    #[test]
+    #[ignore = "needs a kernel built with CONFIG_PKCS8_PRIVATE_KEY_PARSER"]
+    fn test_decrypt_rsa() {
+        let test_keyring = TestKeyring::new();
+        test_keyring.add_private_key("client", RSA_KEY);
+        test_keyring.write_cert("client.crt", RSA_CERT);
+        let mut backend = test_keyring.backend();
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 1);
+        let (_, key) = &identities[0];
+        let public_key = RsaPrivateKey::from_pkcs8_der(RSA_KEY)
+            .unwrap()
+            .to_public_key();
+        let encrypted = public_key
+            .encrypt(&mut rsa::rand_core::OsRng, Pkcs1v15Encrypt, b"secret")
+            .unwrap();
+        assert_eq!(
+            backend.decrypt(key, &encrypted, &None),
+            Ok(b"secret".to_vec())
+        );
+        let oaep_params = Some(OaepParams {
+            hash_algorithm: CKM_SHA256,
+        });
+        assert_eq!(
+            backend.decrypt(key, &encrypted, &oaep_params),
+            Err(Error::MechanismInvalid)
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a kernel built with CONFIG_PKCS8_PRIVATE_KEY_PARSER"]
+    fn test_sign_ecdsa() {
+        let test_keyring = TestKeyring::new();
+        test_keyring.add_private_key("p256", P256_KEY);
+        test_keyring.add_private_key("p384", P384_KEY);
+        test_keyring.write_cert("p256.crt", P256_CERT);
+        test_keyring.write_cert("p384.crt", P384_CERT);
+        let mut backend = test_keyring.backend();
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 2);
+        for (_, key) in &identities {
+            if key.ec_params() == Some(OID_BYTES_SECP256R1) {
+                let hash = Sha256::digest(b"hello");
+                let signature = backend.sign(key, &hash, &None).unwrap();
+                assert_eq!(signature.len(), 64);
+                let verifying_key = *p256::ecdsa::SigningKey::from_pkcs8_der(P256_KEY)
+                    .unwrap()
+                    .verifying_key();
+                let signature = p256::ecdsa::Signature::from_slice(&signature).unwrap();
+                assert!(verifying_key.verify_prehash(&hash, &signature).is_ok());
+            } else {
+                let hash = Sha384::digest(b"hello");
+                let signature = backend.sign(key, &hash, &None).unwrap();
+                assert_eq!(signature.len(), 96);
+                let verifying_key = *p384::ecdsa::SigningKey::from_pkcs8_der(P384_KEY)
+                    .unwrap()
+                    .verifying_key();
+                let signature = p384::ecdsa::Signature::from_slice(&signature).unwrap();
+                assert!(verifying_key.verify_prehash(&hash, &signature).is_ok());
+            }
+        }
+    }
+}
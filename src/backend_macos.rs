@@ -7,7 +7,6 @@
 
 use libloading::{Library, Symbol};
 use pkcs11::types::*;
-use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
 use std::os::raw::c_void;
 
@@ -25,6 +24,8 @@ use core_foundation::string::*;
 // etc.. This is easier.
 include!("bindings_macos.rs");
 
+use crate::backend::*;
+use crate::error::Error;
 use crate::util::*;
 
 #[repr(C)]
@@ -47,6 +48,8 @@ impl_TCFType!(SecKey, SecKeyRef, SecKeyGetTypeID);
 
 type SecKeyCreateSignatureType =
     unsafe extern "C" fn(SecKeyRef, SecKeyAlgorithm, CFDataRef, *mut CFErrorRef) -> CFDataRef;
+type SecKeyCreateDecryptedDataType =
+    unsafe extern "C" fn(SecKeyRef, SecKeyAlgorithm, CFDataRef, *mut CFErrorRef) -> CFDataRef;
 type SecKeyCopyAttributesType = unsafe extern "C" fn(SecKeyRef) -> CFDictionaryRef;
 type SecKeyCopyExternalRepresentationType =
     unsafe extern "C" fn(SecKeyRef, *mut CFErrorRef) -> CFDataRef;
@@ -66,6 +69,9 @@ enum SecStringConstant {
     SecKeyAlgorithmECDSASignatureDigestX962SHA384,
     SecKeyAlgorithmECDSASignatureDigestX962SHA512,
     SecKeyAlgorithmRSASignatureDigestPKCS1v15Raw,
+    SecKeyAlgorithmRSAEncryptionPKCS1,
+    SecKeyAlgorithmRSAEncryptionOAEPSHA1,
+    SecKeyAlgorithmRSAEncryptionOAEPSHA256,
     SecAttrKeyTypeECSECPrimeRandom,
     // These are available in macOS 10.13
     SecKeyAlgorithmRSASignatureDigestPSSSHA1,
@@ -78,6 +84,7 @@ enum SecStringConstant {
 // `RentedSecurityFramework` must be public in the rental declaration.
 pub struct SecurityFrameworkFunctions<'a> {
     sec_key_create_signature: Symbol<'a, SecKeyCreateSignatureType>,
+    sec_key_create_decrypted_data: Symbol<'a, SecKeyCreateDecryptedDataType>,
     sec_key_copy_attributes: Symbol<'a, SecKeyCopyAttributesType>,
     sec_key_copy_external_representation: Symbol<'a, SecKeyCopyExternalRepresentationType>,
     sec_certificate_copy_serial_number_data: Symbol<'a, SecCertificateCopySerialNumberDataType>,
@@ -123,6 +130,9 @@ impl SecurityFramework {
                 let sec_key_create_signature = library
                     .get::<SecKeyCreateSignatureType>(b"SecKeyCreateSignature\0")
                     .map_err(|_| ())?;
+                let sec_key_create_decrypted_data = library
+                    .get::<SecKeyCreateDecryptedDataType>(b"SecKeyCreateDecryptedData\0")
+                    .map_err(|_| ())?;
                 let sec_key_copy_attributes = library
                     .get::<SecKeyCopyAttributesType>(b"SecKeyCopyAttributes\0")
                     .map_err(|_| ())?;
@@ -171,6 +181,18 @@ impl SecurityFramework {
                         b"kSecKeyAlgorithmRSASignatureDigestPKCS1v15Raw\0".as_ref(),
                         SecStringConstant::SecKeyAlgorithmRSASignatureDigestPKCS1v15Raw,
                     ),
+                    (
+                        b"kSecKeyAlgorithmRSAEncryptionPKCS1\0".as_ref(),
+                        SecStringConstant::SecKeyAlgorithmRSAEncryptionPKCS1,
+                    ),
+                    (
+                        b"kSecKeyAlgorithmRSAEncryptionOAEPSHA1\0".as_ref(),
+                        SecStringConstant::SecKeyAlgorithmRSAEncryptionOAEPSHA1,
+                    ),
+                    (
+                        b"kSecKeyAlgorithmRSAEncryptionOAEPSHA256\0".as_ref(),
+                        SecStringConstant::SecKeyAlgorithmRSAEncryptionOAEPSHA256,
+                    ),
                     (
                         b"kSecKeyAlgorithmRSASignatureDigestPSSSHA1\0".as_ref(),
                         SecStringConstant::SecKeyAlgorithmRSASignatureDigestPSSSHA1,
@@ -201,6 +223,7 @@ impl SecurityFramework {
                 }
                 Ok(SecurityFrameworkFunctions {
                     sec_key_create_signature,
+                    sec_key_create_decrypted_data,
                     sec_key_copy_attributes,
                     sec_key_copy_external_representation,
                     sec_certificate_copy_serial_number_data,
@@ -224,7 +247,7 @@ impl SecurityFramework {
         key: &SecKey,
         algorithm: SecKeyAlgorithm,
         data_to_sign: &CFData,
-    ) -> Result<CFData, ()> {
+    ) -> Result<CFData, Error> {
         match &self.rental {
             Some(rental) => rental.rent(|framework| unsafe {
                 let mut error = std::ptr::null_mut();
@@ -236,12 +259,48 @@ impl SecurityFramework {
                 );
                 if result.is_null() {
                     let error = CFError::wrap_under_create_rule(error);
+                    // The keychain may have asked the user for permission to use the key.
+                    if error.code() == errSecUserCanceled as CFIndex {
+                        debug!("SecKeyCreateSignature was cancelled: {}", error);
+                        return Err(Error::Cancelled);
+                    }
                     error!("SecKeyCreateSignature failed: {}", error);
-                    return Err(());
+                    return Err(Error::BackendFailure);
                 }
                 Ok(CFData::wrap_under_create_rule(result))
             }),
-            None => Err(()),
+            None => Err(Error::BackendFailure),
+        }
+    }
+
+    /// SecKeyCreateDecryptedData is available in macOS 10.12
+    fn sec_key_create_decrypted_data(
+        &self,
+        key: &SecKey,
+        algorithm: SecKeyAlgorithm,
+        data_to_decrypt: &CFData,
+    ) -> Result<CFData, Error> {
+        match &self.rental {
+            Some(rental) => rental.rent(|framework| unsafe {
+                let mut error = std::ptr::null_mut();
+                let result = (framework.sec_key_create_decrypted_data)(
+                    key.as_concrete_TypeRef(),
+                    algorithm,
+                    data_to_decrypt.as_concrete_TypeRef(),
+                    &mut error,
+                );
+                if result.is_null() {
+                    let error = CFError::wrap_under_create_rule(error);
+                    if error.code() == errSecUserCanceled as CFIndex {
+                        debug!("SecKeyCreateDecryptedData was cancelled: {}", error);
+                        return Err(Error::Cancelled);
+                    }
+                    error!("SecKeyCreateDecryptedData failed: {}", error);
+                    return Err(Error::EncryptedDataInvalid);
+                }
+                Ok(CFData::wrap_under_create_rule(result))
+            }),
+            None => Err(Error::BackendFailure),
         }
     }
 
@@ -426,113 +485,22 @@ fn sec_identity_copy_private_key(identity: &SecIdentity) -> Result<SecKey, ()> {
     Ok(unsafe { SecKey::wrap_under_create_rule(key) })
 }
 
-pub struct Cert {
-    class: Vec<u8>,
-    token: Vec<u8>,
-    id: Vec<u8>,
-    label: Vec<u8>,
-    value: Vec<u8>,
-    issuer: Vec<u8>,
-    serial_number: Vec<u8>,
-    subject: Vec<u8>,
-}
-
-impl Cert {
-    fn new(identity: &SecIdentity) -> Result<Cert, ()> {
-        let certificate = sec_identity_copy_certificate(identity)?;
-        let label = sec_certificate_copy_subject_summary(&certificate)?;
-        let der = sec_certificate_copy_data(&certificate)?;
-        let id = Sha256::digest(der.bytes()).to_vec();
-        let issuer =
-            SECURITY_FRAMEWORK.sec_certificate_copy_normalized_issuer_sequence(&certificate)?;
-        let serial_number =
-            SECURITY_FRAMEWORK.sec_certificate_copy_serial_number_data(&certificate)?;
-        let subject =
-            SECURITY_FRAMEWORK.sec_certificate_copy_normalized_subject_sequence(&certificate)?;
-        Ok(Cert {
-            class: serialize_uint(CKO_CERTIFICATE)?,
-            token: serialize_uint(CK_TRUE)?,
-            id,
-            label: label.to_string().into_bytes(),
-            value: der.bytes().to_vec(),
-            issuer: issuer.bytes().to_vec(),
-            serial_number: serial_number.bytes().to_vec(),
-            subject: subject.bytes().to_vec(),
-        })
-    }
-
-    fn class(&self) -> &[u8] {
-        &self.class
-    }
-
-    fn token(&self) -> &[u8] {
-        &self.token
-    }
-
-    pub fn id(&self) -> &[u8] {
-        &self.id
-    }
-
-    fn label(&self) -> &[u8] {
-        &self.label
-    }
-
-    fn value(&self) -> &[u8] {
-        &self.value
-    }
-
-    fn issuer(&self) -> &[u8] {
-        &self.issuer
-    }
-
-    fn serial_number(&self) -> &[u8] {
-        &self.serial_number
-    }
-
-    fn subject(&self) -> &[u8] {
-        &self.subject
-    }
-
-    fn matches(&self, attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)]) -> bool {
-        for (attr_type, attr_value) in attrs {
-            let comparison = match *attr_type {
-                CKA_CLASS => self.class(),
-                CKA_TOKEN => self.token(),
-                CKA_LABEL => self.label(),
-                CKA_ID => self.id(),
-                CKA_VALUE => self.value(),
-                CKA_ISSUER => self.issuer(),
-                CKA_SERIAL_NUMBER => self.serial_number(),
-                CKA_SUBJECT => self.subject(),
-                _ => return false,
-            };
-            if attr_value.as_slice() != comparison {
-                return false;
-            }
-        }
-        true
-    }
-
-    fn get_attribute(&self, attribute: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
-        let result = match attribute {
-            CKA_CLASS => self.class(),
-            CKA_TOKEN => self.token(),
-            CKA_LABEL => self.label(),
-            CKA_ID => self.id(),
-            CKA_VALUE => self.value(),
-            CKA_ISSUER => self.issuer(),
-            CKA_SERIAL_NUMBER => self.serial_number(),
-            CKA_SUBJECT => self.subject(),
-            _ => return None,
-        };
-        Some(result)
-    }
-}
-
-#[derive(Clone, Copy, Debug)]
-pub enum KeyType {
-    EC(usize),
-    RSA,
+fn new_cert(identity: &SecIdentity) -> Result<Cert, ()> {
+    let certificate = sec_identity_copy_certificate(identity)?;
+    let label = sec_certificate_copy_subject_summary(&certificate)?;
+    let der = sec_certificate_copy_data(&certificate)?;
+    let issuer =
+        SECURITY_FRAMEWORK.sec_certificate_copy_normalized_issuer_sequence(&certificate)?;
+    let serial_number = SECURITY_FRAMEWORK.sec_certificate_copy_serial_number_data(&certificate)?;
+    let subject =
+        SECURITY_FRAMEWORK.sec_certificate_copy_normalized_subject_sequence(&certificate)?;
+    Cert::new(
+        der.bytes().to_vec(),
+        label.to_string().into_bytes(),
+        issuer.bytes().to_vec(),
+        serial_number.bytes().to_vec(),
+        subject.bytes().to_vec(),
+    )
 }
 
 enum SignParams {
@@ -545,14 +513,14 @@ impl SignParams {
         key_type: KeyType,
         data_len: usize,
         params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
-    ) -> Result<SignParams, ()> {
+    ) -> Result<SignParams, Error> {
         match key_type {
             KeyType::EC(_) => SignParams::new_ec_params(data_len),
             KeyType::RSA => SignParams::new_rsa_params(params),
         }
     }
 
-    fn new_ec_params(data_len: usize) -> Result<SignParams, ()> {
+    fn new_ec_params(data_len: usize) -> Result<SignParams, Error> {
         let algorithm_id = match data_len {
             20 => SecStringConstant::SecKeyAlgorithmECDSASignatureDigestX962SHA1,
             32 => SecStringConstant::SecKeyAlgorithmECDSASignatureDigestX962SHA256,
@@ -563,21 +531,25 @@ impl SignParams {
                     "Unexpected digested signature input length for ECDSA: {}",
                     data_len
                 );
-                return Err(());
+                return Err(Error::DataLenRange);
             }
         };
-        let algorithm = SECURITY_FRAMEWORK.get_sec_string_constant(algorithm_id)?;
+        let algorithm = SECURITY_FRAMEWORK
+            .get_sec_string_constant(algorithm_id)
+            .map_err(|()| Error::BackendFailure)?;
         Ok(SignParams::EC(algorithm))
     }
 
-    fn new_rsa_params(params: &Option<CK_RSA_PKCS_PSS_PARAMS>) -> Result<SignParams, ()> {
+    fn new_rsa_params(params: &Option<CK_RSA_PKCS_PSS_PARAMS>) -> Result<SignParams, Error> {
         let pss_params = match params {
             Some(pss_params) => pss_params,
             None => {
                 return Ok(SignParams::RSA(
-                    SECURITY_FRAMEWORK.get_sec_string_constant(
-                        SecStringConstant::SecKeyAlgorithmRSASignatureDigestPKCS1v15Raw,
-                    )?,
+                    SECURITY_FRAMEWORK
+                        .get_sec_string_constant(
+                            SecStringConstant::SecKeyAlgorithmRSASignatureDigestPKCS1v15Raw,
+                        )
+                        .map_err(|()| Error::BackendFailure)?,
                 ));
             }
         };
@@ -592,10 +564,12 @@ impl SignParams {
                         "unsupported algorithm to use with RSA-PSS: {}",
                         unsafe_packed_field_access!(pss_params.hashAlg)
                     );
-                    return Err(());
+                    return Err(Error::MechanismParamInvalid);
                 }
             };
-            SECURITY_FRAMEWORK.get_sec_string_constant(algorithm_id)?
+            SECURITY_FRAMEWORK
+                .get_sec_string_constant(algorithm_id)
+                .map_err(|()| Error::BackendFailure)?
         };
         Ok(SignParams::RSA(algorithm))
     }
@@ -608,185 +582,119 @@ impl SignParams {
     }
 }
 
-pub struct Key {
-    identity: SecIdentity,
-    class: Vec<u8>,
-    token: Vec<u8>,
-    id: Vec<u8>,
-    private: Vec<u8>,
-    key_type: Vec<u8>,
-    modulus: Option<Vec<u8>>,
-    ec_params: Option<Vec<u8>>,
-    key_type_enum: KeyType,
-}
-
-impl Key {
-    fn new(identity: &SecIdentity) -> Result<Key, ()> {
-        let certificate = sec_identity_copy_certificate(identity)?;
-        let der = sec_certificate_copy_data(&certificate)?;
-        let id = Sha256::digest(der.bytes()).to_vec();
-        let key = SECURITY_FRAMEWORK.sec_certificate_copy_key(&certificate)?;
-        let key_type: CFString = get_key_attribute(&key, unsafe { kSecAttrKeyType })?;
-        let key_size_in_bits: CFNumber = get_key_attribute(&key, unsafe { kSecAttrKeySizeInBits })?;
-        let mut modulus = None;
-        let mut ec_params = None;
-        let sec_attr_key_type_ec = SECURITY_FRAMEWORK
-            .get_sec_string_constant(SecStringConstant::SecAttrKeyTypeECSECPrimeRandom)?;
-        let (key_type_enum, key_type_attribute) =
-            if key_type.as_concrete_TypeRef() == unsafe { kSecAttrKeyTypeRSA } {
-                let public_key = SECURITY_FRAMEWORK.sec_key_copy_external_representation(&key)?;
-                let modulus_value = read_rsa_modulus(public_key.bytes())?;
-                modulus = Some(modulus_value);
-                (KeyType::RSA, CKK_RSA)
-            } else if key_type == sec_attr_key_type_ec {
-                // Assume all EC keys are secp256r1, secp384r1, or secp521r1. This
-                // is wrong, but the API doesn't seem to give us a way to determine
-                // which curve this key is on.
-                // This might not matter in practice, because it seems all NSS uses
-                // this for is to get the signature size.
-                let key_size_in_bits = match key_size_in_bits.to_i64() {
-                    Some(value) => value,
-                    None => return Err(()),
-                };
-                match key_size_in_bits {
-                    256 => ec_params = Some(OID_BYTES_SECP256R1.to_vec()),
-                    384 => ec_params = Some(OID_BYTES_SECP384R1.to_vec()),
-                    521 => ec_params = Some(OID_BYTES_SECP521R1.to_vec()),
-                    _ => {
-                        error!("unsupported EC key");
-                        return Err(());
-                    }
-                }
-                let coordinate_width = (key_size_in_bits as usize + 7) / 8;
-                (KeyType::EC(coordinate_width), CKK_EC)
-            } else {
-                error!("unsupported key type");
+fn new_key(identity: &SecIdentity) -> Result<Key, ()> {
+    let certificate = sec_identity_copy_certificate(identity)?;
+    let der = sec_certificate_copy_data(&certificate)?;
+    let key = SECURITY_FRAMEWORK.sec_certificate_copy_key(&certificate)?;
+    let key_type: CFString = get_key_attribute(&key, unsafe { kSecAttrKeyType })?;
+    let key_size_in_bits: CFNumber = get_key_attribute(&key, unsafe { kSecAttrKeySizeInBits })?;
+    let mut modulus = None;
+    let mut ec_params = None;
+    let sec_attr_key_type_ec = SECURITY_FRAMEWORK
+        .get_sec_string_constant(SecStringConstant::SecAttrKeyTypeECSECPrimeRandom)?;
+    let key_type_enum = if key_type.as_concrete_TypeRef() == unsafe { kSecAttrKeyTypeRSA } {
+        let public_key = SECURITY_FRAMEWORK.sec_key_copy_external_representation(&key)?;
+        let modulus_value = read_rsa_modulus(public_key.bytes())?;
+        modulus = Some(modulus_value);
+        KeyType::RSA
+    } else if key_type == sec_attr_key_type_ec {
+        // Assume all EC keys are secp256r1, secp384r1, or secp521r1. This
+        // is wrong, but the API doesn't seem to give us a way to determine
+        // which curve this key is on.
+        // This might not matter in practice, because it seems all NSS uses
+        // this for is to get the signature size.
+        let key_size_in_bits = match key_size_in_bits.to_i64() {
+            Some(value) => value,
+            None => return Err(()),
+        };
+        match key_size_in_bits {
+            256 => ec_params = Some(OID_BYTES_SECP256R1.to_vec()),
+            384 => ec_params = Some(OID_BYTES_SECP384R1.to_vec()),
+            521 => ec_params = Some(OID_BYTES_SECP521R1.to_vec()),
+            _ => {
+                error!("unsupported EC key");
                 return Err(());
-            };
-
-        Ok(Key {
-            identity: identity.clone(),
-            class: serialize_uint(CKO_PRIVATE_KEY)?,
-            token: serialize_uint(CK_TRUE)?,
-            id,
-            private: serialize_uint(CK_TRUE)?,
-            key_type: serialize_uint(key_type_attribute)?,
-            modulus,
-            ec_params,
-            key_type_enum,
-        })
-    }
-
-    fn class(&self) -> &[u8] {
-        &self.class
-    }
-
-    fn token(&self) -> &[u8] {
-        &self.token
-    }
-
-    pub fn id(&self) -> &[u8] {
-        &self.id
-    }
-
-    fn private(&self) -> &[u8] {
-        &self.private
-    }
-
-    fn key_type(&self) -> &[u8] {
-        &self.key_type
-    }
-
-    fn modulus(&self) -> Option<&[u8]> {
-        match &self.modulus {
-            Some(modulus) => Some(modulus.as_slice()),
-            None => None,
+            }
         }
-    }
+        let coordinate_width = (key_size_in_bits as usize + 7) / 8;
+        KeyType::EC(coordinate_width)
+    } else {
+        error!("unsupported key type");
+        return Err(());
+    };
+    Key::new(der.bytes(), key_type_enum, modulus, ec_params)
+}
 
-    fn ec_params(&self) -> Option<&[u8]> {
-        match &self.ec_params {
-            Some(ec_params) => Some(ec_params.as_slice()),
-            None => None,
-        }
-    }
+/// The macOS backend. Enumerates identities (certificates with corresponding private keys) in the
+/// keychain and signs with those keys using the security framework.
+pub struct MacOSBackend {
+    /// A map of key identifiers to the identity each key is a part of.
+    identities: BTreeMap<Vec<u8>, SecIdentity>,
+}
 
-    fn matches(&self, attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)]) -> bool {
-        for (attr_type, attr_value) in attrs {
-            let comparison = match *attr_type {
-                CKA_CLASS => self.class(),
-                CKA_TOKEN => self.token(),
-                CKA_ID => self.id(),
-                CKA_PRIVATE => self.private(),
-                CKA_KEY_TYPE => self.key_type(),
-                CKA_MODULUS => {
-                    if let Some(modulus) = self.modulus() {
-                        modulus
-                    } else {
-                        return false;
-                    }
-                }
-                CKA_EC_PARAMS => {
-                    if let Some(ec_params) = self.ec_params() {
-                        ec_params
-                    } else {
-                        return false;
-                    }
-                }
-                _ => return false,
-            };
-            if attr_value.as_slice() != comparison {
-                return false;
-            }
+impl MacOSBackend {
+    pub fn new() -> MacOSBackend {
+        MacOSBackend {
+            identities: BTreeMap::new(),
         }
-        true
     }
+}
 
-    fn get_attribute(&self, attribute: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
-        match attribute {
-            CKA_CLASS => Some(self.class()),
-            CKA_TOKEN => Some(self.token()),
-            CKA_ID => Some(self.id()),
-            CKA_PRIVATE => Some(self.private()),
-            CKA_KEY_TYPE => Some(self.key_type()),
-            CKA_MODULUS => self.modulus(),
-            CKA_EC_PARAMS => self.ec_params(),
-            _ => None,
+impl Backend for MacOSBackend {
+    fn list_identities(&mut self) -> Vec<(Cert, Key)> {
+        let mut identities_out = Vec::new();
+        if let Some(identities) = list_identities() {
+            for (identity, cert, key) in identities {
+                self.identities.insert(key.id().to_vec(), identity);
+                identities_out.push((cert, key));
+            }
         }
+        identities_out
     }
 
-    pub fn get_signature_length(
+    fn get_signature_length(
         &self,
+        key: &Key,
         data: &[u8],
         params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
-    ) -> Result<usize, ()> {
+    ) -> Result<usize, Error> {
         // Unfortunately we don't have a way of getting the length of a signature without creating
         // one.
-        let dummy_signature_bytes = self.sign(data, params)?;
+        let dummy_signature_bytes = self.sign(key, data, params)?;
         Ok(dummy_signature_bytes.len())
     }
 
     // The input data is a hash. What algorithm we use depends on the size of the hash.
-    pub fn sign(
+    fn sign(
         &self,
+        key: &Key,
         data: &[u8],
         params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
-    ) -> Result<Vec<u8>, ()> {
-        let key = sec_identity_copy_private_key(&self.identity)?;
-        let sign_params = SignParams::new(self.key_type_enum, data.len(), params)?;
+    ) -> Result<Vec<u8>, Error> {
+        let identity = match self.identities.get(key.id()) {
+            Some(identity) => identity,
+            None => {
+                error!("no identity corresponding to key");
+                return Err(Error::KeyHandleInvalid);
+            }
+        };
+        let key_type_enum = key.key_type_enum();
+        let key = sec_identity_copy_private_key(identity).map_err(|()| Error::BackendFailure)?;
+        let sign_params = SignParams::new(key_type_enum, data.len(), params)?;
         let signing_algorithm = sign_params.get_algorithm();
         let data = CFData::from_buffer(data);
         let signature =
             SECURITY_FRAMEWORK.sec_key_create_signature(&key, signing_algorithm, &data)?;
-        let signature_value = match self.key_type_enum {
+        let signature_value = match key_type_enum {
             KeyType::EC(coordinate_width) => {
                 // We need to convert the DER Ecdsa-Sig-Value to the
                 // concatenation of r and s, the coordinates of the point on
                 // the curve. r and s must be 0-padded to be coordinate_width
                 // total bytes.
-                let (r, s) = read_ec_sig_point(signature.bytes())?;
+                let (r, s) =
+                    read_ec_sig_point(signature.bytes()).map_err(|()| Error::BackendFailure)?;
                 if r.len() > coordinate_width || s.len() > coordinate_width {
-                    return Err(());
+                    return Err(Error::BackendFailure);
                 }
                 let mut signature_value = Vec::with_capacity(2 * coordinate_width);
                 let r_padding = vec![0; coordinate_width - r.len()];
@@ -801,53 +709,41 @@ impl Key {
         };
         Ok(signature_value)
     }
-}
-
-pub enum Object {
-    Cert(Cert),
-    Key(Key),
-}
-
-impl Object {
-    pub fn matches(&self, attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)]) -> bool {
-        match self {
-            Object::Cert(cert) => cert.matches(attrs),
-            Object::Key(key) => key.matches(attrs),
-        }
-    }
-
-    pub fn get_attribute(&self, attribute: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
-        match self {
-            Object::Cert(cert) => cert.get_attribute(attribute),
-            Object::Key(key) => key.get_attribute(attribute),
-        }
-    }
-}
 
-pub const SUPPORTED_ATTRIBUTES: &[CK_ATTRIBUTE_TYPE] = &[
-    CKA_CLASS,
-    CKA_TOKEN,
-    CKA_LABEL,
-    CKA_ID,
-    CKA_VALUE,
-    CKA_ISSUER,
-    CKA_SERIAL_NUMBER,
-    CKA_SUBJECT,
-    CKA_PRIVATE,
-    CKA_KEY_TYPE,
-    CKA_MODULUS,
-    CKA_EC_PARAMS,
-];
-
-pub fn list_objects() -> Vec<Object> {
-    let mut objects = Vec::new();
-    if let Some(identities) = list_identities() {
-        for (cert, key) in identities {
-            objects.push(Object::Cert(cert));
-            objects.push(Object::Key(key));
+    fn decrypt(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<OaepParams>,
+    ) -> Result<Vec<u8>, Error> {
+        if key.key_type_enum() != KeyType::RSA {
+            return Err(Error::KeyTypeInconsistent);
         }
+        let identity = match self.identities.get(key.id()) {
+            Some(identity) => identity,
+            None => {
+                error!("no identity corresponding to key");
+                return Err(Error::KeyHandleInvalid);
+            }
+        };
+        let algorithm_id = match params.map(|params| params.hash_algorithm) {
+            None => SecStringConstant::SecKeyAlgorithmRSAEncryptionPKCS1,
+            Some(CKM_SHA_1) => SecStringConstant::SecKeyAlgorithmRSAEncryptionOAEPSHA1,
+            Some(CKM_SHA256) => SecStringConstant::SecKeyAlgorithmRSAEncryptionOAEPSHA256,
+            Some(_) => return Err(Error::MechanismParamInvalid),
+        };
+        let algorithm = SECURITY_FRAMEWORK
+            .get_sec_string_constant(algorithm_id)
+            .map_err(|()| Error::BackendFailure)?;
+        let key = sec_identity_copy_private_key(identity).map_err(|()| Error::BackendFailure)?;
+        let data = CFData::from_buffer(data);
+        let decrypted = SECURITY_FRAMEWORK.sec_key_create_decrypted_data(
+            &key,
+            algorithm.as_concrete_TypeRef(),
+            &data,
+        )?;
+        Ok(decrypted.bytes().to_vec())
     }
-    objects
 }
 
 fn get_key_attribute<T: TCFType + Clone>(key: &SecKey, attr: CFStringRef) -> Result<T, ()> {
@@ -858,7 +754,7 @@ fn get_key_attribute<T: TCFType + Clone>(key: &SecKey, attr: CFStringRef) -> Res
     }
 }
 
-fn list_identities() -> Option<Vec<(Cert, Key)>> {
+fn list_identities() -> Option<Vec<(SecIdentity, Cert, Key)>> {
     let identities = unsafe {
         let class_key = CFString::wrap_under_get_rule(kSecClass);
         let class_value = CFString::wrap_under_get_rule(kSecClassIdentity);
@@ -887,10 +783,10 @@ fn list_identities() -> Option<Vec<(Cert, Key)>> {
     let mut identities_out = Vec::with_capacity(identities.len() as usize);
     for identity in identities.get_all_values().iter() {
         let identity = unsafe { SecIdentity::wrap_under_get_rule(*identity as SecIdentityRef) };
-        let cert = Cert::new(&identity);
-        let key = Key::new(&identity);
+        let cert = new_cert(&identity);
+        let key = new_key(&identity);
         if let (Ok(cert), Ok(key)) = (cert, key) {
-            identities_out.push((cert, key));
+            identities_out.push((identity, cert, key));
         }
     }
     Some(identities_out)
@@ -0,0 +1,298 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use pkcs11::types::*;
+use rsa::traits::PublicKeyParts;
+use rsa::Pkcs1v15Encrypt;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::backend::*;
+use crate::digest::{encode_digest_info, Hasher};
+use crate::error::Error;
+use crate::software_key::SoftwareKey;
+use crate::util::*;
+
+pub const RSA_CERT: &[u8] = include_bytes!("../test/rsa-cert.der");
+pub const RSA_KEY: &[u8] = include_bytes!("../test/rsa-key.der");
+/// A certificate for the key in `RSA_KEY` whose key usage only allows signing.
+pub const RSA_SIGNING_CERT: &[u8] = include_bytes!("../test/rsa-signing-cert.der");
+pub const P256_CERT: &[u8] = include_bytes!("../test/p256-cert.der");
+pub const P256_KEY: &[u8] = include_bytes!("../test/p256-key.der");
+pub const P384_CERT: &[u8] = include_bytes!("../test/p384-cert.der");
+pub const P384_KEY: &[u8] = include_bytes!("../test/p384-key.der");
+
+/// The ID that backends give the certificate with the given DER encoding and its key.
+pub fn id_of(cert_der: &[u8]) -> Vec<u8> {
+    Sha256::digest(cert_der).to_vec()
+}
+
+/// The contents of a `MockStore`.
+#[derive(Default)]
+struct MockStoreContents {
+    /// Pairs of certificate DER bytes and PKCS #8 private key DER bytes.
+    identities: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Identities (as above) that are only available after logging in with the accompanying PIN.
+    locked_identities: Vec<(Vec<u8>, Vec<u8>, String)>,
+    /// The number of times a `MockBackend` has listed the identities in this store.
+    scan_count: usize,
+    /// If set, signing fails with this error (e.g. to simulate the user cancelling a prompt).
+    sign_error: Option<Error>,
+    /// Whether or not keys can only sign whole messages (like those in ssh-agent).
+    signs_messages_only: bool,
+    /// If set, every key has to be unlocked with this PIN (by logging in with
+    /// `CKU_CONTEXT_SPECIFIC`) each time it is used to sign.
+    always_authenticate_pin: Option<String>,
+}
+
+/// An in-memory store of identities that a `MockBackend` serves. Clones of a `MockStore` refer to
+/// the same contents, so a test can hold on to one and modify it while a `Manager` (possibly on
+/// another thread) is using a `MockBackend` created with another.
+#[derive(Clone, Default)]
+pub struct MockStore {
+    contents: Arc<Mutex<MockStoreContents>>,
+}
+
+impl MockStore {
+    pub fn new() -> MockStore {
+        MockStore::default()
+    }
+
+    /// Creates a store that contains the RSA, secp256r1, and secp384r1 fixture identities.
+    pub fn with_fixtures() -> MockStore {
+        let store = MockStore::new();
+        store.add_identity(RSA_CERT, RSA_KEY);
+        store.add_identity(P256_CERT, P256_KEY);
+        store.add_identity(P384_CERT, P384_KEY);
+        store
+    }
+
+    pub fn add_identity(&self, cert_der: &[u8], key_der: &[u8]) {
+        let mut contents = self.contents.lock().unwrap();
+        contents
+            .identities
+            .push((cert_der.to_vec(), key_der.to_vec()));
+    }
+
+    pub fn add_locked_identity(&self, cert_der: &[u8], key_der: &[u8], pin: &str) {
+        let mut contents = self.contents.lock().unwrap();
+        contents
+            .locked_identities
+            .push((cert_der.to_vec(), key_der.to_vec(), pin.to_owned()));
+    }
+
+    /// Removes the identity with the given certificate (whether or not it is locked).
+    pub fn remove_identity(&self, cert_der: &[u8]) {
+        let mut contents = self.contents.lock().unwrap();
+        contents.identities.retain(|(cert, _)| cert != cert_der);
+        contents
+            .locked_identities
+            .retain(|(cert, _, _)| cert != cert_der);
+    }
+
+    pub fn scan_count(&self) -> usize {
+        self.contents.lock().unwrap().scan_count
+    }
+
+    /// Makes signing fail with the given error (or succeed again, if `None`).
+    pub fn set_sign_error(&self, sign_error: Option<Error>) {
+        self.contents.lock().unwrap().sign_error = sign_error;
+    }
+
+    /// Makes keys only able to sign whole messages with `sign_message` (or able to sign hashes
+    /// with `sign` again, if `false`).
+    pub fn set_signs_messages_only(&self, signs_messages_only: bool) {
+        self.contents.lock().unwrap().signs_messages_only = signs_messages_only;
+    }
+
+    /// Makes every key need the given PIN each time it is used to sign (or not need it any more,
+    /// if `None`). Takes effect the next time the identities are listed.
+    pub fn set_always_authenticate_pin(&self, pin: Option<&str>) {
+        self.contents.lock().unwrap().always_authenticate_pin = pin.map(str::to_owned);
+    }
+}
+
+/// A backend that serves the identities in a `MockStore` and signs with their keys in software.
+pub struct MockBackend {
+    store: MockStore,
+    /// A map of key identifiers to the keys they identify.
+    keys: BTreeMap<Vec<u8>, SoftwareKey>,
+    /// The PIN the user logged in with, if any.
+    pin: Option<String>,
+}
+
+impl MockBackend {
+    pub fn new(store: MockStore) -> MockBackend {
+        MockBackend {
+            store,
+            keys: BTreeMap::new(),
+            pin: None,
+        }
+    }
+
+    /// Returns the software key for the given key, which fails if the key has gone away.
+    fn get_software_key(&self, key: &Key) -> Result<&SoftwareKey, Error> {
+        self.keys.get(key.id()).ok_or(Error::KeyHandleInvalid)
+    }
+}
+
+impl Backend for MockBackend {
+    fn token_label(&self) -> String {
+        String::from("mock token")
+    }
+
+    fn list_identities(&mut self) -> Vec<(Cert, Key)> {
+        let mut contents = self.store.contents.lock().unwrap();
+        contents.scan_count += 1;
+        let logged_in_pin = self.pin.clone();
+        let unlocked = contents
+            .locked_identities
+            .iter()
+            .filter(|(_, _, pin)| Some(pin) == logged_in_pin.as_ref())
+            .map(|(cert_der, key_der, _)| (cert_der, key_der));
+        let mut identities = Vec::with_capacity(contents.identities.len());
+        for (cert_der, key_der) in contents
+            .identities
+            .iter()
+            .map(|(cert_der, key_der)| (cert_der, key_der))
+            .chain(unlocked)
+        {
+            let fields = read_certificate_fields(cert_der).expect("malformed fixture certificate");
+            let software_key = SoftwareKey::from_pkcs8_der(key_der).expect("malformed fixture key");
+            let cert = Cert::new(
+                cert_der.clone(),
+                b"mock certificate".to_vec(),
+                fields.issuer.to_vec(),
+                fields.serial_number.to_vec(),
+                fields.subject.to_vec(),
+            )
+            .unwrap();
+            let mut key = software_key.new_key(cert_der).unwrap();
+            if contents.always_authenticate_pin.is_some() {
+                key.set_always_authenticate(true).unwrap();
+            }
+            self.keys.insert(key.id().to_vec(), software_key);
+            identities.push((cert, key));
+        }
+        identities
+    }
+
+    fn get_signature_length(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<usize, Error> {
+        self.get_software_key(key)?
+            .get_signature_length(data, params)
+    }
+
+    fn sign(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<Vec<u8>, Error> {
+        let contents = self.store.contents.lock().unwrap();
+        if let Some(sign_error) = contents.sign_error {
+            return Err(sign_error);
+        }
+        if contents.signs_messages_only {
+            return Err(Error::MechanismInvalid);
+        }
+        self.get_software_key(key)?.sign(data, params)
+    }
+
+    /// Hashes the message and signs the hash (or, for RSA keys, a DigestInfo of it), if keys can
+    /// only sign whole messages.
+    fn sign_message(
+        &self,
+        key: &Key,
+        message: &[u8],
+        hash_algorithm: CK_MECHANISM_TYPE,
+    ) -> Result<Vec<u8>, Error> {
+        let contents = self.store.contents.lock().unwrap();
+        if let Some(sign_error) = contents.sign_error {
+            return Err(sign_error);
+        }
+        if !contents.signs_messages_only {
+            return Err(Error::MechanismInvalid);
+        }
+        let mut hasher = Hasher::new(hash_algorithm).ok_or(Error::MechanismInvalid)?;
+        hasher.update(message);
+        let hash = hasher.finalize();
+        let data = match key.key_type_enum() {
+            KeyType::RSA => encode_digest_info(hash_algorithm, &hash)?,
+            KeyType::EC(_) => hash,
+        };
+        self.get_software_key(key)?.sign(&data, &None)
+    }
+
+    fn decrypt(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<OaepParams>,
+    ) -> Result<Vec<u8>, Error> {
+        match (self.get_software_key(key)?, params) {
+            // `SoftwareKey` refuses PKCS #1 v1.5 because it can't do it in constant time, but the
+            // backends this stands in for can, and timing doesn't matter here.
+            (SoftwareKey::RSA(rsa_key), None) => {
+                if data.len() != rsa_key.size() {
+                    return Err(Error::EncryptedDataLenRange);
+                }
+                rsa_key
+                    .decrypt(Pkcs1v15Encrypt, data)
+                    .map_err(|_| Error::EncryptedDataInvalid)
+            }
+            (software_key, params) => software_key.decrypt(data, params),
+        }
+    }
+
+    fn signs_hashes(&self, _key: &Key) -> bool {
+        !self.store.contents.lock().unwrap().signs_messages_only
+    }
+
+    fn login_required(&self) -> bool {
+        let contents = self.store.contents.lock().unwrap();
+        contents
+            .locked_identities
+            .iter()
+            .any(|(_, _, pin)| Some(pin) != self.pin.as_ref())
+    }
+
+    fn login(&mut self, pin: &str) -> Result<(), Error> {
+        let contents = self.store.contents.lock().unwrap();
+        if !contents.locked_identities.is_empty()
+            && !contents
+                .locked_identities
+                .iter()
+                .any(|(_, _, locked_pin)| locked_pin == pin)
+        {
+            return Err(Error::PinIncorrect);
+        }
+        self.pin = Some(pin.to_owned());
+        Ok(())
+    }
+
+    fn logout(&mut self) {
+        self.pin = None;
+    }
+
+    fn login_context_specific(
+        &mut self,
+        _key: &Key,
+        _params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+        pin: &str,
+    ) -> Result<(), Error> {
+        let contents = self.store.contents.lock().unwrap();
+        if contents.always_authenticate_pin.as_deref() == Some(pin) {
+            Ok(())
+        } else {
+            Err(Error::PinIncorrect)
+        }
+    }
+}
@@ -0,0 +1,259 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use pkcs11::types::*;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::backend::*;
+use crate::error::Error;
+
+/// A backend that combines the identities of several other backends, so that they can be exposed
+/// as a single token. Operations on a key are forwarded to the backend the key came from. If more
+/// than one backend has the same identity, the first one is used.
+pub struct MultiBackend {
+    backends: Vec<Box<dyn Backend>>,
+    /// A map of key identifiers to the index of the backend that has the key.
+    key_backends: BTreeMap<Vec<u8>, usize>,
+}
+
+impl MultiBackend {
+    /// Creates a `MultiBackend` that combines the given backends.
+    pub fn with_backends(backends: Vec<Box<dyn Backend>>) -> MultiBackend {
+        MultiBackend {
+            backends,
+            key_backends: BTreeMap::new(),
+        }
+    }
+
+    fn get_backend(&self, key: &Key) -> Result<&dyn Backend, Error> {
+        match self.key_backends.get(key.id()) {
+            Some(index) => Ok(self.backends[*index].as_ref()),
+            None => Err(Error::KeyHandleInvalid),
+        }
+    }
+}
+
+impl Backend for MultiBackend {
+    /// The directories of the combined backends can only be watched instead of asking the backends
+    /// for their identities if all of the backends' certificates and keys come from files.
+    fn watched_directories(&self) -> Vec<PathBuf> {
+        let mut directories = Vec::new();
+        for backend in &self.backends {
+            let backend_directories = backend.watched_directories();
+            if backend_directories.is_empty() {
+                return Vec::new();
+            }
+            directories.extend(backend_directories);
+        }
+        directories
+    }
+
+    fn list_identities(&mut self) -> Vec<(Cert, Key)> {
+        self.key_backends.clear();
+        let mut identities = Vec::new();
+        for (index, backend) in self.backends.iter_mut().enumerate() {
+            for (cert, key) in backend.list_identities() {
+                self.key_backends.entry(key.id().to_vec()).or_insert(index);
+                identities.push((cert, key));
+            }
+        }
+        identities
+    }
+
+    fn get_signature_length(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<usize, Error> {
+        self.get_backend(key)?
+            .get_signature_length(key, data, params)
+    }
+
+    fn sign(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<Vec<u8>, Error> {
+        self.get_backend(key)?.sign(key, data, params)
+    }
+
+    fn sign_message(
+        &self,
+        key: &Key,
+        message: &[u8],
+        hash_algorithm: CK_MECHANISM_TYPE,
+    ) -> Result<Vec<u8>, Error> {
+        self.get_backend(key)?
+            .sign_message(key, message, hash_algorithm)
+    }
+
+    fn decrypt(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<OaepParams>,
+    ) -> Result<Vec<u8>, Error> {
+        self.get_backend(key)?.decrypt(key, data, params)
+    }
+
+    fn signs_hashes(&self, key: &Key) -> bool {
+        match self.get_backend(key) {
+            Ok(backend) => backend.signs_hashes(key),
+            Err(_) => true,
+        }
+    }
+
+    fn is_hardware(&self) -> bool {
+        !self.backends.is_empty() && self.backends.iter().all(|backend| backend.is_hardware())
+    }
+
+    fn login_required(&self) -> bool {
+        self.backends.iter().any(|backend| backend.login_required())
+    }
+
+    /// Logs in to each backend that requires it. Succeeds if at least one of them accepts the PIN
+    /// (or if none of them require logging in). Otherwise, fails the way the last backend did.
+    fn login(&mut self, pin: &str) -> Result<(), Error> {
+        let mut result = if self.login_required() {
+            Err(Error::PinIncorrect)
+        } else {
+            Ok(())
+        };
+        for backend in self.backends.iter_mut() {
+            if backend.login_required() {
+                match backend.login(pin) {
+                    Ok(()) => result = Ok(()),
+                    Err(e) if result.is_err() => result = Err(e),
+                    Err(_) => {}
+                }
+            }
+        }
+        result
+    }
+
+    fn logout(&mut self) {
+        for backend in self.backends.iter_mut() {
+            backend.logout();
+        }
+    }
+
+    fn login_context_specific(
+        &mut self,
+        key: &Key,
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+        pin: &str,
+    ) -> Result<(), Error> {
+        match self.key_backends.get(key.id()) {
+            Some(index) => self.backends[*index].login_context_specific(key, params, pin),
+            None => Err(Error::KeyHandleInvalid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_mock::*;
+
+    fn new_backend(stores: &[&MockStore]) -> MultiBackend {
+        MultiBackend::with_backends(
+            stores
+                .iter()
+                .map(|store| Box::new(MockBackend::new((*store).clone())) as Box<dyn Backend>)
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_identities_are_combined() {
+        let first_store = MockStore::new();
+        first_store.add_identity(RSA_CERT, RSA_KEY);
+        let second_store = MockStore::new();
+        second_store.add_identity(P256_CERT, P256_KEY);
+        second_store.add_identity(P384_CERT, P384_KEY);
+        let mut backend = new_backend(&[&first_store, &second_store]);
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 3);
+        assert_eq!(first_store.scan_count(), 1);
+        assert_eq!(second_store.scan_count(), 1);
+        for (_, key) in &identities {
+            assert!(backend.sign(key, &[0; 32], &None).is_ok());
+            assert!(backend.get_signature_length(key, &[0; 32], &None).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_duplicate_identities_use_first_backend() {
+        let first_store = MockStore::new();
+        first_store.add_identity(P256_CERT, P256_KEY);
+        let second_store = MockStore::new();
+        second_store.add_identity(P256_CERT, P256_KEY);
+        let mut backend = new_backend(&[&first_store, &second_store]);
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 2);
+        assert_eq!(backend.key_backends.len(), 1);
+        assert_eq!(backend.key_backends.get(identities[0].1.id()), Some(&0));
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let store = MockStore::new();
+        store.add_identity(P256_CERT, P256_KEY);
+        let (_, key) = MockBackend::new(store.clone())
+            .list_identities()
+            .pop()
+            .unwrap();
+        // This backend hasn't listed the key, so it doesn't know which backend it belongs to.
+        let backend = new_backend(&[&store]);
+        assert!(backend.sign(&key, &[0; 32], &None).is_err());
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    #[test]
+    fn test_watched_directories() {
+        use crate::backend_file::FileBackend;
+
+        let file_backend = |directory: &str| {
+            Box::new(FileBackend::with_directories(
+                "test",
+                vec![PathBuf::from(directory)],
+            )) as Box<dyn Backend>
+        };
+        let backend = MultiBackend::with_backends(vec![file_backend("/a"), file_backend("/b")]);
+        assert_eq!(
+            backend.watched_directories(),
+            vec![PathBuf::from("/a"), PathBuf::from("/b")]
+        );
+        // If any of the backends' identities don't come from files, nothing can be watched.
+        let backend = MultiBackend::with_backends(vec![
+            file_backend("/a"),
+            Box::new(MockBackend::new(MockStore::new())),
+        ]);
+        assert!(backend.watched_directories().is_empty());
+    }
+
+    #[test]
+    fn test_login() {
+        let first_store = MockStore::new();
+        first_store.add_identity(RSA_CERT, RSA_KEY);
+        let second_store = MockStore::new();
+        second_store.add_locked_identity(P256_CERT, P256_KEY, "1234");
+        let mut backend = new_backend(&[&first_store, &second_store]);
+        assert_eq!(backend.list_identities().len(), 1);
+        assert!(backend.login_required());
+        assert!(backend.login("0000").is_err());
+        assert!(backend.login("1234").is_ok());
+        assert!(!backend.login_required());
+        assert_eq!(backend.list_identities().len(), 2);
+        // With nothing left to unlock, any PIN is accepted.
+        assert!(backend.login("0000").is_ok());
+        backend.logout();
+        assert!(backend.login_required());
+        assert_eq!(backend.list_identities().len(), 1);
+    }
+}
@@ -0,0 +1,1007 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use pkcs11::errors::Error as Pkcs11Error;
+use pkcs11::types::*;
+use pkcs11::Ctx;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+use crate::backend::*;
+use crate::error::Error;
+use crate::util::*;
+
+/// The environment variable that lists the PKCS #11 modules to load, separated by ':'.
+const PKCS11_MODULES: &str = "OSCLIENTCERTS_PKCS11_MODULES";
+
+/// How many object handles to ask for at a time when searching for objects.
+const FIND_OBJECTS_BATCH_SIZE: CK_ULONG = 32;
+
+/// Returns the paths of the modules listed in `$OSCLIENTCERTS_PKCS11_MODULES`.
+pub fn configured_modules() -> Vec<PathBuf> {
+    match std::env::var_os(PKCS11_MODULES) {
+        Some(modules) => std::env::split_paths(&modules)
+            .filter(|path| !path.as_os_str().is_empty())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Reads the value of the given attribute of the given object. Returns `None` if the object
+/// doesn't have the attribute (or if it can't be read).
+fn get_attribute(
+    ctx: &Ctx,
+    session: CK_SESSION_HANDLE,
+    object: CK_OBJECT_HANDLE,
+    attribute_type: CK_ATTRIBUTE_TYPE,
+) -> Option<Vec<u8>> {
+    let mut template = vec![CK_ATTRIBUTE::new(attribute_type)];
+    match ctx.get_attribute_value(session, object, &mut template) {
+        Ok((CKR_OK, _)) => {}
+        _ => return None,
+    }
+    if template[0].ulValueLen == CK_UNAVAILABLE_INFORMATION {
+        return None;
+    }
+    let mut value = vec![0; template[0].ulValueLen as usize];
+    template[0].pValue = value.as_mut_ptr() as CK_VOID_PTR;
+    match ctx.get_attribute_value(session, object, &mut template) {
+        Ok((CKR_OK, _)) => {}
+        _ => return None,
+    }
+    value.truncate(template[0].ulValueLen as usize);
+    Some(value)
+}
+
+/// Reads the value of the given CK_ULONG attribute of the given object.
+fn get_ulong_attribute(
+    ctx: &Ctx,
+    session: CK_SESSION_HANDLE,
+    object: CK_OBJECT_HANDLE,
+    attribute_type: CK_ATTRIBUTE_TYPE,
+) -> Option<CK_ULONG> {
+    let value = get_attribute(ctx, session, object, attribute_type)?;
+    let bytes = value.get(..std::mem::size_of::<CK_ULONG>())?;
+    let mut ulong = [0; std::mem::size_of::<CK_ULONG>()];
+    ulong.copy_from_slice(bytes);
+    Some(CK_ULONG::from_ne_bytes(ulong))
+}
+
+/// Returns the handles of the objects of the given class in the given session.
+fn find_objects(
+    ctx: &Ctx,
+    session: CK_SESSION_HANDLE,
+    class: CK_OBJECT_CLASS,
+) -> Result<Vec<CK_OBJECT_HANDLE>, Pkcs11Error> {
+    let template = vec![CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class)];
+    ctx.find_objects_init(session, &template)?;
+    let mut objects = Vec::new();
+    let result = loop {
+        match ctx.find_objects(session, FIND_OBJECTS_BATCH_SIZE) {
+            Ok(batch) if batch.is_empty() => break Ok(objects),
+            Ok(batch) => objects.extend(batch),
+            Err(e) => break Err(e),
+        }
+    };
+    ctx.find_objects_final(session)?;
+    result
+}
+
+/// Converts an error from the module to the error this module returns for it, so that (for
+/// example) the user cancelling the module's PIN prompt isn't reported as a failure.
+fn to_error(error: Pkcs11Error) -> Error {
+    match error {
+        Pkcs11Error::Pkcs11(CKR_FUNCTION_CANCELED) => Error::Cancelled,
+        Pkcs11Error::Pkcs11(CKR_PIN_INCORRECT)
+        | Pkcs11Error::Pkcs11(CKR_PIN_INVALID)
+        | Pkcs11Error::Pkcs11(CKR_PIN_LEN_RANGE) => Error::PinIncorrect,
+        Pkcs11Error::Pkcs11(CKR_PIN_LOCKED) => Error::PinLocked,
+        Pkcs11Error::Pkcs11(CKR_USER_NOT_LOGGED_IN) => Error::UserNotLoggedIn,
+        Pkcs11Error::Pkcs11(CKR_MECHANISM_INVALID) => Error::MechanismInvalid,
+        Pkcs11Error::Pkcs11(CKR_MECHANISM_PARAM_INVALID) => Error::MechanismParamInvalid,
+        Pkcs11Error::Pkcs11(CKR_KEY_TYPE_INCONSISTENT) => Error::KeyTypeInconsistent,
+        Pkcs11Error::Pkcs11(CKR_DATA_INVALID) => Error::DataInvalid,
+        Pkcs11Error::Pkcs11(CKR_DATA_LEN_RANGE) => Error::DataLenRange,
+        Pkcs11Error::Pkcs11(CKR_ENCRYPTED_DATA_INVALID) => Error::EncryptedDataInvalid,
+        Pkcs11Error::Pkcs11(CKR_ENCRYPTED_DATA_LEN_RANGE) => Error::EncryptedDataLenRange,
+        Pkcs11Error::Pkcs11(CKR_KEY_FUNCTION_NOT_PERMITTED) => Error::KeyFunctionNotPermitted,
+        _ => Error::BackendFailure,
+    }
+}
+
+/// The types of keys supported by this backend. For RSA, the value is the length of the modulus in
+/// bytes. For ECDSA, the value is the width in bytes of each coordinate of a point on the key's
+/// curve.
+#[derive(Clone, Copy)]
+enum Pkcs11KeyType {
+    Rsa(usize),
+    Ecdsa(usize),
+}
+
+/// A private key in one of the module's tokens.
+struct Pkcs11Key {
+    slot: CK_SLOT_ID,
+    handle: CK_OBJECT_HANDLE,
+    key_type: Pkcs11KeyType,
+    /// Whether or not the module needs the PIN every time the key is used to sign.
+    always_authenticate: bool,
+}
+
+/// A session with one of the module's tokens, along with what we know about the token.
+struct Pkcs11Session {
+    handle: CK_SESSION_HANDLE,
+    /// The label of the token, which is used to label certificates that don't have a label.
+    token_label: Vec<u8>,
+    /// Whether or not the token needs a PIN before its private keys can be used.
+    needs_login: bool,
+    /// Whether or not the token is in a hardware slot (e.g. it is a smart card in a reader).
+    hardware: bool,
+    logged_in: bool,
+}
+
+/// A backend that re-exports the certificates and private keys of another PKCS #11 module (e.g. a
+/// smart card driver or an HSM library). Each certificate on each of the module's tokens is paired
+/// with the private key on the same token that has the same CKA_ID, and signing is forwarded to the
+/// module. If a token requires a PIN, logging in to this backend logs in to that token.
+pub struct Pkcs11Backend {
+    /// The path of the module, for logging.
+    path: PathBuf,
+    /// The label of the token this backend is exposed on: the module's description of itself.
+    label: String,
+    ctx: Ctx,
+    /// A session with each token that's present, by slot. Sessions are kept open so that the
+    /// tokens stay logged in.
+    sessions: BTreeMap<CK_SLOT_ID, Pkcs11Session>,
+    /// A map of key identifiers to the keys they identify.
+    keys: BTreeMap<Vec<u8>, Pkcs11Key>,
+    /// The slot and the identifier of the key of a signature that `login_context_specific` has
+    /// started on the module (and given the PIN for), which the next `sign` with that key
+    /// finishes. Until then, nothing else can be signed with the token in that slot.
+    pending_sign: RefCell<Option<(CK_SLOT_ID, Vec<u8>)>>,
+}
+
+impl Pkcs11Backend {
+    /// Loads and initializes the module at the given path. If given, `parameters` is passed to the
+    /// module in the pReserved field of CK_C_INITIALIZE_ARGS (NSS's softoken, for example, needs
+    /// this to know where its database is).
+    pub fn new(path: &Path, parameters: Option<&str>) -> Result<Pkcs11Backend, ()> {
+        let mut ctx = Ctx::new(path)
+            .map_err(|e| error!("couldn't load PKCS #11 module '{}': {}", path.display(), e))?;
+        let parameters = match parameters {
+            Some(parameters) => Some(CString::new(parameters).map_err(|_| ())?),
+            None => None,
+        };
+        let mut init_args = CK_C_INITIALIZE_ARGS::new();
+        if let Some(parameters) = &parameters {
+            init_args.pReserved = parameters.as_ptr() as CK_VOID_PTR;
+        }
+        ctx.initialize(Some(init_args)).map_err(|e| {
+            error!(
+                "couldn't initialize PKCS #11 module '{}': {}",
+                path.display(),
+                e
+            )
+        })?;
+        let label = match ctx.get_info() {
+            Ok(info) => {
+                let description = info.libraryDescription;
+                String::from_utf8_lossy(&description).trim_end().to_owned()
+            }
+            Err(_) => String::new(),
+        };
+        let label = if label.is_empty() {
+            path.file_name()
+                .map(|file_name| file_name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        } else {
+            label
+        };
+        Ok(Pkcs11Backend {
+            path: path.to_path_buf(),
+            label,
+            ctx,
+            sessions: BTreeMap::new(),
+            keys: BTreeMap::new(),
+            pending_sign: RefCell::new(None),
+        })
+    }
+
+    /// Makes sure there's a session with each token that's present, closing sessions with tokens
+    /// that have gone away.
+    fn update_sessions(&mut self) {
+        let slots = match self.ctx.get_slot_list(true) {
+            Ok(slots) => slots,
+            Err(e) => {
+                error!("C_GetSlotList failed for '{}': {}", self.path.display(), e);
+                Vec::new()
+            }
+        };
+        let ctx = &self.ctx;
+        self.sessions.retain(|slot, session| {
+            let valid = slots.contains(slot) && ctx.get_session_info(session.handle).is_ok();
+            if !valid {
+                let _ = ctx.close_session(session.handle);
+            }
+            valid
+        });
+        for slot in slots {
+            let token_info = match self.ctx.get_token_info(slot) {
+                Ok(token_info) => token_info,
+                Err(_) => continue,
+            };
+            let handle = match self.sessions.get(&slot) {
+                Some(session) => session.handle,
+                None => match self.ctx.open_session(slot, CKF_SERIAL_SESSION, None, None) {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        warn!("couldn't open a session on slot {}: {}", slot, e);
+                        continue;
+                    }
+                },
+            };
+            let hardware = match self.ctx.get_slot_info(slot) {
+                Ok(slot_info) => slot_info.flags & CKF_HW_SLOT != 0,
+                Err(_) => false,
+            };
+            let token_label = token_info.label;
+            let session = Pkcs11Session {
+                handle,
+                token_label: String::from_utf8_lossy(&token_label)
+                    .trim_end()
+                    .as_bytes()
+                    .to_vec(),
+                needs_login: token_info.flags & CKF_LOGIN_REQUIRED != 0,
+                hardware,
+                logged_in: self.session_is_logged_in(handle),
+            };
+            self.sessions.insert(slot, session);
+        }
+    }
+
+    fn session_is_logged_in(&self, session: CK_SESSION_HANDLE) -> bool {
+        match self.ctx.get_session_info(session) {
+            Ok(session_info) => {
+                let state = session_info.state;
+                state == CKS_RO_USER_FUNCTIONS || state == CKS_RW_USER_FUNCTIONS
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the certificates on the token in the given slot that have a private key, along with
+    /// their keys.
+    fn list_token_identities(
+        &self,
+        slot: CK_SLOT_ID,
+        session: &Pkcs11Session,
+    ) -> Vec<(Cert, Key, Pkcs11Key)> {
+        let ctx = &self.ctx;
+        let session_handle = session.handle;
+        let private_keys = match find_objects(ctx, session_handle, CKO_PRIVATE_KEY) {
+            Ok(private_keys) => private_keys,
+            Err(e) => {
+                error!("couldn't find private keys on slot {}: {}", slot, e);
+                return Vec::new();
+            }
+        };
+        let mut private_keys_by_id = BTreeMap::new();
+        for private_key in private_keys {
+            if let Some(id) = get_attribute(ctx, session_handle, private_key, CKA_ID) {
+                private_keys_by_id.entry(id).or_insert(private_key);
+            }
+        }
+        if private_keys_by_id.is_empty() {
+            return Vec::new();
+        }
+        let certs = match find_objects(ctx, session_handle, CKO_CERTIFICATE) {
+            Ok(certs) => certs,
+            Err(e) => {
+                error!("couldn't find certificates on slot {}: {}", slot, e);
+                return Vec::new();
+            }
+        };
+        let mut identities = Vec::new();
+        for cert in certs {
+            if get_ulong_attribute(ctx, session_handle, cert, CKA_CERTIFICATE_TYPE)
+                != Some(CKC_X_509)
+            {
+                continue;
+            }
+            let private_key = match get_attribute(ctx, session_handle, cert, CKA_ID)
+                .and_then(|id| private_keys_by_id.get(&id))
+            {
+                Some(private_key) => *private_key,
+                None => continue,
+            };
+            let cert_der = match get_attribute(ctx, session_handle, cert, CKA_VALUE) {
+                Some(cert_der) => cert_der,
+                None => continue,
+            };
+            let fields = match read_certificate_fields(&cert_der) {
+                Ok(fields) => fields,
+                Err(()) => continue,
+            };
+            let (mut key, key_type) =
+                match get_ulong_attribute(ctx, session_handle, private_key, CKA_KEY_TYPE) {
+                    Some(CKK_RSA) => {
+                        let modulus =
+                            match get_attribute(ctx, session_handle, private_key, CKA_MODULUS) {
+                                Some(modulus) => modulus,
+                                None => continue,
+                            };
+                        let modulus_length = modulus.iter().skip_while(|byte| **byte == 0).count();
+                        match Key::new(&cert_der, KeyType::RSA, Some(modulus), None) {
+                            Ok(key) => (key, Pkcs11KeyType::Rsa(modulus_length)),
+                            Err(()) => continue,
+                        }
+                    }
+                    Some(CKK_EC) => {
+                        let ec_params =
+                            match get_attribute(ctx, session_handle, private_key, CKA_EC_PARAMS) {
+                                Some(ec_params) => ec_params,
+                                None => continue,
+                            };
+                        let coordinate_width = match get_ec_coordinate_width(&ec_params) {
+                            Ok(coordinate_width) => coordinate_width,
+                            Err(()) => continue,
+                        };
+                        match Key::new(
+                            &cert_der,
+                            KeyType::EC(coordinate_width),
+                            None,
+                            Some(ec_params),
+                        ) {
+                            Ok(key) => (key, Pkcs11KeyType::Ecdsa(coordinate_width)),
+                            Err(()) => continue,
+                        }
+                    }
+                    _ => continue,
+                };
+            let always_authenticate =
+                get_attribute(ctx, session_handle, private_key, CKA_ALWAYS_AUTHENTICATE)
+                    == Some(vec![CK_TRUE]);
+            if key.set_always_authenticate(always_authenticate).is_err() {
+                continue;
+            }
+            let label = match get_attribute(ctx, session_handle, cert, CKA_LABEL) {
+                Some(label) if !label.is_empty() => label,
+                _ => session.token_label.clone(),
+            };
+            let cert = match Cert::new(
+                cert_der.clone(),
+                label,
+                fields.issuer.to_vec(),
+                fields.serial_number.to_vec(),
+                fields.subject.to_vec(),
+            ) {
+                Ok(cert) => cert,
+                Err(()) => continue,
+            };
+            identities.push((
+                cert,
+                key,
+                Pkcs11Key {
+                    slot,
+                    handle: private_key,
+                    key_type,
+                    always_authenticate,
+                },
+            ));
+        }
+        identities
+    }
+
+    /// Returns the module's key for the given key, along with the session with its token. Fails if
+    /// the key (or its token) has gone away.
+    fn get_key(&self, key: &Key) -> Result<(&Pkcs11Key, &Pkcs11Session), Error> {
+        let pkcs11_key = self.keys.get(key.id()).ok_or(Error::KeyHandleInvalid)?;
+        let session = self
+            .sessions
+            .get(&pkcs11_key.slot)
+            .ok_or(Error::KeyHandleInvalid)?;
+        Ok((pkcs11_key, session))
+    }
+
+    /// Starts a signature on the module using CKM_RSA_PKCS, CKM_RSA_PKCS_PSS, or CKM_ECDSA, as
+    /// appropriate. Any signature `login_context_specific` started on the same token is abandoned
+    /// first, since the token can only do one at a time.
+    fn sign_init(
+        &self,
+        pkcs11_key: &Pkcs11Key,
+        session: &Pkcs11Session,
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<(), Error> {
+        self.abandon_pending_sign(Some(pkcs11_key.slot));
+        let mut params = *params;
+        let mechanism = match (pkcs11_key.key_type, &mut params) {
+            (Pkcs11KeyType::Rsa(_), None) => CK_MECHANISM {
+                mechanism: CKM_RSA_PKCS,
+                pParameter: std::ptr::null_mut(),
+                ulParameterLen: 0,
+            },
+            (Pkcs11KeyType::Rsa(_), Some(params)) => CK_MECHANISM {
+                mechanism: CKM_RSA_PKCS_PSS,
+                pParameter: params as *mut CK_RSA_PKCS_PSS_PARAMS as CK_VOID_PTR,
+                ulParameterLen: std::mem::size_of::<CK_RSA_PKCS_PSS_PARAMS>() as CK_ULONG,
+            },
+            (Pkcs11KeyType::Ecdsa(_), _) => CK_MECHANISM {
+                mechanism: CKM_ECDSA,
+                pParameter: std::ptr::null_mut(),
+                ulParameterLen: 0,
+            },
+        };
+        self.ctx
+            .sign_init(session.handle, &mechanism, pkcs11_key.handle)
+            .map_err(|e| {
+                error!("C_SignInit failed for '{}': {}", self.path.display(), e);
+                to_error(e)
+            })
+    }
+
+    /// Ends the signature that was started on the module in the given session, without caring
+    /// about the result. C_Sign always ends the operation, unless it only returns the length of the
+    /// signature (which `Ctx::sign` follows with another call) or the buffer is too small (which
+    /// `Ctx::sign` avoids).
+    fn end_sign(&self, session: &Pkcs11Session) {
+        let _ = self.ctx.sign(session.handle, &[]);
+    }
+
+    /// Ends the signature `login_context_specific` started, if there is one (and it is on the token
+    /// in the given slot, if given).
+    fn abandon_pending_sign(&self, slot: Option<CK_SLOT_ID>) {
+        let mut pending_sign = self.pending_sign.borrow_mut();
+        let pending_slot = match &*pending_sign {
+            Some((pending_slot, _)) if slot.is_none() || slot == Some(*pending_slot) => {
+                *pending_slot
+            }
+            _ => return,
+        };
+        *pending_sign = None;
+        if let Some(session) = self.sessions.get(&pending_slot) {
+            debug!("abandoning signature on slot {}", pending_slot);
+            self.end_sign(session);
+        }
+    }
+}
+
+impl Backend for Pkcs11Backend {
+    fn token_label(&self) -> String {
+        self.label.clone()
+    }
+
+    fn list_identities(&mut self) -> Vec<(Cert, Key)> {
+        self.keys.clear();
+        self.update_sessions();
+        let mut identities = Vec::new();
+        for (slot, session) in &self.sessions {
+            for (cert, key, pkcs11_key) in self.list_token_identities(*slot, session) {
+                self.keys.insert(key.id().to_vec(), pkcs11_key);
+                identities.push((cert, key));
+            }
+        }
+        identities
+    }
+
+    fn get_signature_length(
+        &self,
+        key: &Key,
+        _data: &[u8],
+        _params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<usize, Error> {
+        match self.get_key(key)?.0.key_type {
+            Pkcs11KeyType::Rsa(modulus_length) => Ok(modulus_length),
+            Pkcs11KeyType::Ecdsa(coordinate_width) => Ok(2 * coordinate_width),
+        }
+    }
+
+    /// Signs the data with the module using CKM_RSA_PKCS, CKM_RSA_PKCS_PSS, or CKM_ECDSA, as
+    /// appropriate. If the key needs the PIN every time it is used, the signature has to have been
+    /// started by `login_context_specific` (which gave the module the PIN), and this finishes it.
+    fn sign(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<Vec<u8>, Error> {
+        let (pkcs11_key, session) = self.get_key(key)?;
+        if pkcs11_key.always_authenticate {
+            let mut pending_sign = self.pending_sign.borrow_mut();
+            match &*pending_sign {
+                Some((slot, id)) if *slot == pkcs11_key.slot && id == key.id() => {
+                    *pending_sign = None
+                }
+                _ => return Err(Error::UserNotLoggedIn),
+            }
+        } else {
+            self.sign_init(pkcs11_key, session, params)?;
+        }
+        self.ctx.sign(session.handle, data).map_err(|e| {
+            error!("C_Sign failed for '{}': {}", self.path.display(), e);
+            to_error(e)
+        })
+    }
+
+    /// Decrypts the data with the module using CKM_RSA_PKCS or CKM_RSA_PKCS_OAEP, as appropriate.
+    fn decrypt(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<OaepParams>,
+    ) -> Result<Vec<u8>, Error> {
+        let (pkcs11_key, session) = self.get_key(key)?;
+        if let Pkcs11KeyType::Ecdsa(_) = pkcs11_key.key_type {
+            return Err(Error::KeyTypeInconsistent);
+        }
+        let mut oaep_params = match params {
+            Some(params) => Some(CK_RSA_PKCS_OAEP_PARAMS {
+                hashAlg: params.hash_algorithm,
+                mgf: match params.hash_algorithm {
+                    CKM_SHA_1 => CKG_MGF1_SHA1,
+                    CKM_SHA256 => CKG_MGF1_SHA256,
+                    _ => return Err(Error::MechanismParamInvalid),
+                },
+                source: CKZ_DATA_SPECIFIED,
+                pSourceData: std::ptr::null_mut(),
+                ulSourceDataLen: 0,
+            }),
+            None => None,
+        };
+        let mechanism = match &mut oaep_params {
+            None => CK_MECHANISM {
+                mechanism: CKM_RSA_PKCS,
+                pParameter: std::ptr::null_mut(),
+                ulParameterLen: 0,
+            },
+            Some(oaep_params) => CK_MECHANISM {
+                mechanism: CKM_RSA_PKCS_OAEP,
+                pParameter: oaep_params as *mut CK_RSA_PKCS_OAEP_PARAMS as CK_VOID_PTR,
+                ulParameterLen: std::mem::size_of::<CK_RSA_PKCS_OAEP_PARAMS>() as CK_ULONG,
+            },
+        };
+        self.ctx
+            .decrypt_init(session.handle, &mechanism, pkcs11_key.handle)
+            .map_err(|e| {
+                error!("C_DecryptInit failed for '{}': {}", self.path.display(), e);
+                to_error(e)
+            })?;
+        self.ctx.decrypt(session.handle, data).map_err(|e| {
+            error!("C_Decrypt failed for '{}': {}", self.path.display(), e);
+            to_error(e)
+        })
+    }
+
+    /// The module's keys are used by hardware if all of its tokens are in hardware slots.
+    fn is_hardware(&self) -> bool {
+        !self.sessions.is_empty() && self.sessions.values().all(|session| session.hardware)
+    }
+
+    fn login_required(&self) -> bool {
+        self.sessions
+            .values()
+            .any(|session| session.needs_login && !session.logged_in)
+    }
+
+    /// Logs in to each token that requires it. Succeeds if at least one of them accepts the PIN.
+    /// Otherwise, fails the way the last token did.
+    fn login(&mut self, pin: &str) -> Result<(), Error> {
+        let mut result = Err(Error::PinIncorrect);
+        for (slot, session) in self.sessions.iter_mut() {
+            if !session.needs_login || session.logged_in {
+                continue;
+            }
+            match self.ctx.login(session.handle, CKU_USER, Some(pin)) {
+                Ok(()) | Err(Pkcs11Error::Pkcs11(CKR_USER_ALREADY_LOGGED_IN)) => {
+                    session.logged_in = true;
+                    result = Ok(());
+                }
+                Err(e) => {
+                    warn!("couldn't log in to slot {}: {}", slot, e);
+                    if result.is_err() {
+                        result = Err(to_error(e));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn logout(&mut self) {
+        self.abandon_pending_sign(None);
+        for session in self.sessions.values_mut() {
+            if session.logged_in {
+                let _ = self.ctx.logout(session.handle);
+                session.logged_in = false;
+            }
+        }
+    }
+
+    /// The module can only be given the PIN once the signature has been started, so this starts it
+    /// (to be finished by `sign`) and gives the module the PIN. If the module doesn't accept the
+    /// PIN, the signature is ended again.
+    fn login_context_specific(
+        &mut self,
+        key: &Key,
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+        pin: &str,
+    ) -> Result<(), Error> {
+        let (pkcs11_key, session) = self.get_key(key)?;
+        self.sign_init(pkcs11_key, session, params)?;
+        if let Err(e) = self
+            .ctx
+            .login(session.handle, CKU_CONTEXT_SPECIFIC, Some(pin))
+        {
+            error!("C_Login failed for '{}': {}", self.path.display(), e);
+            self.end_sign(session);
+            return Err(to_error(e));
+        }
+        self.pending_sign
+            .replace(Some((pkcs11_key.slot, key.id().to_vec())));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_mock::*;
+    use crate::digest::encode_digest_info;
+    use p256::ecdsa::signature::hazmat::PrehashVerifier;
+    use rsa::pkcs8::DecodePrivateKey;
+    use rsa::traits::PrivateKeyParts;
+    use rsa::traits::PublicKeyParts;
+    use rsa::{Oaep, Pkcs1v15Encrypt, Pkcs1v15Sign, Pss, RsaPrivateKey};
+    use sha2::{Digest, Sha256};
+    use std::sync::Mutex;
+
+    /// The environment variable that can name NSS's softoken, which stands in for a smart card or
+    /// HSM in these tests. If it isn't set, softoken is looked for in NSS's library directory, as
+    /// reported by pkg-config.
+    const SOFTOKEN_PATH_VARIABLE: &str = "OSCLIENTCERTS_TEST_SOFTOKEN";
+
+    /// Finds softoken, panicking if it isn't installed rather than letting the tests pass without
+    /// running.
+    fn softoken_path() -> PathBuf {
+        if let Some(path) = std::env::var_os(SOFTOKEN_PATH_VARIABLE) {
+            return PathBuf::from(path);
+        }
+        let output = std::process::Command::new("pkg-config")
+            .arg("--variable=libdir")
+            .arg("nss")
+            .output()
+            .expect("couldn't run pkg-config to find softoken");
+        assert!(
+            output.status.success(),
+            "pkg-config couldn't find NSS; set {} to the path of libsoftokn3.so",
+            SOFTOKEN_PATH_VARIABLE
+        );
+        let libdir = String::from_utf8(output.stdout).unwrap();
+        let path = Path::new(libdir.trim_end()).join("libsoftokn3.so");
+        assert!(
+            path.exists(),
+            "{} doesn't exist; set {} to the path of libsoftokn3.so",
+            path.display(),
+            SOFTOKEN_PATH_VARIABLE
+        );
+        path
+    }
+
+    /// The label NSS gives the token backed by its database.
+    const SOFTOKEN_DATABASE_LABEL: &str = "NSS Certificate DB";
+
+    /// softoken keeps global state, so only one test can use it at a time.
+    static SOFTOKEN_LOCK: Mutex<()> = Mutex::new(());
+
+    /// An NSS database in a temporary directory.
+    struct SoftokenDatabase {
+        softoken_path: PathBuf,
+        directory: tempfile::TempDir,
+    }
+
+    impl SoftokenDatabase {
+        /// Creates a database holding the given identities, protected by the given PIN, if any.
+        fn new(identities: &[(&[u8], &[u8])], pin: Option<&str>) -> SoftokenDatabase {
+            SoftokenDatabase::with_always_authenticate(identities, pin, &[])
+        }
+
+        /// Like `new`, but the given private keys have `CKA_ALWAYS_AUTHENTICATE` set.
+        fn with_always_authenticate(
+            identities: &[(&[u8], &[u8])],
+            pin: Option<&str>,
+            always_authenticate: &[&[u8]],
+        ) -> SoftokenDatabase {
+            let database = SoftokenDatabase {
+                softoken_path: softoken_path(),
+                directory: tempfile::tempdir().unwrap(),
+            };
+            let mut ctx = Ctx::new(&database.softoken_path).unwrap();
+            let parameters = CString::new(database.parameters()).unwrap();
+            let mut init_args = CK_C_INITIALIZE_ARGS::new();
+            init_args.pReserved = parameters.as_ptr() as CK_VOID_PTR;
+            ctx.initialize(Some(init_args)).unwrap();
+            let slot = ctx
+                .get_slot_list(true)
+                .unwrap()
+                .into_iter()
+                .find(|slot| {
+                    let label = ctx.get_token_info(*slot).unwrap().label;
+                    String::from_utf8_lossy(&label).trim_end() == SOFTOKEN_DATABASE_LABEL
+                })
+                .unwrap();
+            let session = ctx
+                .open_session(slot, CKF_SERIAL_SESSION | CKF_RW_SESSION, None, None)
+                .unwrap();
+            // Private keys can only be created once the database has a PIN, even if it's empty.
+            let pin = pin.unwrap_or("");
+            ctx.login(session, CKU_SO, Some("")).unwrap();
+            ctx.init_pin(session, Some(pin)).unwrap();
+            ctx.logout(session).unwrap();
+            ctx.login(session, CKU_USER, Some(pin)).unwrap();
+            for (index, (cert_der, key_der)) in identities.iter().enumerate() {
+                let id = vec![index as u8];
+                create_certificate(&ctx, session, cert_der, &id);
+                let always_authenticate = always_authenticate.contains(key_der);
+                create_private_key(&ctx, session, key_der, &id, always_authenticate);
+            }
+            database
+        }
+
+        fn parameters(&self) -> String {
+            format!(
+                "configdir='sql:{}' certPrefix='' keyPrefix='' secmod='secmod.db'",
+                self.directory.path().display()
+            )
+        }
+
+        fn backend(&self) -> Pkcs11Backend {
+            Pkcs11Backend::new(&self.softoken_path, Some(&self.parameters())).unwrap()
+        }
+    }
+
+    fn create_certificate(ctx: &Ctx, session: CK_SESSION_HANDLE, cert_der: &[u8], id: &[u8]) {
+        let fields = read_certificate_fields(cert_der).unwrap();
+        let template = vec![
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_CERTIFICATE),
+            CK_ATTRIBUTE::new(CKA_CERTIFICATE_TYPE).with_ck_ulong(&CKC_X_509),
+            CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_ID).with_bytes(id),
+            CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(cert_der),
+            CK_ATTRIBUTE::new(CKA_SUBJECT).with_bytes(fields.subject),
+            CK_ATTRIBUTE::new(CKA_ISSUER).with_bytes(fields.issuer),
+            CK_ATTRIBUTE::new(CKA_SERIAL_NUMBER).with_bytes(fields.serial_number),
+        ];
+        ctx.create_object(session, &template).unwrap();
+    }
+
+    fn create_private_key(
+        ctx: &Ctx,
+        session: CK_SESSION_HANDLE,
+        key_der: &[u8],
+        id: &[u8],
+        always_authenticate: bool,
+    ) {
+        let always_authenticate = if always_authenticate {
+            CK_TRUE
+        } else {
+            CK_FALSE
+        };
+        let mut template = vec![
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_PRIVATE_KEY),
+            CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_PRIVATE).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_SIGN).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_ALWAYS_AUTHENTICATE).with_bool(&always_authenticate),
+            CK_ATTRIBUTE::new(CKA_ID).with_bytes(id),
+        ];
+        // The attribute values have to outlive the template, which only points to them.
+        let mut values: Vec<Vec<u8>> = Vec::new();
+        let mut attributes: Vec<(CK_ATTRIBUTE_TYPE, usize)> = Vec::new();
+        let key_type = if let Ok(key) = RsaPrivateKey::from_pkcs8_der(key_der) {
+            let primes = key.primes();
+            let crt_coefficient = key.crt_coefficient().unwrap();
+            for (attribute_type, value) in [
+                (CKA_MODULUS, key.n()),
+                (CKA_PUBLIC_EXPONENT, key.e()),
+                (CKA_PRIVATE_EXPONENT, key.d()),
+                (CKA_PRIME_1, &primes[0]),
+                (CKA_PRIME_2, &primes[1]),
+                (CKA_EXPONENT_1, key.dp().unwrap()),
+                (CKA_EXPONENT_2, key.dq().unwrap()),
+                (CKA_COEFFICIENT, &crt_coefficient),
+            ] {
+                attributes.push((attribute_type, values.len()));
+                values.push(value.to_bytes_be());
+            }
+            template.push(CK_ATTRIBUTE::new(CKA_DECRYPT).with_bool(&CK_TRUE));
+            CKK_RSA
+        } else {
+            let key = p256::ecdsa::SigningKey::from_pkcs8_der(key_der).unwrap();
+            attributes.push((CKA_EC_PARAMS, values.len()));
+            values.push(OID_BYTES_SECP256R1.to_vec());
+            attributes.push((CKA_VALUE, values.len()));
+            values.push(key.to_bytes().to_vec());
+            CKK_EC
+        };
+        template.push(CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&key_type));
+        for (attribute_type, index) in attributes {
+            template.push(CK_ATTRIBUTE::new(attribute_type).with_bytes(&values[index]));
+        }
+        ctx.create_object(session, &template).unwrap();
+    }
+
+    #[test]
+    fn test_configured_modules() {
+        std::env::set_var(PKCS11_MODULES, "/a/module.so::/b/module.so");
+        assert_eq!(
+            configured_modules(),
+            vec![PathBuf::from("/a/module.so"), PathBuf::from("/b/module.so")]
+        );
+        std::env::remove_var(PKCS11_MODULES);
+        assert!(configured_modules().is_empty());
+    }
+
+    #[test]
+    fn test_missing_module() {
+        assert!(Pkcs11Backend::new(Path::new("/nonexistent/module.so"), None).is_err());
+    }
+
+    #[test]
+    fn test_identities_and_signing() {
+        let _guard = SOFTOKEN_LOCK.lock().unwrap();
+        let database = SoftokenDatabase::new(&[(RSA_CERT, RSA_KEY), (P256_CERT, P256_KEY)], None);
+        let mut backend = database.backend();
+        assert!(!backend.login_required());
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 2);
+
+        let (_, rsa_key) = identities
+            .iter()
+            .find(|(_, key)| key.key_type_enum() == KeyType::RSA)
+            .unwrap();
+        let public_key = RsaPrivateKey::from_pkcs8_der(RSA_KEY)
+            .unwrap()
+            .to_public_key();
+        let hash = Sha256::digest(b"hello");
+        let digest_info = encode_digest_info(CKM_SHA256, &hash).unwrap();
+        let signature = backend.sign(rsa_key, &digest_info, &None).unwrap();
+        assert_eq!(
+            backend.get_signature_length(rsa_key, &digest_info, &None),
+            Ok(signature.len())
+        );
+        assert!(public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), &digest_info, &signature)
+            .is_ok());
+        let params = Some(CK_RSA_PKCS_PSS_PARAMS {
+            hashAlg: CKM_SHA256,
+            mgf: CKG_MGF1_SHA256,
+            sLen: 32,
+        });
+        let signature = backend.sign(rsa_key, &hash, &params).unwrap();
+        assert!(public_key
+            .verify(Pss::new::<Sha256>(), &hash, &signature)
+            .is_ok());
+
+        let (_, ec_key) = identities
+            .iter()
+            .find(|(_, key)| key.key_type_enum() == KeyType::EC(32))
+            .unwrap();
+        let signature = backend.sign(ec_key, &hash, &None).unwrap();
+        assert_eq!(signature.len(), 64);
+        assert_eq!(backend.get_signature_length(ec_key, &hash, &None), Ok(64));
+        let verifying_key = *p256::ecdsa::SigningKey::from_pkcs8_der(P256_KEY)
+            .unwrap()
+            .verifying_key();
+        let signature = p256::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(verifying_key.verify_prehash(&hash, &signature).is_ok());
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let _guard = SOFTOKEN_LOCK.lock().unwrap();
+        let database = SoftokenDatabase::new(&[(RSA_CERT, RSA_KEY), (P256_CERT, P256_KEY)], None);
+        let mut backend = database.backend();
+        let identities = backend.list_identities();
+        let (_, rsa_key) = identities
+            .iter()
+            .find(|(_, key)| key.key_type_enum() == KeyType::RSA)
+            .unwrap();
+        let public_key = RsaPrivateKey::from_pkcs8_der(RSA_KEY)
+            .unwrap()
+            .to_public_key();
+        let encrypted = public_key
+            .encrypt(&mut rsa::rand_core::OsRng, Pkcs1v15Encrypt, b"secret")
+            .unwrap();
+        assert_eq!(
+            backend.decrypt(rsa_key, &encrypted, &None),
+            Ok(b"secret".to_vec())
+        );
+        let encrypted = public_key
+            .encrypt(&mut rsa::rand_core::OsRng, Oaep::new::<Sha256>(), b"secret")
+            .unwrap();
+        let params = Some(OaepParams {
+            hash_algorithm: CKM_SHA256,
+        });
+        assert_eq!(
+            backend.decrypt(rsa_key, &encrypted, &params),
+            Ok(b"secret".to_vec())
+        );
+
+        let (_, ec_key) = identities
+            .iter()
+            .find(|(_, key)| key.key_type_enum() == KeyType::EC(32))
+            .unwrap();
+        assert_eq!(
+            backend.decrypt(ec_key, &encrypted, &None),
+            Err(Error::KeyTypeInconsistent)
+        );
+    }
+
+    #[test]
+    fn test_login() {
+        let _guard = SOFTOKEN_LOCK.lock().unwrap();
+        let database = SoftokenDatabase::new(&[(P256_CERT, P256_KEY)], Some("1234"));
+        let mut backend = database.backend();
+        // Without logging in, the private key isn't visible.
+        assert!(backend.list_identities().is_empty());
+        assert!(backend.login_required());
+        assert!(backend.login("0000").is_err());
+        assert!(backend.login("1234").is_ok());
+        assert!(!backend.login_required());
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 1);
+        let label = Object::Cert(identities.into_iter().next().unwrap().0)
+            .get_attribute(CKA_LABEL)
+            .map(|label| label.to_vec());
+        // The certificate doesn't have a label of its own, so it's labeled with the token's.
+        assert_eq!(label, Some(SOFTOKEN_DATABASE_LABEL.as_bytes().to_vec()));
+        backend.logout();
+        assert!(backend.login_required());
+        assert!(backend.list_identities().is_empty());
+    }
+
+    #[test]
+    fn test_context_specific_login() {
+        let _guard = SOFTOKEN_LOCK.lock().unwrap();
+        let database = SoftokenDatabase::with_always_authenticate(
+            &[(RSA_CERT, RSA_KEY), (P256_CERT, P256_KEY)],
+            Some("1234"),
+            &[P256_KEY],
+        );
+        let mut backend = database.backend();
+        assert!(backend.list_identities().is_empty());
+        assert!(backend.login("1234").is_ok());
+        let identities = backend.list_identities();
+        assert_eq!(identities.len(), 2);
+        let (_, rsa_key) = identities
+            .iter()
+            .find(|(_, key)| key.key_type_enum() == KeyType::RSA)
+            .unwrap();
+        let (_, ec_key) = identities
+            .iter()
+            .find(|(_, key)| key.key_type_enum() == KeyType::EC(32))
+            .unwrap();
+        assert!(!rsa_key.needs_context_specific_login());
+        assert!(ec_key.needs_context_specific_login());
+        let hash = Sha256::digest(b"hello");
+        // Without the PIN, nothing is started on the module.
+        assert_eq!(
+            backend.sign(ec_key, &hash, &None),
+            Err(Error::UserNotLoggedIn)
+        );
+        // softoken doesn't support CKU_CONTEXT_SPECIFIC, so it rejects the right PIN as well as
+        // the wrong one. Either way, the PIN is checked when it is given, and the signature that
+        // had to be started to give it is ended again.
+        for pin in ["0000", "1234"] {
+            assert!(backend.login_context_specific(ec_key, &None, pin).is_err());
+            assert_eq!(
+                backend.sign(ec_key, &hash, &None),
+                Err(Error::UserNotLoggedIn)
+            );
+            let digest_info = encode_digest_info(CKM_SHA256, &hash).unwrap();
+            assert!(backend.sign(rsa_key, &digest_info, &None).is_ok());
+        }
+    }
+}
@@ -0,0 +1,682 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use pkcs11::types::*;
+use rsa::pkcs8::EncodePublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, RsaPublicKey};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::backend::*;
+use crate::backend_file::{default_directories, find_certificates};
+use crate::error::Error;
+use crate::util::*;
+
+/// The environment variable that holds the path of the agent's socket.
+const SSH_AUTH_SOCK: &str = "SSH_AUTH_SOCK";
+
+// Message numbers from the ssh-agent protocol (draft-miller-ssh-agent).
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH2_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH2_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH2_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH2_AGENT_SIGN_RESPONSE: u8 = 14;
+
+/// Flags for SSH2_AGENTC_SIGN_REQUEST that ask for RSA signatures using SHA-256 or SHA-512 (rather
+/// than SHA-1).
+const SSH_AGENT_RSA_SHA2_256: u32 = 2;
+const SSH_AGENT_RSA_SHA2_512: u32 = 4;
+
+/// The largest response we'll accept from the agent.
+const MAX_RESPONSE_LENGTH: usize = 256 * 1024;
+/// How long to wait for the agent to respond. The agent may ask the user to confirm each use of a
+/// key (see `ssh-add -c`), so this is fairly generous.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A helper for reading data in the SSH wire encoding (RFC 4251, section 5).
+struct SshReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SshReader<'a> {
+    fn new(data: &'a [u8]) -> SshReader<'a> {
+        SshReader { data }
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], ()> {
+        if self.data.len() < length {
+            return Err(());
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, ()> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, ()> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_string(&mut self) -> Result<&'a [u8], ()> {
+        let length = self.read_u32()?;
+        self.read_bytes(usize::try_from(length).map_err(|_| ())?)
+    }
+
+    /// Reads an mpint, which must not be negative. The returned bytes have no leading zeroes.
+    fn read_mpint(&mut self) -> Result<&'a [u8], ()> {
+        let bytes = self.read_string()?;
+        if bytes.first().is_some_and(|byte| byte & 0x80 != 0) {
+            return Err(());
+        }
+        let leading_zeroes = bytes.iter().take_while(|byte| **byte == 0).count();
+        Ok(&bytes[leading_zeroes..])
+    }
+
+    fn at_end(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+fn write_string(buffer: &mut Vec<u8>, string: &[u8]) -> Result<(), ()> {
+    let length = u32::try_from(string.len()).map_err(|_| ())?;
+    buffer.extend_from_slice(&length.to_be_bytes());
+    buffer.extend_from_slice(string);
+    Ok(())
+}
+
+/// The types of keys supported by this backend. For RSA, the value is the length of the modulus in
+/// bytes. For ECDSA, the value is the width in bytes of each coordinate of a point on the key's
+/// curve.
+#[derive(Clone, Copy)]
+enum AgentKeyType {
+    Rsa(usize),
+    Ecdsa(usize),
+}
+
+/// A public key listed by the agent.
+struct AgentKey {
+    /// The SSH wire encoding of the public key. This is how keys are identified to the agent.
+    blob: Vec<u8>,
+    key_type: AgentKeyType,
+    /// The DER encoding of the SubjectPublicKeyInfo corresponding to the key, which is used to find
+    /// the key's certificate.
+    subject_public_key_info: Vec<u8>,
+    /// For RSA keys, the modulus. For ECDSA keys, the DER encoding of the OID of the curve.
+    modulus_or_ec_params: Vec<u8>,
+}
+
+impl AgentKey {
+    /// Decodes a public key blob listed by the agent. RSA keys and ECDSA keys on the P-256 and
+    /// P-384 curves are supported.
+    fn new(blob: &[u8]) -> Result<AgentKey, ()> {
+        let mut reader = SshReader::new(blob);
+        let algorithm = reader.read_string()?;
+        let (key_type, subject_public_key_info, modulus_or_ec_params) = match algorithm {
+            b"ssh-rsa" => {
+                let e = reader.read_mpint()?;
+                let n = reader.read_mpint()?;
+                let public_key =
+                    RsaPublicKey::new(BigUint::from_bytes_be(n), BigUint::from_bytes_be(e))
+                        .map_err(|e| error!("invalid RSA key in ssh-agent: {}", e))?;
+                let spki = public_key.to_public_key_der().map_err(|_| ())?;
+                (AgentKeyType::Rsa(public_key.size()), spki, n.to_vec())
+            }
+            b"ecdsa-sha2-nistp256" => {
+                if reader.read_string()? != b"nistp256" {
+                    return Err(());
+                }
+                let public_key =
+                    p256::PublicKey::from_sec1_bytes(reader.read_string()?).map_err(|_| ())?;
+                let spki = public_key.to_public_key_der().map_err(|_| ())?;
+                (AgentKeyType::Ecdsa(32), spki, OID_BYTES_SECP256R1.to_vec())
+            }
+            b"ecdsa-sha2-nistp384" => {
+                if reader.read_string()? != b"nistp384" {
+                    return Err(());
+                }
+                let public_key =
+                    p384::PublicKey::from_sec1_bytes(reader.read_string()?).map_err(|_| ())?;
+                let spki = public_key.to_public_key_der().map_err(|_| ())?;
+                (AgentKeyType::Ecdsa(48), spki, OID_BYTES_SECP384R1.to_vec())
+            }
+            _ => {
+                debug!(
+                    "ignoring unsupported ssh-agent key type '{}'",
+                    String::from_utf8_lossy(algorithm)
+                );
+                return Err(());
+            }
+        };
+        if !reader.at_end() {
+            return Err(());
+        }
+        Ok(AgentKey {
+            blob: blob.to_vec(),
+            key_type,
+            subject_public_key_info: subject_public_key_info.into_vec(),
+            modulus_or_ec_params,
+        })
+    }
+
+    fn new_key(&self, cert_der: &[u8]) -> Result<Key, ()> {
+        match self.key_type {
+            AgentKeyType::Rsa(_) => Key::new(
+                cert_der,
+                KeyType::RSA,
+                Some(self.modulus_or_ec_params.clone()),
+                None,
+            ),
+            AgentKeyType::Ecdsa(coordinate_width) => Key::new(
+                cert_der,
+                KeyType::EC(coordinate_width),
+                None,
+                Some(self.modulus_or_ec_params.clone()),
+            ),
+        }
+    }
+}
+
+/// Converts an ECDSA signature in the SSH wire encoding (the mpints r and s) to the concatenation
+/// of r and s, each 0-padded to be `coordinate_width` bytes.
+fn ssh_ecdsa_signature_to_raw(signature: &[u8], coordinate_width: usize) -> Result<Vec<u8>, ()> {
+    let mut reader = SshReader::new(signature);
+    let r = reader.read_mpint()?;
+    let s = reader.read_mpint()?;
+    if !reader.at_end() || r.len() > coordinate_width || s.len() > coordinate_width {
+        return Err(());
+    }
+    let mut signature_value = Vec::with_capacity(2 * coordinate_width);
+    signature_value.resize(coordinate_width - r.len(), 0);
+    signature_value.extend_from_slice(r);
+    signature_value.resize(2 * coordinate_width - s.len(), 0);
+    signature_value.extend_from_slice(s);
+    Ok(signature_value)
+}
+
+/// A backend that uses keys held by an ssh-agent (possibly one forwarded from another machine).
+/// The agent only knows about keys, so certificates are found in the same directories the
+/// `FileBackend` uses and paired with the agent's keys by public key.
+/// Because the agent hashes the data it signs, it can't be given a precomputed hash to sign. This
+/// means keys from this backend can only be used with `sign_message`, not `sign`.
+pub struct SshAgentBackend {
+    /// The path of the agent's socket.
+    socket_path: PathBuf,
+    /// The directories to search for certificates.
+    directories: Vec<PathBuf>,
+    /// A map of key identifiers to the keys they identify.
+    keys: BTreeMap<Vec<u8>, AgentKey>,
+}
+
+impl SshAgentBackend {
+    /// Creates an `SshAgentBackend` that uses the agent at `$SSH_AUTH_SOCK` with certificates from
+    /// the default directories, if `$SSH_AUTH_SOCK` is set.
+    pub fn new() -> Option<SshAgentBackend> {
+        SshAgentBackend::with_options(None, None)
+    }
+
+    /// Creates an `SshAgentBackend` that uses the agent with the given socket (or the one at
+    /// `$SSH_AUTH_SOCK`, if set) with certificates from the given directories (or the default
+    /// directories).
+    pub fn with_options(
+        socket_path: Option<PathBuf>,
+        directories: Option<Vec<PathBuf>>,
+    ) -> Option<SshAgentBackend> {
+        let socket_path = match socket_path {
+            Some(socket_path) => socket_path,
+            None => PathBuf::from(std::env::var_os(SSH_AUTH_SOCK)?),
+        };
+        Some(SshAgentBackend::with_socket_path(
+            socket_path,
+            directories.unwrap_or_else(default_directories),
+        ))
+    }
+
+    /// Creates an `SshAgentBackend` that uses the agent with the given socket and certificates from
+    /// the given directories.
+    pub fn with_socket_path(socket_path: PathBuf, directories: Vec<PathBuf>) -> SshAgentBackend {
+        SshAgentBackend {
+            socket_path,
+            directories,
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Sends the given request to the agent and returns its response. A new connection is made for
+    /// each request so that nothing goes wrong if the agent restarts.
+    fn call_agent(&self, request: &[u8]) -> Result<Vec<u8>, ()> {
+        let mut stream = UnixStream::connect(&self.socket_path).map_err(|e| {
+            error!(
+                "couldn't connect to ssh-agent at '{}': {}",
+                self.socket_path.display(),
+                e
+            )
+        })?;
+        stream
+            .set_read_timeout(Some(RESPONSE_TIMEOUT))
+            .map_err(|_| ())?;
+        let mut message = Vec::with_capacity(request.len() + 4);
+        write_string(&mut message, request)?;
+        stream
+            .write_all(&message)
+            .map_err(|e| error!("couldn't write to ssh-agent: {}", e))?;
+        let mut length = [0; 4];
+        stream
+            .read_exact(&mut length)
+            .map_err(|e| error!("couldn't read from ssh-agent: {}", e))?;
+        let length = usize::try_from(u32::from_be_bytes(length)).map_err(|_| ())?;
+        if length == 0 || length > MAX_RESPONSE_LENGTH {
+            error!("invalid response length from ssh-agent");
+            return Err(());
+        }
+        let mut response = vec![0; length];
+        stream
+            .read_exact(&mut response)
+            .map_err(|e| error!("couldn't read from ssh-agent: {}", e))?;
+        Ok(response)
+    }
+
+    /// Asks the agent for the keys it has. Keys of unsupported types are skipped.
+    fn request_identities(&self) -> Result<Vec<AgentKey>, ()> {
+        let response = self.call_agent(&[SSH2_AGENTC_REQUEST_IDENTITIES])?;
+        let mut reader = SshReader::new(&response);
+        if reader.read_u8()? != SSH2_AGENT_IDENTITIES_ANSWER {
+            error!("unexpected response to ssh-agent identities request");
+            return Err(());
+        }
+        let count = reader.read_u32()?;
+        let mut keys = Vec::new();
+        for _ in 0..count {
+            let blob = reader.read_string()?;
+            let _comment = reader.read_string()?;
+            if let Ok(key) = AgentKey::new(blob) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Asks the agent to sign the message with the given key and flags, and returns the value of
+    /// the signature, which has to be of the given algorithm. The value is in SSH's format, except
+    /// that RSA signatures are padded to the length of the modulus.
+    fn request_signature(
+        &self,
+        agent_key: &AgentKey,
+        message: &[u8],
+        flags: u32,
+        expected_algorithm: &[u8],
+    ) -> Result<Vec<u8>, ()> {
+        let mut request = vec![SSH2_AGENTC_SIGN_REQUEST];
+        write_string(&mut request, &agent_key.blob)?;
+        write_string(&mut request, message)?;
+        request.extend_from_slice(&flags.to_be_bytes());
+        let response = self.call_agent(&request)?;
+        let mut reader = SshReader::new(&response);
+        match reader.read_u8()? {
+            SSH2_AGENT_SIGN_RESPONSE => {}
+            SSH_AGENT_FAILURE => {
+                error!("ssh-agent refused to sign");
+                return Err(());
+            }
+            _ => {
+                error!("unexpected response to ssh-agent sign request");
+                return Err(());
+            }
+        }
+        let mut signature = SshReader::new(reader.read_string()?);
+        if !reader.at_end() || signature.read_string()? != expected_algorithm {
+            error!("unexpected signature from ssh-agent");
+            return Err(());
+        }
+        let signature_value = signature.read_string()?;
+        if !signature.at_end() {
+            return Err(());
+        }
+        match agent_key.key_type {
+            AgentKeyType::Rsa(modulus_length) => {
+                if signature_value.len() > modulus_length {
+                    return Err(());
+                }
+                let mut padded_signature = vec![0; modulus_length - signature_value.len()];
+                padded_signature.extend_from_slice(signature_value);
+                Ok(padded_signature)
+            }
+            AgentKeyType::Ecdsa(_) => Ok(signature_value.to_vec()),
+        }
+    }
+}
+
+impl Backend for SshAgentBackend {
+    fn token_label(&self) -> String {
+        String::from("SSH Agent")
+    }
+
+    fn list_identities(&mut self) -> Vec<(Cert, Key)> {
+        self.keys.clear();
+        let mut agent_keys = match self.request_identities() {
+            Ok(agent_keys) => agent_keys,
+            Err(()) => return Vec::new(),
+        };
+        if agent_keys.is_empty() {
+            return Vec::new();
+        }
+        let mut identities = Vec::new();
+        for cert in find_certificates(&self.directories) {
+            let fields = match read_certificate_fields(&cert.der) {
+                Ok(fields) => fields,
+                Err(()) => continue,
+            };
+            let index = match agent_keys.iter().position(|agent_key| {
+                agent_key.subject_public_key_info == fields.subject_public_key_info
+            }) {
+                Some(index) => index,
+                None => continue,
+            };
+            let key = match agent_keys[index].new_key(&cert.der) {
+                Ok(key) => key,
+                Err(()) => continue,
+            };
+            let cert = match Cert::new(
+                cert.der.clone(),
+                cert.label,
+                fields.issuer.to_vec(),
+                fields.serial_number.to_vec(),
+                fields.subject.to_vec(),
+            ) {
+                Ok(cert) => cert,
+                Err(()) => continue,
+            };
+            self.keys
+                .insert(key.id().to_vec(), agent_keys.swap_remove(index));
+            identities.push((cert, key));
+        }
+        identities
+    }
+
+    fn get_signature_length(
+        &self,
+        key: &Key,
+        _data: &[u8],
+        _params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<usize, Error> {
+        match self
+            .keys
+            .get(key.id())
+            .ok_or(Error::KeyHandleInvalid)?
+            .key_type
+        {
+            AgentKeyType::Rsa(modulus_length) => Ok(modulus_length),
+            AgentKeyType::Ecdsa(coordinate_width) => Ok(2 * coordinate_width),
+        }
+    }
+
+    fn sign(
+        &self,
+        _key: &Key,
+        _data: &[u8],
+        _params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<Vec<u8>, Error> {
+        error!("ssh-agent can't sign precomputed hashes");
+        Err(Error::MechanismInvalid)
+    }
+
+    fn signs_hashes(&self, _key: &Key) -> bool {
+        false
+    }
+
+    /// Asks the agent to sign the message. RSA keys can be used with SHA-256 or SHA-512. ECDSA keys
+    /// can only be used with the hash algorithm SSH uses with their curve (SHA-256 for P-256 and
+    /// SHA-384 for P-384).
+    fn sign_message(
+        &self,
+        key: &Key,
+        message: &[u8],
+        hash_algorithm: CK_MECHANISM_TYPE,
+    ) -> Result<Vec<u8>, Error> {
+        let agent_key = self.keys.get(key.id()).ok_or(Error::KeyHandleInvalid)?;
+        let (flags, expected_algorithm): (u32, &[u8]) = match (agent_key.key_type, hash_algorithm) {
+            (AgentKeyType::Rsa(_), CKM_SHA256) => (SSH_AGENT_RSA_SHA2_256, b"rsa-sha2-256"),
+            (AgentKeyType::Rsa(_), CKM_SHA512) => (SSH_AGENT_RSA_SHA2_512, b"rsa-sha2-512"),
+            (AgentKeyType::Ecdsa(32), CKM_SHA256) => (0, b"ecdsa-sha2-nistp256"),
+            (AgentKeyType::Ecdsa(48), CKM_SHA384) => (0, b"ecdsa-sha2-nistp384"),
+            _ => {
+                error!(
+                    "unsupported hash algorithm for ssh-agent key: {}",
+                    hash_algorithm
+                );
+                return Err(Error::MechanismInvalid);
+            }
+        };
+        // Anything that goes wrong talking to the agent (including it refusing to sign) is a
+        // failure of the backend.
+        let signature = self
+            .request_signature(agent_key, message, flags, expected_algorithm)
+            .map_err(|()| Error::BackendFailure)?;
+        match agent_key.key_type {
+            AgentKeyType::Rsa(_) => Ok(signature),
+            AgentKeyType::Ecdsa(coordinate_width) => {
+                ssh_ecdsa_signature_to_raw(&signature, coordinate_width)
+                    .map_err(|()| Error::BackendFailure)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_mock::*;
+    use crate::digest::encode_digest_info;
+    use p256::ecdsa::signature::hazmat::PrehashVerifier;
+    use rsa::pkcs8::DecodePrivateKey;
+    use rsa::{Pkcs1v15Sign, RsaPrivateKey};
+    use sha2::{Digest, Sha256, Sha384, Sha512};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::{Child, Command, Stdio};
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    /// How long to wait for ssh-agent to create its socket.
+    const AGENT_STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// An ssh-agent run for the duration of a test, along with a directory of certificates.
+    struct TestAgent {
+        agent: Child,
+        directory: TempDir,
+    }
+
+    impl TestAgent {
+        /// Starts an ssh-agent with the given PKCS #8 keys and writes the given certificates to a
+        /// directory. Panics if ssh-agent isn't installed or doesn't start.
+        fn new(keys: &[&[u8]], certs: &[&[u8]]) -> TestAgent {
+            let directory = tempfile::tempdir().unwrap();
+            let socket_path = directory.path().join("agent.sock");
+            let agent = Command::new("ssh-agent")
+                .arg("-D")
+                .arg("-a")
+                .arg(&socket_path)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("couldn't run ssh-agent");
+            let mut test_agent = TestAgent { agent, directory };
+            let deadline = Instant::now() + AGENT_STARTUP_TIMEOUT;
+            while !socket_path.exists() {
+                if let Some(status) = test_agent.agent.try_wait().unwrap() {
+                    panic!("ssh-agent exited before creating its socket ({})", status);
+                }
+                assert!(
+                    Instant::now() < deadline,
+                    "ssh-agent didn't create its socket within {:?}",
+                    AGENT_STARTUP_TIMEOUT
+                );
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            for (index, key) in keys.iter().enumerate() {
+                let key_path = test_agent.directory.path().join(format!("{}.key", index));
+                fs::write(&key_path, pem::encode(&pem::Pem::new("PRIVATE KEY", *key))).unwrap();
+                fs::set_permissions(&key_path, fs::Permissions::from_mode(0o600)).unwrap();
+                let status = Command::new("ssh-add")
+                    .arg(&key_path)
+                    .env(SSH_AUTH_SOCK, &socket_path)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+                // Keep the key out of the certificate directory.
+                fs::remove_file(&key_path).unwrap();
+            }
+            for (index, cert) in certs.iter().enumerate() {
+                let cert_path = test_agent.directory.path().join(format!("{}.crt", index));
+                fs::write(cert_path, cert).unwrap();
+            }
+            test_agent
+        }
+
+        fn backend(&self) -> SshAgentBackend {
+            SshAgentBackend::with_socket_path(
+                self.directory.path().join("agent.sock"),
+                vec![self.directory.path().to_path_buf()],
+            )
+        }
+    }
+
+    impl Drop for TestAgent {
+        fn drop(&mut self) {
+            let _ = self.agent.kill();
+            let _ = self.agent.wait();
+        }
+    }
+
+    fn find_key<'a>(identities: &'a [(Cert, Key)], cert_der: &[u8]) -> &'a Key {
+        let (_, key) = identities
+            .iter()
+            .find(|(cert, _)| cert.id() == id_of(cert_der).as_slice())
+            .unwrap();
+        key
+    }
+
+    #[test]
+    fn test_identities_are_paired_with_certificates() {
+        let agent = TestAgent::new(&[RSA_KEY, P256_KEY], &[RSA_CERT, P256_CERT, P384_CERT]);
+        let mut backend = agent.backend();
+        let identities = backend.list_identities();
+        // There's no key in the agent for the P-384 certificate.
+        assert_eq!(identities.len(), 2);
+        let mut ids: Vec<&[u8]> = identities.iter().map(|(cert, _)| cert.id()).collect();
+        ids.sort();
+        let mut expected_ids = vec![id_of(RSA_CERT), id_of(P256_CERT)];
+        expected_ids.sort();
+        assert_eq!(ids, expected_ids);
+        for (cert, key) in &identities {
+            assert_eq!(cert.id(), key.id());
+            if cert.id() == id_of(P256_CERT).as_slice() {
+                assert_eq!(key.ec_params(), Some(OID_BYTES_SECP256R1));
+            } else {
+                let rsa_key = RsaPrivateKey::from_pkcs8_der(RSA_KEY).unwrap();
+                assert_eq!(key.modulus(), Some(rsa_key.n().to_bytes_be().as_slice()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_sign_message_rsa() {
+        let agent = TestAgent::new(&[RSA_KEY], &[RSA_CERT]);
+        let mut backend = agent.backend();
+        let identities = backend.list_identities();
+        let key = find_key(&identities, RSA_CERT);
+        let public_key = RsaPrivateKey::from_pkcs8_der(RSA_KEY)
+            .unwrap()
+            .to_public_key();
+        let message = b"a message to sign";
+        let signature = backend.sign_message(key, message, CKM_SHA256).unwrap();
+        assert_eq!(
+            backend.get_signature_length(key, message, &None),
+            Ok(signature.len())
+        );
+        let digest_info = encode_digest_info(CKM_SHA256, &Sha256::digest(message)).unwrap();
+        assert!(public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), &digest_info, &signature)
+            .is_ok());
+        let signature = backend.sign_message(key, message, CKM_SHA512).unwrap();
+        let digest_info = encode_digest_info(CKM_SHA512, &Sha512::digest(message)).unwrap();
+        assert!(public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), &digest_info, &signature)
+            .is_ok());
+        // ssh-agent doesn't do RSA with SHA-384, and it can't sign hashes.
+        assert!(backend.sign_message(key, message, CKM_SHA384).is_err());
+        assert!(backend.sign(key, &digest_info, &None).is_err());
+    }
+
+    #[test]
+    fn test_sign_message_ecdsa() {
+        let agent = TestAgent::new(&[P256_KEY, P384_KEY], &[P256_CERT, P384_CERT]);
+        let mut backend = agent.backend();
+        let identities = backend.list_identities();
+        let message = b"another message to sign";
+
+        let key = find_key(&identities, P256_CERT);
+        let signature = backend.sign_message(key, message, CKM_SHA256).unwrap();
+        assert_eq!(signature.len(), 64);
+        assert_eq!(backend.get_signature_length(key, message, &None), Ok(64));
+        let verifying_key = *p256::ecdsa::SigningKey::from_pkcs8_der(P256_KEY)
+            .unwrap()
+            .verifying_key();
+        let signature = p256::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(verifying_key
+            .verify_prehash(&Sha256::digest(message), &signature)
+            .is_ok());
+        assert!(backend.sign_message(key, message, CKM_SHA384).is_err());
+
+        let key = find_key(&identities, P384_CERT);
+        let signature = backend.sign_message(key, message, CKM_SHA384).unwrap();
+        assert_eq!(signature.len(), 96);
+        let verifying_key = *p384::ecdsa::SigningKey::from_pkcs8_der(P384_KEY)
+            .unwrap()
+            .verifying_key();
+        let signature = p384::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(verifying_key
+            .verify_prehash(&Sha384::digest(message), &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_no_agent() {
+        let directory = tempfile::tempdir().unwrap();
+        fs::write(directory.path().join("rsa.crt"), RSA_CERT).unwrap();
+        let mut backend = SshAgentBackend::with_socket_path(
+            directory.path().join("nonexistent.sock"),
+            vec![directory.path().to_path_buf()],
+        );
+        assert!(backend.list_identities().is_empty());
+    }
+
+    #[test]
+    fn test_ssh_ecdsa_signature_to_raw() {
+        let mut signature = Vec::new();
+        // r has a leading zero byte because its high bit is set. s is short.
+        write_string(&mut signature, &[0x00, 0x80, 0x01]).unwrap();
+        write_string(&mut signature, &[0x02]).unwrap();
+        assert_eq!(
+            ssh_ecdsa_signature_to_raw(&signature, 4),
+            Ok(vec![0, 0, 0x80, 0x01, 0, 0, 0, 0x02])
+        );
+        assert!(ssh_ecdsa_signature_to_raw(&signature, 1).is_err());
+        let mut negative = Vec::new();
+        write_string(&mut negative, &[0x80]).unwrap();
+        write_string(&mut negative, &[0x01]).unwrap();
+        assert!(ssh_ecdsa_signature_to_raw(&negative, 4).is_err());
+    }
+}
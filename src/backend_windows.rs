@@ -7,7 +7,7 @@
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
 use pkcs11::types::*;
-use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::ffi::{CStr, CString};
 use std::ops::Deref;
@@ -16,6 +16,9 @@ use winapi::shared::bcrypt::*;
 use winapi::um::ncrypt::*;
 use winapi::um::wincrypt::*;
 
+use crate::backend::*;
+use crate::config::WindowsStoreLocation;
+use crate::error::Error;
 use crate::util::*;
 
 /// Given a `CERT_INFO`, tries to return the bytes of the subject distinguished name as formatted by
@@ -49,128 +52,64 @@ fn get_cert_subject_dn(cert_info: &CERT_INFO) -> Result<Vec<u8>, ()> {
     Ok(subject_dn_string_bytes)
 }
 
-/// Represents a certificate for which there exists a corresponding private key.
-pub struct Cert {
-    /// PKCS #11 object class. Will be `CKO_CERTIFICATE`.
-    class: Vec<u8>,
-    /// Whether or not this is on a token. Will be `CK_TRUE`.
-    token: Vec<u8>,
-    /// An identifier unique to this certificate. Must be the same as the ID for the private key.
-    id: Vec<u8>,
-    /// The bytes of a human-readable label for this certificate. Will be the subject DN.
-    label: Vec<u8>,
-    /// The DER bytes of the certificate.
-    value: Vec<u8>,
-    /// The DER bytes of the issuer distinguished name of the certificate.
-    issuer: Vec<u8>,
-    /// The DER bytes of the serial number of the certificate.
-    serial_number: Vec<u8>,
-    /// The DER bytes of the subject distinguished name of the certificate.
-    subject: Vec<u8>,
+/// Given a `CERT_CONTEXT`, tries to create a `Cert` representing it.
+fn new_cert(cert: PCCERT_CONTEXT) -> Result<Cert, ()> {
+    let cert = unsafe { &*cert };
+    let cert_info = unsafe { &*cert.pCertInfo };
+    let value = unsafe { slice::from_raw_parts(cert.pbCertEncoded, cert.cbCertEncoded as usize) };
+    let value = value.to_vec();
+    let label = get_cert_subject_dn(&cert_info)?;
+    let issuer =
+        unsafe { slice::from_raw_parts(cert_info.Issuer.pbData, cert_info.Issuer.cbData as usize) };
+    let issuer = issuer.to_vec();
+    let serial_number = unsafe {
+        slice::from_raw_parts(
+            cert_info.SerialNumber.pbData,
+            cert_info.SerialNumber.cbData as usize,
+        )
+    };
+    let serial_number = serial_number.to_vec();
+    let subject = unsafe {
+        slice::from_raw_parts(cert_info.Subject.pbData, cert_info.Subject.cbData as usize)
+    };
+    let subject = subject.to_vec();
+    Cert::new(value, label, issuer, serial_number, subject)
 }
 
-impl Cert {
-    fn new(cert: PCCERT_CONTEXT) -> Result<Cert, ()> {
-        let cert = unsafe { &*cert };
-        let cert_info = unsafe { &*cert.pCertInfo };
-        let value =
-            unsafe { slice::from_raw_parts(cert.pbCertEncoded, cert.cbCertEncoded as usize) };
-        let value = value.to_vec();
-        let id = Sha256::digest(&value).to_vec();
-        let label = get_cert_subject_dn(&cert_info)?;
-        let issuer = unsafe {
-            slice::from_raw_parts(cert_info.Issuer.pbData, cert_info.Issuer.cbData as usize)
-        };
-        let issuer = issuer.to_vec();
-        let serial_number = unsafe {
-            slice::from_raw_parts(
-                cert_info.SerialNumber.pbData,
-                cert_info.SerialNumber.cbData as usize,
-            )
-        };
-        let serial_number = serial_number.to_vec();
-        let subject = unsafe {
-            slice::from_raw_parts(cert_info.Subject.pbData, cert_info.Subject.cbData as usize)
-        };
-        let subject = subject.to_vec();
-        Ok(Cert {
-            class: serialize_uint(CKO_CERTIFICATE)?,
-            token: serialize_uint(CK_TRUE)?,
-            id,
-            label,
-            value,
-            issuer,
-            serial_number,
-            subject,
-        })
-    }
-
-    fn class(&self) -> &[u8] {
-        &self.class
-    }
-
-    fn token(&self) -> &[u8] {
-        &self.token
-    }
-
-    pub fn id(&self) -> &[u8] {
-        &self.id
-    }
-
-    fn label(&self) -> &[u8] {
-        &self.label
-    }
-
-    fn value(&self) -> &[u8] {
-        &self.value
-    }
-
-    fn issuer(&self) -> &[u8] {
-        &self.issuer
-    }
-
-    fn serial_number(&self) -> &[u8] {
-        &self.serial_number
-    }
-
-    fn subject(&self) -> &[u8] {
-        &self.subject
-    }
-
-    fn matches(&self, attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)]) -> bool {
-        for (attr_type, attr_value) in attrs {
-            let comparison = match *attr_type {
-                CKA_CLASS => self.class(),
-                CKA_TOKEN => self.token(),
-                CKA_LABEL => self.label(),
-                CKA_ID => self.id(),
-                CKA_VALUE => self.value(),
-                CKA_ISSUER => self.issuer(),
-                CKA_SERIAL_NUMBER => self.serial_number(),
-                CKA_SUBJECT => self.subject(),
-                _ => return false,
-            };
-            if attr_value.as_slice() != comparison {
-                return false;
-            }
+/// Given a `CERT_CONTEXT`, tries to create a `Key` representing the private key corresponding to
+/// it. The public key information of the certificate is used to determine the key's type.
+fn new_key(cert_context: PCCERT_CONTEXT) -> Result<Key, ()> {
+    let cert = unsafe { *cert_context };
+    let cert_der =
+        unsafe { slice::from_raw_parts(cert.pbCertEncoded, cert.cbCertEncoded as usize) };
+    let cert_info = unsafe { &*cert.pCertInfo };
+    let mut modulus = None;
+    let mut ec_params = None;
+    let spki = &cert_info.SubjectPublicKeyInfo;
+    let algorithm_oid = unsafe { CStr::from_ptr(spki.Algorithm.pszObjId) }
+        .to_str()
+        .map_err(|_| ())?;
+    let key_type = if algorithm_oid == szOID_RSA_RSA {
+        if spki.PublicKey.cUnusedBits != 0 {
+            return Err(());
         }
-        true
-    }
-
-    fn get_attribute(&self, attribute: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
-        let result = match attribute {
-            CKA_CLASS => self.class(),
-            CKA_TOKEN => self.token(),
-            CKA_LABEL => self.label(),
-            CKA_ID => self.id(),
-            CKA_VALUE => self.value(),
-            CKA_ISSUER => self.issuer(),
-            CKA_SERIAL_NUMBER => self.serial_number(),
-            CKA_SUBJECT => self.subject(),
-            _ => return None,
+        let public_key_bytes = unsafe {
+            std::slice::from_raw_parts(spki.PublicKey.pbData, spki.PublicKey.cbData as usize)
         };
-        Some(result)
-    }
+        let modulus_value = read_rsa_modulus(public_key_bytes)?;
+        modulus = Some(modulus_value);
+        KeyType::RSA
+    } else if algorithm_oid == szOID_ECC_PUBLIC_KEY {
+        let params = &spki.Algorithm.Parameters;
+        let params =
+            unsafe { std::slice::from_raw_parts(params.pbData, params.cbData as usize) }.to_vec();
+        let coordinate_width = get_ec_coordinate_width(&params)?;
+        ec_params = Some(params);
+        KeyType::EC(coordinate_width)
+    } else {
+        return Err(());
+    };
+    Key::new(cert_der, key_type, modulus, ec_params)
 }
 
 struct CertContext(PCCERT_CONTEXT);
@@ -270,7 +209,7 @@ impl SignParams {
     fn new(key_type: KeyType, params: &Option<CK_RSA_PKCS_PSS_PARAMS>) -> Result<SignParams, ()> {
         // EC is easy, so handle that first.
         match key_type {
-            KeyType::EC => return Ok(SignParams::EC),
+            KeyType::EC(_) => return Ok(SignParams::EC),
             KeyType::RSA => {}
         }
         // If `params` is `Some`, we're doing RSA-PSS. If it is `None`, we're doing RSA-PKCS1.
@@ -324,272 +263,182 @@ impl SignParams {
     }
 }
 
-/// A helper enum to identify a private key's type. We support EC and RSA.
-#[derive(Clone, Copy, Debug)]
-pub enum KeyType {
-    EC,
-    RSA,
-}
-
-/// Represents a private key for which there exists a corresponding certificate.
-pub struct Key {
-    /// A handle on the OS mechanism that represents the certificate for this key.
-    cert: CertContext,
-    /// PKCS #11 object class. Will be `CKO_PRIVATE_KEY`.
-    class: Vec<u8>,
-    /// Whether or not this is on a token. Will be `CK_TRUE`.
-    token: Vec<u8>,
-    /// An identifier unique to this key. Must be the same as the ID for the certificate.
-    id: Vec<u8>,
-    /// Whether or not this key is "private" (can it be exported?). Will be CK_TRUE (it can't be
-    /// exported).
-    private: Vec<u8>,
-    /// PKCS #11 key type. Will be `CKK_EC` for EC, and `CKK_RSA` for RSA.
-    key_type: Vec<u8>,
-    /// If this is an RSA key, this is the value of the modulus as an unsigned integer.
-    modulus: Option<Vec<u8>>,
-    /// If this is an EC key, this is the DER bytes of the OID identifying the curve the key is on.
-    ec_params: Option<Vec<u8>>,
-    /// An enum identifying this key's type.
-    key_type_enum: KeyType,
-}
-
-impl Key {
-    fn new(cert_context: PCCERT_CONTEXT) -> Result<Key, ()> {
-        let cert = unsafe { *cert_context };
-        let cert_der =
-            unsafe { slice::from_raw_parts(cert.pbCertEncoded, cert.cbCertEncoded as usize) };
-        let id = Sha256::digest(cert_der).to_vec();
-        let id = id.to_vec();
-        let cert_info = unsafe { &*cert.pCertInfo };
-        let mut modulus = None;
-        let mut ec_params = None;
-        let spki = &cert_info.SubjectPublicKeyInfo;
-        let algorithm_oid = unsafe { CStr::from_ptr(spki.Algorithm.pszObjId) }
-            .to_str()
-            .map_err(|_| ())?;
-        let (key_type_enum, key_type_attribute) = if algorithm_oid == szOID_RSA_RSA {
-            if spki.PublicKey.cUnusedBits != 0 {
-                return Err(());
-            }
-            let public_key_bytes = unsafe {
-                std::slice::from_raw_parts(spki.PublicKey.pbData, spki.PublicKey.cbData as usize)
-            };
-            let modulus_value = read_rsa_modulus(public_key_bytes)?;
-            modulus = Some(modulus_value);
-            (KeyType::RSA, CKK_RSA)
-        } else if algorithm_oid == szOID_ECC_PUBLIC_KEY {
-            let params = &spki.Algorithm.Parameters;
-            ec_params = Some(
-                unsafe { std::slice::from_raw_parts(params.pbData, params.cbData as usize) }
-                    .to_vec(),
-            );
-            (KeyType::EC, CKK_EC)
-        } else {
-            return Err(());
-        };
-        Ok(Key {
-            cert: CertContext::new(cert_context),
-            class: serialize_uint(CKO_PRIVATE_KEY)?,
-            token: serialize_uint(CK_TRUE)?,
-            id,
-            private: serialize_uint(CK_TRUE)?,
-            key_type: serialize_uint(key_type_attribute)?,
-            modulus,
-            ec_params,
-            key_type_enum,
-        })
-    }
-
-    fn class(&self) -> &[u8] {
-        &self.class
-    }
-
-    fn token(&self) -> &[u8] {
-        &self.token
-    }
-
-    pub fn id(&self) -> &[u8] {
-        &self.id
-    }
-
-    fn private(&self) -> &[u8] {
-        &self.private
-    }
-
-    fn key_type(&self) -> &[u8] {
-        &self.key_type
-    }
-
-    fn modulus(&self) -> Option<&[u8]> {
-        match &self.modulus {
-            Some(modulus) => Some(modulus.as_slice()),
-            None => None,
-        }
-    }
-
-    fn ec_params(&self) -> Option<&[u8]> {
-        match &self.ec_params {
-            Some(ec_params) => Some(ec_params.as_slice()),
-            None => None,
-        }
-    }
+/// Signs the given data with the private key corresponding to the given certificate.
+/// data: the data to sign
+/// do_signature: if true, actually perform the signature. Otherwise, return a `Vec<u8>` of the
+/// length the signature would be, if performed.
+/// The statuses NCryptSignHash fails with if the user cancels a prompt (e.g. for a smart card PIN):
+/// NTE_USER_CANCELLED, SCARD_W_CANCELLED_BY_USER, and HRESULT_FROM_WIN32(ERROR_CANCELLED).
+const CANCELLED_STATUSES: &[SECURITY_STATUS] = &[
+    0x80090036u32 as SECURITY_STATUS,
+    0x8010006Eu32 as SECURITY_STATUS,
+    0x800704C7u32 as SECURITY_STATUS,
+];
 
-    fn matches(&self, attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)]) -> bool {
-        for (attr_type, attr_value) in attrs {
-            let comparison = match *attr_type {
-                CKA_CLASS => self.class(),
-                CKA_TOKEN => self.token(),
-                CKA_ID => self.id(),
-                CKA_PRIVATE => self.private(),
-                CKA_KEY_TYPE => self.key_type(),
-                CKA_MODULUS => {
-                    if let Some(modulus) = self.modulus() {
-                        modulus
-                    } else {
-                        return false;
-                    }
-                }
-                CKA_EC_PARAMS => {
-                    if let Some(ec_params) = self.ec_params() {
-                        ec_params
-                    } else {
-                        return false;
-                    }
-                }
-                _ => return false,
-            };
-            if attr_value.as_slice() != comparison {
-                return false;
-            }
+/// The status NCryptDecrypt fails with if the data doesn't decrypt (e.g. its padding is wrong):
+/// NTE_BAD_DATA.
+const NTE_BAD_DATA_STATUS: SECURITY_STATUS = 0x80090005u32 as SECURITY_STATUS;
+
+fn sign_internal(
+    cert: &CertContext,
+    key_type: KeyType,
+    data: &[u8],
+    params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    do_signature: bool,
+) -> Result<Vec<u8>, Error> {
+    // Acquiring a handle on the key can cause the OS to show some UI to the user, so we do this
+    // as late as possible (i.e. here).
+    let key = NCryptKeyHandle::from_cert(cert).map_err(|()| Error::BackendFailure)?;
+    let mut sign_params =
+        SignParams::new(key_type, params).map_err(|()| Error::MechanismParamInvalid)?;
+    let params_ptr = sign_params.params_ptr();
+    let flags = sign_params.flags();
+    let mut data = data.to_vec();
+    let mut signature_len = 0;
+    // We call NCryptSignHash twice: the first time to get the size of the buffer we need to
+    // allocate and then again to actually sign the data, if `do_signature` is `true`.
+    let status = unsafe {
+        NCryptSignHash(
+            *key,
+            params_ptr,
+            data.as_mut_ptr(),
+            data.len().try_into().map_err(|_| Error::DataLenRange)?,
+            std::ptr::null_mut(),
+            0,
+            &mut signature_len,
+            flags,
+        )
+    };
+    // 0 is "ERROR_SUCCESS" (but "ERROR_SUCCESS" is unsigned, whereas SECURITY_STATUS is signed)
+    if status != 0 {
+        error!(
+            "NCryptSignHash failed trying to get signature buffer length, {}",
+            status
+        );
+        if CANCELLED_STATUSES.contains(&status) {
+            return Err(Error::Cancelled);
         }
-        true
-    }
-
-    fn get_attribute(&self, attribute: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
-        match attribute {
-            CKA_CLASS => Some(self.class()),
-            CKA_TOKEN => Some(self.token()),
-            CKA_ID => Some(self.id()),
-            CKA_PRIVATE => Some(self.private()),
-            CKA_KEY_TYPE => Some(self.key_type()),
-            CKA_MODULUS => self.modulus(),
-            CKA_EC_PARAMS => self.ec_params(),
-            _ => None,
+        return Err(Error::BackendFailure);
+    }
+    let mut signature = vec![0; signature_len as usize];
+    if !do_signature {
+        return Ok(signature);
+    }
+    let mut final_signature_len = signature_len;
+    let status = unsafe {
+        NCryptSignHash(
+            *key,
+            params_ptr,
+            data.as_mut_ptr(),
+            data.len().try_into().map_err(|_| Error::DataLenRange)?,
+            signature.as_mut_ptr(),
+            signature_len,
+            &mut final_signature_len,
+            flags,
+        )
+    };
+    if status != 0 {
+        if CANCELLED_STATUSES.contains(&status) {
+            debug!("NCryptSignHash was cancelled: {}", status);
+            return Err(Error::Cancelled);
         }
+        error!("NCryptSignHash failed signing data {}", status);
+        return Err(Error::BackendFailure);
     }
-
-    pub fn get_signature_length(
-        &self,
-        data: &[u8],
-        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
-    ) -> Result<usize, ()> {
-        match self.sign_internal(data, params, false) {
-            Ok(dummy_signature_bytes) => Ok(dummy_signature_bytes.len()),
-            Err(()) => Err(()),
-        }
-    }
-
-    pub fn sign(
-        &self,
-        data: &[u8],
-        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
-    ) -> Result<Vec<u8>, ()> {
-        self.sign_internal(data, params, true)
-    }
-
-    /// data: the data to sign
-    /// do_signature: if true, actually perform the signature. Otherwise, return a `Vec<u8>` of the
-    /// length the signature would be, if performed.
-    fn sign_internal(
-        &self,
-        data: &[u8],
-        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
-        do_signature: bool,
-    ) -> Result<Vec<u8>, ()> {
-        // Acquiring a handle on the key can cause the OS to show some UI to the user, so we do this
-        // as late as possible (i.e. here).
-        let key = NCryptKeyHandle::from_cert(&self.cert)?;
-        let mut sign_params = SignParams::new(self.key_type_enum, params)?;
-        let params_ptr = sign_params.params_ptr();
-        let flags = sign_params.flags();
-        let mut data = data.to_vec();
-        let mut signature_len = 0;
-        // We call NCryptSignHash twice: the first time to get the size of the buffer we need to
-        // allocate and then again to actually sign the data, if `do_signature` is `true`.
-        let status = unsafe {
-            NCryptSignHash(
-                *key,
-                params_ptr,
-                data.as_mut_ptr(),
-                data.len().try_into().map_err(|_| ())?,
-                std::ptr::null_mut(),
-                0,
-                &mut signature_len,
-                flags,
-            )
-        };
-        // 0 is "ERROR_SUCCESS" (but "ERROR_SUCCESS" is unsigned, whereas SECURITY_STATUS is signed)
-        if status != 0 {
-            error!(
-                "NCryptSignHash failed trying to get signature buffer length, {}",
-                status
-            );
-            return Err(());
-        }
-        let mut signature = vec![0; signature_len as usize];
-        if !do_signature {
-            return Ok(signature);
-        }
-        let mut final_signature_len = signature_len;
-        let status = unsafe {
-            NCryptSignHash(
-                *key,
-                params_ptr,
-                data.as_mut_ptr(),
-                data.len().try_into().map_err(|_| ())?,
-                signature.as_mut_ptr(),
-                signature_len,
-                &mut final_signature_len,
-                flags,
-            )
-        };
-        if status != 0 {
-            error!("NCryptSignHash failed signing data {}", status);
-            return Err(());
-        }
-        if final_signature_len != signature_len {
-            error!(
-                "NCryptSignHash: inconsistent signature lengths? {} != {}",
-                final_signature_len, signature_len
-            );
-            return Err(());
-        }
-        Ok(signature)
+    if final_signature_len != signature_len {
+        error!(
+            "NCryptSignHash: inconsistent signature lengths? {} != {}",
+            final_signature_len, signature_len
+        );
+        return Err(Error::BackendFailure);
     }
+    Ok(signature)
 }
 
-/// A helper enum that represents the two types of PKCS #11 objects we support: certificates and
-/// keys.
-pub enum Object {
-    Cert(Cert),
-    Key(Key),
-}
-
-impl Object {
-    pub fn matches(&self, attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)]) -> bool {
-        match self {
-            Object::Cert(cert) => cert.matches(attrs),
-            Object::Key(key) => key.matches(attrs),
+/// Decrypts the given data with the private key corresponding to the given certificate, with PKCS #1
+/// v1.5 padding if `params` is `None` and with OAEP padding otherwise.
+fn decrypt_internal(
+    cert: &CertContext,
+    data: &[u8],
+    params: &Option<OaepParams>,
+) -> Result<Vec<u8>, Error> {
+    let mut oaep_padding_info = match params {
+        Some(params) => Some(BCRYPT_OAEP_PADDING_INFO {
+            pszAlgId: match params.hash_algorithm {
+                CKM_SHA_1 => SHA1_ALGORITHM_STRING,
+                CKM_SHA256 => SHA256_ALGORITHM_STRING,
+                _ => return Err(Error::MechanismParamInvalid),
+            }
+            .as_ptr(),
+            pbLabel: std::ptr::null_mut(),
+            cbLabel: 0,
+        }),
+        None => None,
+    };
+    let (params_ptr, flags) = match &mut oaep_padding_info {
+        Some(oaep_padding_info) => (
+            oaep_padding_info as *mut BCRYPT_OAEP_PADDING_INFO as *mut std::ffi::c_void,
+            NCRYPT_PAD_OAEP_FLAG,
+        ),
+        None => (std::ptr::null_mut(), NCRYPT_PAD_PKCS1_FLAG),
+    };
+    let key = NCryptKeyHandle::from_cert(cert).map_err(|()| Error::BackendFailure)?;
+    let mut data = data.to_vec();
+    let mut decrypted_len = 0;
+    // As with NCryptSignHash, we call NCryptDecrypt twice: the first time to get the size of the
+    // buffer we need to allocate and then again to actually decrypt the data.
+    let status = unsafe {
+        NCryptDecrypt(
+            *key,
+            data.as_mut_ptr(),
+            data.len()
+                .try_into()
+                .map_err(|_| Error::EncryptedDataLenRange)?,
+            params_ptr,
+            std::ptr::null_mut(),
+            0,
+            &mut decrypted_len,
+            flags,
+        )
+    };
+    if status != 0 {
+        error!(
+            "NCryptDecrypt failed trying to get decrypted buffer length, {}",
+            status
+        );
+        if CANCELLED_STATUSES.contains(&status) {
+            return Err(Error::Cancelled);
         }
-    }
-
-    pub fn get_attribute(&self, attribute: CK_ATTRIBUTE_TYPE) -> Option<&[u8]> {
-        match self {
-            Object::Cert(cert) => cert.get_attribute(attribute),
-            Object::Key(key) => key.get_attribute(attribute),
+        return Err(Error::BackendFailure);
+    }
+    let mut decrypted = vec![0; decrypted_len as usize];
+    let status = unsafe {
+        NCryptDecrypt(
+            *key,
+            data.as_mut_ptr(),
+            data.len()
+                .try_into()
+                .map_err(|_| Error::EncryptedDataLenRange)?,
+            params_ptr,
+            decrypted.as_mut_ptr(),
+            decrypted_len,
+            &mut decrypted_len,
+            flags,
+        )
+    };
+    if status != 0 {
+        if CANCELLED_STATUSES.contains(&status) {
+            debug!("NCryptDecrypt was cancelled: {}", status);
+            return Err(Error::Cancelled);
         }
+        error!("NCryptDecrypt failed decrypting data {}", status);
+        if status == NTE_BAD_DATA_STATUS {
+            return Err(Error::EncryptedDataInvalid);
+        }
+        return Err(Error::BackendFailure);
     }
+    decrypted.truncate(decrypted_len as usize);
+    Ok(decrypted)
 }
 
 struct CertStore {
@@ -620,73 +469,135 @@ impl CertStore {
     }
 }
 
-pub const SUPPORTED_ATTRIBUTES: &[CK_ATTRIBUTE_TYPE] = &[
-    CKA_CLASS,
-    CKA_TOKEN,
-    CKA_LABEL,
-    CKA_ID,
-    CKA_VALUE,
-    CKA_ISSUER,
-    CKA_SERIAL_NUMBER,
-    CKA_SUBJECT,
-    CKA_PRIVATE,
-    CKA_KEY_TYPE,
-    CKA_MODULUS,
-    CKA_EC_PARAMS,
-];
+/// The Windows backend. Enumerates certificates with private keys using the Windows certificate
+/// store APIs and signs with those keys using CNG.
+pub struct WindowsBackend {
+    /// The name of the system certificate store to look in (e.g. "My").
+    store_name: String,
+    /// Where the store is (the current user's or the local machine's stores).
+    location: WindowsStoreLocation,
+    /// A map of key identifiers to the OS handle on the certificate each key corresponds to.
+    certs: BTreeMap<Vec<u8>, CertContext>,
+}
+
+impl WindowsBackend {
+    /// Creates a `WindowsBackend` that looks in the "My" store of the current user.
+    pub fn new() -> WindowsBackend {
+        WindowsBackend::with_store(String::from("My"), WindowsStoreLocation::CurrentUser)
+    }
 
-/// Attempts to enumerate certificates with private keys exposed by the OS. Currently only looks in
-/// the "My" cert store of the current user. In the future this may look in more locations.
-pub fn list_objects() -> Vec<Object> {
-    let mut objects = Vec::new();
-    let location_flags = CERT_SYSTEM_STORE_CURRENT_USER // TODO: loop over multiple locations
-        | CERT_STORE_OPEN_EXISTING_FLAG
-        | CERT_STORE_READONLY_FLAG;
-    let store_name = match CString::new("My") {
-        Ok(store_name) => store_name,
-        Err(null_error) => {
-            error!("CString::new given input with a null byte: {}", null_error);
-            return objects;
+    /// Creates a `WindowsBackend` that looks in the system store with the given name in the given
+    /// location.
+    pub fn with_store(store_name: String, location: WindowsStoreLocation) -> WindowsBackend {
+        WindowsBackend {
+            store_name,
+            location,
+            certs: BTreeMap::new(),
         }
-    };
-    let store = CertStore::new(unsafe {
-        CertOpenStore(
-            CERT_STORE_PROV_SYSTEM_REGISTRY_A,
-            0,
-            0,
-            location_flags,
-            store_name.as_ptr() as *const winapi::ctypes::c_void,
-        )
-    });
-    if store.is_null() {
-        error!("CertOpenStore failed");
-        return objects;
     }
-    let mut cert_context: PCCERT_CONTEXT = std::ptr::null_mut();
-    loop {
-        cert_context = unsafe {
-            CertFindCertificateInStore(
-                *store,
-                X509_ASN_ENCODING,
-                CERT_FIND_HAS_PRIVATE_KEY,
-                CERT_FIND_ANY,
-                std::ptr::null_mut(),
-                cert_context,
-            )
-        };
-        if cert_context.is_null() {
-            break;
+
+    fn get_cert(&self, key: &Key) -> Result<&CertContext, Error> {
+        match self.certs.get(key.id()) {
+            Some(cert) => Ok(cert),
+            None => {
+                error!("no certificate corresponding to key");
+                Err(Error::KeyHandleInvalid)
+            }
         }
-        let cert = match Cert::new(cert_context) {
-            Ok(cert) => cert,
-            Err(()) => continue,
+    }
+}
+
+impl Backend for WindowsBackend {
+    /// Attempts to enumerate certificates with private keys exposed by the OS in the configured
+    /// cert store (by default, the "My" store of the current user).
+    fn list_identities(&mut self) -> Vec<(Cert, Key)> {
+        let mut identities = Vec::new();
+        let location = match self.location {
+            WindowsStoreLocation::CurrentUser => CERT_SYSTEM_STORE_CURRENT_USER,
+            WindowsStoreLocation::LocalMachine => CERT_SYSTEM_STORE_LOCAL_MACHINE,
         };
-        let key = match Key::new(cert_context) {
-            Ok(key) => key,
-            Err(()) => continue,
+        let location_flags = location | CERT_STORE_OPEN_EXISTING_FLAG | CERT_STORE_READONLY_FLAG;
+        let store_name = match CString::new(self.store_name.as_str()) {
+            Ok(store_name) => store_name,
+            Err(null_error) => {
+                error!("CString::new given input with a null byte: {}", null_error);
+                return identities;
+            }
         };
-        objects.push(Object::Cert(cert));
-        objects.push(Object::Key(key));
+        let store = CertStore::new(unsafe {
+            CertOpenStore(
+                CERT_STORE_PROV_SYSTEM_REGISTRY_A,
+                0,
+                0,
+                location_flags,
+                store_name.as_ptr() as *const winapi::ctypes::c_void,
+            )
+        });
+        if store.is_null() {
+            error!("CertOpenStore failed");
+            return identities;
+        }
+        let mut cert_context: PCCERT_CONTEXT = std::ptr::null_mut();
+        loop {
+            cert_context = unsafe {
+                CertFindCertificateInStore(
+                    *store,
+                    X509_ASN_ENCODING,
+                    CERT_FIND_HAS_PRIVATE_KEY,
+                    CERT_FIND_ANY,
+                    std::ptr::null_mut(),
+                    cert_context,
+                )
+            };
+            if cert_context.is_null() {
+                break;
+            }
+            let cert = match new_cert(cert_context) {
+                Ok(cert) => cert,
+                Err(()) => continue,
+            };
+            let key = match new_key(cert_context) {
+                Ok(key) => key,
+                Err(()) => continue,
+            };
+            self.certs
+                .insert(key.id().to_vec(), CertContext::new(cert_context));
+            identities.push((cert, key));
+        }
+        identities
+    }
+
+    fn get_signature_length(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<usize, Error> {
+        let cert = self.get_cert(key)?;
+        let dummy_signature_bytes = sign_internal(cert, key.key_type_enum(), data, params, false)?;
+        Ok(dummy_signature_bytes.len())
+    }
+
+    fn sign(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<Vec<u8>, Error> {
+        let cert = self.get_cert(key)?;
+        sign_internal(cert, key.key_type_enum(), data, params, true)
+    }
+
+    fn decrypt(
+        &self,
+        key: &Key,
+        data: &[u8],
+        params: &Option<OaepParams>,
+    ) -> Result<Vec<u8>, Error> {
+        if key.key_type_enum() != KeyType::RSA {
+            return Err(Error::KeyTypeInconsistent);
+        }
+        let cert = self.get_cert(key)?;
+        decrypt_internal(cert, data, params)
     }
-    objects
 }
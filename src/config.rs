@@ -0,0 +1,1008 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::backend::{Cert, Key};
+
+/// The environment variable that gives the path of the configuration file.
+const CONFIG_PATH: &str = "OSCLIENTCERTS_CONFIG";
+/// The path of the configuration file, relative to an XDG configuration directory.
+const CONFIG_FILE: &str = "osclientcerts/config.toml";
+/// The system-wide XDG configuration directories, if `$XDG_CONFIG_DIRS` isn't set.
+const DEFAULT_XDG_CONFIG_DIRS: &str = "/etc/xdg";
+
+/// How often backends are asked for their identities by default.
+pub const DEFAULT_RESCAN_INTERVAL: Duration = Duration::from_secs(3);
+/// The manufacturer of each token, unless configured otherwise.
+pub const DEFAULT_MANUFACTURER: &str = "Mozilla Corporation";
+
+/// The maximum lengths (in bytes) of the fields of CK_TOKEN_INFO that can be configured.
+const MAX_LABEL_LENGTH: usize = 32;
+const MAX_SERIAL_NUMBER_LENGTH: usize = 16;
+const MAX_MANUFACTURER_LENGTH: usize = 32;
+
+/// Every type of backend, whether or not it is available on this platform.
+const BACKEND_TYPES: &[&str] = &[
+    "files",
+    "keyring",
+    "ssh-agent",
+    "gpg-agent",
+    "pkcs11",
+    "macos",
+    "windows",
+];
+
+/// The configuration file as written, before it has been validated.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    slots: Option<String>,
+    rescan_interval: Option<u64>,
+    manufacturer: Option<String>,
+    token: Option<RawToken>,
+    backends: Option<Vec<RawBackend>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawToken {
+    label: Option<String>,
+    serial_number: Option<String>,
+    manufacturer: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBackend {
+    #[serde(rename = "type")]
+    backend_type: String,
+    label: Option<String>,
+    serial_number: Option<String>,
+    manufacturer: Option<String>,
+    directories: Option<Vec<String>>,
+    socket: Option<String>,
+    keybox: Option<String>,
+    module: Option<String>,
+    parameters: Option<String>,
+    store: Option<String>,
+    location: Option<String>,
+    filter: Option<RawFilter>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFilter {
+    key_types: Option<Vec<String>>,
+    fingerprints: Option<Vec<String>>,
+    exclude_fingerprints: Option<Vec<String>>,
+}
+
+/// How backends are exposed as slots.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlotMode {
+    /// Each backend is exposed as its own slot (the default).
+    PerStore,
+    /// The identities of all backends are combined and exposed in a single slot.
+    Single,
+}
+
+impl SlotMode {
+    pub fn parse(value: &str) -> Result<SlotMode, String> {
+        match value {
+            "per-store" => Ok(SlotMode::PerStore),
+            "single" => Ok(SlotMode::Single),
+            _ => Err(format!(
+                "invalid slot mode '{}' (expected 'per-store' or 'single')",
+                value
+            )),
+        }
+    }
+}
+
+/// Overrides for the information about a token. Anything that isn't set comes from the backend
+/// (or, for the serial number, from the slot ID).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenConfig {
+    pub label: Option<String>,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+}
+
+/// Which of a backend's identities are exposed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    /// If set, only identities with these types of keys are exposed.
+    pub key_types: Option<Vec<KeyKind>>,
+    /// If set, only identities whose certificates have these SHA-256 fingerprints are exposed.
+    pub fingerprints: Option<BTreeSet<Vec<u8>>>,
+    /// Identities whose certificates have these SHA-256 fingerprints are never exposed.
+    pub exclude_fingerprints: BTreeSet<Vec<u8>>,
+}
+
+/// The types of keys a `Filter` can select.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyKind {
+    Rsa,
+    Ec,
+}
+
+impl Filter {
+    /// Returns whether or not the given identity should be exposed. The ID of a certificate is its
+    /// SHA-256 fingerprint.
+    pub fn allows(&self, cert: &Cert, key: &Key) -> bool {
+        if let Some(key_types) = &self.key_types {
+            let key_kind = if key.modulus().is_some() {
+                KeyKind::Rsa
+            } else {
+                KeyKind::Ec
+            };
+            if !key_types.contains(&key_kind) {
+                return false;
+            }
+        }
+        if let Some(fingerprints) = &self.fingerprints {
+            if !fingerprints.contains(cert.id()) {
+                return false;
+            }
+        }
+        !self.exclude_fingerprints.contains(cert.id())
+    }
+}
+
+/// Where the certificate store the Windows backend uses is.
+#[cfg(target_os = "windows")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowsStoreLocation {
+    CurrentUser,
+    LocalMachine,
+}
+
+/// A type of backend and its options. Only the types of backends available on this platform exist.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BackendKind {
+    /// Certificates and keys in files in the given directories.
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    Files { directories: Vec<PathBuf> },
+    /// Keys in the kernel keyring, with certificates from the given directories (or the default
+    /// directories).
+    #[cfg(target_os = "linux")]
+    Keyring { directories: Option<Vec<PathBuf>> },
+    /// Keys held by the ssh-agent at the given socket (or `$SSH_AUTH_SOCK`), with certificates from
+    /// the given directories (or the default directories).
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    SshAgent {
+        socket: Option<PathBuf>,
+        directories: Option<Vec<PathBuf>>,
+    },
+    /// Keys held by the gpg-agent at the given socket (or the user's gpg-agent), with certificates
+    /// from the given keybox and directories (or gpgsm's keybox and the default directories).
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    GpgAgent {
+        socket: Option<PathBuf>,
+        keybox: Option<PathBuf>,
+        directories: Option<Vec<PathBuf>>,
+    },
+    /// The certificates and keys on the tokens of the given PKCS #11 module.
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    Pkcs11 {
+        module: PathBuf,
+        parameters: Option<String>,
+    },
+    /// The macOS keychain.
+    #[cfg(target_os = "macos")]
+    MacOS,
+    /// The Windows certificate store with the given name in the given location.
+    #[cfg(target_os = "windows")]
+    Windows {
+        store: String,
+        location: WindowsStoreLocation,
+    },
+}
+
+/// The configuration of one backend.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackendConfig {
+    pub kind: BackendKind,
+    pub token: TokenConfig,
+    pub filter: Filter,
+}
+
+/// The configuration of this module.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// How backends are exposed as slots.
+    pub slots: SlotMode,
+    /// How often backends are asked for their identities.
+    pub rescan_interval: Duration,
+    /// The manufacturer of each token, unless configured otherwise for a backend.
+    pub manufacturer: Option<String>,
+    /// The token that combines all backends if `slots` is `SlotMode::Single`.
+    pub token: TokenConfig,
+    /// The backends to use, in order. If not set, the default backends for this platform are used.
+    pub backends: Option<Vec<BackendConfig>>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            slots: SlotMode::PerStore,
+            rescan_interval: DEFAULT_RESCAN_INTERVAL,
+            manufacturer: None,
+            token: TokenConfig::default(),
+            backends: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration file. If `path` is given, that file is loaded. Otherwise, the file
+    /// given by `$OSCLIENTCERTS_CONFIG` is loaded, or else the first `osclientcerts/config.toml` in
+    /// the XDG configuration directories (`$XDG_CONFIG_HOME` or `~/.config`, then
+    /// `$XDG_CONFIG_DIRS` or `/etc/xdg`). If there is no configuration file, the default
+    /// configuration is used. Fails with a description of the problem if the file can't be read or
+    /// isn't valid.
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match std::env::var_os(CONFIG_PATH) {
+                Some(path) => PathBuf::from(path),
+                None => match xdg_config_paths().into_iter().find(|path| path.is_file()) {
+                    Some(path) => path,
+                    None => {
+                        debug!("no configuration file - using the default configuration");
+                        return Ok(Config::default());
+                    }
+                },
+            },
+        };
+        debug!("loading configuration from '{}'", path.display());
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| format!("couldn't read '{}': {}", path.display(), e))?;
+        Config::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Loads the configuration file (the one given by the module parameters, if any) and applies
+    /// the overrides of the module parameters to it.
+    pub fn load_with_parameters(parameters: &ModuleParameters) -> Result<Config, String> {
+        let mut config = Config::load(parameters.config_path.as_deref())?;
+        if let Some(slots) = parameters.slots {
+            config.slots = slots;
+        }
+        if let Some(backends) = &parameters.backends {
+            config.backends = Some(backends.clone());
+        }
+        Ok(config)
+    }
+
+    /// Parses and validates the given configuration.
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        let raw: RawConfig = toml::from_str(contents).map_err(|e| e.message().to_owned())?;
+        let mut config = Config::default();
+        if let Some(slots) = raw.slots {
+            config.slots = SlotMode::parse(&slots)?;
+        }
+        if let Some(rescan_interval) = raw.rescan_interval {
+            if rescan_interval == 0 {
+                return Err(String::from("rescan_interval must be at least 1 second"));
+            }
+            config.rescan_interval = Duration::from_secs(rescan_interval);
+        }
+        if let Some(manufacturer) = raw.manufacturer {
+            check_length("manufacturer", &manufacturer, MAX_MANUFACTURER_LENGTH)?;
+            config.manufacturer = Some(manufacturer);
+        }
+        if let Some(token) = raw.token {
+            config.token = parse_token(token.label, token.serial_number, token.manufacturer)
+                .map_err(|e| format!("token: {}", e))?;
+        }
+        if let Some(raw_backends) = raw.backends {
+            let mut backends = Vec::with_capacity(raw_backends.len());
+            for (index, raw_backend) in raw_backends.into_iter().enumerate() {
+                let backend_type = raw_backend.backend_type.clone();
+                backends.push(
+                    parse_backend(raw_backend)
+                        .map_err(|e| format!("backend {} ({}): {}", index + 1, backend_type, e))?,
+                );
+            }
+            config.backends = Some(backends);
+        }
+        Ok(config)
+    }
+}
+
+/// Overrides of the configuration for one load of this module, given as module parameters. NSS
+/// passes the `parameters=` string of the module's entry in `pkcs11.txt` (or the `-string` given
+/// to `modutil`) in the `pReserved` field of `CK_C_INITIALIZE_ARGS`. The parameters are
+/// space-separated `name=value` pairs, e.g. `config=/path backend=pem:/dir slots=per-store
+/// loglevel=debug`. Values may be quoted with `"` or `'`, and `\` escapes the next character.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ModuleParameters {
+    /// The configuration file to load instead of looking for one.
+    pub config_path: Option<PathBuf>,
+    /// How backends are exposed as slots, regardless of the configuration file.
+    pub slots: Option<SlotMode>,
+    /// The backends to use instead of those in the configuration file. Each `backend=` parameter
+    /// adds one, given as its type optionally followed by `:` and its main option: a directory for
+    /// `pem` (or `files`), `keyring`, `ssh-agent`, and `gpg-agent`, a socket for `ssh-agent` and
+    /// `gpg-agent` if it isn't a directory, a module for `pkcs11`, or a store for `windows`.
+    pub backends: Option<Vec<BackendConfig>>,
+    /// The maximum level of messages to log.
+    pub log_level: Option<log::LevelFilter>,
+}
+
+impl ModuleParameters {
+    /// Parses and validates the given module parameters.
+    pub fn parse(parameters: &str) -> Result<ModuleParameters, String> {
+        let mut module_parameters = ModuleParameters::default();
+        for (name, value) in split_parameters(parameters)? {
+            let already_given = match name.as_str() {
+                "config" => module_parameters
+                    .config_path
+                    .replace(expand_path(&value).map_err(|e| format!("config: {}", e))?)
+                    .is_some(),
+                "slots" => module_parameters
+                    .slots
+                    .replace(SlotMode::parse(&value)?)
+                    .is_some(),
+                "loglevel" => module_parameters
+                    .log_level
+                    .replace(value.parse().map_err(|_| {
+                        format!(
+                            "invalid log level '{}' (expected one of off, error, warn, info, \
+                             debug, trace)",
+                            value
+                        )
+                    })?)
+                    .is_some(),
+                "backend" => {
+                    let backend = parse_backend_parameter(&value)
+                        .map_err(|e| format!("backend '{}': {}", value, e))?;
+                    module_parameters
+                        .backends
+                        .get_or_insert_with(Vec::new)
+                        .push(backend);
+                    false
+                }
+                _ => {
+                    return Err(format!(
+                        "unknown parameter '{}' (expected config, backend, slots, or loglevel)",
+                        name
+                    ))
+                }
+            };
+            if already_given {
+                return Err(format!("'{}' is given more than once", name));
+            }
+        }
+        Ok(module_parameters)
+    }
+}
+
+/// Splits module parameters into `name=value` pairs.
+fn split_parameters(parameters: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    let mut chars = parameters.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(pairs);
+        }
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && !c.is_whitespace()) {
+            name.push(c);
+        }
+        if chars.next() != Some('=') {
+            return Err(format!("expected '=' after '{}'", name));
+        }
+        if name.is_empty() {
+            return Err(String::from("expected a parameter name before '='"));
+        }
+        let mut value = String::new();
+        let mut quote = None;
+        loop {
+            match (chars.next(), quote) {
+                (None, None) => break,
+                (None, Some(_)) => return Err(format!("unterminated quote in '{}'", name)),
+                (Some('\\'), _) => match chars.next() {
+                    Some(c) => value.push(c),
+                    None => return Err(format!("unterminated escape in '{}'", name)),
+                },
+                (Some(c), None) if c == '"' || c == '\'' => quote = Some(c),
+                (Some(c), Some(q)) if c == q => quote = None,
+                (Some(c), None) if c.is_whitespace() => break,
+                (Some(c), _) => value.push(c),
+            }
+        }
+        pairs.push((name, value));
+    }
+}
+
+/// Parses the value of a `backend=` module parameter (e.g. `pem:/dir` or `ssh-agent`) by
+/// translating it to the equivalent entry of a configuration file.
+fn parse_backend_parameter(value: &str) -> Result<BackendConfig, String> {
+    let (backend_type, option) = match value.split_once(':') {
+        Some((backend_type, option)) => (backend_type, Some(option.to_owned())),
+        None => (value, None),
+    };
+    let backend_type = match backend_type {
+        "pem" => "files",
+        backend_type => backend_type,
+    };
+    let mut raw = RawBackend {
+        backend_type: backend_type.to_owned(),
+        label: None,
+        serial_number: None,
+        manufacturer: None,
+        directories: None,
+        socket: None,
+        keybox: None,
+        module: None,
+        parameters: None,
+        store: None,
+        location: None,
+        filter: None,
+    };
+    match backend_type {
+        "files" | "keyring" => raw.directories = option.map(|directory| vec![directory]),
+        "ssh-agent" | "gpg-agent" => match option {
+            Some(option) if Path::new(&option).is_dir() => raw.directories = Some(vec![option]),
+            option => raw.socket = option,
+        },
+        "pkcs11" => raw.module = option,
+        "windows" => raw.store = option,
+        _ => {
+            if option.is_some() {
+                return Err(String::from("this backend doesn't take an option"));
+            }
+        }
+    }
+    parse_backend(raw)
+}
+
+/// Returns the paths a configuration file is looked for in the XDG configuration directories, in
+/// order of preference.
+fn xdg_config_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(config_home) if !config_home.is_empty() => {
+            paths.push(Path::new(&config_home).join(CONFIG_FILE))
+        }
+        _ => {
+            if let Some(home) = std::env::var_os("HOME") {
+                paths.push(Path::new(&home).join(".config").join(CONFIG_FILE));
+            }
+        }
+    }
+    let config_dirs = match std::env::var_os("XDG_CONFIG_DIRS") {
+        Some(config_dirs) if !config_dirs.is_empty() => config_dirs,
+        _ => DEFAULT_XDG_CONFIG_DIRS.into(),
+    };
+    for config_dir in std::env::split_paths(&config_dirs) {
+        if !config_dir.as_os_str().is_empty() {
+            paths.push(config_dir.join(CONFIG_FILE));
+        }
+    }
+    paths
+}
+
+fn check_length(name: &str, value: &str, max_length: usize) -> Result<(), String> {
+    if value.is_empty() {
+        return Err(format!("{} must not be empty", name));
+    }
+    if value.len() > max_length {
+        return Err(format!(
+            "{} '{}' is longer than {} bytes",
+            name, value, max_length
+        ));
+    }
+    Ok(())
+}
+
+fn parse_token(
+    label: Option<String>,
+    serial_number: Option<String>,
+    manufacturer: Option<String>,
+) -> Result<TokenConfig, String> {
+    if let Some(label) = &label {
+        check_length("label", label, MAX_LABEL_LENGTH)?;
+    }
+    if let Some(serial_number) = &serial_number {
+        check_length("serial_number", serial_number, MAX_SERIAL_NUMBER_LENGTH)?;
+    }
+    if let Some(manufacturer) = &manufacturer {
+        check_length("manufacturer", manufacturer, MAX_MANUFACTURER_LENGTH)?;
+    }
+    Ok(TokenConfig {
+        label,
+        serial_number,
+        manufacturer,
+    })
+}
+
+/// Expands a leading `~` in the given path to the user's home directory.
+fn expand_path(path: &str) -> Result<PathBuf, String> {
+    if path.is_empty() {
+        return Err(String::from("paths must not be empty"));
+    }
+    if path == "~" || path.starts_with("~/") {
+        let home = std::env::var_os("HOME")
+            .ok_or_else(|| format!("can't expand '{}' because $HOME isn't set", path))?;
+        return Ok(Path::new(&home).join(path.trim_start_matches('~').trim_start_matches('/')));
+    }
+    Ok(PathBuf::from(path))
+}
+
+fn expand_paths(paths: Vec<String>) -> Result<Vec<PathBuf>, String> {
+    if paths.is_empty() {
+        return Err(String::from("directories must not be empty"));
+    }
+    paths.iter().map(|path| expand_path(path)).collect()
+}
+
+/// Parses a SHA-256 fingerprint written in hex, optionally with ':' between bytes.
+fn parse_fingerprint(fingerprint: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = fingerprint.chars().filter(|c| *c != ':').collect();
+    let invalid = || format!("invalid SHA-256 fingerprint '{}'", fingerprint);
+    if digits.len() != 64 {
+        return Err(invalid());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let byte: String = pair.iter().collect();
+            u8::from_str_radix(&byte, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+fn parse_filter(raw: RawFilter) -> Result<Filter, String> {
+    let mut filter = Filter::default();
+    if let Some(key_types) = raw.key_types {
+        let mut key_kinds = Vec::with_capacity(key_types.len());
+        for key_type in key_types {
+            key_kinds.push(match key_type.as_str() {
+                "rsa" => KeyKind::Rsa,
+                "ec" => KeyKind::Ec,
+                _ => {
+                    return Err(format!(
+                        "invalid key type '{}' (expected 'rsa' or 'ec')",
+                        key_type
+                    ))
+                }
+            });
+        }
+        filter.key_types = Some(key_kinds);
+    }
+    if let Some(fingerprints) = raw.fingerprints {
+        filter.fingerprints = Some(
+            fingerprints
+                .iter()
+                .map(|fingerprint| parse_fingerprint(fingerprint))
+                .collect::<Result<_, _>>()?,
+        );
+    }
+    if let Some(exclude_fingerprints) = raw.exclude_fingerprints {
+        filter.exclude_fingerprints = exclude_fingerprints
+            .iter()
+            .map(|fingerprint| parse_fingerprint(fingerprint))
+            .collect::<Result<_, _>>()?;
+    }
+    Ok(filter)
+}
+
+fn parse_backend(raw: RawBackend) -> Result<BackendConfig, String> {
+    if !BACKEND_TYPES.contains(&raw.backend_type.as_str()) {
+        return Err(format!(
+            "unknown backend type (expected one of {})",
+            BACKEND_TYPES.join(", ")
+        ));
+    }
+    let token = parse_token(raw.label, raw.serial_number, raw.manufacturer)?;
+    let filter = match raw.filter {
+        Some(filter) => parse_filter(filter)?,
+        None => Filter::default(),
+    };
+    // Each option only applies to some types of backends. Anything else is a mistake.
+    let options = [
+        ("directories", raw.directories.is_some()),
+        ("socket", raw.socket.is_some()),
+        ("keybox", raw.keybox.is_some()),
+        ("module", raw.module.is_some()),
+        ("parameters", raw.parameters.is_some()),
+        ("store", raw.store.is_some()),
+        ("location", raw.location.is_some()),
+    ];
+    let valid_options: &[&str] = match raw.backend_type.as_str() {
+        "files" | "keyring" => &["directories"],
+        "ssh-agent" => &["socket", "directories"],
+        "gpg-agent" => &["socket", "keybox", "directories"],
+        "pkcs11" => &["module", "parameters"],
+        "windows" => &["store", "location"],
+        _ => &[],
+    };
+    for (option, is_set) in options.iter() {
+        if *is_set && !valid_options.contains(option) {
+            return Err(format!("'{}' isn't an option of this backend", option));
+        }
+    }
+    let kind = match raw.backend_type.as_str() {
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        "files" => BackendKind::Files {
+            directories: expand_paths(
+                raw.directories
+                    .ok_or_else(|| String::from("'directories' is required"))?,
+            )?,
+        },
+        #[cfg(target_os = "linux")]
+        "keyring" => BackendKind::Keyring {
+            directories: raw.directories.map(expand_paths).transpose()?,
+        },
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        "ssh-agent" => BackendKind::SshAgent {
+            socket: raw.socket.as_deref().map(expand_path).transpose()?,
+            directories: raw.directories.map(expand_paths).transpose()?,
+        },
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        "gpg-agent" => BackendKind::GpgAgent {
+            socket: raw.socket.as_deref().map(expand_path).transpose()?,
+            keybox: raw.keybox.as_deref().map(expand_path).transpose()?,
+            directories: raw.directories.map(expand_paths).transpose()?,
+        },
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        "pkcs11" => BackendKind::Pkcs11 {
+            module: expand_path(
+                &raw.module
+                    .ok_or_else(|| String::from("'module' is required"))?,
+            )?,
+            parameters: raw.parameters,
+        },
+        #[cfg(target_os = "macos")]
+        "macos" => BackendKind::MacOS,
+        #[cfg(target_os = "windows")]
+        "windows" => BackendKind::Windows {
+            store: raw.store.unwrap_or_else(|| String::from("My")),
+            location: match raw.location.as_deref() {
+                None | Some("current-user") => WindowsStoreLocation::CurrentUser,
+                Some("local-machine") => WindowsStoreLocation::LocalMachine,
+                Some(location) => {
+                    return Err(format!(
+                        "invalid location '{}' (expected 'current-user' or 'local-machine')",
+                        location
+                    ))
+                }
+            },
+        },
+        _ => {
+            return Err(String::from(
+                "this backend isn't available on this platform",
+            ))
+        }
+    };
+    Ok(BackendConfig {
+        kind,
+        token,
+        filter,
+    })
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::backend_mock::*;
+    use sha2::{Digest, Sha256};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn parse_error(contents: &str) -> String {
+        Config::parse(contents).unwrap_err()
+    }
+
+    #[test]
+    fn test_empty_config() {
+        assert_eq!(Config::parse(""), Ok(Config::default()));
+    }
+
+    #[test]
+    fn test_full_config() {
+        let config = Config::parse(
+            r#"
+            slots = "single"
+            rescan_interval = 10
+            manufacturer = "Example Corp"
+
+            [token]
+            label = "Example Token"
+            serial_number = "1234"
+
+            [[backends]]
+            type = "files"
+            label = "Team Certificates"
+            directories = ["/srv/certs", "~/certs"]
+
+            [backends.filter]
+            key_types = ["ec"]
+            exclude_fingerprints = ["00:01:02:03:04:05:06:07:08:09:0a:0b:0c:0d:0e:0f:10:11:12:13:14:15:16:17:18:19:1a:1b:1c:1d:1e:1f"]
+
+            [[backends]]
+            type = "pkcs11"
+            module = "/usr/lib/opensc-pkcs11.so"
+            parameters = "slot=1"
+            serial_number = "smartcard"
+
+            [[backends]]
+            type = "ssh-agent"
+            socket = "/run/agent.sock"
+
+            [[backends]]
+            type = "keyring"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.slots, SlotMode::Single);
+        assert_eq!(config.rescan_interval, Duration::from_secs(10));
+        assert_eq!(config.manufacturer.as_deref(), Some("Example Corp"));
+        assert_eq!(config.token.label.as_deref(), Some("Example Token"));
+        assert_eq!(config.token.serial_number.as_deref(), Some("1234"));
+        let backends = config.backends.unwrap();
+        assert_eq!(backends.len(), 4);
+        let home = PathBuf::from(std::env::var_os("HOME").unwrap());
+        assert_eq!(
+            backends[0].kind,
+            BackendKind::Files {
+                directories: vec![PathBuf::from("/srv/certs"), home.join("certs")]
+            }
+        );
+        assert_eq!(
+            backends[0].token.label.as_deref(),
+            Some("Team Certificates")
+        );
+        assert_eq!(backends[0].filter.key_types, Some(vec![KeyKind::Ec]));
+        assert_eq!(
+            backends[0].filter.exclude_fingerprints,
+            BTreeSet::from([(0..32).collect::<Vec<u8>>()])
+        );
+        assert_eq!(
+            backends[1].kind,
+            BackendKind::Pkcs11 {
+                module: PathBuf::from("/usr/lib/opensc-pkcs11.so"),
+                parameters: Some(String::from("slot=1")),
+            }
+        );
+        assert_eq!(
+            backends[1].token.serial_number.as_deref(),
+            Some("smartcard")
+        );
+        assert_eq!(
+            backends[2].kind,
+            BackendKind::SshAgent {
+                socket: Some(PathBuf::from("/run/agent.sock")),
+                directories: None,
+            }
+        );
+        assert_eq!(backends[3].kind, BackendKind::Keyring { directories: None });
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        assert!(parse_error("slots = 1").contains("expected a string"));
+        assert_eq!(
+            parse_error("slots = \"some\""),
+            "invalid slot mode 'some' (expected 'per-store' or 'single')"
+        );
+        assert_eq!(
+            parse_error("rescan_interval = 0"),
+            "rescan_interval must be at least 1 second"
+        );
+        assert!(parse_error("unknown = 1").contains("unknown field `unknown`"));
+        assert_eq!(
+            parse_error("[token]\nlabel = \"a label that is much too long for a token\""),
+            "token: label 'a label that is much too long for a token' is longer than 32 bytes"
+        );
+        assert_eq!(
+            parse_error("[[backends]]\ntype = \"floppy\""),
+            "backend 1 (floppy): unknown backend type (expected one of files, keyring, \
+             ssh-agent, gpg-agent, pkcs11, macos, windows)"
+        );
+        assert_eq!(
+            parse_error("[[backends]]\ntype = \"windows\""),
+            "backend 1 (windows): this backend isn't available on this platform"
+        );
+        assert_eq!(
+            parse_error("[[backends]]\ntype = \"files\""),
+            "backend 1 (files): 'directories' is required"
+        );
+        assert_eq!(
+            parse_error("[[backends]]\ntype = \"files\"\ndirectories = []"),
+            "backend 1 (files): directories must not be empty"
+        );
+        assert_eq!(
+            parse_error("[[backends]]\ntype = \"keyring\"\n[[backends]]\ntype = \"pkcs11\""),
+            "backend 2 (pkcs11): 'module' is required"
+        );
+        assert_eq!(
+            parse_error("[[backends]]\ntype = \"files\"\ndirectories = [\"/a\"]\nmodule = \"/b\""),
+            "backend 1 (files): 'module' isn't an option of this backend"
+        );
+        assert_eq!(
+            parse_error("[[backends]]\ntype = \"keyring\"\nserial_number = \"\""),
+            "backend 1 (keyring): serial_number must not be empty"
+        );
+        assert_eq!(
+            parse_error(
+                "[[backends]]\ntype = \"keyring\"\n[backends.filter]\nkey_types = [\"dsa\"]"
+            ),
+            "backend 1 (keyring): invalid key type 'dsa' (expected 'rsa' or 'ec')"
+        );
+        assert_eq!(
+            parse_error(
+                "[[backends]]\ntype = \"keyring\"\n[backends.filter]\nfingerprints = [\"abcd\"]"
+            ),
+            "backend 1 (keyring): invalid SHA-256 fingerprint 'abcd'"
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let store = MockStore::with_fixtures();
+        let identities = MockBackend::new(store).list_identities();
+        let allowed = |filter: &Filter| -> Vec<bool> {
+            identities
+                .iter()
+                .map(|(cert, key)| filter.allows(cert, key))
+                .collect()
+        };
+        assert_eq!(allowed(&Filter::default()), vec![true, true, true]);
+        let config = Config::parse(&format!(
+            "[[backends]]\ntype = \"keyring\"\n[backends.filter]\nkey_types = [\"rsa\"]\n\
+             [[backends]]\ntype = \"keyring\"\n[backends.filter]\nfingerprints = [\"{}\", \"{}\"]\n\
+             exclude_fingerprints = [\"{}\"]",
+            hex(&Sha256::digest(P256_CERT)),
+            hex(&Sha256::digest(P384_CERT)),
+            hex(&Sha256::digest(P384_CERT)).to_uppercase(),
+        ))
+        .unwrap();
+        let backends = config.backends.unwrap();
+        assert_eq!(allowed(&backends[0].filter), vec![true, false, false]);
+        assert_eq!(allowed(&backends[1].filter), vec![false, true, false]);
+    }
+
+    #[test]
+    fn test_load() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        let error = Config::load(Some(&path)).unwrap_err();
+        assert!(error.starts_with(&format!("couldn't read '{}'", path.display())));
+        std::fs::write(&path, "slots = \"single\"").unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.slots, SlotMode::Single);
+        std::fs::write(&path, "slots = 1").unwrap();
+        let error = Config::load(Some(&path)).unwrap_err();
+        assert!(error.starts_with(&format!("{}: ", path.display())));
+    }
+
+    #[test]
+    fn test_module_parameters() {
+        assert_eq!(ModuleParameters::parse(""), Ok(ModuleParameters::default()));
+        let directory = tempfile::tempdir().unwrap();
+        let parameters = ModuleParameters::parse(&format!(
+            "config=/etc/osclientcerts.toml  backend=pem:/srv/certs backend='pem:/srv/team certs' \
+             backend=ssh-agent:/run/agent.sock backend=gpg-agent:{} backend=keyring \
+             slots=single loglevel=debug",
+            directory.path().display()
+        ))
+        .unwrap();
+        assert_eq!(
+            parameters.config_path,
+            Some(PathBuf::from("/etc/osclientcerts.toml"))
+        );
+        assert_eq!(parameters.slots, Some(SlotMode::Single));
+        assert_eq!(parameters.log_level, Some(log::LevelFilter::Debug));
+        let kinds: Vec<BackendKind> = parameters
+            .backends
+            .unwrap()
+            .into_iter()
+            .map(|backend| backend.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                BackendKind::Files {
+                    directories: vec![PathBuf::from("/srv/certs")]
+                },
+                BackendKind::Files {
+                    directories: vec![PathBuf::from("/srv/team certs")]
+                },
+                BackendKind::SshAgent {
+                    socket: Some(PathBuf::from("/run/agent.sock")),
+                    directories: None,
+                },
+                BackendKind::GpgAgent {
+                    socket: None,
+                    keybox: None,
+                    directories: Some(vec![directory.path().to_path_buf()]),
+                },
+                BackendKind::Keyring { directories: None },
+            ]
+        );
+        let parameters = ModuleParameters::parse(r#"config="/a b/c\"d" slots=per-store"#).unwrap();
+        assert_eq!(parameters.config_path, Some(PathBuf::from("/a b/c\"d")));
+        assert_eq!(parameters.slots, Some(SlotMode::PerStore));
+    }
+
+    #[test]
+    fn test_invalid_module_parameters() {
+        let parse_error = |parameters: &str| ModuleParameters::parse(parameters).unwrap_err();
+        assert_eq!(parse_error("config"), "expected '=' after 'config'");
+        assert_eq!(parse_error("=/a"), "expected a parameter name before '='");
+        assert_eq!(parse_error("config='/a"), "unterminated quote in 'config'");
+        assert_eq!(
+            parse_error("verbose=1"),
+            "unknown parameter 'verbose' (expected config, backend, slots, or loglevel)"
+        );
+        assert_eq!(
+            parse_error("slots=single slots=per-store"),
+            "'slots' is given more than once"
+        );
+        assert_eq!(
+            parse_error("loglevel=loud"),
+            "invalid log level 'loud' (expected one of off, error, warn, info, debug, trace)"
+        );
+        assert_eq!(
+            parse_error("backend=pem"),
+            "backend 'pem': 'directories' is required"
+        );
+        assert_eq!(
+            parse_error("backend=floppy:/dev/fd0"),
+            "backend 'floppy:/dev/fd0': this backend doesn't take an option"
+        );
+        assert_eq!(
+            parse_error("backend=windows:My"),
+            "backend 'windows:My': this backend isn't available on this platform"
+        );
+    }
+
+    #[test]
+    fn test_load_with_parameters() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        std::fs::write(
+            &path,
+            "slots = \"single\"\nrescan_interval = 5\n[[backends]]\ntype = \"keyring\"",
+        )
+        .unwrap();
+        let parameters = ModuleParameters::parse(&format!("config={}", path.display())).unwrap();
+        let config = Config::load_with_parameters(&parameters).unwrap();
+        assert_eq!(config.slots, SlotMode::Single);
+        assert_eq!(config.rescan_interval, Duration::from_secs(5));
+        assert_eq!(config.backends.unwrap().len(), 1);
+        let parameters = ModuleParameters::parse(&format!(
+            "config={} slots=per-store backend=pem:/srv/certs",
+            path.display()
+        ))
+        .unwrap();
+        let config = Config::load_with_parameters(&parameters).unwrap();
+        assert_eq!(config.slots, SlotMode::PerStore);
+        assert_eq!(config.rescan_interval, Duration::from_secs(5));
+        assert_eq!(
+            config.backends.unwrap()[0].kind,
+            BackendKind::Files {
+                directories: vec![PathBuf::from("/srv/certs")]
+            }
+        );
+        let parameters = ModuleParameters::parse(&format!(
+            "config={}",
+            directory.path().join("missing").display()
+        ))
+        .unwrap();
+        assert!(Config::load_with_parameters(&parameters).is_err());
+    }
+}
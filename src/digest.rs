@@ -0,0 +1,153 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use pkcs11::types::*;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use crate::error::Error;
+
+/// The DER encoding of everything in a DigestInfo with a SHA-256 hash that comes before the hash.
+const SHA256_DIGEST_INFO_PREFIX: &[u8] = &[
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0x04, 0x20,
+];
+/// The DER encoding of everything in a DigestInfo with a SHA-384 hash that comes before the hash.
+const SHA384_DIGEST_INFO_PREFIX: &[u8] = &[
+    0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02, 0x05,
+    0x00, 0x04, 0x30,
+];
+/// The DER encoding of everything in a DigestInfo with a SHA-512 hash that comes before the hash.
+const SHA512_DIGEST_INFO_PREFIX: &[u8] = &[
+    0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03, 0x05,
+    0x00, 0x04, 0x40,
+];
+
+/// Hashes data incrementally with the hash algorithm of a hash-and-sign mechanism (e.g.
+/// `CKM_SHA256_RSA_PKCS`), so the data can be given to `C_SignUpdate` in as many parts as the
+/// application likes.
+#[derive(Clone)]
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha384(Sha384),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    /// Returns a `Hasher` for the given hash algorithm (e.g. `CKM_SHA256`), if it is supported.
+    pub fn new(hash_algorithm: CK_MECHANISM_TYPE) -> Option<Hasher> {
+        match hash_algorithm {
+            CKM_SHA256 => Some(Hasher::Sha256(Sha256::new())),
+            CKM_SHA384 => Some(Hasher::Sha384(Sha384::new())),
+            CKM_SHA512 => Some(Hasher::Sha512(Sha512::new())),
+            _ => None,
+        }
+    }
+
+    /// Returns the hash algorithm this hasher uses.
+    pub fn algorithm(&self) -> CK_MECHANISM_TYPE {
+        match self {
+            Hasher::Sha256(_) => CKM_SHA256,
+            Hasher::Sha384(_) => CKM_SHA384,
+            Hasher::Sha512(_) => CKM_SHA512,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha384(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Returns the hash of all of the data given to `update`.
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha384(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// Returns the DER encoding of a DigestInfo (what gets signed with RSA PKCS #1 v1.5) with the given
+/// hash, which was computed with the given hash algorithm.
+///   DigestInfo ::= SEQUENCE {
+///       digestAlgorithm DigestAlgorithmIdentifier,
+///       digest Digest }
+pub fn encode_digest_info(
+    hash_algorithm: CK_MECHANISM_TYPE,
+    hash: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let prefix = match hash_algorithm {
+        CKM_SHA256 => SHA256_DIGEST_INFO_PREFIX,
+        CKM_SHA384 => SHA384_DIGEST_INFO_PREFIX,
+        CKM_SHA512 => SHA512_DIGEST_INFO_PREFIX,
+        _ => return Err(Error::MechanismInvalid),
+    };
+    // The last byte of the prefix is the length of the hash.
+    if prefix.last() != Some(&(hash.len() as u8)) {
+        return Err(Error::DataLenRange);
+    }
+    let mut digest_info = prefix.to_vec();
+    digest_info.extend_from_slice(hash);
+    Ok(digest_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::pkcs::*;
+
+    #[test]
+    fn test_hasher() {
+        for (hash_algorithm, expected) in [
+            (CKM_SHA256, Sha256::digest(b"hello, world").to_vec()),
+            (CKM_SHA384, Sha384::digest(b"hello, world").to_vec()),
+            (CKM_SHA512, Sha512::digest(b"hello, world").to_vec()),
+        ] {
+            let mut hasher = Hasher::new(hash_algorithm).unwrap();
+            assert_eq!(hasher.algorithm(), hash_algorithm);
+            hasher.update(b"hello, ");
+            let copy = hasher.clone();
+            hasher.update(b"world");
+            assert_eq!(hasher.finalize(), expected);
+            assert_ne!(copy.finalize(), expected);
+        }
+        assert!(Hasher::new(CKM_SHA_1).is_none());
+    }
+
+    #[test]
+    fn test_encode_digest_info() {
+        for (hash_algorithm, oid, hash) in [
+            (
+                CKM_SHA256,
+                OID_BYTES_SHA256,
+                Sha256::digest(b"data").to_vec(),
+            ),
+            (
+                CKM_SHA384,
+                OID_BYTES_SHA384,
+                Sha384::digest(b"data").to_vec(),
+            ),
+            (
+                CKM_SHA512,
+                OID_BYTES_SHA512,
+                Sha512::digest(b"data").to_vec(),
+            ),
+        ] {
+            let digest_info = encode_digest_info(hash_algorithm, &hash).unwrap();
+            assert_eq!(read_digest_info(&digest_info), Ok((oid, hash.as_slice())));
+        }
+        assert_eq!(
+            encode_digest_info(CKM_SHA256, &[0; 48]),
+            Err(Error::DataLenRange)
+        );
+        assert_eq!(
+            encode_digest_info(CKM_MD5, &[0; 16]),
+            Err(Error::MechanismInvalid)
+        );
+    }
+}
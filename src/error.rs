@@ -0,0 +1,157 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use pkcs11::types::*;
+use std::fmt;
+
+/// Why an operation failed. Applications (NSS in particular) behave differently depending on the
+/// `CK_RV` they get back, so each cause is converted to the corresponding `CK_RV` when it is
+/// returned from this module.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// There is no slot with the given ID.
+    SlotIdInvalid,
+    /// There is no session with the given handle.
+    SessionHandleInvalid,
+    /// The session already has an operation of the given kind in progress.
+    OperationActive,
+    /// The session doesn't have an operation of the given kind in progress.
+    OperationNotInitialized,
+    /// There is no object with the given handle in the session's slot (any more). Objects can
+    /// disappear when the backend's certificates and keys are removed.
+    ObjectHandleInvalid,
+    /// The object has the attribute, but its value can't be revealed (e.g. the private exponent of
+    /// an RSA key).
+    AttributeSensitive,
+    /// The object doesn't have the attribute.
+    AttributeTypeInvalid,
+    /// There is no key with the given handle in the session's slot (any more).
+    KeyHandleInvalid,
+    /// The mechanism isn't supported.
+    MechanismInvalid,
+    /// The parameters of the mechanism aren't valid or aren't supported.
+    MechanismParamInvalid,
+    /// The key can't be used with the mechanism (e.g. an EC key with an RSA mechanism).
+    KeyTypeInconsistent,
+    /// The key isn't allowed to be used for the operation (e.g. decrypting with a key whose
+    /// certificate doesn't allow key encipherment).
+    KeyFunctionNotPermitted,
+    /// The input data isn't valid for the operation (e.g. it isn't a DigestInfo).
+    DataInvalid,
+    /// The input data has the wrong length for the operation.
+    DataLenRange,
+    /// The data to decrypt couldn't be decrypted (e.g. its padding is wrong).
+    EncryptedDataInvalid,
+    /// The data to decrypt has the wrong length for the key.
+    EncryptedDataLenRange,
+    /// The signature to verify isn't a valid signature of the data.
+    SignatureInvalid,
+    /// The signature to verify has the wrong length for the key.
+    SignatureLenRange,
+    /// The output of the operation doesn't fit in the buffer the application gave.
+    BufferTooSmall,
+    /// The arguments aren't valid.
+    ArgumentsBad,
+    /// The PIN didn't unlock anything.
+    PinIncorrect,
+    /// Too many incorrect PINs have been given, so no more are accepted.
+    PinLocked,
+    /// The user is already logged in to the token.
+    UserAlreadyLoggedIn,
+    /// The user isn't logged in (e.g. to the token, or for an operation with a key that needs the
+    /// PIN every time it is used).
+    UserNotLoggedIn,
+    /// The user cancelled a prompt (e.g. for a passphrase or a smart card PIN) the backend showed.
+    Cancelled,
+    /// The backend failed (e.g. because an OS API or an agent returned an error).
+    BackendFailure,
+    /// Something went wrong in this module itself.
+    General,
+}
+
+impl Error {
+    /// Returns the `CK_RV` that corresponds to this error.
+    pub fn ck_rv(self) -> CK_RV {
+        match self {
+            Error::SlotIdInvalid => CKR_SLOT_ID_INVALID,
+            Error::SessionHandleInvalid => CKR_SESSION_HANDLE_INVALID,
+            Error::OperationActive => CKR_OPERATION_ACTIVE,
+            Error::OperationNotInitialized => CKR_OPERATION_NOT_INITIALIZED,
+            Error::ObjectHandleInvalid => CKR_OBJECT_HANDLE_INVALID,
+            Error::AttributeSensitive => CKR_ATTRIBUTE_SENSITIVE,
+            Error::AttributeTypeInvalid => CKR_ATTRIBUTE_TYPE_INVALID,
+            Error::KeyHandleInvalid => CKR_KEY_HANDLE_INVALID,
+            Error::MechanismInvalid => CKR_MECHANISM_INVALID,
+            Error::MechanismParamInvalid => CKR_MECHANISM_PARAM_INVALID,
+            Error::KeyTypeInconsistent => CKR_KEY_TYPE_INCONSISTENT,
+            Error::KeyFunctionNotPermitted => CKR_KEY_FUNCTION_NOT_PERMITTED,
+            Error::DataInvalid => CKR_DATA_INVALID,
+            Error::DataLenRange => CKR_DATA_LEN_RANGE,
+            Error::EncryptedDataInvalid => CKR_ENCRYPTED_DATA_INVALID,
+            Error::EncryptedDataLenRange => CKR_ENCRYPTED_DATA_LEN_RANGE,
+            Error::SignatureInvalid => CKR_SIGNATURE_INVALID,
+            Error::SignatureLenRange => CKR_SIGNATURE_LEN_RANGE,
+            Error::BufferTooSmall => CKR_BUFFER_TOO_SMALL,
+            Error::ArgumentsBad => CKR_ARGUMENTS_BAD,
+            Error::PinIncorrect => CKR_PIN_INCORRECT,
+            Error::PinLocked => CKR_PIN_LOCKED,
+            Error::UserAlreadyLoggedIn => CKR_USER_ALREADY_LOGGED_IN,
+            Error::UserNotLoggedIn => CKR_USER_NOT_LOGGED_IN,
+            Error::Cancelled => CKR_FUNCTION_CANCELED,
+            Error::BackendFailure => CKR_DEVICE_ERROR,
+            Error::General => CKR_GENERAL_ERROR,
+        }
+    }
+}
+
+/// Errors are logged by the name of the `CK_RV` they correspond to.
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Error::SlotIdInvalid => "CKR_SLOT_ID_INVALID",
+            Error::SessionHandleInvalid => "CKR_SESSION_HANDLE_INVALID",
+            Error::OperationActive => "CKR_OPERATION_ACTIVE",
+            Error::OperationNotInitialized => "CKR_OPERATION_NOT_INITIALIZED",
+            Error::ObjectHandleInvalid => "CKR_OBJECT_HANDLE_INVALID",
+            Error::AttributeSensitive => "CKR_ATTRIBUTE_SENSITIVE",
+            Error::AttributeTypeInvalid => "CKR_ATTRIBUTE_TYPE_INVALID",
+            Error::KeyHandleInvalid => "CKR_KEY_HANDLE_INVALID",
+            Error::MechanismInvalid => "CKR_MECHANISM_INVALID",
+            Error::MechanismParamInvalid => "CKR_MECHANISM_PARAM_INVALID",
+            Error::KeyTypeInconsistent => "CKR_KEY_TYPE_INCONSISTENT",
+            Error::KeyFunctionNotPermitted => "CKR_KEY_FUNCTION_NOT_PERMITTED",
+            Error::DataInvalid => "CKR_DATA_INVALID",
+            Error::DataLenRange => "CKR_DATA_LEN_RANGE",
+            Error::EncryptedDataInvalid => "CKR_ENCRYPTED_DATA_INVALID",
+            Error::EncryptedDataLenRange => "CKR_ENCRYPTED_DATA_LEN_RANGE",
+            Error::SignatureInvalid => "CKR_SIGNATURE_INVALID",
+            Error::SignatureLenRange => "CKR_SIGNATURE_LEN_RANGE",
+            Error::BufferTooSmall => "CKR_BUFFER_TOO_SMALL",
+            Error::ArgumentsBad => "CKR_ARGUMENTS_BAD",
+            Error::PinIncorrect => "CKR_PIN_INCORRECT",
+            Error::PinLocked => "CKR_PIN_LOCKED",
+            Error::UserAlreadyLoggedIn => "CKR_USER_ALREADY_LOGGED_IN",
+            Error::UserNotLoggedIn => "CKR_USER_NOT_LOGGED_IN",
+            Error::Cancelled => "CKR_FUNCTION_CANCELED",
+            Error::BackendFailure => "CKR_DEVICE_ERROR",
+            Error::General => "CKR_GENERAL_ERROR",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ck_rv() {
+        assert_eq!(Error::Cancelled.ck_rv(), CKR_FUNCTION_CANCELED);
+        assert_eq!(Error::KeyHandleInvalid.ck_rv(), CKR_KEY_HANDLE_INVALID);
+        assert_eq!(Error::BackendFailure.ck_rv(), CKR_DEVICE_ERROR);
+        assert_eq!(Error::PinLocked.ck_rv(), CKR_PIN_LOCKED);
+        assert_eq!(Error::OperationActive.to_string(), "CKR_OPERATION_ACTIVE");
+    }
+}
@@ -0,0 +1,178 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::path::PathBuf;
+
+/// The events that indicate that the contents of a watched directory have changed.
+#[cfg(target_os = "linux")]
+const WATCH_MASK: u32 = libc::IN_ATTRIB
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO;
+
+/// The size of the fixed part of a `struct inotify_event` (wd, mask, cookie, and len).
+#[cfg(target_os = "linux")]
+const INOTIFY_EVENT_HEADER_LENGTH: usize = 16;
+
+/// Watches directories for changes with inotify, so that backends whose certificates and keys come
+/// from files don't have to be asked for their identities to find out whether they have changed.
+#[cfg(target_os = "linux")]
+pub struct FileWatcher {
+    fd: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl FileWatcher {
+    /// Starts watching the given directories. Fails if any of them can't be watched (e.g. because
+    /// it doesn't exist).
+    pub fn new(directories: &[PathBuf]) -> Result<FileWatcher, ()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        if directories.is_empty() {
+            return Err(());
+        }
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            error!("inotify_init1 failed: {}", std::io::Error::last_os_error());
+            return Err(());
+        }
+        // From here on, dropping the watcher closes the file descriptor.
+        let watcher = FileWatcher { fd };
+        for directory in directories {
+            let path = std::ffi::CString::new(directory.as_os_str().as_bytes()).map_err(|_| ())?;
+            let watch = unsafe { libc::inotify_add_watch(fd, path.as_ptr(), WATCH_MASK) };
+            if watch < 0 {
+                debug!(
+                    "couldn't watch '{}': {}",
+                    directory.display(),
+                    std::io::Error::last_os_error()
+                );
+                return Err(());
+            }
+        }
+        Ok(watcher)
+    }
+
+    /// Consumes any pending events without blocking. Returns whether or not anything in the watched
+    /// directories has changed since the last call. Fails if a directory is no longer being watched
+    /// (e.g. because it was deleted), in which case the watcher is no longer useful.
+    pub fn poll(&mut self) -> Result<bool, ()> {
+        let mut buffer = [0u8; 4096];
+        let mut changed = false;
+        loop {
+            let length = unsafe {
+                libc::read(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if length < 0 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::WouldBlock {
+                    break;
+                }
+                error!("reading inotify events failed: {}", e);
+                return Err(());
+            }
+            let mut events = &buffer[..length as usize];
+            if events.is_empty() {
+                break;
+            }
+            while events.len() >= INOTIFY_EVENT_HEADER_LENGTH {
+                let read_u32 = |offset: usize| {
+                    let mut bytes = [0; 4];
+                    bytes.copy_from_slice(&events[offset..offset + 4]);
+                    u32::from_ne_bytes(bytes)
+                };
+                let mask = read_u32(4);
+                let name_length = read_u32(12) as usize;
+                if mask & (libc::IN_IGNORED | libc::IN_Q_OVERFLOW) != 0 {
+                    return Err(());
+                }
+                changed = true;
+                let event_length =
+                    std::cmp::min(INOTIFY_EVENT_HEADER_LENGTH + name_length, events.len());
+                events = &events[event_length..];
+            }
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for FileWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Watching directories is only implemented on Linux. Elsewhere, backends are always asked for
+/// their identities to find out whether they have changed.
+#[cfg(not(target_os = "linux"))]
+pub struct FileWatcher;
+
+#[cfg(not(target_os = "linux"))]
+impl FileWatcher {
+    pub fn new(_directories: &[PathBuf]) -> Result<FileWatcher, ()> {
+        Err(())
+    }
+
+    pub fn poll(&mut self) -> Result<bool, ()> {
+        Err(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_no_directories() {
+        assert!(FileWatcher::new(&[]).is_err());
+    }
+
+    #[test]
+    fn test_missing_directory() {
+        let directory = tempfile::tempdir().unwrap();
+        assert!(FileWatcher::new(&[directory.path().join("missing")]).is_err());
+    }
+
+    #[test]
+    fn test_changes() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut watcher = FileWatcher::new(&[directory.path().to_path_buf()]).unwrap();
+        assert_eq!(watcher.poll(), Ok(false));
+        fs::write(directory.path().join("cert.pem"), b"contents").unwrap();
+        assert_eq!(watcher.poll(), Ok(true));
+        assert_eq!(watcher.poll(), Ok(false));
+        fs::rename(
+            directory.path().join("cert.pem"),
+            directory.path().join("renamed.pem"),
+        )
+        .unwrap();
+        assert_eq!(watcher.poll(), Ok(true));
+        fs::remove_file(directory.path().join("renamed.pem")).unwrap();
+        assert_eq!(watcher.poll(), Ok(true));
+        assert_eq!(watcher.poll(), Ok(false));
+    }
+
+    #[test]
+    fn test_directory_removed() {
+        let parent = tempfile::tempdir().unwrap();
+        let directory = parent.path().join("watched");
+        fs::create_dir(&directory).unwrap();
+        let mut watcher = FileWatcher::new(std::slice::from_ref(&directory)).unwrap();
+        fs::remove_dir(&directory).unwrap();
+        assert!(watcher.poll().is_err());
+    }
+}
@@ -0,0 +1,245 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use pkcs11::types::*;
+use std::sync::Arc;
+
+/// What the application asked of this module in the `CK_C_INITIALIZE_ARGS` it passed to
+/// `C_Initialize`.
+pub struct InitializeArgs {
+    /// Whether or not this module may create threads (i.e. whether
+    /// `CKF_LIBRARY_CANT_CREATE_OS_THREADS` wasn't set).
+    pub can_create_threads: bool,
+    /// The mutex to lock around calls into this module, if the application supplied the functions
+    /// to create one.
+    pub mutex: Option<ApplicationMutex>,
+}
+
+impl InitializeArgs {
+    /// Validates the given arguments (which may be NULL) and creates a mutex with the application's
+    /// functions, if it supplied them. Fails with `CKR_ARGUMENTS_BAD` if the functions are only
+    /// partially supplied or if unknown flags are set, or with whatever `CreateMutex` returned if
+    /// that fails. `pReserved` isn't checked here, because NSS uses it to pass module parameters.
+    pub unsafe fn new(pInitArgs: CK_C_INITIALIZE_ARGS_PTR) -> Result<InitializeArgs, CK_RV> {
+        if pInitArgs.is_null() {
+            return Ok(InitializeArgs {
+                can_create_threads: true,
+                mutex: None,
+            });
+        }
+        let args = *pInitArgs;
+        let flags = args.flags;
+        if flags & !(CKF_LIBRARY_CANT_CREATE_OS_THREADS | CKF_OS_LOCKING_OK) != 0 {
+            error!("unknown C_Initialize flags: {:#x}", flags);
+            return Err(CKR_ARGUMENTS_BAD);
+        }
+        let mutex = match (
+            args.CreateMutex,
+            args.DestroyMutex,
+            args.LockMutex,
+            args.UnlockMutex,
+        ) {
+            (None, None, None, None) => None,
+            (Some(create), Some(destroy), Some(lock), Some(unlock)) => {
+                Some(ApplicationMutex::new(create, destroy, lock, unlock)?)
+            }
+            _ => {
+                error!("either all or none of the mutex functions must be given");
+                return Err(CKR_ARGUMENTS_BAD);
+            }
+        };
+        Ok(InitializeArgs {
+            can_create_threads: flags & CKF_LIBRARY_CANT_CREATE_OS_THREADS == 0,
+            mutex,
+        })
+    }
+}
+
+/// A mutex created by the application's `CreateMutex` function, which is locked and unlocked with
+/// its `LockMutex` and `UnlockMutex` functions and destroyed with its `DestroyMutex` function when
+/// this is dropped.
+pub struct ApplicationMutex {
+    mutex: CK_VOID_PTR,
+    destroy: extern "C" fn(CK_VOID_PTR) -> CK_RV,
+    lock: extern "C" fn(CK_VOID_PTR) -> CK_RV,
+    unlock: extern "C" fn(CK_VOID_PTR) -> CK_RV,
+}
+
+// The mutex is only an opaque pointer that is given back to the application's functions, which
+// PKCS #11 requires to work from any thread.
+unsafe impl Send for ApplicationMutex {}
+unsafe impl Sync for ApplicationMutex {}
+
+impl ApplicationMutex {
+    fn new(
+        create: extern "C" fn(CK_VOID_PTR_PTR) -> CK_RV,
+        destroy: extern "C" fn(CK_VOID_PTR) -> CK_RV,
+        lock: extern "C" fn(CK_VOID_PTR) -> CK_RV,
+        unlock: extern "C" fn(CK_VOID_PTR) -> CK_RV,
+    ) -> Result<ApplicationMutex, CK_RV> {
+        let mut mutex: CK_VOID_PTR = std::ptr::null_mut();
+        let rv = create(&mut mutex);
+        if rv != CKR_OK {
+            error!("CreateMutex failed: {:#x}", rv);
+            return Err(rv);
+        }
+        Ok(ApplicationMutex {
+            mutex,
+            destroy,
+            lock,
+            unlock,
+        })
+    }
+
+    /// Locks the mutex. It is unlocked when the returned guard is dropped.
+    pub fn lock(self: &Arc<Self>) -> Result<ApplicationMutexGuard, CK_RV> {
+        let rv = (self.lock)(self.mutex);
+        if rv != CKR_OK {
+            error!("LockMutex failed: {:#x}", rv);
+            return Err(rv);
+        }
+        Ok(ApplicationMutexGuard {
+            mutex: Arc::clone(self),
+        })
+    }
+}
+
+impl Drop for ApplicationMutex {
+    fn drop(&mut self) {
+        let rv = (self.destroy)(self.mutex);
+        if rv != CKR_OK {
+            error!("DestroyMutex failed: {:#x}", rv);
+        }
+    }
+}
+
+/// Unlocks an `ApplicationMutex` when dropped.
+pub struct ApplicationMutexGuard {
+    mutex: Arc<ApplicationMutex>,
+}
+
+impl Drop for ApplicationMutexGuard {
+    fn drop(&mut self) {
+        let rv = (self.mutex.unlock)(self.mutex.mutex);
+        if rv != CKR_OK {
+            error!("UnlockMutex failed: {:#x}", rv);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+
+    // The mock mutex functions keep track of how many mutexes exist and of how many times the
+    // mutex has been locked (minus how many times it has been unlocked). Tests that use them must
+    // not run concurrently, so they all run in one test.
+    static MUTEXES: AtomicUsize = AtomicUsize::new(0);
+    static LOCK_COUNT: AtomicIsize = AtomicIsize::new(0);
+    /// The value the mock mutex points to.
+    static mut MUTEX: u8 = 0;
+
+    extern "C" fn create_mutex(mutex: CK_VOID_PTR_PTR) -> CK_RV {
+        MUTEXES.fetch_add(1, Ordering::SeqCst);
+        unsafe {
+            *mutex = std::ptr::addr_of_mut!(MUTEX) as CK_VOID_PTR;
+        }
+        CKR_OK
+    }
+
+    extern "C" fn create_mutex_fails(_mutex: CK_VOID_PTR_PTR) -> CK_RV {
+        CKR_HOST_MEMORY
+    }
+
+    extern "C" fn destroy_mutex(mutex: CK_VOID_PTR) -> CK_RV {
+        assert_eq!(mutex, std::ptr::addr_of_mut!(MUTEX) as CK_VOID_PTR);
+        MUTEXES.fetch_sub(1, Ordering::SeqCst);
+        CKR_OK
+    }
+
+    extern "C" fn lock_mutex(mutex: CK_VOID_PTR) -> CK_RV {
+        assert_eq!(mutex, std::ptr::addr_of_mut!(MUTEX) as CK_VOID_PTR);
+        LOCK_COUNT.fetch_add(1, Ordering::SeqCst);
+        CKR_OK
+    }
+
+    extern "C" fn unlock_mutex(mutex: CK_VOID_PTR) -> CK_RV {
+        assert_eq!(mutex, std::ptr::addr_of_mut!(MUTEX) as CK_VOID_PTR);
+        LOCK_COUNT.fetch_sub(1, Ordering::SeqCst);
+        CKR_OK
+    }
+
+    fn new_args(flags: CK_FLAGS) -> CK_C_INITIALIZE_ARGS {
+        CK_C_INITIALIZE_ARGS {
+            CreateMutex: None,
+            DestroyMutex: None,
+            LockMutex: None,
+            UnlockMutex: None,
+            flags,
+            pReserved: std::ptr::null_mut(),
+        }
+    }
+
+    fn with_mutex_functions(mut args: CK_C_INITIALIZE_ARGS) -> CK_C_INITIALIZE_ARGS {
+        args.CreateMutex = Some(create_mutex);
+        args.DestroyMutex = Some(destroy_mutex);
+        args.LockMutex = Some(lock_mutex);
+        args.UnlockMutex = Some(unlock_mutex);
+        args
+    }
+
+    fn initialize_args(mut args: CK_C_INITIALIZE_ARGS) -> Result<InitializeArgs, CK_RV> {
+        unsafe { InitializeArgs::new(&mut args) }
+    }
+
+    #[test]
+    fn test_initialize_args() {
+        let args = unsafe { InitializeArgs::new(std::ptr::null_mut()) }.unwrap();
+        assert!(args.can_create_threads);
+        assert!(args.mutex.is_none());
+
+        let args = initialize_args(new_args(CKF_OS_LOCKING_OK)).unwrap();
+        assert!(args.can_create_threads);
+        assert!(args.mutex.is_none());
+
+        let args = initialize_args(new_args(CKF_LIBRARY_CANT_CREATE_OS_THREADS)).unwrap();
+        assert!(!args.can_create_threads);
+        assert!(args.mutex.is_none());
+
+        assert_eq!(
+            initialize_args(new_args(0x4)).err(),
+            Some(CKR_ARGUMENTS_BAD)
+        );
+
+        let mut args = new_args(0);
+        args.CreateMutex = Some(create_mutex);
+        args.LockMutex = Some(lock_mutex);
+        assert_eq!(initialize_args(args).err(), Some(CKR_ARGUMENTS_BAD));
+        let mut args = with_mutex_functions(new_args(0));
+        args.UnlockMutex = None;
+        assert_eq!(initialize_args(args).err(), Some(CKR_ARGUMENTS_BAD));
+        assert_eq!(MUTEXES.load(Ordering::SeqCst), 0);
+
+        let mut args = with_mutex_functions(new_args(0));
+        args.CreateMutex = Some(create_mutex_fails);
+        assert_eq!(initialize_args(args).err(), Some(CKR_HOST_MEMORY));
+
+        let args = initialize_args(with_mutex_functions(new_args(
+            CKF_OS_LOCKING_OK | CKF_LIBRARY_CANT_CREATE_OS_THREADS,
+        )))
+        .unwrap();
+        assert!(!args.can_create_threads);
+        assert_eq!(MUTEXES.load(Ordering::SeqCst), 1);
+        let mutex = Arc::new(args.mutex.unwrap());
+        {
+            let _guard = mutex.lock().unwrap();
+            assert_eq!(LOCK_COUNT.load(Ordering::SeqCst), 1);
+        }
+        assert_eq!(LOCK_COUNT.load(Ordering::SeqCst), 0);
+        drop(mutex);
+        assert_eq!(MUTEXES.load(Ordering::SeqCst), 0);
+    }
+}
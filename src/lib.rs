@@ -4,38 +4,108 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 #![allow(non_snake_case)]
+// CK_ULONG is 32 bits on Windows and 64 bits elsewhere, so casts that are unnecessary on one
+// platform are required on another.
+#![allow(clippy::unnecessary_cast)]
 
+extern crate aes;
+extern crate aes_gcm;
 extern crate byteorder;
+extern crate cbc;
 #[cfg(target_os = "macos")]
 #[macro_use]
 extern crate core_foundation;
+extern crate des;
 extern crate env_logger;
+extern crate hmac;
 #[macro_use]
 extern crate lazy_static;
+#[cfg(target_os = "linux")]
+extern crate libc;
 #[cfg(target_os = "macos")]
 extern crate libloading;
 #[macro_use]
 extern crate log;
+extern crate md5;
+extern crate p256;
+extern crate p384;
+extern crate pbkdf2;
+extern crate pem;
 extern crate pkcs11;
+extern crate rc2;
 #[cfg(target_os = "macos")]
 #[macro_use]
 extern crate rental;
+extern crate rsa;
+extern crate scrypt;
+extern crate serde;
+extern crate sha1;
 extern crate sha2;
+extern crate toml;
 #[cfg(target_os = "windows")]
 extern crate winapi;
 
 use pkcs11::types::*;
-use std::sync::Mutex;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
-mod manager;
 #[macro_use]
 mod util;
+mod backend;
+mod backend_configured;
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+mod backend_file;
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+mod backend_gpg_agent;
+#[cfg(target_os = "linux")]
+mod backend_keyring;
 #[cfg(target_os = "macos")]
 mod backend_macos;
+#[cfg(test)]
+mod backend_mock;
+mod backend_multi;
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+mod backend_pkcs11;
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+mod backend_ssh_agent;
 #[cfg(target_os = "windows")]
 mod backend_windows;
-
-use manager::ManagerProxy;
+mod config;
+mod digest;
+mod error;
+mod file_watcher;
+mod initialize_args;
+mod manager;
+#[cfg(any(test, not(any(target_os = "macos", target_os = "windows"))))]
+mod pbe;
+#[cfg(any(test, not(any(target_os = "macos", target_os = "windows"))))]
+mod pkcs12;
+#[cfg(any(test, not(any(target_os = "macos", target_os = "windows"))))]
+mod software_key;
+mod verify;
+
+use backend::{Backend, OaepParams};
+use backend_configured::ConfiguredBackend;
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+use backend_file::FileBackend;
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+use backend_gpg_agent::GpgAgentBackend;
+#[cfg(target_os = "linux")]
+use backend_keyring::KeyringBackend;
+#[cfg(target_os = "macos")]
+use backend_macos::MacOSBackend;
+use backend_multi::MultiBackend;
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+use backend_pkcs11::{configured_modules, Pkcs11Backend};
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+use backend_ssh_agent::SshAgentBackend;
+#[cfg(target_os = "windows")]
+use backend_windows::WindowsBackend;
+use config::{BackendKind, Config, Filter, ModuleParameters, SlotMode, TokenConfig};
+use error::Error;
+use initialize_args::{ApplicationMutex, ApplicationMutexGuard, InitializeArgs};
+use manager::{ManagerProxy, MAX_PIN_ATTEMPTS, SUPPORTED_MECHANISMS};
 
 lazy_static! {
     /// The singleton `ManagerProxy` that handles state with respect to PKCS #11. Only one thread
@@ -44,6 +114,61 @@ lazy_static! {
     /// thread-local-storage), the `ManagerProxy` forwards calls from any thread to a single thread
     /// where the real `Manager` does the actual work.
     static ref MANAGER_PROXY: Mutex<Option<ManagerProxy>> = Mutex::new(None);
+    /// The mutex created with the functions the application supplied to `C_Initialize`, if any. It
+    /// is locked whenever `MANAGER_PROXY` is.
+    static ref APPLICATION_MUTEX: Mutex<Option<Arc<ApplicationMutex>>> = Mutex::new(None);
+}
+
+/// A locked `MANAGER_PROXY`, along with the application's mutex, if it supplied one.
+struct ManagerGuard {
+    // Fields are dropped in order, so the application's mutex is unlocked last.
+    manager_proxy: MutexGuard<'static, Option<ManagerProxy>>,
+    _application_mutex_guard: Option<ApplicationMutexGuard>,
+}
+
+impl Deref for ManagerGuard {
+    type Target = Option<ManagerProxy>;
+
+    fn deref(&self) -> &Option<ManagerProxy> {
+        &self.manager_proxy
+    }
+}
+
+impl DerefMut for ManagerGuard {
+    fn deref_mut(&mut self) -> &mut Option<ManagerProxy> {
+        &mut self.manager_proxy
+    }
+}
+
+/// Locks the application's mutex (if there is one) and then `MANAGER_PROXY`.
+fn lock_manager() -> Result<ManagerGuard, CK_RV> {
+    let application_mutex = match APPLICATION_MUTEX.lock() {
+        Ok(application_mutex) => application_mutex.clone(),
+        Err(poison_error) => {
+            error!(
+                "previous thread panicked acquiring application mutex lock: {}",
+                poison_error
+            );
+            return Err(CKR_DEVICE_ERROR);
+        }
+    };
+    let application_mutex_guard = match application_mutex {
+        Some(application_mutex) => Some(application_mutex.lock()?),
+        None => None,
+    };
+    match MANAGER_PROXY.lock() {
+        Ok(manager_proxy) => Ok(ManagerGuard {
+            manager_proxy,
+            _application_mutex_guard: application_mutex_guard,
+        }),
+        Err(poison_error) => {
+            error!(
+                "previous thread panicked acquiring manager lock: {}",
+                poison_error
+            );
+            Err(CKR_DEVICE_ERROR)
+        }
+    }
 }
 
 // Obtaining a handle on the manager proxy is a two-step process. First the mutex must be locked,
@@ -55,15 +180,9 @@ lazy_static! {
 //   let manager = manager_guard_to_manager!(manager_guard);
 macro_rules! try_to_get_manager_guard {
     () => {
-        match MANAGER_PROXY.lock() {
-            Ok(maybe_manager_proxy) => maybe_manager_proxy,
-            Err(poison_error) => {
-                error!(
-                    "previous thread panicked acquiring manager lock: {}",
-                    poison_error
-                );
-                return CKR_DEVICE_ERROR;
-            }
+        match lock_manager() {
+            Ok(manager_guard) => manager_guard,
+            Err(rv) => return rv,
         }
     };
 }
@@ -80,41 +199,253 @@ macro_rules! manager_guard_to_manager {
     };
 }
 
-/// This gets called to initialize the module. For this implementation, this consists of
-/// instantiating the `ManagerProxy`.
-extern "C" fn C_Initialize(_pInitArgs: CK_C_INITIALIZE_ARGS_PTR) -> CK_RV {
+/// Creates the backends for the current platform. Each one is exposed as its own slot.
+#[cfg(target_os = "macos")]
+fn platform_backends() -> Vec<Box<dyn Backend>> {
+    vec![Box::new(MacOSBackend::new())]
+}
+
+/// Creates the backends for the current platform. Each one is exposed as its own slot.
+#[cfg(target_os = "windows")]
+fn platform_backends() -> Vec<Box<dyn Backend>> {
+    vec![Box::new(WindowsBackend::new())]
+}
+
+/// Creates the backends for the current platform. Each one is exposed as its own slot: a
+/// `FileBackend` for each of the default directories, a `KeyringBackend` on Linux, an
+/// `SshAgentBackend` if there's an ssh-agent, a `GpgAgentBackend` if GnuPG is set up, and a
+/// `Pkcs11Backend` for each module listed in `$OSCLIENTCERTS_PKCS11_MODULES`.
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn platform_backends() -> Vec<Box<dyn Backend>> {
+    let mut backends: Vec<Box<dyn Backend>> = Vec::new();
+    for file_backend in FileBackend::new_defaults() {
+        backends.push(Box::new(file_backend));
+    }
+    #[cfg(target_os = "linux")]
+    backends.push(Box::new(KeyringBackend::new()));
+    if let Some(ssh_agent_backend) = SshAgentBackend::new() {
+        backends.push(Box::new(ssh_agent_backend));
+    }
+    if let Some(gpg_agent_backend) = GpgAgentBackend::new() {
+        backends.push(Box::new(gpg_agent_backend));
+    }
+    for module in configured_modules() {
+        if let Ok(pkcs11_backend) = Pkcs11Backend::new(&module, None) {
+            backends.push(Box::new(pkcs11_backend));
+        }
+    }
+    backends
+}
+
+/// Creates the backend of the given kind. Returns `None` if it isn't available (e.g. if the
+/// PKCS #11 module can't be loaded).
+fn new_backend(kind: &BackendKind) -> Option<Box<dyn Backend>> {
+    let backend: Box<dyn Backend> = match kind {
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        BackendKind::Files { directories } => Box::new(FileBackend::with_directories(
+            "Certificate Files",
+            directories.clone(),
+        )),
+        #[cfg(target_os = "linux")]
+        BackendKind::Keyring { directories } => Box::new(match directories {
+            Some(directories) => KeyringBackend::with_directories(directories.clone()),
+            None => KeyringBackend::new(),
+        }),
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        BackendKind::SshAgent {
+            socket,
+            directories,
+        } => Box::new(SshAgentBackend::with_options(
+            socket.clone(),
+            directories.clone(),
+        )?),
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        BackendKind::GpgAgent {
+            socket,
+            keybox,
+            directories,
+        } => Box::new(GpgAgentBackend::with_options(
+            socket.clone(),
+            keybox.clone(),
+            directories.clone(),
+        )?),
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        BackendKind::Pkcs11 { module, parameters } => {
+            Box::new(Pkcs11Backend::new(module, parameters.as_deref()).ok()?)
+        }
+        #[cfg(target_os = "macos")]
+        BackendKind::MacOS => Box::new(MacOSBackend::new()),
+        #[cfg(target_os = "windows")]
+        BackendKind::Windows { store, location } => {
+            Box::new(WindowsBackend::with_store(store.clone(), *location))
+        }
+    };
+    Some(backend)
+}
+
+/// Creates the backends described by the given configuration (or the default backends for the
+/// current platform, if it doesn't list any). Each backend is wrapped in a `ConfiguredBackend` that
+/// applies its token information and filter. If all backends are to be exposed in a single slot,
+/// they are combined in a `MultiBackend`.
+fn configured_backends(config: &Config) -> Vec<Box<dyn Backend>> {
+    let with_default_manufacturer = |token: &TokenConfig| TokenConfig {
+        manufacturer: token
+            .manufacturer
+            .clone()
+            .or_else(|| config.manufacturer.clone()),
+        ..token.clone()
+    };
+    let mut backends: Vec<Box<dyn Backend>> = Vec::new();
+    match &config.backends {
+        Some(backend_configs) => {
+            for backend_config in backend_configs {
+                match new_backend(&backend_config.kind) {
+                    Some(backend) => backends.push(Box::new(ConfiguredBackend::new(
+                        backend,
+                        with_default_manufacturer(&backend_config.token),
+                        backend_config.filter.clone(),
+                    ))),
+                    None => warn!("backend {:?} isn't available", backend_config.kind),
+                }
+            }
+        }
+        None => {
+            for backend in platform_backends() {
+                backends.push(Box::new(ConfiguredBackend::new(
+                    backend,
+                    with_default_manufacturer(&TokenConfig::default()),
+                    Filter::default(),
+                )));
+            }
+        }
+    }
+    match config.slots {
+        SlotMode::PerStore => backends,
+        SlotMode::Single => vec![Box::new(ConfiguredBackend::new(
+            Box::new(MultiBackend::with_backends(backends)),
+            with_default_manufacturer(&config.token),
+            Filter::default(),
+        ))],
+    }
+}
+
+/// Reads the module parameters from the `pReserved` field of the given arguments, where NSS puts
+/// them (the specification says it must be NULL, so other callers won't have put anything there).
+unsafe fn get_module_parameters(
+    pInitArgs: CK_C_INITIALIZE_ARGS_PTR,
+) -> Result<ModuleParameters, String> {
+    if pInitArgs.is_null() || (*pInitArgs).pReserved.is_null() {
+        return Ok(ModuleParameters::default());
+    }
+    let parameters =
+        std::ffi::CStr::from_ptr((*pInitArgs).pReserved as *const std::os::raw::c_char);
+    let parameters = parameters
+        .to_str()
+        .map_err(|_| String::from("module parameters aren't valid UTF-8"))?;
+    ModuleParameters::parse(parameters)
+}
+
+/// This gets called to initialize the module. For this implementation, this consists of loading
+/// the configuration (with any overrides given as module parameters) and instantiating the
+/// `ManagerProxy` with the backends it describes. If the configuration or the module parameters
+/// aren't valid, this fails rather than silently using a different configuration. If the
+/// application doesn't allow this module to create threads, the `Manager` runs on the calling
+/// thread. If the application supplies functions to create and lock mutexes, they are used to
+/// serialize calls into this module.
+extern "C" fn C_Initialize(pInitArgs: CK_C_INITIALIZE_ARGS_PTR) -> CK_RV {
+    let parameters = unsafe { get_module_parameters(pInitArgs) };
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Ok(ModuleParameters {
+        log_level: Some(log_level),
+        ..
+    }) = &parameters
+    {
+        logger.filter_level(*log_level);
+    }
     // This will fail if this has already been called, but this isn't a problem because either way,
     // logging has been initialized.
-    let _ = env_logger::try_init();
+    let _ = logger.try_init();
+    let parameters = match parameters {
+        Ok(parameters) => parameters,
+        Err(e) => {
+            error!("C_Initialize: invalid module parameters: {}", e);
+            return CKR_ARGUMENTS_BAD;
+        }
+    };
+    let config = match Config::load_with_parameters(&parameters) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("C_Initialize: invalid configuration: {}", e);
+            return CKR_GENERAL_ERROR;
+        }
+    };
+    let initialize_args = match unsafe { InitializeArgs::new(pInitArgs) } {
+        Ok(initialize_args) => initialize_args,
+        Err(rv) => {
+            error!("C_Initialize: {:#x}", rv);
+            return rv;
+        }
+    };
+    match APPLICATION_MUTEX.lock() {
+        Ok(mut application_mutex) => *application_mutex = initialize_args.mutex.map(Arc::new),
+        Err(poison_error) => {
+            error!(
+                "C_Initialize: previous thread panicked acquiring application mutex lock: {}",
+                poison_error
+            );
+            return CKR_DEVICE_ERROR;
+        }
+    }
+    let rescan_interval = config.rescan_interval;
+    let manager_proxy = if initialize_args.can_create_threads {
+        ManagerProxy::new(move || configured_backends(&config), rescan_interval)
+    } else {
+        debug!("C_Initialize: not allowed to create threads - running the manager inline");
+        ManagerProxy::new_inline(configured_backends(&config), rescan_interval)
+    };
     let mut manager_guard = try_to_get_manager_guard!();
-    match manager_guard.replace(ManagerProxy::new()) {
-        Some(_unexpected_previous_manager) => {
-            #[cfg(target_os = "macos")]
-            {
-                info!("C_Initialize: manager previously set (this is expected on macOS - replacing it)");
-            }
-            #[cfg(target_os = "windows")]
-            {
-                warn!("C_Initialize: manager unexpectedly previously set (bravely continuing by replacing it)");
-            }
+    if let Some(_unexpected_previous_manager) = manager_guard.replace(manager_proxy) {
+        #[cfg(target_os = "macos")]
+        {
+            info!(
+                "C_Initialize: manager previously set (this is expected on macOS - replacing it)"
+            );
+        }
+        #[cfg(target_os = "windows")]
+        {
+            warn!("C_Initialize: manager unexpectedly previously set (bravely continuing by replacing it)");
         }
-        None => {}
     }
     debug!("C_Initialize: CKR_OK");
     CKR_OK
 }
 
+/// This gets called to finalize the module. The `ManagerProxy` is stopped and removed, which also
+/// causes any blocking `C_WaitForSlotEvent` calls to return.
 extern "C" fn C_Finalize(_pReserved: CK_VOID_PTR) -> CK_RV {
     let mut manager_guard = try_to_get_manager_guard!();
-    let manager = manager_guard_to_manager!(manager_guard);
-    match manager.stop() {
+    let mut manager = match manager_guard.take() {
+        Some(manager) => manager,
+        None => {
+            error!("C_Finalize: CKR_CRYPTOKI_NOT_INITIALIZED");
+            return CKR_CRYPTOKI_NOT_INITIALIZED;
+        }
+    };
+    let result = manager.stop();
+    drop(manager_guard);
+    // Any calls that were waiting for the application's mutex have now finished (or will find that
+    // the module isn't initialized), so it can be destroyed.
+    if let Ok(mut application_mutex) = APPLICATION_MUTEX.lock() {
+        application_mutex.take();
+    }
+    match result {
         Ok(()) => {
             debug!("C_Finalize: CKR_OK");
             CKR_OK
         }
-        Err(()) => {
-            debug!("C_Finalize: CKR_DEVICE_ERROR");
-            CKR_DEVICE_ERROR
+        Err(e) => {
+            error!("C_Finalize: {}", e);
+            e.ck_rv()
         }
     }
 }
@@ -144,11 +475,42 @@ extern "C" fn C_GetInfo(pInfo: CK_INFO_PTR) -> CK_RV {
     CKR_OK
 }
 
-/// This module only has one slot. Its ID is 1.
-const SLOT_ID: CK_SLOT_ID = 1;
+/// Pads the given string with spaces (truncating it if necessary) to fill one of the fixed-size
+/// strings of CK_TOKEN_INFO. Truncation doesn't split UTF-8 characters.
+fn pad_string<const N: usize>(string: &str) -> [u8; N] {
+    let mut padded = [b' '; N];
+    let mut length = 0;
+    for c in string.chars() {
+        if length + c.len_utf8() > N {
+            break;
+        }
+        length += c.len_utf8();
+    }
+    padded[..length].copy_from_slice(&string.as_bytes()[..length]);
+    padded
+}
+
+/// Helper macro that returns `$error` from the calling function (logging that the function named
+/// `$function` failed) if the `ManagerProxy` doesn't know of a slot with the given ID.
+macro_rules! check_slot_id {
+    ($manager:ident, $slot_id:expr, $function:expr, $error:ident) => {
+        match $manager.get_slot_ids() {
+            Ok(slot_ids) if slot_ids.contains(&$slot_id) => {}
+            Ok(_) => {
+                error!("{}: {}", $function, stringify!($error));
+                return $error;
+            }
+            Err(e) => {
+                error!("{}: {}", $function, e);
+                return e.ck_rv();
+            }
+        }
+    };
+}
 
 /// This gets called twice: once with a null `pSlotList` to get the number of slots (returned via
-/// `pulCount`) and a second time to get the ID for each slot.
+/// `pulCount`) and a second time to get the ID for each slot. There is one slot for each backend,
+/// and the token is always present in each of them.
 extern "C" fn C_GetSlotList(
     _tokenPresent: CK_BBOOL,
     pSlotList: CK_SLOT_ID_PTR,
@@ -158,19 +520,33 @@ extern "C" fn C_GetSlotList(
         error!("C_GetSlotList: CKR_ARGUMENTS_BAD");
         return CKR_ARGUMENTS_BAD;
     }
-    unsafe {
-        *pulCount = 1;
-    }
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    let slot_ids = match manager.get_slot_ids() {
+        Ok(slot_ids) => slot_ids,
+        Err(e) => {
+            error!("C_GetSlotList: {}", e);
+            return e.ck_rv();
+        }
+    };
     if !pSlotList.is_null() {
         let slotCount = unsafe { *pulCount };
-        if slotCount < 1 {
+        if (slotCount as usize) < slot_ids.len() {
+            unsafe {
+                *pulCount = slot_ids.len() as CK_ULONG;
+            }
             error!("C_GetSlotList: CKR_BUFFER_TOO_SMALL");
             return CKR_BUFFER_TOO_SMALL;
         }
-        unsafe {
-            *pSlotList = SLOT_ID;
+        for (i, slot_id) in slot_ids.iter().enumerate() {
+            unsafe {
+                *pSlotList.add(i) = *slot_id;
+            }
         }
     };
+    unsafe {
+        *pulCount = slot_ids.len() as CK_ULONG;
+    }
     debug!("C_GetSlotList: CKR_OK");
     CKR_OK
 }
@@ -181,13 +557,23 @@ const SLOT_DESCRIPTION_BYTES: &[u8; 64] =
 /// This gets called to obtain information about slots. In this implementation, the token is always
 /// present in the slot.
 extern "C" fn C_GetSlotInfo(slotID: CK_SLOT_ID, pInfo: CK_SLOT_INFO_PTR) -> CK_RV {
-    if slotID != SLOT_ID || pInfo.is_null() {
+    if pInfo.is_null() {
         error!("C_GetSlotInfo: CKR_ARGUMENTS_BAD");
         return CKR_ARGUMENTS_BAD;
     }
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    check_slot_id!(manager, slotID, "C_GetSlotInfo", CKR_SLOT_ID_INVALID);
+    let info = match manager.get_token_info(slotID) {
+        Ok(info) => info,
+        Err(e) => {
+            error!("C_GetSlotInfo: {}", e);
+            return e.ck_rv();
+        }
+    };
     let slot_info = CK_SLOT_INFO {
         slotDescription: *SLOT_DESCRIPTION_BYTES,
-        manufacturerID: *MANUFACTURER_ID_BYTES,
+        manufacturerID: pad_string(&info.manufacturer),
         flags: CKF_TOKEN_PRESENT,
         hardwareVersion: CK_VERSION::default(),
         firmwareVersion: CK_VERSION::default(),
@@ -199,22 +585,46 @@ extern "C" fn C_GetSlotInfo(slotID: CK_SLOT_ID, pInfo: CK_SLOT_INFO_PTR) -> CK_R
     CKR_OK
 }
 
-const TOKEN_LABEL_BYTES: &[u8; 32] = b"OS Client Cert Token            ";
 const TOKEN_MODEL_BYTES: &[u8; 16] = b"osclientcerts   ";
-const TOKEN_SERIAL_NUMBER_BYTES: &[u8; 16] = b"0000000000000000";
 
-/// This gets called to obtain some information about tokens. This implementation only has one slot,
-/// so it only has one token. This information is primarily for display purposes.
+/// This gets called to obtain some information about tokens. Each slot has one token, which is
+/// labeled after the backend of the slot. This information is primarily for display purposes.
 extern "C" fn C_GetTokenInfo(slotID: CK_SLOT_ID, pInfo: CK_TOKEN_INFO_PTR) -> CK_RV {
-    if slotID != SLOT_ID || pInfo.is_null() {
+    if pInfo.is_null() {
         error!("C_GetTokenInfo: CKR_ARGUMENTS_BAD");
         return CKR_ARGUMENTS_BAD;
     }
-    let mut token_info = CK_TOKEN_INFO::default();
-    token_info.label = *TOKEN_LABEL_BYTES;
-    token_info.manufacturerID = *MANUFACTURER_ID_BYTES;
-    token_info.model = *TOKEN_MODEL_BYTES;
-    token_info.serialNumber = *TOKEN_SERIAL_NUMBER_BYTES;
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    check_slot_id!(manager, slotID, "C_GetTokenInfo", CKR_SLOT_ID_INVALID);
+    let info = match manager.get_token_info(slotID) {
+        Ok(info) => info,
+        Err(e) => {
+            error!("C_GetTokenInfo: {}", e);
+            return e.ck_rv();
+        }
+    };
+    let mut flags = if info.login_required {
+        CKF_LOGIN_REQUIRED | CKF_USER_PIN_INITIALIZED
+    } else {
+        0
+    };
+    if info.pin_attempts_remaining < MAX_PIN_ATTEMPTS {
+        flags |= CKF_USER_PIN_COUNT_LOW;
+    }
+    match info.pin_attempts_remaining {
+        0 => flags |= CKF_USER_PIN_LOCKED,
+        1 => flags |= CKF_USER_PIN_FINAL_TRY,
+        _ => {}
+    }
+    let token_info = CK_TOKEN_INFO {
+        label: pad_string(&info.label),
+        manufacturerID: pad_string(&info.manufacturer),
+        model: *TOKEN_MODEL_BYTES,
+        serialNumber: pad_string(&info.serial_number),
+        flags,
+        ..Default::default()
+    };
     unsafe {
         *pInfo = token_info;
     }
@@ -223,25 +633,35 @@ extern "C" fn C_GetTokenInfo(slotID: CK_SLOT_ID, pInfo: CK_TOKEN_INFO_PTR) -> CK
 }
 
 /// This gets called to determine what mechanisms a slot supports. This implementation supports
-/// ECDSA, RSA PKCS, and RSA PSS.
+/// signing with ECDSA, RSA PKCS, and RSA PSS, each either on its own or combined with SHA-256,
+/// SHA-384, or SHA-512, and decrypting with RSA PKCS and RSA OAEP.
 extern "C" fn C_GetMechanismList(
     slotID: CK_SLOT_ID,
     pMechanismList: CK_MECHANISM_TYPE_PTR,
     pulCount: CK_ULONG_PTR,
 ) -> CK_RV {
-    if slotID != SLOT_ID || pulCount.is_null() {
+    if pulCount.is_null() {
         error!("C_GetMechanismList: CKR_ARGUMENTS_BAD");
         return CKR_ARGUMENTS_BAD;
     }
-    let mechanisms = [CKM_ECDSA, CKM_RSA_PKCS, CKM_RSA_PKCS_PSS];
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    check_slot_id!(manager, slotID, "C_GetMechanismList", CKR_SLOT_ID_INVALID);
+    let mechanisms: Vec<CK_MECHANISM_TYPE> = SUPPORTED_MECHANISMS
+        .iter()
+        .map(|(mechanism, _, _, _)| *mechanism)
+        .collect();
     if !pMechanismList.is_null() {
         if unsafe { *pulCount as usize } < mechanisms.len() {
-            error!("C_GetMechanismList: CKR_ARGUMENTS_BAD");
-            return CKR_ARGUMENTS_BAD;
+            unsafe {
+                *pulCount = mechanisms.len() as CK_ULONG;
+            }
+            error!("C_GetMechanismList: CKR_BUFFER_TOO_SMALL");
+            return CKR_BUFFER_TOO_SMALL;
         }
-        for i in 0..mechanisms.len() {
+        for (i, mechanism) in mechanisms.iter().enumerate() {
             unsafe {
-                *pMechanismList.offset(i as isize) = mechanisms[i];
+                *pMechanismList.add(i) = *mechanism;
             }
         }
     }
@@ -252,13 +672,37 @@ extern "C" fn C_GetMechanismList(
     CKR_OK
 }
 
+/// This gets called to obtain information about a mechanism. The range of key sizes is that of the
+/// keys currently in the slot's token that the mechanism can be used with.
 extern "C" fn C_GetMechanismInfo(
-    _slotID: CK_SLOT_ID,
-    _type: CK_MECHANISM_TYPE,
-    _pInfo: CK_MECHANISM_INFO_PTR,
+    slotID: CK_SLOT_ID,
+    type_: CK_MECHANISM_TYPE,
+    pInfo: CK_MECHANISM_INFO_PTR,
 ) -> CK_RV {
-    error!("C_GetMechanismInfo: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+    if pInfo.is_null() {
+        error!("C_GetMechanismInfo: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    check_slot_id!(manager, slotID, "C_GetMechanismInfo", CKR_SLOT_ID_INVALID);
+    let info = match manager.get_mechanism_info(slotID, type_) {
+        Ok(info) => info,
+        Err(e) => {
+            error!("C_GetMechanismInfo: {}", e);
+            return e.ck_rv();
+        }
+    };
+    let mechanism_info = CK_MECHANISM_INFO {
+        ulMinKeySize: info.min_key_size as CK_ULONG,
+        ulMaxKeySize: info.max_key_size as CK_ULONG,
+        flags: info.flags,
+    };
+    unsafe {
+        *pInfo = mechanism_info;
+    }
+    debug!("C_GetMechanismInfo: CKR_OK");
+    CKR_OK
 }
 
 extern "C" fn C_InitToken(
@@ -292,25 +736,31 @@ extern "C" fn C_SetPIN(
 }
 
 /// This gets called to create a new session. This module defers to the `ManagerProxy` to implement
-/// this.
+/// this. As required by PKCS #11, `CKF_SERIAL_SESSION` must be set.
 extern "C" fn C_OpenSession(
     slotID: CK_SLOT_ID,
-    _flags: CK_FLAGS,
+    flags: CK_FLAGS,
     _pApplication: CK_VOID_PTR,
     _Notify: CK_NOTIFY,
     phSession: CK_SESSION_HANDLE_PTR,
 ) -> CK_RV {
-    if slotID != SLOT_ID || phSession.is_null() {
+    if phSession.is_null() {
         error!("C_OpenSession: CKR_ARGUMENTS_BAD");
         return CKR_ARGUMENTS_BAD;
     }
+    if flags & CKF_SERIAL_SESSION == 0 {
+        error!("C_OpenSession: CKR_SESSION_PARALLEL_NOT_SUPPORTED");
+        return CKR_SESSION_PARALLEL_NOT_SUPPORTED;
+    }
     let mut manager_guard = try_to_get_manager_guard!();
     let manager = manager_guard_to_manager!(manager_guard);
-    let session_handle = match manager.open_session() {
+    check_slot_id!(manager, slotID, "C_OpenSession", CKR_SLOT_ID_INVALID);
+    let read_write = flags & CKF_RW_SESSION != 0;
+    let session_handle = match manager.open_session(slotID, read_write) {
         Ok(session_handle) => session_handle,
-        Err(()) => {
-            error!("C_OpenSession: open_session failed");
-            return CKR_DEVICE_ERROR;
+        Err(e) => {
+            error!("C_OpenSession: {}", e);
+            return e.ck_rv();
         }
     };
     unsafe {
@@ -324,37 +774,75 @@ extern "C" fn C_OpenSession(
 extern "C" fn C_CloseSession(hSession: CK_SESSION_HANDLE) -> CK_RV {
     let mut manager_guard = try_to_get_manager_guard!();
     let manager = manager_guard_to_manager!(manager_guard);
-    if manager.close_session(hSession).is_err() {
-        error!("C_CloseSession: CKR_SESSION_HANDLE_INVALID");
-        return CKR_SESSION_HANDLE_INVALID;
+    if let Err(e) = manager.close_session(hSession) {
+        error!("C_CloseSession: {}", e);
+        return e.ck_rv();
     }
     debug!("C_CloseSession: CKR_OK");
     CKR_OK
 }
 
-/// This gets called to close all open sessions at once. This is handled by the `ManagerProxy`.
+/// This gets called to close all open sessions on a slot at once. This is handled by the
+/// `ManagerProxy`.
 extern "C" fn C_CloseAllSessions(slotID: CK_SLOT_ID) -> CK_RV {
-    if slotID != SLOT_ID {
-        error!("C_CloseAllSessions: CKR_ARGUMENTS_BAD");
-        return CKR_ARGUMENTS_BAD;
-    }
     let mut manager_guard = try_to_get_manager_guard!();
     let manager = manager_guard_to_manager!(manager_guard);
-    match manager.close_all_sessions() {
+    check_slot_id!(manager, slotID, "C_CloseAllSessions", CKR_SLOT_ID_INVALID);
+    match manager.close_all_sessions(slotID) {
         Ok(()) => {
             debug!("C_CloseAllSessions: CKR_OK");
             CKR_OK
         }
-        Err(()) => {
-            debug!("C_CloseAllSessions: close_all_sessions failed");
-            CKR_DEVICE_ERROR
+        Err(e) => {
+            error!("C_CloseAllSessions: {}", e);
+            e.ck_rv()
         }
     }
 }
 
-extern "C" fn C_GetSessionInfo(_hSession: CK_SESSION_HANDLE, _pInfo: CK_SESSION_INFO_PTR) -> CK_RV {
-    error!("C_GetSessionInfo: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+/// This gets called to obtain information about a session: its slot, whether or not it is a
+/// read/write session, and whether or not the user is logged in to its token. The operations that
+/// are active in the session are logged, which helps when debugging.
+extern "C" fn C_GetSessionInfo(hSession: CK_SESSION_HANDLE, pInfo: CK_SESSION_INFO_PTR) -> CK_RV {
+    if pInfo.is_null() {
+        error!("C_GetSessionInfo: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    let info = match manager.get_session_info(hSession) {
+        Ok(info) => info,
+        Err(e) => {
+            error!("C_GetSessionInfo: {}", e);
+            return e.ck_rv();
+        }
+    };
+    let (state, flags) = match (info.read_write, info.logged_in) {
+        (false, false) => (CKS_RO_PUBLIC_SESSION, CKF_SERIAL_SESSION),
+        (false, true) => (CKS_RO_USER_FUNCTIONS, CKF_SERIAL_SESSION),
+        (true, false) => (CKS_RW_PUBLIC_SESSION, CKF_SERIAL_SESSION | CKF_RW_SESSION),
+        (true, true) => (CKS_RW_USER_FUNCTIONS, CKF_SERIAL_SESSION | CKF_RW_SESSION),
+    };
+    let session_info = CK_SESSION_INFO {
+        slotID: info.slot_id,
+        state,
+        flags,
+        ulDeviceError: 0,
+    };
+    unsafe {
+        *pInfo = session_info;
+    }
+    debug!(
+        "C_GetSessionInfo: CKR_OK (slot {}, state {}, search active: {}, sign active: {}, \
+         decrypt active: {}, verify active: {})",
+        info.slot_id,
+        state,
+        info.search_active,
+        info.sign_active,
+        info.decrypt_active,
+        info.verify_active
+    );
+    CKR_OK
 }
 
 extern "C" fn C_GetOperationState(
@@ -377,20 +865,57 @@ extern "C" fn C_SetOperationState(
     CKR_FUNCTION_NOT_SUPPORTED
 }
 
+/// This gets called to log in to the token of the session's slot. The PIN unlocks whatever the
+/// slot's backend has that is locked (e.g. it is the password of encrypted PKCS #12 files). With
+/// `CKU_CONTEXT_SPECIFIC`, the PIN is instead for the session's sign operation, whose key needs the
+/// PIN every time it is used. Logging in with a protected authentication path (i.e. a null `pPin`)
+/// is not supported.
 extern "C" fn C_Login(
-    _hSession: CK_SESSION_HANDLE,
-    _userType: CK_USER_TYPE,
-    _pPin: CK_UTF8CHAR_PTR,
-    _ulPinLen: CK_ULONG,
+    hSession: CK_SESSION_HANDLE,
+    userType: CK_USER_TYPE,
+    pPin: CK_UTF8CHAR_PTR,
+    ulPinLen: CK_ULONG,
 ) -> CK_RV {
-    error!("C_Login: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+    if userType != CKU_USER && userType != CKU_CONTEXT_SPECIFIC {
+        error!("C_Login: CKR_USER_TYPE_INVALID");
+        return CKR_USER_TYPE_INVALID;
+    }
+    if pPin.is_null() {
+        error!("C_Login: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    let pin = unsafe { std::slice::from_raw_parts(pPin, ulPinLen as usize) };
+    let pin = match String::from_utf8(pin.to_vec()) {
+        Ok(pin) => pin,
+        Err(_) => {
+            error!("C_Login: CKR_ARGUMENTS_BAD");
+            return CKR_ARGUMENTS_BAD;
+        }
+    };
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    let result = if userType == CKU_CONTEXT_SPECIFIC {
+        manager.login_context_specific(hSession, pin)
+    } else {
+        manager.login(hSession, pin)
+    };
+    if let Err(e) = result {
+        error!("C_Login: {}", e);
+        return e.ck_rv();
+    }
+    debug!("C_Login: CKR_OK");
+    CKR_OK
 }
 
-/// This gets called to log out and drop any authenticated resources. Because this module does not
-/// hold on to authenticated resources, this module "implements" this by doing nothing and
-/// returning a success result.
-extern "C" fn C_Logout(_hSession: CK_SESSION_HANDLE) -> CK_RV {
+/// This gets called to log out and drop any authenticated resources. The backend of the session's
+/// slot forgets the PIN, and anything it unlocked is no longer available from then on.
+extern "C" fn C_Logout(hSession: CK_SESSION_HANDLE) -> CK_RV {
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    if let Err(e) = manager.logout(hSession) {
+        error!("C_Logout: {}", e);
+        return e.ck_rv();
+    }
     debug!("C_Logout: CKR_OK");
     CKR_OK
 }
@@ -432,12 +957,15 @@ extern "C" fn C_GetObjectSize(
 
 /// This gets called to obtain the values of a number of attributes of an object identified by the
 /// given handle. This module implements this by requesting that the `ManagerProxy` find the object
-/// and attempt to get the value of each attribute. If a specified attribute is not defined on the
-/// object, the length of that attribute is set to -1 to indicate that it is not available.
-/// This gets called twice: once to obtain the lengths of the attributes and again to get the
-/// values.
+/// and attempt to get the value of each attribute. As specified by PKCS #11, every attribute is
+/// handled, even if some of them fail: if the object doesn't have an attribute or its value is
+/// sensitive, or if the given buffer is too small for the value, the length of that attribute is
+/// set to `CK_UNAVAILABLE_INFORMATION` and the corresponding error is returned once all the
+/// attributes have been handled. If the buffer of an attribute is null, only its length is set.
+/// This usually gets called twice: once to obtain the lengths of the attributes and again to get
+/// the values.
 extern "C" fn C_GetAttributeValue(
-    _hSession: CK_SESSION_HANDLE,
+    hSession: CK_SESSION_HANDLE,
     hObject: CK_OBJECT_HANDLE,
     pTemplate: CK_ATTRIBUTE_PTR,
     ulCount: CK_ULONG,
@@ -448,16 +976,16 @@ extern "C" fn C_GetAttributeValue(
     }
     let mut attr_types = Vec::with_capacity(ulCount as usize);
     for i in 0..ulCount {
-        let attr = unsafe { &*pTemplate.offset(i as isize) };
+        let attr = unsafe { &*pTemplate.add(i as usize) };
         attr_types.push(attr.attrType);
     }
     let mut manager_guard = try_to_get_manager_guard!();
     let manager = manager_guard_to_manager!(manager_guard);
-    let values = match manager.get_attributes(hObject, attr_types) {
+    let values = match manager.get_attributes(hSession, hObject, attr_types) {
         Ok(values) => values,
-        Err(()) => {
-            error!("C_GetAttributeValue: CKR_ARGUMENTS_BAD");
-            return CKR_ARGUMENTS_BAD;
+        Err(e) => {
+            error!("C_GetAttributeValue: {}", e);
+            return e.ck_rv();
         }
     };
     if values.len() != ulCount as usize {
@@ -466,28 +994,53 @@ extern "C" fn C_GetAttributeValue(
         );
         return CKR_DEVICE_ERROR;
     }
-    for i in 0..ulCount as usize {
-        let mut attr = unsafe { &mut *pTemplate.offset(i as isize) };
-        // NB: the safety of this array access depends on the length check above
-        if let Some(attr_value) = &values[i] {
-            if attr.pValue.is_null() {
+    // The first failure determines what is returned.
+    let mut rv = CKR_OK;
+    for (i, value) in values.iter().enumerate() {
+        let attr = unsafe { &mut *pTemplate.add(i) };
+        // NB: the safety of this pointer access depends on the length check above
+        let attr_rv = match value {
+            Ok(attr_value) if attr.pValue.is_null() => {
                 attr.ulValueLen = attr_value.len() as CK_ULONG;
-            } else {
-                let ptr: *mut u8 = attr.pValue as *mut u8;
-                if attr_value.len() != attr.ulValueLen as usize {
-                    error!("C_GetAttributeValue: incorrect attr size");
-                    return CKR_ARGUMENTS_BAD;
-                }
+                CKR_OK
+            }
+            Ok(attr_value) if (attr.ulValueLen as usize) < attr_value.len() => {
+                attr.ulValueLen = CK_UNAVAILABLE_INFORMATION;
+                CKR_BUFFER_TOO_SMALL
+            }
+            Ok(attr_value) => {
                 unsafe {
-                    std::ptr::copy_nonoverlapping(attr_value.as_ptr(), ptr, attr_value.len());
+                    std::ptr::copy_nonoverlapping(
+                        attr_value.as_ptr(),
+                        attr.pValue as *mut u8,
+                        attr_value.len(),
+                    );
                 }
+                attr.ulValueLen = attr_value.len() as CK_ULONG;
+                CKR_OK
+            }
+            Err(e) => {
+                attr.ulValueLen = CK_UNAVAILABLE_INFORMATION;
+                e.ck_rv()
+            }
+        };
+        if attr_rv != CKR_OK {
+            debug!(
+                "C_GetAttributeValue: {:#x} for attribute {:#x}",
+                attr_rv,
+                unsafe_packed_field_access!(attr.attrType)
+            );
+            if rv == CKR_OK {
+                rv = attr_rv;
             }
-        } else {
-            attr.ulValueLen = (0 - 1) as CK_ULONG;
         }
     }
-    debug!("C_GetAttributeValue: CKR_OK");
-    CKR_OK
+    if rv == CKR_OK {
+        debug!("C_GetAttributeValue: CKR_OK");
+    } else {
+        debug!("C_GetAttributeValue: {:#x}", rv);
+    }
+    rv
 }
 
 extern "C" fn C_SetAttributeValue(
@@ -515,7 +1068,7 @@ extern "C" fn C_FindObjectsInit(
     let mut attrs = Vec::new();
     info!("C_FindObjectsInit:");
     for i in 0..ulCount {
-        let attr = unsafe { &*pTemplate.offset(i as isize) };
+        let attr = unsafe { &*pTemplate.add(i as usize) };
         info!("  {:?}", attr);
         let slice = unsafe {
             std::slice::from_raw_parts(attr.pValue as *const u8, attr.ulValueLen as usize)
@@ -524,12 +1077,9 @@ extern "C" fn C_FindObjectsInit(
     }
     let mut manager_guard = try_to_get_manager_guard!();
     let manager = manager_guard_to_manager!(manager_guard);
-    match manager.start_search(hSession, attrs) {
-        Ok(()) => {}
-        Err(()) => {
-            error!("C_FindObjectsInit: CKR_ARGUMENTS_BAD");
-            return CKR_ARGUMENTS_BAD;
-        }
+    if let Err(e) = manager.start_search(hSession, attrs) {
+        error!("C_FindObjectsInit: {}", e);
+        return e.ck_rv();
     }
     debug!("C_FindObjectsInit: CKR_OK");
     CKR_OK
@@ -552,9 +1102,9 @@ extern "C" fn C_FindObjects(
     let manager = manager_guard_to_manager!(manager_guard);
     let handles = match manager.search(hSession, ulMaxObjectCount as usize) {
         Ok(handles) => handles,
-        Err(()) => {
-            error!("C_FindObjects: CKR_ARGUMENTS_BAD");
-            return CKR_ARGUMENTS_BAD;
+        Err(e) => {
+            error!("C_FindObjects: {}", e);
+            return e.ck_rv();
         }
     };
     debug!("C_FindObjects: found handles {:?}", handles);
@@ -581,15 +1131,14 @@ extern "C" fn C_FindObjects(
 extern "C" fn C_FindObjectsFinal(hSession: CK_SESSION_HANDLE) -> CK_RV {
     let mut manager_guard = try_to_get_manager_guard!();
     let manager = manager_guard_to_manager!(manager_guard);
-    // It would be an error if there were no search for this session, but we can be permissive here.
     match manager.clear_search(hSession) {
         Ok(()) => {
             debug!("C_FindObjectsFinal: CKR_OK");
             CKR_OK
         }
-        Err(()) => {
-            debug!("C_FindObjectsFinal: clear_search failed");
-            CKR_DEVICE_ERROR
+        Err(e) => {
+            error!("C_FindObjectsFinal: {}", e);
+            e.ck_rv()
         }
     }
 }
@@ -634,24 +1183,122 @@ extern "C" fn C_EncryptFinal(
     CKR_FUNCTION_NOT_SUPPORTED
 }
 
+/// Converts the params of a `CKM_RSA_PKCS_OAEP` mechanism to the `OaepParams` backends are given.
+/// Only SHA-1 and SHA-256 with MGF1 using the same hash algorithm and an empty label are supported.
+fn get_oaep_params(mechanism: &CK_MECHANISM) -> Result<OaepParams, CK_RV> {
+    if mechanism.pParameter.is_null()
+        || mechanism.ulParameterLen as usize != std::mem::size_of::<CK_RSA_PKCS_OAEP_PARAMS>()
+    {
+        error!(
+            "C_DecryptInit: bad ulParameterLen for OAEP mechanism: {}",
+            unsafe_packed_field_access!(mechanism.ulParameterLen)
+        );
+        return Err(CKR_MECHANISM_PARAM_INVALID);
+    }
+    let params = unsafe { *(mechanism.pParameter as *const CK_RSA_PKCS_OAEP_PARAMS) };
+    let has_label = params.source != CKZ_DATA_SPECIFIED
+        || (!params.pSourceData.is_null() && params.ulSourceDataLen != 0);
+    let mgf_matches = matches!(
+        (params.hashAlg, params.mgf),
+        (CKM_SHA_1, CKG_MGF1_SHA1) | (CKM_SHA256, CKG_MGF1_SHA256)
+    );
+    if has_label || !mgf_matches {
+        error!("C_DecryptInit: unsupported OAEP params: {:?}", params);
+        return Err(CKR_MECHANISM_PARAM_INVALID);
+    }
+    Ok(OaepParams {
+        hash_algorithm: params.hashAlg,
+    })
+}
+
+/// This gets called to set up a decrypt operation with `CKM_RSA_PKCS` or `CKM_RSA_PKCS_OAEP` (e.g.
+/// to decrypt the key of an S/MIME message). The module essentially defers to the `ManagerProxy`.
 extern "C" fn C_DecryptInit(
-    _hSession: CK_SESSION_HANDLE,
-    _pMechanism: CK_MECHANISM_PTR,
-    _hKey: CK_OBJECT_HANDLE,
+    hSession: CK_SESSION_HANDLE,
+    pMechanism: CK_MECHANISM_PTR,
+    hKey: CK_OBJECT_HANDLE,
 ) -> CK_RV {
-    error!("C_DecryptInit: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+    if pMechanism.is_null() {
+        error!("C_DecryptInit: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    let mechanism = unsafe { *pMechanism };
+    debug!("C_DecryptInit: mechanism is {:?}", mechanism);
+    let mechanism_params = if mechanism.mechanism == CKM_RSA_PKCS_OAEP {
+        match get_oaep_params(&mechanism) {
+            Ok(params) => Some(params),
+            Err(rv) => return rv,
+        }
+    } else {
+        None
+    };
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    if let Err(e) = manager.start_decrypt(hSession, hKey, mechanism.mechanism, mechanism_params) {
+        error!("C_DecryptInit: {}", e);
+        return e.ck_rv();
+    }
+    debug!("C_DecryptInit: CKR_OK");
+    CKR_OK
 }
 
+/// This gets called after `C_DecryptInit` to decrypt data in one part. If no buffer is given, the
+/// length of the key's modulus is returned, which is as long as the decrypted data can be. If the
+/// given buffer is too small, the exact required length is returned and the decrypt operation
+/// remains active, so the caller can try again.
 extern "C" fn C_Decrypt(
-    _hSession: CK_SESSION_HANDLE,
-    _pEncryptedData: CK_BYTE_PTR,
-    _ulEncryptedDataLen: CK_ULONG,
-    _pData: CK_BYTE_PTR,
-    _pulDataLen: CK_ULONG_PTR,
+    hSession: CK_SESSION_HANDLE,
+    pEncryptedData: CK_BYTE_PTR,
+    ulEncryptedDataLen: CK_ULONG,
+    pData: CK_BYTE_PTR,
+    pulDataLen: CK_ULONG_PTR,
 ) -> CK_RV {
-    error!("C_Decrypt: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+    if pEncryptedData.is_null() || pulDataLen.is_null() {
+        error!("C_Decrypt: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    let encrypted_data =
+        unsafe { std::slice::from_raw_parts(pEncryptedData, ulEncryptedDataLen as usize) };
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    if pData.is_null() {
+        let data_length = match manager.get_decrypted_length(hSession, encrypted_data.to_vec()) {
+            Ok(data_length) => data_length,
+            Err(e) => {
+                error!("C_Decrypt: {}", e);
+                return e.ck_rv();
+            }
+        };
+        unsafe {
+            *pulDataLen = data_length as CK_ULONG;
+        }
+        debug!("C_Decrypt: CKR_OK");
+        return CKR_OK;
+    }
+    let data_capacity = unsafe { *pulDataLen } as usize;
+    let data = match manager.decrypt(hSession, encrypted_data.to_vec(), data_capacity) {
+        Ok(data) => data,
+        Err(Error::BufferTooSmall) => {
+            if let Ok(data_length) = manager.get_decrypted_length(hSession, encrypted_data.to_vec())
+            {
+                unsafe {
+                    *pulDataLen = data_length as CK_ULONG;
+                }
+            }
+            error!("C_Decrypt: CKR_BUFFER_TOO_SMALL");
+            return CKR_BUFFER_TOO_SMALL;
+        }
+        Err(e) => {
+            error!("C_Decrypt: {}", e);
+            return e.ck_rv();
+        }
+    };
+    unsafe {
+        std::ptr::copy_nonoverlapping(data.as_ptr(), pData, data.len());
+        *pulDataLen = data.len() as CK_ULONG;
+    }
+    debug!("C_Decrypt: CKR_OK");
+    CKR_OK
 }
 
 extern "C" fn C_DecryptUpdate(
@@ -713,6 +1360,32 @@ extern "C" fn C_DigestFinal(
     CKR_FUNCTION_NOT_SUPPORTED
 }
 
+/// Returns the params of the given mechanism if it is an RSA PSS mechanism, or `None` if it is
+/// another mechanism.
+fn get_pss_params(mechanism: &CK_MECHANISM) -> Result<Option<CK_RSA_PKCS_PSS_PARAMS>, CK_RV> {
+    if !matches!(
+        mechanism.mechanism,
+        CKM_RSA_PKCS_PSS
+            | CKM_SHA256_RSA_PKCS_PSS
+            | CKM_SHA384_RSA_PKCS_PSS
+            | CKM_SHA512_RSA_PKCS_PSS
+    ) {
+        return Ok(None);
+    }
+    if mechanism.pParameter.is_null()
+        || mechanism.ulParameterLen as usize != std::mem::size_of::<CK_RSA_PKCS_PSS_PARAMS>()
+    {
+        error!(
+            "bad ulParameterLen for PSS mechanism: {}",
+            unsafe_packed_field_access!(mechanism.ulParameterLen)
+        );
+        return Err(CKR_MECHANISM_PARAM_INVALID);
+    }
+    Ok(Some(unsafe {
+        *(mechanism.pParameter as *const CK_RSA_PKCS_PSS_PARAMS)
+    }))
+}
+
 /// This gets called to set up a sign operation. The module essentially defers to the
 /// `ManagerProxy`.
 extern "C" fn C_SignInit(
@@ -724,38 +1397,25 @@ extern "C" fn C_SignInit(
         error!("C_SignInit: CKR_ARGUMENTS_BAD");
         return CKR_ARGUMENTS_BAD;
     }
-    // Presumably we should validate the mechanism against hKey, but the specification doesn't
-    // actually seem to require this.
     let mechanism = unsafe { *pMechanism };
     debug!("C_SignInit: mechanism is {:?}", mechanism);
-    let mechanism_params = if mechanism.mechanism == CKM_RSA_PKCS_PSS {
-        if mechanism.ulParameterLen as usize != std::mem::size_of::<CK_RSA_PKCS_PSS_PARAMS>() {
-            error!(
-                "C_SignInit: bad ulParameterLen for CKM_RSA_PKCS_PSS: {}",
-                unsafe_packed_field_access!(mechanism.ulParameterLen)
-            );
-            return CKR_ARGUMENTS_BAD;
-        }
-        Some(unsafe { *(mechanism.pParameter as *const CK_RSA_PKCS_PSS_PARAMS) })
-    } else {
-        None
+    let mechanism_params = match get_pss_params(&mechanism) {
+        Ok(params) => params,
+        Err(rv) => return rv,
     };
     let mut manager_guard = try_to_get_manager_guard!();
     let manager = manager_guard_to_manager!(manager_guard);
-    match manager.start_sign(hSession, hKey, mechanism_params) {
-        Ok(()) => {}
-        Err(()) => {
-            error!("C_SignInit: CKR_GENERAL_ERROR");
-            return CKR_GENERAL_ERROR;
-        }
-    };
+    if let Err(e) = manager.start_sign(hSession, hKey, mechanism.mechanism, mechanism_params) {
+        error!("C_SignInit: {}", e);
+        return e.ck_rv();
+    }
     debug!("C_SignInit: CKR_OK");
     CKR_OK
 }
 
-/// NSS calls this after `C_SignInit` (there are more ways in the PKCS #11 specification to sign
-/// data, but this is the only way supported by this module). The module essentially defers to the
-/// `ManagerProxy` and copies out the resulting signature.
+/// NSS calls this after `C_SignInit` to sign data in one part. The module essentially defers to the
+/// `ManagerProxy` and copies out the resulting signature. If the given buffer is too small, the
+/// required length is returned and the sign operation remains active, so the caller can try again.
 extern "C" fn C_Sign(
     hSession: CK_SESSION_HANDLE,
     pData: CK_BYTE_PTR,
@@ -768,60 +1428,126 @@ extern "C" fn C_Sign(
         return CKR_ARGUMENTS_BAD;
     }
     let data = unsafe { std::slice::from_raw_parts(pData, ulDataLen as usize) };
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    let signature_length = match manager.get_signature_length(hSession, data.to_vec()) {
+        Ok(signature_length) => signature_length,
+        Err(e) => {
+            error!("C_Sign: {}", e);
+            return e.ck_rv();
+        }
+    };
     if pSignature.is_null() {
-        let mut manager_guard = try_to_get_manager_guard!();
-        let manager = manager_guard_to_manager!(manager_guard);
-        match manager.get_signature_length(hSession, data.to_vec()) {
-            Ok(signature_length) => unsafe {
-                *pulSignatureLen = signature_length as CK_ULONG;
-            },
-            Err(()) => {
-                error!("C_Sign: get_signature_length failed");
-                return CKR_GENERAL_ERROR;
-            }
+        unsafe {
+            *pulSignatureLen = signature_length as CK_ULONG;
         }
     } else {
-        let mut manager_guard = try_to_get_manager_guard!();
-        let manager = manager_guard_to_manager!(manager_guard);
-        match manager.sign(hSession, data.to_vec()) {
-            Ok(signature) => {
-                let signature_capacity = unsafe { *pulSignatureLen } as usize;
-                if signature_capacity < signature.len() {
-                    error!("C_Sign: CKR_ARGUMENTS_BAD");
-                    return CKR_ARGUMENTS_BAD;
-                }
-                let ptr: *mut u8 = pSignature as *mut u8;
-                unsafe {
-                    std::ptr::copy_nonoverlapping(signature.as_ptr(), ptr, signature.len());
-                    *pulSignatureLen = signature.len() as CK_ULONG;
-                }
+        let signature_capacity = unsafe { *pulSignatureLen } as usize;
+        if signature_capacity < signature_length {
+            unsafe {
+                *pulSignatureLen = signature_length as CK_ULONG;
             }
-            Err(()) => {
-                error!("C_Sign: sign failed");
-                return CKR_GENERAL_ERROR;
+            error!("C_Sign: CKR_BUFFER_TOO_SMALL");
+            return CKR_BUFFER_TOO_SMALL;
+        }
+        let signature = match manager.sign(hSession, data.to_vec()) {
+            Ok(signature) => signature,
+            Err(e) => {
+                error!("C_Sign: {}", e);
+                return e.ck_rv();
             }
+        };
+        if signature_capacity < signature.len() {
+            error!("C_Sign: signature is longer than expected");
+            return CKR_GENERAL_ERROR;
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(signature.as_ptr(), pSignature, signature.len());
+            *pulSignatureLen = signature.len() as CK_ULONG;
         }
     }
     debug!("C_Sign: CKR_OK");
     CKR_OK
 }
 
+/// This gets called after `C_SignInit` to give the next part of the data to sign with one of the
+/// mechanisms that hash the data before signing it (e.g. `CKM_SHA256_RSA_PKCS`).
 extern "C" fn C_SignUpdate(
-    _hSession: CK_SESSION_HANDLE,
-    _pPart: CK_BYTE_PTR,
-    _ulPartLen: CK_ULONG,
+    hSession: CK_SESSION_HANDLE,
+    pPart: CK_BYTE_PTR,
+    ulPartLen: CK_ULONG,
 ) -> CK_RV {
-    error!("C_SignUpdate: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+    if pPart.is_null() && ulPartLen != 0 {
+        error!("C_SignUpdate: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    let part = if ulPartLen == 0 {
+        Vec::new()
+    } else {
+        unsafe { std::slice::from_raw_parts(pPart, ulPartLen as usize) }.to_vec()
+    };
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    if let Err(e) = manager.sign_update(hSession, part) {
+        error!("C_SignUpdate: {}", e);
+        return e.ck_rv();
+    }
+    debug!("C_SignUpdate: CKR_OK");
+    CKR_OK
 }
 
+/// This gets called to finish a sign operation whose data was given with `C_SignUpdate`. Like with
+/// `C_Sign`, if the given buffer is too small, the required length is returned and the sign
+/// operation remains active, so the caller can try again.
 extern "C" fn C_SignFinal(
-    _hSession: CK_SESSION_HANDLE,
-    _pSignature: CK_BYTE_PTR,
-    _pulSignatureLen: CK_ULONG_PTR,
+    hSession: CK_SESSION_HANDLE,
+    pSignature: CK_BYTE_PTR,
+    pulSignatureLen: CK_ULONG_PTR,
 ) -> CK_RV {
-    error!("C_SignFinal: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+    if pulSignatureLen.is_null() {
+        error!("C_SignFinal: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    let signature_length = match manager.get_signature_length(hSession, Vec::new()) {
+        Ok(signature_length) => signature_length,
+        Err(e) => {
+            error!("C_SignFinal: {}", e);
+            return e.ck_rv();
+        }
+    };
+    if pSignature.is_null() {
+        unsafe {
+            *pulSignatureLen = signature_length as CK_ULONG;
+        }
+    } else {
+        let signature_capacity = unsafe { *pulSignatureLen } as usize;
+        if signature_capacity < signature_length {
+            unsafe {
+                *pulSignatureLen = signature_length as CK_ULONG;
+            }
+            error!("C_SignFinal: CKR_BUFFER_TOO_SMALL");
+            return CKR_BUFFER_TOO_SMALL;
+        }
+        let signature = match manager.sign_final(hSession) {
+            Ok(signature) => signature,
+            Err(e) => {
+                error!("C_SignFinal: {}", e);
+                return e.ck_rv();
+            }
+        };
+        if signature_capacity < signature.len() {
+            error!("C_SignFinal: signature is longer than expected");
+            return CKR_GENERAL_ERROR;
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(signature.as_ptr(), pSignature, signature.len());
+            *pulSignatureLen = signature.len() as CK_ULONG;
+        }
+    }
+    debug!("C_SignFinal: CKR_OK");
+    CKR_OK
 }
 
 extern "C" fn C_SignRecoverInit(
@@ -844,42 +1570,104 @@ extern "C" fn C_SignRecover(
     CKR_FUNCTION_NOT_SUPPORTED
 }
 
+/// This gets called to set up a verify operation with one of the public key objects. The module
+/// essentially defers to the `ManagerProxy`.
 extern "C" fn C_VerifyInit(
-    _hSession: CK_SESSION_HANDLE,
-    _pMechanism: CK_MECHANISM_PTR,
-    _hKey: CK_OBJECT_HANDLE,
+    hSession: CK_SESSION_HANDLE,
+    pMechanism: CK_MECHANISM_PTR,
+    hKey: CK_OBJECT_HANDLE,
 ) -> CK_RV {
-    error!("C_VerifyInit: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+    if pMechanism.is_null() {
+        error!("C_VerifyInit: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    let mechanism = unsafe { *pMechanism };
+    debug!("C_VerifyInit: mechanism is {:?}", mechanism);
+    let mechanism_params = match get_pss_params(&mechanism) {
+        Ok(params) => params,
+        Err(rv) => return rv,
+    };
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    if let Err(e) = manager.start_verify(hSession, hKey, mechanism.mechanism, mechanism_params) {
+        error!("C_VerifyInit: {}", e);
+        return e.ck_rv();
+    }
+    debug!("C_VerifyInit: CKR_OK");
+    CKR_OK
 }
 
+/// This gets called after `C_VerifyInit` to verify a signature of data given in one part. This
+/// finishes the verify operation, whether or not the signature is valid.
 extern "C" fn C_Verify(
-    _hSession: CK_SESSION_HANDLE,
-    _pData: CK_BYTE_PTR,
-    _ulDataLen: CK_ULONG,
-    _pSignature: CK_BYTE_PTR,
-    _ulSignatureLen: CK_ULONG,
+    hSession: CK_SESSION_HANDLE,
+    pData: CK_BYTE_PTR,
+    ulDataLen: CK_ULONG,
+    pSignature: CK_BYTE_PTR,
+    ulSignatureLen: CK_ULONG,
 ) -> CK_RV {
-    error!("C_Verify: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+    if pData.is_null() || pSignature.is_null() {
+        error!("C_Verify: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    let data = unsafe { std::slice::from_raw_parts(pData, ulDataLen as usize) };
+    let signature = unsafe { std::slice::from_raw_parts(pSignature, ulSignatureLen as usize) };
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    if let Err(e) = manager.verify(hSession, data.to_vec(), signature.to_vec()) {
+        error!("C_Verify: {}", e);
+        return e.ck_rv();
+    }
+    debug!("C_Verify: CKR_OK");
+    CKR_OK
 }
 
+/// This gets called after `C_VerifyInit` to give the next part of the signed data with one of the
+/// mechanisms that hash the data (e.g. `CKM_SHA256_RSA_PKCS`).
 extern "C" fn C_VerifyUpdate(
-    _hSession: CK_SESSION_HANDLE,
-    _pPart: CK_BYTE_PTR,
-    _ulPartLen: CK_ULONG,
+    hSession: CK_SESSION_HANDLE,
+    pPart: CK_BYTE_PTR,
+    ulPartLen: CK_ULONG,
 ) -> CK_RV {
-    error!("C_VerifyUpdate: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+    if pPart.is_null() && ulPartLen != 0 {
+        error!("C_VerifyUpdate: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    let part = if ulPartLen == 0 {
+        Vec::new()
+    } else {
+        unsafe { std::slice::from_raw_parts(pPart, ulPartLen as usize) }.to_vec()
+    };
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    if let Err(e) = manager.verify_update(hSession, part) {
+        error!("C_VerifyUpdate: {}", e);
+        return e.ck_rv();
+    }
+    debug!("C_VerifyUpdate: CKR_OK");
+    CKR_OK
 }
 
+/// This gets called to verify a signature of the data given with `C_VerifyUpdate`, which finishes
+/// the verify operation.
 extern "C" fn C_VerifyFinal(
-    _hSession: CK_SESSION_HANDLE,
-    _pSignature: CK_BYTE_PTR,
-    _ulSignatureLen: CK_ULONG,
+    hSession: CK_SESSION_HANDLE,
+    pSignature: CK_BYTE_PTR,
+    ulSignatureLen: CK_ULONG,
 ) -> CK_RV {
-    error!("C_VerifyFinal: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+    if pSignature.is_null() {
+        error!("C_VerifyFinal: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    let signature = unsafe { std::slice::from_raw_parts(pSignature, ulSignatureLen as usize) };
+    let mut manager_guard = try_to_get_manager_guard!();
+    let manager = manager_guard_to_manager!(manager_guard);
+    if let Err(e) = manager.verify_final(hSession, signature.to_vec()) {
+        error!("C_VerifyFinal: {}", e);
+        return e.ck_rv();
+    }
+    debug!("C_VerifyFinal: CKR_OK");
+    CKR_OK
 }
 
 extern "C" fn C_VerifyRecoverInit(
@@ -1037,13 +1825,56 @@ extern "C" fn C_CancelFunction(_hSession: CK_SESSION_HANDLE) -> CK_RV {
     CKR_FUNCTION_NOT_SUPPORTED
 }
 
+/// How often a blocking `C_WaitForSlotEvent` checks for slot events.
+const SLOT_EVENT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// This gets called to wait for a slot event, which for this module means that certificates or
+/// keys have appeared in or disappeared from the token in a slot. The `ManagerProxy` checks for
+/// events by watching directories (on Linux) and by asking backends for their identities. If
+/// `CKF_DONT_BLOCK` is set, this returns `CKR_NO_EVENT` if there is no event. Otherwise, this
+/// blocks until there is an event or until the module is finalized.
 extern "C" fn C_WaitForSlotEvent(
-    _flags: CK_FLAGS,
-    _pSlot: CK_SLOT_ID_PTR,
-    _pRserved: CK_VOID_PTR,
+    flags: CK_FLAGS,
+    pSlot: CK_SLOT_ID_PTR,
+    pRserved: CK_VOID_PTR,
 ) -> CK_RV {
-    error!("C_WaitForSlotEvent: CKR_FUNCTION_NOT_SUPPORTED");
-    CKR_FUNCTION_NOT_SUPPORTED
+    if pSlot.is_null() || !pRserved.is_null() {
+        error!("C_WaitForSlotEvent: CKR_ARGUMENTS_BAD");
+        return CKR_ARGUMENTS_BAD;
+    }
+    loop {
+        {
+            let mut manager_guard = try_to_get_manager_guard!();
+            let manager = match manager_guard.as_mut() {
+                Some(manager) => manager,
+                None => {
+                    debug!("C_WaitForSlotEvent: CKR_CRYPTOKI_NOT_INITIALIZED");
+                    return CKR_CRYPTOKI_NOT_INITIALIZED;
+                }
+            };
+            match manager.get_slot_event() {
+                Ok(Some(slot_id)) => {
+                    unsafe {
+                        *pSlot = slot_id;
+                    }
+                    debug!("C_WaitForSlotEvent: CKR_OK (slot {})", slot_id);
+                    return CKR_OK;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("C_WaitForSlotEvent: {}", e);
+                    return e.ck_rv();
+                }
+            }
+        }
+        if flags & CKF_DONT_BLOCK != 0 {
+            debug!("C_WaitForSlotEvent: CKR_NO_EVENT");
+            return CKR_NO_EVENT;
+        }
+        // The manager lock isn't held while sleeping, so other functions (including C_Finalize)
+        // can be called in the meantime.
+        std::thread::sleep(SLOT_EVENT_POLL_INTERVAL);
+    }
 }
 
 /// To be a valid PKCS #11 module, this list of functions must be supported. At least cryptoki 2.2
@@ -1123,12 +1954,13 @@ static mut FUNCTION_LIST: CK_FUNCTION_LIST = CK_FUNCTION_LIST {
 /// This is the only function this module exposes. NSS calls it to obtain the list of functions
 /// comprising this module.
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn C_GetFunctionList(ppFunctionList: CK_FUNCTION_LIST_PTR_PTR) -> CK_RV {
     if ppFunctionList.is_null() {
         return CKR_ARGUMENTS_BAD;
     }
     unsafe {
-        *ppFunctionList = &mut FUNCTION_LIST;
+        *ppFunctionList = std::ptr::addr_of_mut!(FUNCTION_LIST);
     }
     CKR_OK
 }
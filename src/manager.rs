@@ -4,13 +4,15 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use pkcs11::types::*;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
 use std::collections::{BTreeMap, BTreeSet};
 
-#[cfg(target_os = "macos")]
-use crate::backend_macos as backend;
-#[cfg(target_os = "windows")]
-use crate::backend_windows as backend;
-use backend::*;
+use crate::backend::*;
+use crate::config::DEFAULT_MANUFACTURER;
+use crate::digest::{encode_digest_info, Hasher};
+use crate::error::Error;
+use crate::file_watcher::FileWatcher;
 
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
@@ -26,20 +28,48 @@ type ManagerReturnValueReceiver = Receiver<ManagerReturnValue>;
 /// `ManagerArguments::Stop` is a special variant that stops the background thread and drops the
 /// `Manager`.
 enum ManagerArguments {
-    OpenSession,
+    GetSlotIds,
+    GetTokenInfo(CK_SLOT_ID),
+    GetMechanismInfo(CK_SLOT_ID, CK_MECHANISM_TYPE),
+    GetSlotEvent,
+    OpenSession(CK_SLOT_ID, bool),
     CloseSession(CK_SESSION_HANDLE),
-    CloseAllSessions,
+    CloseAllSessions(CK_SLOT_ID),
+    GetSessionInfo(CK_SESSION_HANDLE),
     StartSearch(CK_SESSION_HANDLE, Vec<(CK_ATTRIBUTE_TYPE, Vec<u8>)>),
     Search(CK_SESSION_HANDLE, usize),
     ClearSearch(CK_SESSION_HANDLE),
-    GetAttributes(CK_OBJECT_HANDLE, Vec<CK_ATTRIBUTE_TYPE>),
+    GetAttributes(CK_SESSION_HANDLE, CK_OBJECT_HANDLE, Vec<CK_ATTRIBUTE_TYPE>),
     StartSign(
         CK_SESSION_HANDLE,
         CK_OBJECT_HANDLE,
+        CK_MECHANISM_TYPE,
         Option<CK_RSA_PKCS_PSS_PARAMS>,
     ),
     GetSignatureLength(CK_SESSION_HANDLE, Vec<u8>),
     Sign(CK_SESSION_HANDLE, Vec<u8>),
+    SignUpdate(CK_SESSION_HANDLE, Vec<u8>),
+    SignFinal(CK_SESSION_HANDLE),
+    StartDecrypt(
+        CK_SESSION_HANDLE,
+        CK_OBJECT_HANDLE,
+        CK_MECHANISM_TYPE,
+        Option<OaepParams>,
+    ),
+    GetDecryptedLength(CK_SESSION_HANDLE, Vec<u8>),
+    Decrypt(CK_SESSION_HANDLE, Vec<u8>, usize),
+    StartVerify(
+        CK_SESSION_HANDLE,
+        CK_OBJECT_HANDLE,
+        CK_MECHANISM_TYPE,
+        Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ),
+    Verify(CK_SESSION_HANDLE, Vec<u8>, Vec<u8>),
+    VerifyUpdate(CK_SESSION_HANDLE, Vec<u8>),
+    VerifyFinal(CK_SESSION_HANDLE, Vec<u8>),
+    Login(CK_SESSION_HANDLE, String),
+    LoginContextSpecific(CK_SESSION_HANDLE, String),
+    Logout(CK_SESSION_HANDLE),
     Stop,
 }
 
@@ -47,17 +77,34 @@ enum ManagerArguments {
 /// `ManagerProxy`. `ManagerReturnValue::Stop` is a special variant that indicates that the
 /// `Manager` will stop.
 enum ManagerReturnValue {
-    OpenSession(Result<CK_SESSION_HANDLE, ()>),
-    CloseSession(Result<(), ()>),
-    CloseAllSessions(Result<(), ()>),
-    StartSearch(Result<(), ()>),
-    Search(Result<Vec<CK_OBJECT_HANDLE>, ()>),
-    ClearSearch(Result<(), ()>),
-    GetAttributes(Result<Vec<Option<Vec<u8>>>, ()>),
-    StartSign(Result<(), ()>),
-    GetSignatureLength(Result<usize, ()>),
-    Sign(Result<Vec<u8>, ()>),
-    Stop(Result<(), ()>),
+    GetSlotIds(Result<Vec<CK_SLOT_ID>, Error>),
+    GetTokenInfo(Result<TokenInfo, Error>),
+    GetMechanismInfo(Result<MechanismInfo, Error>),
+    GetSlotEvent(Result<Option<CK_SLOT_ID>, Error>),
+    OpenSession(Result<CK_SESSION_HANDLE, Error>),
+    CloseSession(Result<(), Error>),
+    CloseAllSessions(Result<(), Error>),
+    GetSessionInfo(Result<SessionInfo, Error>),
+    StartSearch(Result<(), Error>),
+    Search(Result<Vec<CK_OBJECT_HANDLE>, Error>),
+    ClearSearch(Result<(), Error>),
+    GetAttributes(Result<Vec<Result<Vec<u8>, Error>>, Error>),
+    StartSign(Result<(), Error>),
+    GetSignatureLength(Result<usize, Error>),
+    Sign(Result<Vec<u8>, Error>),
+    SignUpdate(Result<(), Error>),
+    SignFinal(Result<Vec<u8>, Error>),
+    StartDecrypt(Result<(), Error>),
+    GetDecryptedLength(Result<usize, Error>),
+    Decrypt(Result<Vec<u8>, Error>),
+    StartVerify(Result<(), Error>),
+    Verify(Result<(), Error>),
+    VerifyUpdate(Result<(), Error>),
+    VerifyFinal(Result<(), Error>),
+    Login(Result<(), Error>),
+    LoginContextSpecific(Result<(), Error>),
+    Logout(Result<(), Error>),
+    Stop(Result<(), Error>),
 }
 
 /// Helper macro to implement the body of each public `ManagerProxy` function. Takes a
@@ -70,29 +117,154 @@ macro_rules! manager_proxy_fn_impl {
             Ok($return_type(result)) => result,
             Ok(_) => {
                 error!("unexpected return value from manager");
-                Err(())
+                Err(Error::General)
             }
-            Err(()) => Err(()),
+            Err(e) => Err(e),
         }
     };
 }
 
+/// Calls the `Manager` function described by `arguments` and returns what it returned.
+fn call_manager(manager: &mut Manager, arguments: ManagerArguments) -> ManagerReturnValue {
+    match arguments {
+        ManagerArguments::GetSlotIds => ManagerReturnValue::GetSlotIds(manager.get_slot_ids()),
+        ManagerArguments::GetTokenInfo(slot_id) => {
+            ManagerReturnValue::GetTokenInfo(manager.get_token_info(slot_id))
+        }
+        ManagerArguments::GetMechanismInfo(slot_id, mechanism) => {
+            ManagerReturnValue::GetMechanismInfo(manager.get_mechanism_info(slot_id, mechanism))
+        }
+        ManagerArguments::GetSlotEvent => {
+            ManagerReturnValue::GetSlotEvent(manager.get_slot_event())
+        }
+        ManagerArguments::OpenSession(slot_id, read_write) => {
+            ManagerReturnValue::OpenSession(manager.open_session(slot_id, read_write))
+        }
+        ManagerArguments::CloseSession(session_handle) => {
+            ManagerReturnValue::CloseSession(manager.close_session(session_handle))
+        }
+        ManagerArguments::CloseAllSessions(slot_id) => {
+            ManagerReturnValue::CloseAllSessions(manager.close_all_sessions(slot_id))
+        }
+        ManagerArguments::GetSessionInfo(session) => {
+            ManagerReturnValue::GetSessionInfo(manager.get_session_info(session))
+        }
+        ManagerArguments::StartSearch(session, attrs) => {
+            ManagerReturnValue::StartSearch(manager.start_search(session, &attrs))
+        }
+        ManagerArguments::Search(session, max_objects) => {
+            ManagerReturnValue::Search(manager.search(session, max_objects))
+        }
+        ManagerArguments::ClearSearch(session) => {
+            ManagerReturnValue::ClearSearch(manager.clear_search(session))
+        }
+        ManagerArguments::GetAttributes(session, object_handle, attr_types) => {
+            ManagerReturnValue::GetAttributes(manager.get_attributes(
+                session,
+                object_handle,
+                attr_types,
+            ))
+        }
+        ManagerArguments::StartSign(session, key_handle, mechanism, params) => {
+            ManagerReturnValue::StartSign(
+                manager.start_sign(session, key_handle, mechanism, params),
+            )
+        }
+        ManagerArguments::GetSignatureLength(session, data) => {
+            ManagerReturnValue::GetSignatureLength(manager.get_signature_length(session, &data))
+        }
+        ManagerArguments::Sign(session, data) => {
+            ManagerReturnValue::Sign(manager.sign(session, &data))
+        }
+        ManagerArguments::SignUpdate(session, data) => {
+            ManagerReturnValue::SignUpdate(manager.sign_update(session, &data))
+        }
+        ManagerArguments::SignFinal(session) => {
+            ManagerReturnValue::SignFinal(manager.sign_final(session))
+        }
+        ManagerArguments::StartDecrypt(session, key_handle, mechanism, params) => {
+            ManagerReturnValue::StartDecrypt(
+                manager.start_decrypt(session, key_handle, mechanism, params),
+            )
+        }
+        ManagerArguments::GetDecryptedLength(session, data) => {
+            ManagerReturnValue::GetDecryptedLength(manager.get_decrypted_length(session, &data))
+        }
+        ManagerArguments::Decrypt(session, data, capacity) => {
+            ManagerReturnValue::Decrypt(manager.decrypt(session, &data, capacity))
+        }
+        ManagerArguments::StartVerify(session, key_handle, mechanism, params) => {
+            ManagerReturnValue::StartVerify(
+                manager.start_verify(session, key_handle, mechanism, params),
+            )
+        }
+        ManagerArguments::Verify(session, data, signature) => {
+            ManagerReturnValue::Verify(manager.verify(session, &data, &signature))
+        }
+        ManagerArguments::VerifyUpdate(session, data) => {
+            ManagerReturnValue::VerifyUpdate(manager.verify_update(session, &data))
+        }
+        ManagerArguments::VerifyFinal(session, signature) => {
+            ManagerReturnValue::VerifyFinal(manager.verify_final(session, &signature))
+        }
+        ManagerArguments::Login(session, pin) => {
+            ManagerReturnValue::Login(manager.login(session, &pin))
+        }
+        ManagerArguments::LoginContextSpecific(session, pin) => {
+            ManagerReturnValue::LoginContextSpecific(manager.login_context_specific(session, &pin))
+        }
+        ManagerArguments::Logout(session) => ManagerReturnValue::Logout(manager.logout(session)),
+        ManagerArguments::Stop => {
+            debug!("ManagerArguments::Stop received - stopping Manager thread.");
+            ManagerReturnValue::Stop(Ok(()))
+        }
+    }
+}
+
+/// A `Manager` that runs on whichever thread calls the `ManagerProxy`, for applications that don't
+/// allow this module to create threads.
+struct InlineManager(Manager);
+
+// The backends aren't necessarily safe to use from different threads, which is why the `Manager`
+// normally runs on its own thread. An application that doesn't allow that takes on the
+// responsibility of calling this module from a suitable thread. Calls are still serialized by the
+// lock around the `ManagerProxy`.
+unsafe impl Send for InlineManager {}
+
+/// Where the real `Manager` runs.
+enum ManagerRunner {
+    /// On a thread of its own. Calls are sent to it and results are received from it over channels.
+    Thread {
+        sender: ManagerArgumentsSender,
+        receiver: ManagerReturnValueReceiver,
+        thread_handle: Option<JoinHandle<()>>,
+    },
+    /// On the calling thread. This is `None` once the `Manager` has been stopped.
+    Inline(RefCell<Option<InlineManager>>),
+}
+
 /// `ManagerProxy` synchronously proxies calls from any thread to the `Manager` that runs on a
 /// single thread. This is necessary because the underlying OS APIs in use are not guaranteed to be
 /// thread-safe (e.g. they may use thread-local storage). Using it should be identical to using the
-/// real `Manager`.
+/// real `Manager`. If the application doesn't allow this module to create threads, the `Manager`
+/// instead runs on the calling thread.
 pub struct ManagerProxy {
-    sender: ManagerArgumentsSender,
-    receiver: ManagerReturnValueReceiver,
-    thread_handle: Option<JoinHandle<()>>,
+    runner: ManagerRunner,
 }
 
 impl ManagerProxy {
-    pub fn new() -> ManagerProxy {
+    /// Creates a new `ManagerProxy` and starts the thread the real `Manager` runs on. Because
+    /// backends may use OS APIs that are not thread-safe, the backends are created on that thread by
+    /// calling `make_backends`. Each backend is exposed as its own slot. Backends are asked for
+    /// their identities at most once every `rescan_interval`.
+    pub fn new<F>(make_backends: F, rescan_interval: Duration) -> ManagerProxy
+    where
+        F: FnOnce() -> Vec<Box<dyn Backend>> + Send + 'static,
+    {
         let (proxy_sender, manager_receiver) = channel();
         let (manager_sender, proxy_receiver) = channel();
         let thread_handle = thread::spawn(move || {
-            let mut real_manager = Manager::new();
+            let mut real_manager = Manager::new(make_backends(), rescan_interval);
             loop {
                 let arguments = match manager_receiver.recv() {
                     Ok(arguments) => arguments,
@@ -101,52 +273,8 @@ impl ManagerProxy {
                         break;
                     }
                 };
-                let results = match arguments {
-                    ManagerArguments::OpenSession => {
-                        ManagerReturnValue::OpenSession(real_manager.open_session())
-                    }
-                    ManagerArguments::CloseSession(session_handle) => {
-                        ManagerReturnValue::CloseSession(real_manager.close_session(session_handle))
-                    }
-                    ManagerArguments::CloseAllSessions => {
-                        ManagerReturnValue::CloseAllSessions(real_manager.close_all_sessions())
-                    }
-                    ManagerArguments::StartSearch(session, attrs) => {
-                        ManagerReturnValue::StartSearch(real_manager.start_search(session, &attrs))
-                    }
-                    ManagerArguments::Search(session, max_objects) => {
-                        ManagerReturnValue::Search(real_manager.search(session, max_objects))
-                    }
-                    ManagerArguments::ClearSearch(session) => {
-                        ManagerReturnValue::ClearSearch(real_manager.clear_search(session))
-                    }
-                    ManagerArguments::GetAttributes(object_handle, attr_types) => {
-                        ManagerReturnValue::GetAttributes(
-                            real_manager.get_attributes(object_handle, attr_types),
-                        )
-                    }
-                    ManagerArguments::StartSign(session, key_handle, params) => {
-                        ManagerReturnValue::StartSign(
-                            real_manager.start_sign(session, key_handle, params),
-                        )
-                    }
-                    ManagerArguments::GetSignatureLength(session, data) => {
-                        ManagerReturnValue::GetSignatureLength(
-                            real_manager.get_signature_length(session, &data),
-                        )
-                    }
-                    ManagerArguments::Sign(session, data) => {
-                        ManagerReturnValue::Sign(real_manager.sign(session, &data))
-                    }
-                    ManagerArguments::Stop => {
-                        debug!("ManagerArguments::Stop received - stopping Manager thread.");
-                        ManagerReturnValue::Stop(Ok(()))
-                    }
-                };
-                let stop_after_send = match &results {
-                    &ManagerReturnValue::Stop(_) => true,
-                    _ => false,
-                };
+                let results = call_manager(&mut real_manager, arguments);
+                let stop_after_send = matches!(&results, &ManagerReturnValue::Stop(_));
                 match manager_sender.send(results) {
                     Ok(()) => {}
                     Err(e) => {
@@ -160,39 +288,111 @@ impl ManagerProxy {
             }
         });
         ManagerProxy {
-            sender: proxy_sender,
-            receiver: proxy_receiver,
-            thread_handle: Some(thread_handle),
+            runner: ManagerRunner::Thread {
+                sender: proxy_sender,
+                receiver: proxy_receiver,
+                thread_handle: Some(thread_handle),
+            },
+        }
+    }
+
+    /// Creates a new `ManagerProxy` whose `Manager` runs on the calling thread rather than on a
+    /// thread of its own. The backends are created immediately.
+    pub fn new_inline(backends: Vec<Box<dyn Backend>>, rescan_interval: Duration) -> ManagerProxy {
+        ManagerProxy {
+            runner: ManagerRunner::Inline(RefCell::new(Some(InlineManager(Manager::new(
+                backends,
+                rescan_interval,
+            ))))),
         }
     }
 
-    fn proxy_call(&self, args: ManagerArguments) -> Result<ManagerReturnValue, ()> {
-        match self.sender.send(args) {
+    fn proxy_call(&self, args: ManagerArguments) -> Result<ManagerReturnValue, Error> {
+        let (sender, receiver) = match &self.runner {
+            ManagerRunner::Thread {
+                sender, receiver, ..
+            } => (sender, receiver),
+            ManagerRunner::Inline(manager) => {
+                let mut manager = manager.borrow_mut();
+                let result = match manager.as_mut() {
+                    Some(InlineManager(manager)) => call_manager(manager, args),
+                    None => {
+                        error!("Manager called after it was stopped");
+                        return Err(Error::General);
+                    }
+                };
+                if let ManagerReturnValue::Stop(_) = result {
+                    manager.take();
+                }
+                return Ok(result);
+            }
+        };
+        match sender.send(args) {
             Ok(()) => {}
             Err(e) => {
                 error!("error send()ing arguments to Manager: {}", e);
-                return Err(());
+                return Err(Error::General);
             }
         };
-        let result = match self.receiver.recv() {
+        let result = match receiver.recv() {
             Ok(result) => result,
             Err(e) => {
                 error!("error recv()ing result from Manager: {}", e);
-                return Err(());
+                return Err(Error::General);
             }
         };
         Ok(result)
     }
 
-    pub fn open_session(&mut self) -> Result<CK_SESSION_HANDLE, ()> {
+    pub fn get_slot_ids(&self) -> Result<Vec<CK_SLOT_ID>, Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::GetSlotIds,
+            ManagerReturnValue::GetSlotIds
+        )
+    }
+
+    pub fn get_token_info(&self, slot_id: CK_SLOT_ID) -> Result<TokenInfo, Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::GetTokenInfo(slot_id),
+            ManagerReturnValue::GetTokenInfo
+        )
+    }
+
+    pub fn get_mechanism_info(
+        &self,
+        slot_id: CK_SLOT_ID,
+        mechanism: CK_MECHANISM_TYPE,
+    ) -> Result<MechanismInfo, Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::GetMechanismInfo(slot_id, mechanism),
+            ManagerReturnValue::GetMechanismInfo
+        )
+    }
+
+    pub fn get_slot_event(&mut self) -> Result<Option<CK_SLOT_ID>, Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::GetSlotEvent,
+            ManagerReturnValue::GetSlotEvent
+        )
+    }
+
+    pub fn open_session(
+        &mut self,
+        slot_id: CK_SLOT_ID,
+        read_write: bool,
+    ) -> Result<CK_SESSION_HANDLE, Error> {
         manager_proxy_fn_impl!(
             self,
-            ManagerArguments::OpenSession,
+            ManagerArguments::OpenSession(slot_id, read_write),
             ManagerReturnValue::OpenSession
         )
     }
 
-    pub fn close_session(&mut self, session: CK_SESSION_HANDLE) -> Result<(), ()> {
+    pub fn close_session(&mut self, session: CK_SESSION_HANDLE) -> Result<(), Error> {
         manager_proxy_fn_impl!(
             self,
             ManagerArguments::CloseSession(session),
@@ -200,19 +400,27 @@ impl ManagerProxy {
         )
     }
 
-    pub fn close_all_sessions(&mut self) -> Result<(), ()> {
+    pub fn close_all_sessions(&mut self, slot_id: CK_SLOT_ID) -> Result<(), Error> {
         manager_proxy_fn_impl!(
             self,
-            ManagerArguments::CloseAllSessions,
+            ManagerArguments::CloseAllSessions(slot_id),
             ManagerReturnValue::CloseAllSessions
         )
     }
 
+    pub fn get_session_info(&self, session: CK_SESSION_HANDLE) -> Result<SessionInfo, Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::GetSessionInfo(session),
+            ManagerReturnValue::GetSessionInfo
+        )
+    }
+
     pub fn start_search(
         &mut self,
         session: CK_SESSION_HANDLE,
         attrs: Vec<(CK_ATTRIBUTE_TYPE, Vec<u8>)>,
-    ) -> Result<(), ()> {
+    ) -> Result<(), Error> {
         manager_proxy_fn_impl!(
             self,
             ManagerArguments::StartSearch(session, attrs),
@@ -224,7 +432,7 @@ impl ManagerProxy {
         &mut self,
         session: CK_SESSION_HANDLE,
         max_objects: usize,
-    ) -> Result<Vec<CK_OBJECT_HANDLE>, ()> {
+    ) -> Result<Vec<CK_OBJECT_HANDLE>, Error> {
         manager_proxy_fn_impl!(
             self,
             ManagerArguments::Search(session, max_objects),
@@ -232,7 +440,7 @@ impl ManagerProxy {
         )
     }
 
-    pub fn clear_search(&mut self, session: CK_SESSION_HANDLE) -> Result<(), ()> {
+    pub fn clear_search(&mut self, session: CK_SESSION_HANDLE) -> Result<(), Error> {
         manager_proxy_fn_impl!(
             self,
             ManagerArguments::ClearSearch(session),
@@ -242,12 +450,13 @@ impl ManagerProxy {
 
     pub fn get_attributes(
         &self,
+        session: CK_SESSION_HANDLE,
         object_handle: CK_OBJECT_HANDLE,
         attr_types: Vec<CK_ATTRIBUTE_TYPE>,
-    ) -> Result<Vec<Option<Vec<u8>>>, ()> {
+    ) -> Result<Vec<Result<Vec<u8>, Error>>, Error> {
         manager_proxy_fn_impl!(
             self,
-            ManagerArguments::GetAttributes(object_handle, attr_types,),
+            ManagerArguments::GetAttributes(session, object_handle, attr_types),
             ManagerReturnValue::GetAttributes
         )
     }
@@ -256,11 +465,12 @@ impl ManagerProxy {
         &mut self,
         session: CK_SESSION_HANDLE,
         key_handle: CK_OBJECT_HANDLE,
+        mechanism: CK_MECHANISM_TYPE,
         params: Option<CK_RSA_PKCS_PSS_PARAMS>,
-    ) -> Result<(), ()> {
+    ) -> Result<(), Error> {
         manager_proxy_fn_impl!(
             self,
-            ManagerArguments::StartSign(session, key_handle, params),
+            ManagerArguments::StartSign(session, key_handle, mechanism, params),
             ManagerReturnValue::StartSign
         )
     }
@@ -269,7 +479,7 @@ impl ManagerProxy {
         &self,
         session: CK_SESSION_HANDLE,
         data: Vec<u8>,
-    ) -> Result<usize, ()> {
+    ) -> Result<usize, Error> {
         manager_proxy_fn_impl!(
             self,
             ManagerArguments::GetSignatureLength(session, data),
@@ -277,7 +487,7 @@ impl ManagerProxy {
         )
     }
 
-    pub fn sign(&mut self, session: CK_SESSION_HANDLE, data: Vec<u8>) -> Result<Vec<u8>, ()> {
+    pub fn sign(&mut self, session: CK_SESSION_HANDLE, data: Vec<u8>) -> Result<Vec<u8>, Error> {
         manager_proxy_fn_impl!(
             self,
             ManagerArguments::Sign(session, data),
@@ -285,37 +495,448 @@ impl ManagerProxy {
         )
     }
 
-    pub fn stop(&mut self) -> Result<(), ()> {
+    pub fn sign_update(&mut self, session: CK_SESSION_HANDLE, data: Vec<u8>) -> Result<(), Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::SignUpdate(session, data),
+            ManagerReturnValue::SignUpdate
+        )
+    }
+
+    pub fn sign_final(&mut self, session: CK_SESSION_HANDLE) -> Result<Vec<u8>, Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::SignFinal(session),
+            ManagerReturnValue::SignFinal
+        )
+    }
+
+    pub fn start_decrypt(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        key_handle: CK_OBJECT_HANDLE,
+        mechanism: CK_MECHANISM_TYPE,
+        params: Option<OaepParams>,
+    ) -> Result<(), Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::StartDecrypt(session, key_handle, mechanism, params),
+            ManagerReturnValue::StartDecrypt
+        )
+    }
+
+    pub fn get_decrypted_length(
+        &self,
+        session: CK_SESSION_HANDLE,
+        data: Vec<u8>,
+    ) -> Result<usize, Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::GetDecryptedLength(session, data),
+            ManagerReturnValue::GetDecryptedLength
+        )
+    }
+
+    pub fn decrypt(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        data: Vec<u8>,
+        capacity: usize,
+    ) -> Result<Vec<u8>, Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::Decrypt(session, data, capacity),
+            ManagerReturnValue::Decrypt
+        )
+    }
+
+    pub fn start_verify(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        key_handle: CK_OBJECT_HANDLE,
+        mechanism: CK_MECHANISM_TYPE,
+        params: Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<(), Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::StartVerify(session, key_handle, mechanism, params),
+            ManagerReturnValue::StartVerify
+        )
+    }
+
+    pub fn verify(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        data: Vec<u8>,
+        signature: Vec<u8>,
+    ) -> Result<(), Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::Verify(session, data, signature),
+            ManagerReturnValue::Verify
+        )
+    }
+
+    pub fn verify_update(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        data: Vec<u8>,
+    ) -> Result<(), Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::VerifyUpdate(session, data),
+            ManagerReturnValue::VerifyUpdate
+        )
+    }
+
+    pub fn verify_final(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        signature: Vec<u8>,
+    ) -> Result<(), Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::VerifyFinal(session, signature),
+            ManagerReturnValue::VerifyFinal
+        )
+    }
+
+    pub fn login(&mut self, session: CK_SESSION_HANDLE, pin: String) -> Result<(), Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::Login(session, pin),
+            ManagerReturnValue::Login
+        )
+    }
+
+    pub fn login_context_specific(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        pin: String,
+    ) -> Result<(), Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::LoginContextSpecific(session, pin),
+            ManagerReturnValue::LoginContextSpecific
+        )
+    }
+
+    pub fn logout(&mut self, session: CK_SESSION_HANDLE) -> Result<(), Error> {
+        manager_proxy_fn_impl!(
+            self,
+            ManagerArguments::Logout(session),
+            ManagerReturnValue::Logout
+        )
+    }
+
+    pub fn stop(&mut self) -> Result<(), Error> {
         manager_proxy_fn_impl!(self, ManagerArguments::Stop, ManagerReturnValue::Stop)?;
-        let thread_handle = match self.thread_handle.take() {
+        let thread_handle = match &mut self.runner {
+            ManagerRunner::Thread { thread_handle, .. } => thread_handle.take(),
+            ManagerRunner::Inline(_) => return Ok(()),
+        };
+        let thread_handle = match thread_handle {
             Some(thread_handle) => thread_handle,
             None => {
                 error!("stop should only be called once");
-                return Err(());
+                return Err(Error::General);
             }
         };
         match thread_handle.join() {
             Ok(()) => {}
             Err(e) => {
                 error!("manager thread panicked: {:?}", e);
-                return Err(());
+                return Err(Error::General);
             }
         };
         Ok(())
     }
 }
 
-/// The `Manager` keeps track of the state of this module with respect to the PKCS #11
-/// specification. This includes what sessions are open, which search and sign operations are
-/// ongoing, and what objects are known and by what handle.
-struct Manager {
-    /// A set of sessions. Sessions can be created (opened) and later closed.
-    sessions: BTreeSet<CK_SESSION_HANDLE>,
-    /// A map of searches to PKCS #11 object handles that match those searches.
-    searches: BTreeMap<CK_SESSION_HANDLE, Vec<CK_OBJECT_HANDLE>>,
-    /// A map of sign operations to a pair of the object handle and optionally some params being
-    /// used by each one.
-    signs: BTreeMap<CK_SESSION_HANDLE, (CK_OBJECT_HANDLE, Option<CK_RSA_PKCS_PSS_PARAMS>)>,
+/// What the `Manager` knows about the token in a slot.
+#[derive(Debug, PartialEq)]
+pub struct TokenInfo {
+    /// The label of the token, which comes from its backend.
+    pub label: String,
+    /// The serial number of the token, which comes from its backend or is derived from the slot ID.
+    pub serial_number: String,
+    /// The manufacturer of the token.
+    pub manufacturer: String,
+    /// Whether or not the backend has anything that can't be used until the user logs in.
+    pub login_required: bool,
+    /// How many more incorrect PINs can be given before the PIN is locked (out of
+    /// `MAX_PIN_ATTEMPTS`).
+    pub pin_attempts_remaining: u32,
+}
+
+/// How many incorrect PINs in a row can be given for a token (when logging in, or for a key that
+/// needs the PIN every time it is used) before its PIN is locked. Once locked, no more PINs are
+/// accepted until this module is loaded again.
+pub const MAX_PIN_ATTEMPTS: u32 = 3;
+
+/// What the `Manager` knows about a mechanism in a slot.
+#[derive(Debug, PartialEq)]
+pub struct MechanismInfo {
+    /// The size in bits of the smallest key in the slot the mechanism can be used with (or 0 if
+    /// there is no such key).
+    pub min_key_size: usize,
+    /// The size in bits of the largest key in the slot the mechanism can be used with (or 0 if
+    /// there is no such key).
+    pub max_key_size: usize,
+    /// The `CKF_` flags describing what the mechanism can be used for.
+    pub flags: CK_FLAGS,
+}
+
+/// The mechanisms this module supports, along with the type of key each one is used with, the hash
+/// algorithm of the mechanisms that hash the data before signing it, and what each one can be used
+/// for (`CKF_SIGN` and `CKF_VERIFY`, and/or `CKF_DECRYPT`). Backends that use the `rsa` crate
+/// (those with keys in files) don't decrypt with `CKM_RSA_PKCS`, because the crate doesn't do it in
+/// constant time (RUSTSEC-2023-0071).
+pub const SUPPORTED_MECHANISMS: &[(
+    CK_MECHANISM_TYPE,
+    CK_KEY_TYPE,
+    Option<CK_MECHANISM_TYPE>,
+    CK_FLAGS,
+)] = &[
+    (CKM_ECDSA, CKK_EC, None, CKF_SIGN | CKF_VERIFY),
+    (
+        CKM_ECDSA_SHA256,
+        CKK_EC,
+        Some(CKM_SHA256),
+        CKF_SIGN | CKF_VERIFY,
+    ),
+    (
+        CKM_ECDSA_SHA384,
+        CKK_EC,
+        Some(CKM_SHA384),
+        CKF_SIGN | CKF_VERIFY,
+    ),
+    (
+        CKM_ECDSA_SHA512,
+        CKK_EC,
+        Some(CKM_SHA512),
+        CKF_SIGN | CKF_VERIFY,
+    ),
+    (
+        CKM_RSA_PKCS,
+        CKK_RSA,
+        None,
+        CKF_SIGN | CKF_VERIFY | CKF_DECRYPT,
+    ),
+    (
+        CKM_SHA256_RSA_PKCS,
+        CKK_RSA,
+        Some(CKM_SHA256),
+        CKF_SIGN | CKF_VERIFY,
+    ),
+    (
+        CKM_SHA384_RSA_PKCS,
+        CKK_RSA,
+        Some(CKM_SHA384),
+        CKF_SIGN | CKF_VERIFY,
+    ),
+    (
+        CKM_SHA512_RSA_PKCS,
+        CKK_RSA,
+        Some(CKM_SHA512),
+        CKF_SIGN | CKF_VERIFY,
+    ),
+    (CKM_RSA_PKCS_PSS, CKK_RSA, None, CKF_SIGN | CKF_VERIFY),
+    (
+        CKM_SHA256_RSA_PKCS_PSS,
+        CKK_RSA,
+        Some(CKM_SHA256),
+        CKF_SIGN | CKF_VERIFY,
+    ),
+    (
+        CKM_SHA384_RSA_PKCS_PSS,
+        CKK_RSA,
+        Some(CKM_SHA384),
+        CKF_SIGN | CKF_VERIFY,
+    ),
+    (
+        CKM_SHA512_RSA_PKCS_PSS,
+        CKK_RSA,
+        Some(CKM_SHA512),
+        CKF_SIGN | CKF_VERIFY,
+    ),
+    (CKM_RSA_PKCS_OAEP, CKK_RSA, None, CKF_DECRYPT),
+];
+
+/// Returns the type of key the given mechanism is used with, the hash algorithm it uses (if any),
+/// and what it can be used for, if it is supported.
+fn get_mechanism_properties(
+    mechanism: CK_MECHANISM_TYPE,
+) -> Option<(CK_KEY_TYPE, Option<CK_MECHANISM_TYPE>, CK_FLAGS)> {
+    SUPPORTED_MECHANISMS
+        .iter()
+        .find(|(supported_mechanism, _, _, _)| *supported_mechanism == mechanism)
+        .map(|(_, key_type, hash_algorithm, flags)| (*key_type, *hash_algorithm, *flags))
+}
+
+/// Returns the PKCS #11 type of the given key.
+fn get_key_type(key: &Key) -> CK_KEY_TYPE {
+    match key.key_type_enum() {
+        KeyType::RSA => CKK_RSA,
+        KeyType::EC(_) => CKK_EC,
+    }
+}
+
+/// What the `Manager` knows about a session.
+#[derive(Debug, PartialEq)]
+pub struct SessionInfo {
+    /// The slot the session is open on.
+    pub slot_id: CK_SLOT_ID,
+    /// Whether or not the session is a read/write session.
+    pub read_write: bool,
+    /// Whether or not the user is logged in to the token of the session's slot.
+    pub logged_in: bool,
+    /// Whether or not the session has an active search operation.
+    pub search_active: bool,
+    /// Whether or not the session has an active sign operation.
+    pub sign_active: bool,
+    /// Whether or not the session has an active decrypt operation.
+    pub decrypt_active: bool,
+    /// Whether or not the session has an active verify operation.
+    pub verify_active: bool,
+}
+
+/// An open session and the operations that are active in it. Each session has at most one search
+/// operation, one sign operation, one decrypt operation, and one verify operation at a time.
+struct Session {
+    /// The slot the session is open on.
+    slot_id: CK_SLOT_ID,
+    /// Whether or not the session was opened with `CKF_RW_SESSION`. This module never modifies
+    /// tokens, but applications may ask for read/write sessions anyway.
+    read_write: bool,
+    /// If a search is active, the handles of the objects that match it that haven't been returned
+    /// yet.
+    search: Option<Vec<CK_OBJECT_HANDLE>>,
+    /// The sign operation, if one is active.
+    sign: Option<SignOperation>,
+    /// The decrypt operation, if one is active.
+    decrypt: Option<DecryptOperation>,
+    /// The verify operation, if one is active.
+    verify: Option<VerifyOperation>,
+}
+
+/// A sign operation that has been started in a session.
+struct SignOperation {
+    /// The handle of the key being used.
+    key_handle: CK_OBJECT_HANDLE,
+    /// The params of the mechanism, if it is an RSA PSS mechanism.
+    params: Option<CK_RSA_PKCS_PSS_PARAMS>,
+    /// What becomes of the data given to the operation before it is signed.
+    input: SignInput,
+    /// Whether or not data has been given to the operation with `sign_update`, in which case it
+    /// can only be finished with `sign_final`.
+    multipart: bool,
+    /// Whether or not the key needs the PIN every time it is used (`CKA_ALWAYS_AUTHENTICATE`) and
+    /// it hasn't been given with `login_context_specific` yet, in which case the operation can't
+    /// be finished.
+    context_specific_login_needed: bool,
+}
+
+/// A decrypt operation that has been started in a session.
+struct DecryptOperation {
+    /// The handle of the key being used.
+    key_handle: CK_OBJECT_HANDLE,
+    /// The params of the mechanism, if it is `CKM_RSA_PKCS_OAEP`.
+    params: Option<OaepParams>,
+    /// If the decrypted data didn't fit in the application's buffer, the data that was decrypted
+    /// and what it decrypted to, so it doesn't have to be decrypted again when the application
+    /// tries again with a bigger buffer.
+    decrypted: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// A verify operation that has been started in a session.
+struct VerifyOperation {
+    /// The handle of the public key being used.
+    key_handle: CK_OBJECT_HANDLE,
+    /// The params of the mechanism, if it is an RSA PSS mechanism.
+    params: Option<CK_RSA_PKCS_PSS_PARAMS>,
+    /// What becomes of the data given to the operation before the signature is verified. This is
+    /// never `SignInput::Message`, because signatures are verified in software.
+    input: SignInput,
+    /// Whether or not data has been given to the operation with `verify_update`, in which case it
+    /// can only be finished with `verify_final`.
+    multipart: bool,
+}
+
+/// How the data given to a sign operation is turned into what the backend signs (or, for a verify
+/// operation, what the signature is verified against).
+#[derive(Clone)]
+enum SignInput {
+    /// The data is signed as-is (it is a hash or, for RSA PKCS #1 v1.5, a DigestInfo). The data has
+    /// to be given in one part.
+    Raw,
+    /// The data is hashed, and the hash (or, for RSA PKCS #1 v1.5, a DigestInfo of it) is signed.
+    /// `digest_info` is whether or not a DigestInfo is signed.
+    Hash {
+        hasher: Box<Hasher>,
+        digest_info: bool,
+    },
+    /// The data is collected until the operation is finished, because the key can only sign whole
+    /// messages. The message is hashed with the given algorithm as part of signing it.
+    Message(CK_MECHANISM_TYPE, Vec<u8>),
+}
+
+/// What the backend is asked to sign to finish a sign operation.
+enum SignData {
+    /// A hash (or a DigestInfo) to sign with `Backend::sign`.
+    Hash(Vec<u8>),
+    /// A message to sign with `Backend::sign_message`, along with the hash algorithm to use.
+    Message(CK_MECHANISM_TYPE, Vec<u8>),
+}
+
+impl SignInput {
+    fn update(&mut self, part: &[u8]) -> Result<(), Error> {
+        match self {
+            // The single-part mechanisms (e.g. CKM_ECDSA) can't be used with C_SignUpdate.
+            SignInput::Raw => return Err(Error::MechanismInvalid),
+            SignInput::Hash { hasher, .. } => hasher.update(part),
+            SignInput::Message(_, message) => message.extend_from_slice(part),
+        }
+        Ok(())
+    }
+
+    /// Adds the given final part of the data (which may be empty) and returns what the backend has
+    /// to sign.
+    fn finish(self, part: &[u8]) -> Result<SignData, Error> {
+        match self {
+            SignInput::Raw => Ok(SignData::Hash(part.to_vec())),
+            SignInput::Hash {
+                mut hasher,
+                digest_info,
+            } => {
+                hasher.update(part);
+                let hash_algorithm = hasher.algorithm();
+                let hash = hasher.finalize();
+                if digest_info {
+                    Ok(SignData::Hash(encode_digest_info(hash_algorithm, &hash)?))
+                } else {
+                    Ok(SignData::Hash(hash))
+                }
+            }
+            SignInput::Message(hash_algorithm, mut message) => {
+                message.extend_from_slice(part);
+                Ok(SignData::Message(hash_algorithm, message))
+            }
+        }
+    }
+}
+
+/// A slot exposes the certificates and keys of one backend as a token. Each slot has its own
+/// objects.
+struct Slot {
+    /// The backend that provides the certificates and keys this slot exposes.
+    backend: Box<dyn Backend>,
+    /// Whether or not the user has logged in to the token. As specified by PKCS #11, this is shared
+    /// by all of the sessions open on the slot.
+    logged_in: bool,
+    /// How many incorrect PINs have been given in a row. The PIN is locked once this reaches
+    /// `MAX_PIN_ATTEMPTS`.
+    failed_pin_attempts: u32,
     /// A map of object handles to the underlying objects.
     objects: BTreeMap<CK_OBJECT_HANDLE, Object>,
     /// A set of certificate identifiers (not the same as handles).
@@ -323,125 +944,496 @@ struct Manager {
     /// A set of key identifiers (not the same as handles). For each id in this set, there should be
     /// a corresponding identical id in the `cert_ids` set, and vice-versa.
     key_ids: BTreeSet<Vec<u8>>,
-    /// The next session handle to hand out.
-    next_session: CK_SESSION_HANDLE,
-    /// The next object handle to hand out.
-    next_handle: CK_OBJECT_HANDLE,
+    /// A set of public key identifiers (not the same as handles). Public keys are derived from
+    /// certificates, so each id in this set is also in the `cert_ids` set (but certificates whose
+    /// public key can't be read have no public key).
+    public_key_ids: BTreeSet<Vec<u8>>,
     /// The last time the implementation looked for new objects in the backend.
-    /// The implementation does this search no more than once every 3 seconds.
+    /// The implementation does this search no more than once every rescan interval.
     last_scan_time: Option<Instant>,
+    /// The identifiers of the keys the backend returned when it was last asked for its identities,
+    /// or `None` if it hasn't been asked yet.
+    identity_ids: Option<BTreeSet<Vec<u8>>>,
+    /// Whether or not the identities in this slot have changed since the last slot event.
+    event_pending: bool,
+    /// If the backend's identities come from files, this watches their directories so the backend
+    /// only has to be asked for its identities when they change.
+    watcher: Option<FileWatcher>,
+    /// What identifies the token regardless of the slot it is in: its label and its serial number
+    /// (if the backend has one). Object handles are derived from this.
+    token_identity: Vec<u8>,
 }
 
-impl Manager {
-    pub fn new() -> Manager {
-        let mut manager = Manager {
-            sessions: BTreeSet::new(),
-            searches: BTreeMap::new(),
-            signs: BTreeMap::new(),
+impl Slot {
+    fn new(backend: Box<dyn Backend>) -> Slot {
+        let watched_directories = backend.watched_directories();
+        let watcher = if watched_directories.is_empty() {
+            None
+        } else {
+            FileWatcher::new(&watched_directories).ok()
+        };
+        let mut token_identity = backend.token_label().into_bytes();
+        if let Some(serial_number) = backend.token_serial_number() {
+            token_identity.push(0);
+            token_identity.extend_from_slice(serial_number.as_bytes());
+        }
+        Slot {
+            backend,
+            logged_in: false,
+            failed_pin_attempts: 0,
             objects: BTreeMap::new(),
             cert_ids: BTreeSet::new(),
             key_ids: BTreeSet::new(),
-            next_session: 1,
-            next_handle: 1,
+            public_key_ids: BTreeSet::new(),
             last_scan_time: None,
-        };
-        manager.maybe_find_new_objects();
-        manager
-    }
-
-    /// When a new `Manager` is created and when a new session is opened (provided at least 3
-    /// seconds have elapsed since the last session was opened), this searches for certificates and
-    /// keys to expose. We de-duplicate previously-found certificates and keys by / keeping track of
-    /// their IDs.
-    fn maybe_find_new_objects(&mut self) {
-        let now = Instant::now();
-        match self.last_scan_time {
-            Some(last_scan_time) => {
-                if now.duration_since(last_scan_time) < Duration::new(3, 0) {
-                    return;
-                }
-            }
-            None => {}
-        }
-        self.last_scan_time = Some(now);
-        let objects = list_objects();
-        debug!("found {} objects", objects.len());
-        for object in objects {
-            match &object {
-                Object::Cert(cert) => {
-                    if self.cert_ids.contains(cert.id()) {
-                        continue;
-                    }
-                    self.cert_ids.insert(cert.id().to_vec());
-                    let handle = self.get_next_handle();
-                    self.objects.insert(handle, object);
-                }
-                Object::Key(key) => {
-                    if self.key_ids.contains(key.id()) {
-                        continue;
-                    }
-                    self.key_ids.insert(key.id().to_vec());
-                    let handle = self.get_next_handle();
-                    self.objects.insert(handle, object);
-                }
-            }
+            identity_ids: None,
+            event_pending: false,
+            watcher,
+            token_identity,
         }
     }
 
-    pub fn open_session(&mut self) -> Result<CK_SESSION_HANDLE, ()> {
-        self.maybe_find_new_objects();
-        let next_session = self.next_session;
-        self.next_session += 1;
-        self.sessions.insert(next_session);
-        Ok(next_session)
+    fn pin_locked(&self) -> bool {
+        self.failed_pin_attempts >= MAX_PIN_ATTEMPTS
     }
 
-    pub fn close_session(&mut self, session: CK_SESSION_HANDLE) -> Result<(), ()> {
-        if self.sessions.remove(&session) {
-            Ok(())
-        } else {
-            Err(())
+    /// Keeps count of incorrect PINs, given the result of giving a PIN to the backend. A correct
+    /// PIN resets the count.
+    fn note_pin_result(&mut self, result: &Result<(), Error>) {
+        match result {
+            Ok(()) => self.failed_pin_attempts = 0,
+            Err(Error::PinIncorrect) => self.failed_pin_attempts += 1,
+            // The backend (e.g. a smart card) may have a PIN counter of its own.
+            Err(Error::PinLocked) => self.failed_pin_attempts = MAX_PIN_ATTEMPTS,
+            Err(_) => {}
         }
     }
 
-    pub fn close_all_sessions(&mut self) -> Result<(), ()> {
-        self.sessions.clear();
-        Ok(())
-    }
-
-    fn get_next_handle(&mut self) -> CK_OBJECT_HANDLE {
-        let next_handle = self.next_handle;
-        self.next_handle += 1;
-        next_handle
+    /// Returns whether or not the backend may have new identities. If the slot's directories are
+    /// being watched, this is only the case if they have changed.
+    fn may_have_changed(&mut self) -> bool {
+        let watcher = match &mut self.watcher {
+            Some(watcher) => watcher,
+            None => return true,
+        };
+        match watcher.poll() {
+            Ok(changed) => {
+                if changed {
+                    // The backend has to be asked for its identities right away.
+                    self.last_scan_time = None;
+                }
+                changed
+            }
+            Err(()) => {
+                // The watcher doesn't work any more (e.g. a directory was removed), so fall back
+                // to asking the backend for its identities periodically.
+                self.watcher = None;
+                self.last_scan_time = None;
+                true
+            }
+        }
+    }
+}
+
+/// Determines the handle of an object with the given class and id in the given slot. Handles are
+/// derived from a hash of these (and of the slot's token identity, rather than of the slot ID,
+/// which depends on which other backends are available), so an object gets the same handle every
+/// time it is found, even across different loads of this module. If the derived handle already
+/// belongs to a different object (which is unlikely, but possible, e.g. if two tokens look the
+/// same), a counter is included in the hash until a handle is found that is unused (or belongs to
+/// this object).
+fn get_object_handle(
+    handle_owners: &mut BTreeMap<CK_OBJECT_HANDLE, (CK_SLOT_ID, CK_OBJECT_CLASS, Vec<u8>)>,
+    slot_id: CK_SLOT_ID,
+    token_identity: &[u8],
+    class: CK_OBJECT_CLASS,
+    id: &[u8],
+) -> CK_OBJECT_HANDLE {
+    let owner = (slot_id, class, id.to_vec());
+    let mut attempt: u32 = 0;
+    loop {
+        let mut hasher = Sha256::new();
+        hasher.update((token_identity.len() as u64).to_be_bytes());
+        hasher.update(token_identity);
+        hasher.update((class as u64).to_be_bytes());
+        hasher.update(id);
+        if attempt > 0 {
+            hasher.update(attempt.to_be_bytes());
+        }
+        attempt += 1;
+        let mut handle_bytes = [0; 8];
+        handle_bytes.copy_from_slice(&hasher.finalize()[..8]);
+        // On platforms where handles are 32 bits, this keeps only the low 32 bits of the hash.
+        let handle = u64::from_be_bytes(handle_bytes) as CK_OBJECT_HANDLE;
+        if handle == CK_INVALID_HANDLE {
+            continue;
+        }
+        match handle_owners.get(&handle) {
+            Some(existing_owner) if *existing_owner == owner => return handle,
+            Some(_) => {
+                debug!("object handle collision for {:?}", owner);
+            }
+            None => {
+                handle_owners.insert(handle, owner);
+                return handle;
+            }
+        }
+    }
+}
+
+/// The `Manager` keeps track of the state of this module with respect to the PKCS #11
+/// specification. This includes what slots there are, what sessions are open on each one, which
+/// search and sign operations are ongoing, and what objects are known and by what handle.
+struct Manager {
+    /// A map of slot IDs to slots. There is one slot for each backend.
+    slots: BTreeMap<CK_SLOT_ID, Slot>,
+    /// A map of session handles to the sessions they identify, across all slots.
+    sessions: BTreeMap<CK_SESSION_HANDLE, Session>,
+    /// The next session handle to hand out. Session handles are unique across slots.
+    next_session: CK_SESSION_HANDLE,
+    /// A map of every object handle that has been handed out to the slot, class, and id of the
+    /// object it was handed out for. Object handles are unique across slots, and a handle is never
+    /// used for a different object, even after the object it was for has been removed.
+    handle_owners: BTreeMap<CK_OBJECT_HANDLE, (CK_SLOT_ID, CK_OBJECT_CLASS, Vec<u8>)>,
+    /// How often a slot's backend is asked for its identities (when a session is opened or when
+    /// looking for slot events), unless the slot's directories are being watched for changes.
+    rescan_interval: Duration,
+}
+
+impl Manager {
+    pub fn new(backends: Vec<Box<dyn Backend>>, rescan_interval: Duration) -> Manager {
+        let mut manager = Manager {
+            slots: BTreeMap::new(),
+            sessions: BTreeMap::new(),
+            next_session: 1,
+            handle_owners: BTreeMap::new(),
+            rescan_interval,
+        };
+        for (slot_id, backend) in (1..).zip(backends) {
+            manager.slots.insert(slot_id, Slot::new(backend));
+            manager.maybe_find_new_objects(slot_id);
+        }
+        manager
+    }
+
+    /// When a new `Manager` is created and when a new session is opened on a slot or slot events are
+    /// checked for (provided the rescan interval has elapsed since the slot was last scanned), this
+    /// searches for certificates and keys to expose in the slot. We de-duplicate previously-found
+    /// certificates and keys by keeping track of their IDs. Objects the backend no longer has are
+    /// removed, along with their handles in any ongoing searches (handles are never used for other
+    /// objects, so anything still referring to them will fail). If the backend's identities are
+    /// different from those it had the last time, a slot event is noted.
+    fn maybe_find_new_objects(&mut self, slot_id: CK_SLOT_ID) {
+        let handle_owners = &mut self.handle_owners;
+        let rescan_interval = self.rescan_interval;
+        let slot = match self.slots.get_mut(&slot_id) {
+            Some(slot) => slot,
+            None => return,
+        };
+        let now = Instant::now();
+        if let Some(last_scan_time) = slot.last_scan_time {
+            if now.duration_since(last_scan_time) < rescan_interval {
+                return;
+            }
+        }
+        slot.last_scan_time = Some(now);
+        let identities = slot.backend.list_identities();
+        debug!("found {} identities in slot {}", identities.len(), slot_id);
+        let identity_ids = identities
+            .iter()
+            .map(|(_, key)| key.id().to_vec())
+            .collect();
+        if let Some(previous_identity_ids) = slot.identity_ids.replace(identity_ids) {
+            if slot.identity_ids.as_ref() != Some(&previous_identity_ids) {
+                debug!("identities in slot {} have changed", slot_id);
+                slot.event_pending = true;
+            }
+        }
+        let cert_ids: BTreeSet<Vec<u8>> = identities
+            .iter()
+            .map(|(cert, _)| cert.id().to_vec())
+            .collect();
+        let key_ids: BTreeSet<Vec<u8>> = identities
+            .iter()
+            .map(|(_, key)| key.id().to_vec())
+            .collect();
+        let removed_handles: BTreeSet<CK_OBJECT_HANDLE> = slot
+            .objects
+            .iter()
+            .filter(|(_, object)| match object {
+                Object::Cert(cert) => !cert_ids.contains(cert.id()),
+                Object::Key(key) => !key_ids.contains(key.id()),
+                Object::PublicKey(public_key) => !cert_ids.contains(public_key.id()),
+            })
+            .map(|(handle, _)| *handle)
+            .collect();
+        if !removed_handles.is_empty() {
+            debug!(
+                "removing {} objects from slot {}",
+                removed_handles.len(),
+                slot_id
+            );
+            slot.objects
+                .retain(|handle, _| !removed_handles.contains(handle));
+            slot.cert_ids.retain(|id| cert_ids.contains(id));
+            slot.key_ids.retain(|id| key_ids.contains(id));
+            slot.public_key_ids.retain(|id| cert_ids.contains(id));
+        }
+        for (cert, key) in identities {
+            if !slot.public_key_ids.contains(cert.id()) {
+                match PublicKey::new(&cert) {
+                    Ok(public_key) => {
+                        slot.public_key_ids.insert(public_key.id().to_vec());
+                        let handle = get_object_handle(
+                            handle_owners,
+                            slot_id,
+                            &slot.token_identity,
+                            CKO_PUBLIC_KEY,
+                            public_key.id(),
+                        );
+                        slot.objects.insert(handle, Object::PublicKey(public_key));
+                    }
+                    Err(()) => error!("couldn't read the public key of a certificate"),
+                }
+            }
+            if !slot.cert_ids.contains(cert.id()) {
+                slot.cert_ids.insert(cert.id().to_vec());
+                let handle = get_object_handle(
+                    handle_owners,
+                    slot_id,
+                    &slot.token_identity,
+                    CKO_CERTIFICATE,
+                    cert.id(),
+                );
+                slot.objects.insert(handle, Object::Cert(cert));
+            }
+            if !slot.key_ids.contains(key.id()) {
+                slot.key_ids.insert(key.id().to_vec());
+                let handle = get_object_handle(
+                    handle_owners,
+                    slot_id,
+                    &slot.token_identity,
+                    CKO_PRIVATE_KEY,
+                    key.id(),
+                );
+                slot.objects.insert(handle, Object::Key(key));
+            }
+        }
+        if !removed_handles.is_empty() {
+            for session in self.sessions.values_mut() {
+                if let Some(search) = &mut session.search {
+                    search.retain(|handle| !removed_handles.contains(handle));
+                }
+            }
+        }
+    }
+
+    /// Returns the ID and the slot of the given session.
+    fn get_session_slot(&self, session: CK_SESSION_HANDLE) -> Result<(CK_SLOT_ID, &Slot), Error> {
+        let slot_id = self
+            .sessions
+            .get(&session)
+            .ok_or(Error::SessionHandleInvalid)?
+            .slot_id;
+        let slot = self.slots.get(&slot_id).ok_or(Error::General)?;
+        Ok((slot_id, slot))
+    }
+
+    /// Returns the given session, along with its slot.
+    fn get_session_mut(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+    ) -> Result<(&mut Session, &Slot), Error> {
+        let session = self
+            .sessions
+            .get_mut(&session)
+            .ok_or(Error::SessionHandleInvalid)?;
+        let slot = self.slots.get(&session.slot_id).ok_or(Error::General)?;
+        Ok((session, slot))
+    }
+
+    pub fn get_slot_ids(&self) -> Result<Vec<CK_SLOT_ID>, Error> {
+        Ok(self.slots.keys().cloned().collect())
+    }
+
+    pub fn get_token_info(&self, slot_id: CK_SLOT_ID) -> Result<TokenInfo, Error> {
+        let slot = self.slots.get(&slot_id).ok_or(Error::SlotIdInvalid)?;
+        Ok(TokenInfo {
+            label: slot.backend.token_label(),
+            serial_number: slot
+                .backend
+                .token_serial_number()
+                .unwrap_or_else(|| format!("{:016}", slot_id)),
+            manufacturer: slot
+                .backend
+                .token_manufacturer()
+                .unwrap_or_else(|| String::from(DEFAULT_MANUFACTURER)),
+            login_required: slot.backend.login_required(),
+            pin_attempts_remaining: MAX_PIN_ATTEMPTS.saturating_sub(slot.failed_pin_attempts),
+        })
+    }
+
+    /// Returns the range of sizes of the keys in the slot that the given mechanism can be used with,
+    /// along with what it can be used for. Mechanisms are performed by hardware if the slot's
+    /// backend uses hardware.
+    pub fn get_mechanism_info(
+        &self,
+        slot_id: CK_SLOT_ID,
+        mechanism: CK_MECHANISM_TYPE,
+    ) -> Result<MechanismInfo, Error> {
+        let slot = self.slots.get(&slot_id).ok_or(Error::SlotIdInvalid)?;
+        let (key_type, _, functions) =
+            get_mechanism_properties(mechanism).ok_or(Error::MechanismInvalid)?;
+        let key_sizes: Vec<usize> = slot
+            .objects
+            .values()
+            .filter_map(|object| match object {
+                Object::Key(key) if get_key_type(key) == key_type => Some(key.key_size()),
+                _ => None,
+            })
+            .collect();
+        let mut flags = functions;
+        if key_type == CKK_EC {
+            flags |= CKF_EC_F_P | CKF_EC_NAMEDCURVE | CKF_EC_UNCOMPRESS;
+        }
+        if slot.backend.is_hardware() {
+            flags |= CKF_HW;
+        }
+        Ok(MechanismInfo {
+            min_key_size: key_sizes.iter().min().cloned().unwrap_or(0),
+            max_key_size: key_sizes.iter().max().cloned().unwrap_or(0),
+            flags,
+        })
+    }
+
+    /// Checks each slot for changes to its identities and returns the ID of a slot whose identities
+    /// have changed since the last time it was returned (if any). Slots with watched directories
+    /// are only scanned if their directories have changed, and other slots are scanned at most once
+    /// every rescan interval.
+    pub fn get_slot_event(&mut self) -> Result<Option<CK_SLOT_ID>, Error> {
+        let slot_ids: Vec<CK_SLOT_ID> = self.slots.keys().cloned().collect();
+        for slot_id in slot_ids {
+            let may_have_changed = match self.slots.get_mut(&slot_id) {
+                Some(slot) => slot.may_have_changed(),
+                None => false,
+            };
+            if may_have_changed {
+                self.maybe_find_new_objects(slot_id);
+            }
+        }
+        for (slot_id, slot) in self.slots.iter_mut() {
+            if slot.event_pending {
+                slot.event_pending = false;
+                return Ok(Some(*slot_id));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn open_session(
+        &mut self,
+        slot_id: CK_SLOT_ID,
+        read_write: bool,
+    ) -> Result<CK_SESSION_HANDLE, Error> {
+        if !self.slots.contains_key(&slot_id) {
+            return Err(Error::SlotIdInvalid);
+        }
+        self.maybe_find_new_objects(slot_id);
+        let next_session = self.next_session;
+        self.next_session += 1;
+        self.sessions.insert(
+            next_session,
+            Session {
+                slot_id,
+                read_write,
+                search: None,
+                sign: None,
+                decrypt: None,
+                verify: None,
+            },
+        );
+        Ok(next_session)
+    }
+
+    /// Closes the given session, which ends any operations that are active in it. If it was the
+    /// last session in its slot, the slot is logged out.
+    pub fn close_session(&mut self, session: CK_SESSION_HANDLE) -> Result<(), Error> {
+        let slot_id = match self.sessions.remove(&session) {
+            Some(session) => session.slot_id,
+            None => return Err(Error::SessionHandleInvalid),
+        };
+        self.maybe_logout_closed_slot(slot_id);
+        Ok(())
+    }
+
+    pub fn close_all_sessions(&mut self, slot_id: CK_SLOT_ID) -> Result<(), Error> {
+        if !self.slots.contains_key(&slot_id) {
+            return Err(Error::SlotIdInvalid);
+        }
+        self.sessions
+            .retain(|_, session| session.slot_id != slot_id);
+        self.maybe_logout_closed_slot(slot_id);
+        Ok(())
+    }
+
+    /// PKCS #11 specifies that closing the last session in a slot logs the slot out.
+    fn maybe_logout_closed_slot(&mut self, slot_id: CK_SLOT_ID) {
+        if self
+            .sessions
+            .values()
+            .all(|session| session.slot_id != slot_id)
+        {
+            self.logout_slot(slot_id);
+        }
+    }
+
+    /// Returns the state of the given session.
+    pub fn get_session_info(&self, session: CK_SESSION_HANDLE) -> Result<SessionInfo, Error> {
+        let (slot_id, slot) = self.get_session_slot(session)?;
+        let session = self
+            .sessions
+            .get(&session)
+            .ok_or(Error::SessionHandleInvalid)?;
+        Ok(SessionInfo {
+            slot_id,
+            read_write: session.read_write,
+            logged_in: slot.logged_in,
+            search_active: session.search.is_some(),
+            sign_active: session.sign.is_some(),
+            decrypt_active: session.decrypt.is_some(),
+            verify_active: session.verify.is_some(),
+        })
     }
 
     /// PKCS #11 specifies that search operations happen in three phases: setup, get any matches
     /// (this part may be repeated if the caller uses a small buffer), and end. This implementation
     /// does all of the work up front and gathers all matching objects during setup and retains them
-    /// until they are retrieved and consumed via `search`.
+    /// until they are retrieved and consumed via `search`. Only the objects in the session's slot
+    /// are searched.
     pub fn start_search(
         &mut self,
         session: CK_SESSION_HANDLE,
         attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)],
-    ) -> Result<(), ()> {
-        if self.searches.contains_key(&session) {
-            return Err(());
+    ) -> Result<(), Error> {
+        let (session, slot) = self.get_session_mut(session)?;
+        if session.search.is_some() {
+            return Err(Error::OperationActive);
         }
         // If the search is for an attribute we don't support, no objects will match. This check
         // saves us having to look through all of our objects.
         for (attr, _) in attrs {
             if !SUPPORTED_ATTRIBUTES.contains(attr) {
-                self.searches.insert(session, Vec::new());
+                session.search = Some(Vec::new());
                 return Ok(());
             }
         }
         let mut handles = Vec::new();
-        for (handle, object) in &self.objects {
+        for (handle, object) in &slot.objects {
             if object.matches(attrs) {
                 handles.push(*handle);
             }
         }
-        self.searches.insert(session, handles);
+        session.search = Some(handles);
         Ok(())
     }
 
@@ -452,11 +1444,12 @@ impl Manager {
         &mut self,
         session: CK_SESSION_HANDLE,
         max_objects: usize,
-    ) -> Result<Vec<CK_OBJECT_HANDLE>, ()> {
+    ) -> Result<Vec<CK_OBJECT_HANDLE>, Error> {
+        let (session, _) = self.get_session_mut(session)?;
         if max_objects == 0 {
-            return Err(());
+            return Err(Error::ArgumentsBad);
         }
-        match self.searches.get_mut(&session) {
+        match &mut session.search {
             Some(search) => {
                 let split_at = if max_objects >= search.len() {
                     0
@@ -470,33 +1463,44 @@ impl Manager {
                         to_return.len(),
                         max_objects
                     );
-                    return Err(());
+                    return Err(Error::General);
                 }
                 Ok(to_return)
             }
-            None => Err(()),
+            None => Err(Error::OperationNotInitialized),
         }
     }
 
-    pub fn clear_search(&mut self, session: CK_SESSION_HANDLE) -> Result<(), ()> {
-        self.searches.remove(&session);
-        Ok(())
+    pub fn clear_search(&mut self, session: CK_SESSION_HANDLE) -> Result<(), Error> {
+        let (session, _) = self.get_session_mut(session)?;
+        match session.search.take() {
+            Some(_) => Ok(()),
+            None => Err(Error::OperationNotInitialized),
+        }
     }
 
+    /// Returns the value of each of the given attributes of the given object, or why it can't be
+    /// returned (the object doesn't have the attribute, or its value is sensitive).
     pub fn get_attributes(
         &self,
+        session: CK_SESSION_HANDLE,
         object_handle: CK_OBJECT_HANDLE,
         attr_types: Vec<CK_ATTRIBUTE_TYPE>,
-    ) -> Result<Vec<Option<Vec<u8>>>, ()> {
-        let object = match self.objects.get(&object_handle) {
+    ) -> Result<Vec<Result<Vec<u8>, Error>>, Error> {
+        let (_, slot) = self.get_session_slot(session)?;
+        let object = match slot.objects.get(&object_handle) {
             Some(object) => object,
-            None => return Err(()),
+            None => return Err(Error::ObjectHandleInvalid),
         };
         let mut results = Vec::with_capacity(attr_types.len());
         for attr_type in attr_types {
-            let result = match object.get_attribute(attr_type) {
-                Some(value) => Some(value.to_owned()),
-                None => None,
+            let result = if object.is_sensitive_attribute(attr_type) {
+                Err(Error::AttributeSensitive)
+            } else {
+                object
+                    .get_attribute(attr_type)
+                    .map(|value| value.to_owned())
+                    .ok_or(Error::AttributeTypeInvalid)
             };
             results.push(result);
         }
@@ -504,53 +1508,2652 @@ impl Manager {
     }
 
     /// The way NSS uses PKCS #11 to sign data happens in two phases: setup and sign. This
-    /// implementation makes a note of which key is to be used (if it exists) during setup. When the
-    /// caller finishes with the sign operation, this implementation retrieves the key handle and
-    /// performs the signature.
+    /// implementation makes a note of which key is to be used (if it exists in the session's slot
+    /// and can be used with the given mechanism) during setup. When the caller finishes with the
+    /// sign operation, this implementation retrieves the key handle and performs the signature.
+    /// With the mechanisms that hash the data before signing it (e.g. `CKM_SHA256_RSA_PKCS`), the
+    /// data may also be given in parts with `sign_update` and the signature performed with
+    /// `sign_final`. The data is hashed as it is given, unless the key can only sign whole messages,
+    /// in which case the data is kept until the signature is performed.
     pub fn start_sign(
         &mut self,
         session: CK_SESSION_HANDLE,
         key_handle: CK_OBJECT_HANDLE,
+        mechanism: CK_MECHANISM_TYPE,
         params: Option<CK_RSA_PKCS_PSS_PARAMS>,
-    ) -> Result<(), ()> {
-        if self.signs.contains_key(&session) {
-            return Err(());
+    ) -> Result<(), Error> {
+        let (session, slot) = self.get_session_mut(session)?;
+        if session.sign.is_some() {
+            return Err(Error::OperationActive);
+        }
+        let (key_type, hash_algorithm, functions) =
+            get_mechanism_properties(mechanism).ok_or(Error::MechanismInvalid)?;
+        if functions & CKF_SIGN == 0 {
+            return Err(Error::MechanismInvalid);
         }
-        match self.objects.get(&key_handle) {
-            Some(Object::Key(_)) => {}
-            _ => return Err(()),
+        let key = match slot.objects.get(&key_handle) {
+            Some(Object::Key(key)) => key,
+            _ => return Err(Error::KeyHandleInvalid),
+        };
+        if get_key_type(key) != key_type {
+            return Err(Error::KeyTypeInconsistent);
+        }
+        let input = match hash_algorithm {
+            None => SignInput::Raw,
+            Some(hash_algorithm) => {
+                // The hash algorithm of the PSS params has to be the one the mechanism uses.
+                if let Some(params) = &params {
+                    if params.hashAlg != hash_algorithm {
+                        return Err(Error::MechanismParamInvalid);
+                    }
+                }
+                if params.is_none() && !slot.backend.signs_hashes(key) {
+                    SignInput::Message(hash_algorithm, Vec::new())
+                } else {
+                    SignInput::Hash {
+                        hasher: Box::new(
+                            Hasher::new(hash_algorithm).ok_or(Error::MechanismInvalid)?,
+                        ),
+                        digest_info: key_type == CKK_RSA && params.is_none(),
+                    }
+                }
+            }
         };
-        self.signs.insert(session, (key_handle, params));
+        session.sign = Some(SignOperation {
+            key_handle,
+            params,
+            input,
+            multipart: false,
+            context_specific_login_needed: key.needs_context_specific_login(),
+        });
         Ok(())
     }
 
+    /// Returns the length of the signature that would be produced by finishing the session's sign
+    /// operation with the given (final part of the) data.
     pub fn get_signature_length(
         &self,
         session: CK_SESSION_HANDLE,
         data: &[u8],
-    ) -> Result<usize, ()> {
-        let (key_handle, params) = match self.signs.get(&session) {
-            Some((key_handle, params)) => (key_handle, params),
-            None => return Err(()),
+    ) -> Result<usize, Error> {
+        let (_, slot) = self.get_session_slot(session)?;
+        let sign = match &self.sessions[&session].sign {
+            Some(sign) => sign,
+            None => return Err(Error::OperationNotInitialized),
         };
-        let key = match self.objects.get(&key_handle) {
+        let key = match slot.objects.get(&sign.key_handle) {
             Some(Object::Key(key)) => key,
-            _ => return Err(()),
+            _ => return Err(Error::KeyHandleInvalid),
+        };
+        match sign.input.clone().finish(data)? {
+            SignData::Hash(hash) => slot.backend.get_signature_length(key, &hash, &sign.params),
+            SignData::Message(_, message) => {
+                slot.backend
+                    .get_signature_length(key, &message, &sign.params)
+            }
+        }
+    }
+
+    /// Signs the given data in one part with the session's sign operation, which finishes it.
+    pub fn sign(&mut self, session: CK_SESSION_HANDLE, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if let Some(sign) = &self.get_session_mut(session)?.0.sign {
+            // An operation that was given data with sign_update has to be finished with
+            // sign_final.
+            if sign.multipart {
+                return Err(Error::OperationActive);
+            }
+        }
+        self.finish_sign(session, data)
+    }
+
+    /// Gives the next part of the data to be signed to the session's sign operation. If this fails,
+    /// the operation is finished.
+    pub fn sign_update(&mut self, session: CK_SESSION_HANDLE, data: &[u8]) -> Result<(), Error> {
+        let (session, _) = self.get_session_mut(session)?;
+        let sign = match &mut session.sign {
+            Some(sign) => sign,
+            None => return Err(Error::OperationNotInitialized),
         };
-        key.get_signature_length(data, params)
+        sign.multipart = true;
+        if let Err(e) = sign.input.update(data) {
+            session.sign = None;
+            return Err(e);
+        }
+        Ok(())
     }
 
-    pub fn sign(&mut self, session: CK_SESSION_HANDLE, data: &[u8]) -> Result<Vec<u8>, ()> {
-        // Performing the signature (via C_Sign, which is the only way we support) finishes the sign
-        // operation, so it needs to be removed here.
-        let (key_handle, params) = match self.signs.remove(&session) {
-            Some((key_handle, params)) => (key_handle, params),
-            None => return Err(()),
+    /// Signs the data given to `sign_update` with the session's sign operation, which finishes it.
+    pub fn sign_final(&mut self, session: CK_SESSION_HANDLE) -> Result<Vec<u8>, Error> {
+        let (session_state, _) = self.get_session_mut(session)?;
+        // The single-part mechanisms (e.g. CKM_ECDSA) can't be finished with C_SignFinal, either.
+        if let Some(SignOperation {
+            input: SignInput::Raw,
+            ..
+        }) = &session_state.sign
+        {
+            session_state.sign = None;
+            return Err(Error::MechanismInvalid);
+        }
+        self.finish_sign(session, &[])
+    }
+
+    fn finish_sign(&mut self, session: CK_SESSION_HANDLE, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let (session, slot) = self.get_session_mut(session)?;
+        // If the key needs the PIN first, the operation stays active so the application can give
+        // it and try again.
+        if let Some(sign) = &session.sign {
+            if sign.context_specific_login_needed {
+                return Err(Error::UserNotLoggedIn);
+            }
+        }
+        // Performing the signature finishes the sign operation, so it needs to be removed here.
+        // This is also the case if the key was removed from the backend after the operation was
+        // started.
+        let sign = match session.sign.take() {
+            Some(sign) => sign,
+            None => return Err(Error::OperationNotInitialized),
         };
-        let key = match self.objects.get(&key_handle) {
+        let key = match slot.objects.get(&sign.key_handle) {
             Some(Object::Key(key)) => key,
-            _ => return Err(()),
+            _ => return Err(Error::KeyHandleInvalid),
         };
-        key.sign(data, &params)
+        let SignOperation {
+            key_handle,
+            params,
+            input,
+            multipart,
+            ..
+        } = sign;
+        // The backend may still need the PIN (e.g. if another signature with a key on the same
+        // smart card got in the way), in which case the operation stays active for another try.
+        let retry_input = if key.needs_context_specific_login() {
+            Some(input.clone())
+        } else {
+            None
+        };
+        let result = match input.finish(data)? {
+            SignData::Hash(hash) => slot.backend.sign(key, &hash, &params),
+            SignData::Message(hash_algorithm, message) => {
+                slot.backend.sign_message(key, &message, hash_algorithm)
+            }
+        };
+        if let (Err(Error::UserNotLoggedIn), Some(input)) = (&result, retry_input) {
+            session.sign = Some(SignOperation {
+                key_handle,
+                params,
+                input,
+                multipart,
+                context_specific_login_needed: true,
+            });
+        }
+        result
+    }
+
+    /// Sets up a decrypt operation with the given key (if it exists in the session's slot and is
+    /// allowed to decrypt) and mechanism. `params` must be given with `CKM_RSA_PKCS_OAEP`, and only
+    /// with it.
+    pub fn start_decrypt(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        key_handle: CK_OBJECT_HANDLE,
+        mechanism: CK_MECHANISM_TYPE,
+        params: Option<OaepParams>,
+    ) -> Result<(), Error> {
+        let (session, slot) = self.get_session_mut(session)?;
+        if session.decrypt.is_some() {
+            return Err(Error::OperationActive);
+        }
+        let (key_type, _, functions) =
+            get_mechanism_properties(mechanism).ok_or(Error::MechanismInvalid)?;
+        if functions & CKF_DECRYPT == 0 {
+            return Err(Error::MechanismInvalid);
+        }
+        let key = match slot.objects.get(&key_handle) {
+            Some(Object::Key(key)) => key,
+            _ => return Err(Error::KeyHandleInvalid),
+        };
+        if get_key_type(key) != key_type {
+            return Err(Error::KeyTypeInconsistent);
+        }
+        // Only signing supports giving the PIN for keys that need it every time they are used.
+        if !key.can_decrypt() || key.needs_context_specific_login() {
+            return Err(Error::KeyFunctionNotPermitted);
+        }
+        match (mechanism, &params) {
+            (CKM_RSA_PKCS, None) => {}
+            (CKM_RSA_PKCS_OAEP, Some(params))
+                if matches!(params.hash_algorithm, CKM_SHA_1 | CKM_SHA256) => {}
+            _ => return Err(Error::MechanismParamInvalid),
+        }
+        session.decrypt = Some(DecryptOperation {
+            key_handle,
+            params,
+            decrypted: None,
+        });
+        Ok(())
+    }
+
+    /// Returns the length of the data that finishing the session's decrypt operation with the given
+    /// data would produce. Until the data has been decrypted, this is the length of the key's
+    /// modulus, which is as long as the decrypted data can be.
+    pub fn get_decrypted_length(
+        &self,
+        session: CK_SESSION_HANDLE,
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        let (_, slot) = self.get_session_slot(session)?;
+        let decrypt = match &self.sessions[&session].decrypt {
+            Some(decrypt) => decrypt,
+            None => return Err(Error::OperationNotInitialized),
+        };
+        if let Some((encrypted, decrypted)) = &decrypt.decrypted {
+            if encrypted == data {
+                return Ok(decrypted.len());
+            }
+        }
+        match slot.objects.get(&decrypt.key_handle) {
+            Some(Object::Key(key)) => Ok(key.key_size().div_ceil(8)),
+            _ => Err(Error::KeyHandleInvalid),
+        }
+    }
+
+    /// Decrypts the given data with the session's decrypt operation, which finishes it. If the
+    /// decrypted data is longer than `capacity`, this fails with `BufferTooSmall` and the operation
+    /// remains active, so the application can try again with a bigger buffer.
+    pub fn decrypt(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        data: &[u8],
+        capacity: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let (session, slot) = self.get_session_mut(session)?;
+        let mut decrypt = match session.decrypt.take() {
+            Some(decrypt) => decrypt,
+            None => return Err(Error::OperationNotInitialized),
+        };
+        let decrypted = match decrypt.decrypted.take() {
+            Some((encrypted, decrypted)) if encrypted == data => decrypted,
+            _ => {
+                let key = match slot.objects.get(&decrypt.key_handle) {
+                    Some(Object::Key(key)) => key,
+                    _ => return Err(Error::KeyHandleInvalid),
+                };
+                slot.backend.decrypt(key, data, &decrypt.params)?
+            }
+        };
+        if decrypted.len() > capacity {
+            decrypt.decrypted = Some((data.to_vec(), decrypted));
+            session.decrypt = Some(decrypt);
+            return Err(Error::BufferTooSmall);
+        }
+        Ok(decrypted)
+    }
+
+    /// Sets up a verify operation with the given public key (if it exists in the session's slot and
+    /// can be used to verify signatures) and mechanism. Signatures are verified in software with the
+    /// public key from the certificate, so this works the same way regardless of the slot's backend.
+    /// As with signing, the hash-and-sign mechanisms can be given the data in parts with
+    /// `verify_update` and finished with `verify_final`.
+    pub fn start_verify(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        key_handle: CK_OBJECT_HANDLE,
+        mechanism: CK_MECHANISM_TYPE,
+        params: Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<(), Error> {
+        let (session, slot) = self.get_session_mut(session)?;
+        if session.verify.is_some() {
+            return Err(Error::OperationActive);
+        }
+        let (key_type, hash_algorithm, functions) =
+            get_mechanism_properties(mechanism).ok_or(Error::MechanismInvalid)?;
+        if functions & CKF_VERIFY == 0 {
+            return Err(Error::MechanismInvalid);
+        }
+        let public_key = match slot.objects.get(&key_handle) {
+            Some(Object::PublicKey(public_key)) => public_key,
+            _ => return Err(Error::KeyHandleInvalid),
+        };
+        if public_key.key_type() != key_type {
+            return Err(Error::KeyTypeInconsistent);
+        }
+        if public_key.verifying_key().is_none() {
+            return Err(Error::KeyFunctionNotPermitted);
+        }
+        let input = match hash_algorithm {
+            None => SignInput::Raw,
+            Some(hash_algorithm) => {
+                // The hash algorithm of the PSS params has to be the one the mechanism uses.
+                if let Some(params) = &params {
+                    if params.hashAlg != hash_algorithm {
+                        return Err(Error::MechanismParamInvalid);
+                    }
+                }
+                SignInput::Hash {
+                    hasher: Box::new(Hasher::new(hash_algorithm).ok_or(Error::MechanismInvalid)?),
+                    digest_info: key_type == CKK_RSA && params.is_none(),
+                }
+            }
+        };
+        session.verify = Some(VerifyOperation {
+            key_handle,
+            params,
+            input,
+            multipart: false,
+        });
+        Ok(())
+    }
+
+    /// Verifies the given signature of the given data (given in one part) with the session's verify
+    /// operation, which finishes it.
+    pub fn verify(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        data: &[u8],
+        signature: &[u8],
+    ) -> Result<(), Error> {
+        if let Some(verify) = &self.get_session_mut(session)?.0.verify {
+            // An operation that was given data with verify_update has to be finished with
+            // verify_final.
+            if verify.multipart {
+                return Err(Error::OperationActive);
+            }
+        }
+        self.finish_verify(session, data, signature)
+    }
+
+    /// Gives the next part of the signed data to the session's verify operation. If this fails, the
+    /// operation is finished.
+    pub fn verify_update(&mut self, session: CK_SESSION_HANDLE, data: &[u8]) -> Result<(), Error> {
+        let (session, _) = self.get_session_mut(session)?;
+        let verify = match &mut session.verify {
+            Some(verify) => verify,
+            None => return Err(Error::OperationNotInitialized),
+        };
+        verify.multipart = true;
+        if let Err(e) = verify.input.update(data) {
+            session.verify = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Verifies the given signature of the data given to `verify_update` with the session's verify
+    /// operation, which finishes it.
+    pub fn verify_final(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        signature: &[u8],
+    ) -> Result<(), Error> {
+        let (session_state, _) = self.get_session_mut(session)?;
+        // As with sign_final, the single-part mechanisms can't be finished this way.
+        if let Some(VerifyOperation {
+            input: SignInput::Raw,
+            ..
+        }) = &session_state.verify
+        {
+            session_state.verify = None;
+            return Err(Error::MechanismInvalid);
+        }
+        self.finish_verify(session, &[], signature)
+    }
+
+    fn finish_verify(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        data: &[u8],
+        signature: &[u8],
+    ) -> Result<(), Error> {
+        let (session, slot) = self.get_session_mut(session)?;
+        // Verifying the signature finishes the verify operation, whether or not the signature is
+        // valid.
+        let verify = match session.verify.take() {
+            Some(verify) => verify,
+            None => return Err(Error::OperationNotInitialized),
+        };
+        let verifying_key = match slot.objects.get(&verify.key_handle) {
+            Some(Object::PublicKey(public_key)) => public_key.verifying_key(),
+            _ => None,
+        }
+        .ok_or(Error::KeyHandleInvalid)?;
+        match verify.input.finish(data)? {
+            SignData::Hash(hash) => verifying_key.verify(&hash, signature, &verify.params),
+            SignData::Message(..) => {
+                error!("verify operation shouldn't collect messages");
+                Err(Error::General)
+            }
+        }
+    }
+
+    /// Logs in to the backend of the session's slot with the given PIN. Because this may make more
+    /// objects available, this looks for new objects immediately rather than waiting for the next
+    /// scan. After `MAX_PIN_ATTEMPTS` incorrect PINs in a row, the PIN is locked.
+    pub fn login(&mut self, session: CK_SESSION_HANDLE, pin: &str) -> Result<(), Error> {
+        let (slot_id, _) = self.get_session_slot(session)?;
+        let slot = self.slots.get_mut(&slot_id).ok_or(Error::SlotIdInvalid)?;
+        if slot.logged_in {
+            return Err(Error::UserAlreadyLoggedIn);
+        }
+        if slot.pin_locked() {
+            return Err(Error::PinLocked);
+        }
+        let result = slot.backend.login(pin);
+        slot.note_pin_result(&result);
+        result?;
+        slot.logged_in = true;
+        slot.last_scan_time = None;
+        self.maybe_find_new_objects(slot_id);
+        Ok(())
+    }
+
+    /// Gives the PIN for the session's sign operation, whose key needs the PIN every time it is used
+    /// (`CKA_ALWAYS_AUTHENTICATE`). This has to happen after the operation is started and before
+    /// it is finished. Incorrect PINs count towards locking the PIN, as with `login`.
+    pub fn login_context_specific(
+        &mut self,
+        session: CK_SESSION_HANDLE,
+        pin: &str,
+    ) -> Result<(), Error> {
+        let session = self
+            .sessions
+            .get_mut(&session)
+            .ok_or(Error::SessionHandleInvalid)?;
+        let slot = self.slots.get_mut(&session.slot_id).ok_or(Error::General)?;
+        let sign = match &mut session.sign {
+            Some(sign) if sign.context_specific_login_needed => sign,
+            _ => return Err(Error::OperationNotInitialized),
+        };
+        if slot.pin_locked() {
+            return Err(Error::PinLocked);
+        }
+        let result = match slot.objects.get(&sign.key_handle) {
+            Some(Object::Key(key)) => slot.backend.login_context_specific(key, &sign.params, pin),
+            _ => return Err(Error::KeyHandleInvalid),
+        };
+        slot.note_pin_result(&result);
+        result?;
+        sign.context_specific_login_needed = false;
+        Ok(())
+    }
+
+    /// Logs out of the backend of the session's slot. Because this may make objects unavailable,
+    /// this removes them immediately rather than waiting for the next scan.
+    pub fn logout(&mut self, session: CK_SESSION_HANDLE) -> Result<(), Error> {
+        let (slot_id, slot) = self.get_session_slot(session)?;
+        if !slot.logged_in {
+            return Err(Error::UserNotLoggedIn);
+        }
+        self.logout_slot(slot_id);
+        Ok(())
+    }
+
+    /// Logs out of the backend of the given slot, if it is logged in, and removes the objects that
+    /// this makes unavailable.
+    fn logout_slot(&mut self, slot_id: CK_SLOT_ID) {
+        let slot = match self.slots.get_mut(&slot_id) {
+            Some(slot) if slot.logged_in => slot,
+            _ => return,
+        };
+        slot.backend.logout();
+        slot.logged_in = false;
+        slot.last_scan_time = None;
+        self.maybe_find_new_objects(slot_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_configured::ConfiguredBackend;
+    use crate::backend_mock::*;
+    use crate::config::{Filter, TokenConfig, DEFAULT_RESCAN_INTERVAL};
+    use crate::util::serialize_uint;
+    use p256::ecdsa::signature::hazmat::PrehashVerifier;
+    use rsa::pkcs8::DecodePrivateKey;
+    use rsa::{Oaep, Pkcs1v15Encrypt, Pkcs1v15Sign, Pss, RsaPrivateKey};
+    use sha1::Sha1;
+    use sha2::{Digest, Sha256};
+    use std::collections::BTreeSet;
+
+    /// The ID of the slot of the first backend.
+    const SLOT_ID: CK_SLOT_ID = 1;
+
+    fn new_manager(store: &MockStore) -> Manager {
+        new_multi_slot_manager(&[store])
+    }
+
+    /// Creates a `Manager` with a slot for each of the given stores.
+    fn new_multi_slot_manager(stores: &[&MockStore]) -> Manager {
+        Manager::new(
+            stores
+                .iter()
+                .map(|store| Box::new(MockBackend::new((*store).clone())) as Box<dyn Backend>)
+                .collect(),
+            DEFAULT_RESCAN_INTERVAL,
+        )
+    }
+
+    /// Makes it so that the next call to `maybe_find_new_objects` will ask the backends for objects
+    /// again rather than waiting for the scan interval to elapse.
+    fn expire_last_scan(manager: &mut Manager) {
+        for slot in manager.slots.values_mut() {
+            slot.last_scan_time = Instant::now().checked_sub(manager.rescan_interval);
+        }
+    }
+
+    fn class_attr(class: CK_OBJECT_CLASS) -> (CK_ATTRIBUTE_TYPE, Vec<u8>) {
+        (CKA_CLASS, serialize_uint(class).unwrap())
+    }
+
+    /// Runs a complete search with the given attributes on a new session on the first slot and
+    /// returns all matching object handles.
+    fn find_objects(
+        manager: &mut Manager,
+        attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)],
+    ) -> Vec<CK_OBJECT_HANDLE> {
+        find_objects_in_slot(manager, SLOT_ID, attrs)
+    }
+
+    /// Runs a complete search with the given attributes on a new session on the given slot and
+    /// returns all matching object handles.
+    fn find_objects_in_slot(
+        manager: &mut Manager,
+        slot_id: CK_SLOT_ID,
+        attrs: &[(CK_ATTRIBUTE_TYPE, Vec<u8>)],
+    ) -> Vec<CK_OBJECT_HANDLE> {
+        let session = manager.open_session(slot_id, false).unwrap();
+        manager.start_search(session, attrs).unwrap();
+        let mut handles = manager.search(session, 100).unwrap();
+        assert!(manager.search(session, 100).unwrap().is_empty());
+        manager.clear_search(session).unwrap();
+        manager.close_session(session).unwrap();
+        handles.sort();
+        handles
+    }
+
+    /// Finds the handle of the object of the given class for the identity with the given
+    /// certificate.
+    fn find_handle(
+        manager: &mut Manager,
+        class: CK_OBJECT_CLASS,
+        cert_der: &[u8],
+    ) -> CK_OBJECT_HANDLE {
+        let id = Sha256::digest(cert_der).to_vec();
+        let handles = find_objects(manager, &[class_attr(class), (CKA_ID, id)]);
+        assert_eq!(handles.len(), 1);
+        handles[0]
+    }
+
+    /// Gets the value of the given attribute of the given object (in the first slot) on a new
+    /// session.
+    fn get_attribute(
+        manager: &mut Manager,
+        handle: CK_OBJECT_HANDLE,
+        attr_type: CK_ATTRIBUTE_TYPE,
+    ) -> Option<Vec<u8>> {
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        let mut values = manager
+            .get_attributes(session, handle, vec![attr_type])
+            .unwrap();
+        manager.close_session(session).unwrap();
+        assert_eq!(values.len(), 1);
+        values.remove(0).ok()
+    }
+
+    fn sha256_digest_info(data: &[u8]) -> Vec<u8> {
+        encode_digest_info(CKM_SHA256, &Sha256::digest(data)).unwrap()
+    }
+
+    fn rsa_public_key() -> rsa::RsaPublicKey {
+        RsaPrivateKey::from_pkcs8_der(RSA_KEY)
+            .unwrap()
+            .to_public_key()
+    }
+
+    /// Starts a sign operation with the given mechanism on a new session with the key corresponding
+    /// to the given certificate, checks that the reported signature length matches the length of the signature
+    /// produced, and returns the signature.
+    fn sign_with(
+        manager: &mut Manager,
+        cert_der: &[u8],
+        mechanism: CK_MECHANISM_TYPE,
+        params: Option<CK_RSA_PKCS_PSS_PARAMS>,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let key_handle = find_handle(manager, CKO_PRIVATE_KEY, cert_der);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager
+            .start_sign(session, key_handle, mechanism, params)
+            .unwrap();
+        let signature_length = manager.get_signature_length(session, data).unwrap();
+        let signature = manager.sign(session, data).unwrap();
+        assert_eq!(signature.len(), signature_length);
+        signature
+    }
+
+    /// Like `sign_with`, but gives the data to the sign operation in the given parts.
+    fn sign_in_parts_with(
+        manager: &mut Manager,
+        cert_der: &[u8],
+        mechanism: CK_MECHANISM_TYPE,
+        params: Option<CK_RSA_PKCS_PSS_PARAMS>,
+        parts: &[&[u8]],
+    ) -> Vec<u8> {
+        let key_handle = find_handle(manager, CKO_PRIVATE_KEY, cert_der);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager
+            .start_sign(session, key_handle, mechanism, params)
+            .unwrap();
+        for part in parts {
+            manager.sign_update(session, part).unwrap();
+        }
+        let signature_length = manager.get_signature_length(session, &[]).unwrap();
+        let signature = manager.sign_final(session).unwrap();
+        assert_eq!(signature.len(), signature_length);
+        signature
+    }
+
+    #[test]
+    fn test_new_manager_scans_backend() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        assert_eq!(store.scan_count(), 1);
+        assert_eq!(find_objects(&mut manager, &[]).len(), 9);
+        assert_eq!(
+            find_objects(&mut manager, &[class_attr(CKO_CERTIFICATE)]).len(),
+            3
+        );
+        assert_eq!(
+            find_objects(&mut manager, &[class_attr(CKO_PRIVATE_KEY)]).len(),
+            3
+        );
+        assert_eq!(
+            find_objects(&mut manager, &[class_attr(CKO_PUBLIC_KEY)]).len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_empty_backend() {
+        let store = MockStore::new();
+        let mut manager = new_manager(&store);
+        assert!(find_objects(&mut manager, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_handles_are_unique() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let handles = find_objects(&mut manager, &[]);
+        let unique_handles: BTreeSet<CK_OBJECT_HANDLE> = handles.iter().cloned().collect();
+        assert_eq!(unique_handles.len(), handles.len());
+        assert!(!unique_handles.contains(&CK_INVALID_HANDLE));
+    }
+
+    #[test]
+    fn test_cert_and_key_ids_match() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        for cert_der in &[RSA_CERT, P256_CERT, P384_CERT] {
+            let id = Sha256::digest(cert_der).to_vec();
+            let cert_handle = find_handle(&mut manager, CKO_CERTIFICATE, cert_der);
+            let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, cert_der);
+            assert_eq!(
+                get_attribute(&mut manager, cert_handle, CKA_ID),
+                Some(id.clone())
+            );
+            assert_eq!(get_attribute(&mut manager, key_handle, CKA_ID), Some(id));
+        }
+    }
+
+    #[test]
+    fn test_duplicate_identities_are_deduplicated() {
+        let store = MockStore::new();
+        store.add_identity(RSA_CERT, RSA_KEY);
+        store.add_identity(RSA_CERT, RSA_KEY);
+        let mut manager = new_manager(&store);
+        assert_eq!(find_objects(&mut manager, &[]).len(), 3);
+        assert_eq!(manager.slots[&SLOT_ID].cert_ids.len(), 1);
+        assert_eq!(manager.slots[&SLOT_ID].key_ids.len(), 1);
+    }
+
+    #[test]
+    fn test_rescan_waits_for_interval() {
+        let store = MockStore::new();
+        store.add_identity(RSA_CERT, RSA_KEY);
+        let mut manager = new_manager(&store);
+        store.add_identity(P256_CERT, P256_KEY);
+        // Opening a session within 3 seconds of the last scan doesn't scan again.
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager.close_session(session).unwrap();
+        assert_eq!(store.scan_count(), 1);
+        assert_eq!(find_objects(&mut manager, &[]).len(), 3);
+    }
+
+    #[test]
+    fn test_rescan_finds_new_objects() {
+        let store = MockStore::new();
+        store.add_identity(RSA_CERT, RSA_KEY);
+        let mut manager = new_manager(&store);
+        let rsa_cert_handle = find_handle(&mut manager, CKO_CERTIFICATE, RSA_CERT);
+        let rsa_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        store.add_identity(P256_CERT, P256_KEY);
+        expire_last_scan(&mut manager);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager.close_session(session).unwrap();
+        assert_eq!(store.scan_count(), 2);
+        let handles = find_objects(&mut manager, &[]);
+        assert_eq!(handles.len(), 6);
+        // Previously-found objects keep their handles and new objects get new handles.
+        assert_eq!(
+            find_handle(&mut manager, CKO_CERTIFICATE, RSA_CERT),
+            rsa_cert_handle
+        );
+        assert_eq!(
+            find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT),
+            rsa_key_handle
+        );
+        let p256_cert_handle = find_handle(&mut manager, CKO_CERTIFICATE, P256_CERT);
+        let p256_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        assert!(p256_cert_handle != rsa_cert_handle && p256_cert_handle != rsa_key_handle);
+        assert!(p256_key_handle != rsa_cert_handle && p256_key_handle != rsa_key_handle);
+        assert_eq!(manager.slots[&SLOT_ID].cert_ids.len(), 2);
+        assert_eq!(manager.slots[&SLOT_ID].key_ids.len(), 2);
+    }
+
+    #[test]
+    fn test_rescan_with_no_changes_adds_nothing() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let handles = find_objects(&mut manager, &[]);
+        expire_last_scan(&mut manager);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager.close_session(session).unwrap();
+        assert_eq!(store.scan_count(), 2);
+        assert_eq!(find_objects(&mut manager, &[]), handles);
+    }
+
+    #[test]
+    fn test_rescan_removes_objects() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let rsa_cert_handle = find_handle(&mut manager, CKO_CERTIFICATE, RSA_CERT);
+        let rsa_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        let rsa_public_key_handle = find_handle(&mut manager, CKO_PUBLIC_KEY, RSA_CERT);
+        let p256_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        store.remove_identity(RSA_CERT);
+        expire_last_scan(&mut manager);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(find_objects(&mut manager, &[]).len(), 6);
+        assert_eq!(manager.slots[&SLOT_ID].cert_ids.len(), 2);
+        assert_eq!(manager.slots[&SLOT_ID].key_ids.len(), 2);
+        assert_eq!(manager.slots[&SLOT_ID].public_key_ids.len(), 2);
+        for handle in [rsa_cert_handle, rsa_key_handle, rsa_public_key_handle] {
+            assert_eq!(
+                manager.get_attributes(session, handle, vec![CKA_CLASS]),
+                Err(Error::ObjectHandleInvalid)
+            );
+        }
+        assert_eq!(
+            manager.start_sign(session, rsa_key_handle, CKM_RSA_PKCS, None),
+            Err(Error::KeyHandleInvalid)
+        );
+        // Objects that are still there keep their handles.
+        assert_eq!(
+            find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT),
+            p256_key_handle
+        );
+        // If the identity comes back, its objects get the same handles as before.
+        store.add_identity(RSA_CERT, RSA_KEY);
+        expire_last_scan(&mut manager);
+        manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(find_objects(&mut manager, &[]).len(), 9);
+        assert_eq!(
+            find_handle(&mut manager, CKO_CERTIFICATE, RSA_CERT),
+            rsa_cert_handle
+        );
+        assert_eq!(
+            find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT),
+            rsa_key_handle
+        );
+        assert_eq!(
+            find_handle(&mut manager, CKO_PUBLIC_KEY, RSA_CERT),
+            rsa_public_key_handle
+        );
+    }
+
+    #[test]
+    fn test_rescan_removes_objects_from_searches() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let rsa_cert_handle = find_handle(&mut manager, CKO_CERTIFICATE, RSA_CERT);
+        let rsa_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager.start_search(session, &[]).unwrap();
+        store.remove_identity(RSA_CERT);
+        expire_last_scan(&mut manager);
+        assert_eq!(manager.get_slot_event(), Ok(Some(SLOT_ID)));
+        // The search doesn't return the removed objects.
+        let handles = manager.search(session, 10).unwrap();
+        assert_eq!(handles.len(), 6);
+        assert!(!handles.contains(&rsa_cert_handle));
+        assert!(!handles.contains(&rsa_key_handle));
+        manager.clear_search(session).unwrap();
+    }
+
+    #[test]
+    fn test_handles_are_stable() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let handles = find_objects(&mut manager, &[]);
+        let rsa_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        // A new manager (e.g. after the module is re-initialized) hands out the same handles, even
+        // if it finds the objects in a different order.
+        let reversed_store = MockStore::new();
+        reversed_store.add_identity(P384_CERT, P384_KEY);
+        reversed_store.add_identity(P256_CERT, P256_KEY);
+        reversed_store.add_identity(RSA_CERT, RSA_KEY);
+        let mut other_manager = new_manager(&reversed_store);
+        assert_eq!(find_objects(&mut other_manager, &[]), handles);
+        assert_eq!(
+            find_handle(&mut other_manager, CKO_PRIVATE_KEY, RSA_CERT),
+            rsa_key_handle
+        );
+        // Handles don't depend on which slot the token ends up in (e.g. because another backend
+        // is available this time).
+        let mut multi_slot_manager = new_multi_slot_manager(&[&MockStore::new(), &store]);
+        assert_eq!(
+            find_objects_in_slot(&mut multi_slot_manager, 2, &[]),
+            handles
+        );
+        // The same object on a different token has a different handle.
+        let configured = ConfiguredBackend::new(
+            Box::new(MockBackend::new(store.clone())),
+            TokenConfig {
+                label: None,
+                serial_number: Some(String::from("serial")),
+                manufacturer: None,
+            },
+            Filter::default(),
+        );
+        let mut configured_manager =
+            Manager::new(vec![Box::new(configured)], DEFAULT_RESCAN_INTERVAL);
+        assert!(!find_objects(&mut configured_manager, &[])
+            .iter()
+            .any(|handle| handles.contains(handle)));
+    }
+
+    #[test]
+    fn test_handle_collisions() {
+        const TOKEN: &[u8] = b"mock token";
+        let mut handle_owners = BTreeMap::new();
+        let id = Sha256::digest(RSA_CERT).to_vec();
+        let handle = get_object_handle(&mut handle_owners, SLOT_ID, TOKEN, CKO_CERTIFICATE, &id);
+        assert_ne!(handle, CK_INVALID_HANDLE);
+        assert_eq!(
+            get_object_handle(&mut handle_owners, SLOT_ID, TOKEN, CKO_CERTIFICATE, &id),
+            handle
+        );
+        // If the handle already belongs to a different object, another one is derived.
+        let mut handle_owners = BTreeMap::new();
+        handle_owners.insert(handle, (SLOT_ID, CKO_PRIVATE_KEY, vec![0; 32]));
+        let other_handle =
+            get_object_handle(&mut handle_owners, SLOT_ID, TOKEN, CKO_CERTIFICATE, &id);
+        assert_ne!(other_handle, handle);
+        assert_ne!(other_handle, CK_INVALID_HANDLE);
+        assert_eq!(
+            get_object_handle(&mut handle_owners, SLOT_ID, TOKEN, CKO_CERTIFICATE, &id),
+            other_handle
+        );
+        assert_eq!(handle_owners.len(), 2);
+    }
+
+    #[test]
+    fn test_sign_with_removed_key() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager
+            .start_sign(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        store.remove_identity(P256_CERT);
+        expire_last_scan(&mut manager);
+        assert_eq!(manager.get_slot_event(), Ok(Some(SLOT_ID)));
+        assert_eq!(
+            manager.get_signature_length(session, &[0; 32]),
+            Err(Error::KeyHandleInvalid)
+        );
+        assert_eq!(
+            manager.sign(session, &[0; 32]),
+            Err(Error::KeyHandleInvalid)
+        );
+        // The failed sign finishes the operation.
+        assert_eq!(
+            manager.sign(session, &[0; 32]),
+            Err(Error::OperationNotInitialized)
+        );
+    }
+
+    #[test]
+    fn test_sessions() {
+        let store = MockStore::new();
+        let mut manager = new_manager(&store);
+        let session1 = manager.open_session(SLOT_ID, false).unwrap();
+        let session2 = manager.open_session(SLOT_ID, false).unwrap();
+        assert!(session1 != session2);
+        assert!(session1 != CK_INVALID_HANDLE && session2 != CK_INVALID_HANDLE);
+        assert!(manager.close_session(session1).is_ok());
+        assert!(manager.close_session(session1).is_err());
+        assert!(manager.close_session(session2).is_ok());
+        let session3 = manager.open_session(SLOT_ID, false).unwrap();
+        assert!(session3 != session1 && session3 != session2);
+        assert!(manager.close_all_sessions(SLOT_ID).is_ok());
+        assert!(manager.close_session(session3).is_err());
+    }
+
+    #[test]
+    fn test_session_info() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        let rw_session = manager.open_session(SLOT_ID, true).unwrap();
+        assert_eq!(
+            manager.get_session_info(session),
+            Ok(SessionInfo {
+                slot_id: SLOT_ID,
+                read_write: false,
+                logged_in: false,
+                search_active: false,
+                sign_active: false,
+                decrypt_active: false,
+                verify_active: false,
+            })
+        );
+        assert_eq!(
+            manager
+                .get_session_info(rw_session)
+                .map(|info| info.read_write),
+            Ok(true)
+        );
+        // Active operations are per-session.
+        manager.start_search(session, &[]).unwrap();
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        manager
+            .start_sign(rw_session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        let info = manager.get_session_info(session).unwrap();
+        assert!(info.search_active && !info.sign_active);
+        let info = manager.get_session_info(rw_session).unwrap();
+        assert!(!info.search_active && info.sign_active);
+        manager.clear_search(session).unwrap();
+        manager.sign(rw_session, &[0; 32]).unwrap();
+        let info = manager.get_session_info(session).unwrap();
+        assert!(!info.search_active && !info.sign_active);
+        let info = manager.get_session_info(rw_session).unwrap();
+        assert!(!info.search_active && !info.sign_active);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        manager
+            .start_decrypt(session, key_handle, CKM_RSA_PKCS, None)
+            .unwrap();
+        assert!(manager.get_session_info(session).unwrap().decrypt_active);
+        assert!(!manager.get_session_info(rw_session).unwrap().decrypt_active);
+        let public_key_handle = find_handle(&mut manager, CKO_PUBLIC_KEY, RSA_CERT);
+        manager
+            .start_verify(rw_session, public_key_handle, CKM_RSA_PKCS, None)
+            .unwrap();
+        assert!(manager.get_session_info(rw_session).unwrap().verify_active);
+        assert!(!manager.get_session_info(session).unwrap().verify_active);
+        // Logging in applies to all of the slot's sessions.
+        manager.login(session, "").unwrap();
+        assert_eq!(
+            manager
+                .get_session_info(rw_session)
+                .map(|info| info.logged_in),
+            Ok(true)
+        );
+        manager.logout(rw_session).unwrap();
+        assert_eq!(
+            manager.get_session_info(session).map(|info| info.logged_in),
+            Ok(false)
+        );
+        manager.close_session(session).unwrap();
+        assert_eq!(
+            manager.get_session_info(session),
+            Err(Error::SessionHandleInvalid)
+        );
+    }
+
+    #[test]
+    fn test_search_by_attributes() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let id = Sha256::digest(P256_CERT).to_vec();
+        let handles = find_objects(&mut manager, &[(CKA_ID, id.clone())]);
+        assert_eq!(handles.len(), 3);
+        for handle in handles {
+            assert_eq!(
+                get_attribute(&mut manager, handle, CKA_ID),
+                Some(id.clone())
+            );
+        }
+        let handles = find_objects(&mut manager, &[(CKA_VALUE, P384_CERT.to_vec())]);
+        assert_eq!(handles.len(), 1);
+        assert_eq!(
+            get_attribute(&mut manager, handles[0], CKA_CLASS),
+            Some(serialize_uint(CKO_CERTIFICATE).unwrap())
+        );
+        let rsa_key_type = (CKA_KEY_TYPE, serialize_uint(CKK_RSA).unwrap());
+        assert_eq!(find_objects(&mut manager, &[rsa_key_type]).len(), 2);
+        let ec_key_type = (CKA_KEY_TYPE, serialize_uint(CKK_EC).unwrap());
+        assert_eq!(find_objects(&mut manager, &[ec_key_type]).len(), 4);
+        let token = (CKA_TOKEN, serialize_uint(CK_TRUE).unwrap());
+        assert_eq!(find_objects(&mut manager, &[token]).len(), 9);
+        // Nothing matches a class this module doesn't expose.
+        assert!(find_objects(&mut manager, &[class_attr(CKO_SECRET_KEY)]).is_empty());
+        // Nothing matches if any of the attributes doesn't match.
+        let mismatched = [
+            class_attr(CKO_CERTIFICATE),
+            (CKA_VALUE, P384_CERT.to_vec()),
+            (CKA_ID, id),
+        ];
+        assert!(find_objects(&mut manager, &mismatched).is_empty());
+    }
+
+    #[test]
+    fn test_search_for_unsupported_attribute() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let attrs = [
+            class_attr(CKO_CERTIFICATE),
+            (CKA_START_DATE, b"20200101".to_vec()),
+        ];
+        assert!(find_objects(&mut manager, &attrs).is_empty());
+    }
+
+    #[test]
+    fn test_search_in_pages() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager.start_search(session, &[]).unwrap();
+        let mut found = manager.search(session, 4).unwrap();
+        assert_eq!(found.len(), 4);
+        let page = manager.search(session, 4).unwrap();
+        assert_eq!(page.len(), 4);
+        found.extend(page);
+        let page = manager.search(session, 4).unwrap();
+        assert_eq!(page.len(), 1);
+        found.extend(page);
+        assert!(manager.search(session, 4).unwrap().is_empty());
+        let unique_handles: BTreeSet<CK_OBJECT_HANDLE> = found.iter().cloned().collect();
+        assert_eq!(unique_handles.len(), 9);
+        manager.clear_search(session).unwrap();
+    }
+
+    #[test]
+    fn test_search_one_at_a_time() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager.start_search(session, &[]).unwrap();
+        let mut found = BTreeSet::new();
+        for _ in 0..9 {
+            let page = manager.search(session, 1).unwrap();
+            assert_eq!(page.len(), 1);
+            assert!(found.insert(page[0]));
+        }
+        assert!(manager.search(session, 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_search_errors() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        // Searching without starting a search fails.
+        assert_eq!(
+            manager.search(session, 1),
+            Err(Error::OperationNotInitialized)
+        );
+        assert_eq!(
+            manager.clear_search(session),
+            Err(Error::OperationNotInitialized)
+        );
+        manager.start_search(session, &[]).unwrap();
+        // Starting a search while one is ongoing fails.
+        assert_eq!(
+            manager.start_search(session, &[]),
+            Err(Error::OperationActive)
+        );
+        // Asking for no results fails.
+        assert_eq!(manager.search(session, 0), Err(Error::ArgumentsBad));
+        // Searches are per-session.
+        let other_session = manager.open_session(SLOT_ID, false).unwrap();
+        assert!(manager.search(other_session, 1).is_err());
+        manager.clear_search(session).unwrap();
+        assert!(manager.search(session, 1).is_err());
+        // Searching requires a valid session.
+        assert_eq!(
+            manager.start_search(other_session + 1, &[]),
+            Err(Error::SessionHandleInvalid)
+        );
+        // Clearing the search makes it possible to start a new one.
+        manager.start_search(session, &[]).unwrap();
+        assert_eq!(manager.search(session, 10).unwrap().len(), 9);
+    }
+
+    #[test]
+    fn test_get_attributes() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let cert_handle = find_handle(&mut manager, CKO_CERTIFICATE, RSA_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        let values = manager
+            .get_attributes(
+                session,
+                cert_handle,
+                vec![CKA_VALUE, CKA_MODULUS, CKA_TOKEN, CKA_START_DATE],
+            )
+            .unwrap();
+        assert_eq!(
+            values,
+            vec![
+                Ok(RSA_CERT.to_vec()),
+                Err(Error::AttributeTypeInvalid),
+                Ok(serialize_uint(CK_TRUE).unwrap()),
+                Err(Error::AttributeTypeInvalid)
+            ]
+        );
+        let fields = crate::util::read_certificate_fields(RSA_CERT).unwrap();
+        assert_eq!(
+            get_attribute(&mut manager, cert_handle, CKA_ISSUER),
+            Some(fields.issuer.to_vec())
+        );
+        assert_eq!(
+            get_attribute(&mut manager, cert_handle, CKA_SUBJECT),
+            Some(fields.subject.to_vec())
+        );
+        assert_eq!(
+            get_attribute(&mut manager, cert_handle, CKA_SERIAL_NUMBER),
+            Some(fields.serial_number.to_vec())
+        );
+
+        let rsa_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        let modulus = rsa::traits::PublicKeyParts::n(&rsa_public_key()).to_bytes_be();
+        assert_eq!(
+            get_attribute(&mut manager, rsa_key_handle, CKA_MODULUS),
+            Some(modulus)
+        );
+        assert_eq!(
+            get_attribute(&mut manager, rsa_key_handle, CKA_EC_PARAMS),
+            None
+        );
+        // The secret parts of keys are sensitive.
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(
+            manager.get_attributes(
+                session,
+                rsa_key_handle,
+                vec![
+                    CKA_SENSITIVE,
+                    CKA_EXTRACTABLE,
+                    CKA_VALUE,
+                    CKA_PRIVATE_EXPONENT
+                ]
+            ),
+            Ok(vec![
+                Ok(serialize_uint(CK_TRUE).unwrap()),
+                Ok(serialize_uint(CK_FALSE).unwrap()),
+                Err(Error::AttributeSensitive),
+                Err(Error::AttributeSensitive)
+            ])
+        );
+
+        let ec_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P384_CERT);
+        assert_eq!(
+            get_attribute(&mut manager, ec_key_handle, CKA_EC_PARAMS),
+            Some(crate::util::OID_BYTES_SECP384R1.to_vec())
+        );
+        assert_eq!(
+            get_attribute(&mut manager, ec_key_handle, CKA_KEY_TYPE),
+            Some(serialize_uint(CKK_EC).unwrap())
+        );
+        assert_eq!(
+            get_attribute(&mut manager, ec_key_handle, CKA_MODULUS),
+            None
+        );
+    }
+
+    #[test]
+    fn test_public_key_attributes() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let rsa_public_key_handle = find_handle(&mut manager, CKO_PUBLIC_KEY, RSA_CERT);
+        let modulus = rsa::traits::PublicKeyParts::n(&rsa_public_key()).to_bytes_be();
+        let fields = crate::util::read_certificate_fields(RSA_CERT).unwrap();
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(
+            manager.get_attributes(
+                session,
+                rsa_public_key_handle,
+                vec![
+                    CKA_KEY_TYPE,
+                    CKA_MODULUS,
+                    CKA_PUBLIC_EXPONENT,
+                    CKA_SUBJECT,
+                    CKA_PRIVATE,
+                    CKA_EC_POINT
+                ]
+            ),
+            Ok(vec![
+                Ok(serialize_uint(CKK_RSA).unwrap()),
+                Ok(modulus),
+                Ok(vec![0x01, 0x00, 0x01]),
+                Ok(fields.subject.to_vec()),
+                Ok(serialize_uint(CK_FALSE).unwrap()),
+                Err(Error::AttributeTypeInvalid)
+            ])
+        );
+
+        // The EC point is the DER encoding of an OCTET STRING containing the uncompressed point.
+        let ec_public_key_handle = find_handle(&mut manager, CKO_PUBLIC_KEY, P256_CERT);
+        let key = p256::ecdsa::SigningKey::from_pkcs8_der(P256_KEY).unwrap();
+        let point = key.verifying_key().to_encoded_point(false);
+        let mut ec_point = vec![0x04, 65];
+        ec_point.extend_from_slice(point.as_bytes());
+        assert_eq!(
+            manager.get_attributes(
+                session,
+                ec_public_key_handle,
+                vec![CKA_KEY_TYPE, CKA_EC_PARAMS, CKA_EC_POINT, CKA_MODULUS]
+            ),
+            Ok(vec![
+                Ok(serialize_uint(CKK_EC).unwrap()),
+                Ok(crate::util::OID_BYTES_SECP256R1.to_vec()),
+                Ok(ec_point),
+                Err(Error::AttributeTypeInvalid)
+            ])
+        );
+        // Public keys can be found by their value.
+        let public_exponent = (CKA_PUBLIC_EXPONENT, vec![0x01, 0x00, 0x01]);
+        assert_eq!(
+            find_objects(&mut manager, &[public_exponent]),
+            vec![rsa_public_key_handle]
+        );
+    }
+
+    #[test]
+    fn test_get_attributes_invalid_handle() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let handles = find_objects(&mut manager, &[]);
+        let invalid_handle = handles.iter().max().unwrap() + 1;
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(
+            manager.get_attributes(session, invalid_handle, vec![CKA_CLASS]),
+            Err(Error::ObjectHandleInvalid)
+        );
+        assert_eq!(
+            manager.get_attributes(session, CK_INVALID_HANDLE, vec![CKA_CLASS]),
+            Err(Error::ObjectHandleInvalid)
+        );
+        // Getting attributes requires a valid session.
+        assert_eq!(
+            manager.get_attributes(session + 1, handles[0], vec![CKA_CLASS]),
+            Err(Error::SessionHandleInvalid)
+        );
+    }
+
+    #[test]
+    fn test_sign_rsa_pkcs1() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let digest_info = sha256_digest_info(b"hello, world");
+        let signature = sign_with(&mut manager, RSA_CERT, CKM_RSA_PKCS, None, &digest_info);
+        assert_eq!(signature.len(), 256);
+        assert!(rsa_public_key()
+            .verify(Pkcs1v15Sign::new_unprefixed(), &digest_info, &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_sign_rsa_pss() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let params = CK_RSA_PKCS_PSS_PARAMS {
+            hashAlg: CKM_SHA256,
+            mgf: CKG_MGF1_SHA256,
+            sLen: 32,
+        };
+        let hash = Sha256::digest(b"hello, world");
+        let signature = sign_with(
+            &mut manager,
+            RSA_CERT,
+            CKM_RSA_PKCS_PSS,
+            Some(params),
+            &hash,
+        );
+        assert_eq!(signature.len(), 256);
+        assert!(rsa_public_key()
+            .verify(Pss::new::<Sha256>(), &hash, &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_sign_rsa_pss_unsupported_hash() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        let params = CK_RSA_PKCS_PSS_PARAMS {
+            hashAlg: CKM_MD5,
+            mgf: CKG_MGF1_SHA256,
+            sLen: 16,
+        };
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager
+            .start_sign(session, key_handle, CKM_RSA_PKCS_PSS, Some(params))
+            .unwrap();
+        assert!(manager.sign(session, &[0; 16]).is_err());
+    }
+
+    #[test]
+    fn test_sign_p256() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let hash = Sha256::digest(b"hello, world");
+        let signature = sign_with(&mut manager, P256_CERT, CKM_ECDSA, None, &hash);
+        assert_eq!(signature.len(), 64);
+        let key = p256::ecdsa::SigningKey::from_pkcs8_der(P256_KEY).unwrap();
+        let signature = p256::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(key
+            .verifying_key()
+            .verify_prehash(&hash, &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_sign_p384() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let hash = sha2::Sha384::digest(b"hello, world");
+        let signature = sign_with(&mut manager, P384_CERT, CKM_ECDSA, None, &hash);
+        assert_eq!(signature.len(), 96);
+        let key = p384::ecdsa::SigningKey::from_pkcs8_der(P384_KEY).unwrap();
+        let signature = p384::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(key
+            .verifying_key()
+            .verify_prehash(&hash, &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_sign_hash_and_sign_rsa_pkcs1() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        for (mechanism, hash_algorithm) in [
+            (CKM_SHA256_RSA_PKCS, CKM_SHA256),
+            (CKM_SHA384_RSA_PKCS, CKM_SHA384),
+            (CKM_SHA512_RSA_PKCS, CKM_SHA512),
+        ] {
+            let mut hasher = Hasher::new(hash_algorithm).unwrap();
+            hasher.update(b"hello, world");
+            let digest_info = encode_digest_info(hash_algorithm, &hasher.finalize()).unwrap();
+            let signature = sign_with(&mut manager, RSA_CERT, mechanism, None, b"hello, world");
+            assert!(rsa_public_key()
+                .verify(Pkcs1v15Sign::new_unprefixed(), &digest_info, &signature)
+                .is_ok());
+            let signature = sign_in_parts_with(
+                &mut manager,
+                RSA_CERT,
+                mechanism,
+                None,
+                &[b"hello", b"", b", world"],
+            );
+            assert!(rsa_public_key()
+                .verify(Pkcs1v15Sign::new_unprefixed(), &digest_info, &signature)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn test_sign_hash_and_sign_rsa_pss() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let params = CK_RSA_PKCS_PSS_PARAMS {
+            hashAlg: CKM_SHA384,
+            mgf: CKG_MGF1_SHA384,
+            sLen: 48,
+        };
+        let signature = sign_in_parts_with(
+            &mut manager,
+            RSA_CERT,
+            CKM_SHA384_RSA_PKCS_PSS,
+            Some(params),
+            &[b"hello, ", b"world"],
+        );
+        assert!(rsa_public_key()
+            .verify(
+                Pss::new::<sha2::Sha384>(),
+                &sha2::Sha384::digest(b"hello, world"),
+                &signature
+            )
+            .is_ok());
+
+        // The params have to use the hash algorithm of the mechanism.
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(
+            manager.start_sign(session, key_handle, CKM_SHA256_RSA_PKCS_PSS, Some(params)),
+            Err(Error::MechanismParamInvalid)
+        );
+    }
+
+    #[test]
+    fn test_sign_hash_and_sign_ecdsa() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let signature = sign_in_parts_with(
+            &mut manager,
+            P256_CERT,
+            CKM_ECDSA_SHA256,
+            None,
+            &[b"hello, ", b"world"],
+        );
+        let key = p256::ecdsa::SigningKey::from_pkcs8_der(P256_KEY).unwrap();
+        let signature = p256::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(key
+            .verifying_key()
+            .verify_prehash(&Sha256::digest(b"hello, world"), &signature)
+            .is_ok());
+
+        let signature = sign_with(
+            &mut manager,
+            P384_CERT,
+            CKM_ECDSA_SHA384,
+            None,
+            b"hello, world",
+        );
+        let key = p384::ecdsa::SigningKey::from_pkcs8_der(P384_KEY).unwrap();
+        let signature = p384::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(key
+            .verifying_key()
+            .verify_prehash(&sha2::Sha384::digest(b"hello, world"), &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_sign_update() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(
+            manager.sign_update(session, b"data"),
+            Err(Error::OperationNotInitialized)
+        );
+        assert_eq!(
+            manager.sign_final(session),
+            Err(Error::OperationNotInitialized)
+        );
+
+        // Single-part mechanisms can't be given data in parts, and trying to finishes the
+        // operation.
+        manager
+            .start_sign(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        assert_eq!(
+            manager.sign_update(session, b"data"),
+            Err(Error::MechanismInvalid)
+        );
+        assert_eq!(
+            manager.sign(session, &[0; 32]),
+            Err(Error::OperationNotInitialized)
+        );
+        // They can't be finished with sign_final (which would sign nothing) either.
+        for (mechanism, cert) in [(CKM_ECDSA, P256_CERT), (CKM_RSA_PKCS, RSA_CERT)] {
+            let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, cert);
+            manager
+                .start_sign(session, key_handle, mechanism, None)
+                .unwrap();
+            assert_eq!(manager.sign_final(session), Err(Error::MechanismInvalid));
+            assert!(!manager.get_session_info(session).unwrap().sign_active);
+        }
+
+        // Once data has been given in parts, the operation can only be finished with sign_final.
+        manager
+            .start_sign(session, key_handle, CKM_ECDSA_SHA256, None)
+            .unwrap();
+        manager.sign_update(session, b"data").unwrap();
+        assert_eq!(
+            manager.sign(session, b"more data"),
+            Err(Error::OperationActive)
+        );
+        assert!(manager.get_session_info(session).unwrap().sign_active);
+        assert_eq!(manager.sign_final(session).unwrap().len(), 64);
+        assert!(!manager.get_session_info(session).unwrap().sign_active);
+    }
+
+    #[test]
+    fn test_sign_messages_only() {
+        let store = MockStore::with_fixtures();
+        store.set_signs_messages_only(true);
+        let mut manager = new_manager(&store);
+        let signature = sign_in_parts_with(
+            &mut manager,
+            RSA_CERT,
+            CKM_SHA256_RSA_PKCS,
+            None,
+            &[b"hello, ", b"world"],
+        );
+        let digest_info = sha256_digest_info(b"hello, world");
+        assert!(rsa_public_key()
+            .verify(Pkcs1v15Sign::new_unprefixed(), &digest_info, &signature)
+            .is_ok());
+        let signature = sign_with(
+            &mut manager,
+            P256_CERT,
+            CKM_ECDSA_SHA256,
+            None,
+            b"hello, world",
+        );
+        assert_eq!(signature.len(), 64);
+
+        // Keys that can only sign messages can't sign precomputed hashes.
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager
+            .start_sign(session, key_handle, CKM_RSA_PKCS, None)
+            .unwrap();
+        assert_eq!(
+            manager.sign(session, &digest_info),
+            Err(Error::MechanismInvalid)
+        );
+    }
+
+    #[test]
+    fn test_start_sign_requires_key() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let cert_handle = find_handle(&mut manager, CKO_CERTIFICATE, RSA_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(
+            manager.start_sign(session, cert_handle, CKM_RSA_PKCS, None),
+            Err(Error::KeyHandleInvalid)
+        );
+        let handles = find_objects(&mut manager, &[]);
+        let invalid_handle = handles.iter().max().unwrap() + 1;
+        assert_eq!(
+            manager.start_sign(session, invalid_handle, CKM_RSA_PKCS, None),
+            Err(Error::KeyHandleInvalid)
+        );
+        // A failed start doesn't leave an operation behind.
+        assert!(manager.sign(session, &[0; 32]).is_err());
+    }
+
+    #[test]
+    fn test_sign_operation_lifecycle() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        // Signing without starting a sign operation fails.
+        assert!(manager.get_signature_length(session, &[0; 32]).is_err());
+        assert!(manager.sign(session, &[0; 32]).is_err());
+        manager
+            .start_sign(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        // Only one sign operation can be active per session.
+        assert!(manager
+            .start_sign(session, key_handle, CKM_ECDSA, None)
+            .is_err());
+        // Sign operations are per-session.
+        let other_session = manager.open_session(SLOT_ID, false).unwrap();
+        assert!(manager.sign(other_session, &[0; 32]).is_err());
+        manager
+            .start_sign(other_session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        // Getting the signature length doesn't finish the operation.
+        assert_eq!(manager.get_signature_length(session, &[0; 32]), Ok(64));
+        assert_eq!(manager.get_signature_length(session, &[0; 32]), Ok(64));
+        assert!(manager.sign(session, &[0; 32]).is_ok());
+        // Signing does finish the operation.
+        assert!(manager.sign(session, &[0; 32]).is_err());
+        manager
+            .start_sign(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        assert!(manager.sign(session, &[0; 32]).is_ok());
+        assert!(manager.sign(other_session, &[0; 32]).is_ok());
+    }
+
+    #[test]
+    fn test_get_mechanism_info() {
+        let store = MockStore::with_fixtures();
+        let manager = new_manager(&store);
+        assert_eq!(
+            manager.get_mechanism_info(SLOT_ID, CKM_RSA_PKCS_PSS),
+            Ok(MechanismInfo {
+                min_key_size: 2048,
+                max_key_size: 2048,
+                flags: CKF_SIGN | CKF_VERIFY,
+            })
+        );
+        assert_eq!(
+            manager.get_mechanism_info(SLOT_ID, CKM_ECDSA),
+            Ok(MechanismInfo {
+                min_key_size: 256,
+                max_key_size: 384,
+                flags: CKF_SIGN | CKF_VERIFY | CKF_EC_F_P | CKF_EC_NAMEDCURVE | CKF_EC_UNCOMPRESS,
+            })
+        );
+        assert_eq!(
+            manager.get_mechanism_info(SLOT_ID, CKM_ECDSA_SHA512),
+            manager.get_mechanism_info(SLOT_ID, CKM_ECDSA)
+        );
+        assert_eq!(
+            manager
+                .get_mechanism_info(SLOT_ID, CKM_RSA_PKCS)
+                .map(|info| info.flags),
+            Ok(CKF_SIGN | CKF_VERIFY | CKF_DECRYPT)
+        );
+        assert_eq!(
+            manager
+                .get_mechanism_info(SLOT_ID, CKM_RSA_PKCS_OAEP)
+                .map(|info| info.flags),
+            Ok(CKF_DECRYPT)
+        );
+        assert_eq!(
+            manager.get_mechanism_info(SLOT_ID, CKM_SHA256),
+            Err(Error::MechanismInvalid)
+        );
+        assert_eq!(
+            manager.get_mechanism_info(SLOT_ID + 1, CKM_ECDSA),
+            Err(Error::SlotIdInvalid)
+        );
+        // Without any keys, there are no key sizes.
+        let manager = new_manager(&MockStore::new());
+        assert_eq!(
+            manager
+                .get_mechanism_info(SLOT_ID, CKM_RSA_PKCS)
+                .map(|info| (info.min_key_size, info.max_key_size)),
+            Ok((0, 0))
+        );
+    }
+
+    #[test]
+    fn test_start_sign_checks_mechanism() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let rsa_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        let ec_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(
+            manager.start_sign(session, rsa_key_handle, CKM_SHA256, None),
+            Err(Error::MechanismInvalid)
+        );
+        assert_eq!(
+            manager.start_sign(session, rsa_key_handle, CKM_RSA_PKCS_OAEP, None),
+            Err(Error::MechanismInvalid)
+        );
+        assert_eq!(
+            manager.start_sign(session, rsa_key_handle, CKM_ECDSA, None),
+            Err(Error::KeyTypeInconsistent)
+        );
+        assert_eq!(
+            manager.start_sign(session, ec_key_handle, CKM_RSA_PKCS, None),
+            Err(Error::KeyTypeInconsistent)
+        );
+        assert_eq!(
+            manager.start_sign(session + 1, ec_key_handle, CKM_ECDSA, None),
+            Err(Error::SessionHandleInvalid)
+        );
+        manager
+            .start_sign(session, ec_key_handle, CKM_ECDSA, None)
+            .unwrap();
+        assert_eq!(
+            manager.start_sign(session, ec_key_handle, CKM_ECDSA, None),
+            Err(Error::OperationActive)
+        );
+    }
+
+    #[test]
+    fn test_backend_sign_errors_are_returned() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        store.set_sign_error(Some(Error::Cancelled));
+        manager
+            .start_sign(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        assert_eq!(manager.sign(session, &[0; 32]), Err(Error::Cancelled));
+        store.set_sign_error(None);
+        manager
+            .start_sign(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        assert!(manager.sign(session, &[0; 32]).is_ok());
+    }
+
+    #[test]
+    fn test_failed_sign_finishes_operation() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager
+            .start_sign(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        // secp256r1 can't sign an empty hash.
+        assert!(manager.sign(session, &[]).is_err());
+        assert!(manager.sign(session, &[0; 32]).is_err());
+        manager
+            .start_sign(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        assert!(manager.sign(session, &[0; 32]).is_ok());
+    }
+
+    /// Starts a decrypt operation with the given mechanism on a new session with the key
+    /// corresponding to the given certificate and decrypts the given data with it.
+    fn decrypt_with(
+        manager: &mut Manager,
+        cert_der: &[u8],
+        mechanism: CK_MECHANISM_TYPE,
+        params: Option<OaepParams>,
+        data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let key_handle = find_handle(manager, CKO_PRIVATE_KEY, cert_der);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager.start_decrypt(session, key_handle, mechanism, params)?;
+        assert_eq!(manager.get_decrypted_length(session, data), Ok(256));
+        manager.decrypt(session, data, 256)
+    }
+
+    #[test]
+    fn test_decrypt_rsa_pkcs1() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let encrypted = rsa_public_key()
+            .encrypt(&mut rsa::rand_core::OsRng, Pkcs1v15Encrypt, b"secret")
+            .unwrap();
+        assert_eq!(
+            decrypt_with(&mut manager, RSA_CERT, CKM_RSA_PKCS, None, &encrypted),
+            Ok(b"secret".to_vec())
+        );
+        // Data that wasn't encrypted with the key doesn't decrypt.
+        assert_eq!(
+            decrypt_with(&mut manager, RSA_CERT, CKM_RSA_PKCS, None, &[1; 256]),
+            Err(Error::EncryptedDataInvalid)
+        );
+        assert_eq!(
+            decrypt_with(&mut manager, RSA_CERT, CKM_RSA_PKCS, None, &encrypted[1..]),
+            Err(Error::EncryptedDataLenRange)
+        );
+    }
+
+    #[test]
+    fn test_decrypt_rsa_oaep() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let encrypted = rsa_public_key()
+            .encrypt(&mut rsa::rand_core::OsRng, Oaep::new::<Sha1>(), b"secret")
+            .unwrap();
+        let sha1_params = Some(OaepParams {
+            hash_algorithm: CKM_SHA_1,
+        });
+        assert_eq!(
+            decrypt_with(
+                &mut manager,
+                RSA_CERT,
+                CKM_RSA_PKCS_OAEP,
+                sha1_params,
+                &encrypted
+            ),
+            Ok(b"secret".to_vec())
+        );
+        let sha256_params = Some(OaepParams {
+            hash_algorithm: CKM_SHA256,
+        });
+        assert_eq!(
+            decrypt_with(
+                &mut manager,
+                RSA_CERT,
+                CKM_RSA_PKCS_OAEP,
+                sha256_params,
+                &encrypted
+            ),
+            Err(Error::EncryptedDataInvalid)
+        );
+        let encrypted = rsa_public_key()
+            .encrypt(&mut rsa::rand_core::OsRng, Oaep::new::<Sha256>(), b"secret")
+            .unwrap();
+        assert_eq!(
+            decrypt_with(
+                &mut manager,
+                RSA_CERT,
+                CKM_RSA_PKCS_OAEP,
+                sha256_params,
+                &encrypted
+            ),
+            Ok(b"secret".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_decrypt_requires_key_encipherment() {
+        let store = MockStore::new();
+        store.add_identity(RSA_CERT, RSA_KEY);
+        store.add_identity(RSA_SIGNING_CERT, RSA_KEY);
+        store.add_identity(P256_CERT, P256_KEY);
+        let mut manager = new_manager(&store);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        let signing_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_SIGNING_CERT);
+        let ec_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        let ck_true = serialize_uint(CK_TRUE).unwrap();
+        let ck_false = serialize_uint(CK_FALSE).unwrap();
+        assert_eq!(
+            get_attribute(&mut manager, key_handle, CKA_DECRYPT),
+            Some(ck_true.clone())
+        );
+        assert_eq!(
+            get_attribute(&mut manager, signing_key_handle, CKA_DECRYPT),
+            Some(ck_false.clone())
+        );
+        assert_eq!(
+            get_attribute(&mut manager, ec_key_handle, CKA_DECRYPT),
+            Some(ck_false.clone())
+        );
+        assert_eq!(
+            find_objects(&mut manager, &[(CKA_DECRYPT, ck_true)]),
+            vec![key_handle]
+        );
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(
+            manager.start_decrypt(session, signing_key_handle, CKM_RSA_PKCS, None),
+            Err(Error::KeyFunctionNotPermitted)
+        );
+        assert_eq!(
+            manager.start_decrypt(session, ec_key_handle, CKM_RSA_PKCS, None),
+            Err(Error::KeyTypeInconsistent)
+        );
+        // The key can still sign.
+        let signature = sign_with(
+            &mut manager,
+            RSA_SIGNING_CERT,
+            CKM_SHA256_RSA_PKCS,
+            None,
+            b"hello",
+        );
+        assert!(rsa_public_key()
+            .verify(
+                Pkcs1v15Sign::new_unprefixed(),
+                &sha256_digest_info(b"hello"),
+                &signature
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_start_decrypt_checks_mechanism() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        let cert_handle = find_handle(&mut manager, CKO_CERTIFICATE, RSA_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(
+            manager.start_decrypt(session, key_handle, CKM_SHA256_RSA_PKCS, None),
+            Err(Error::MechanismInvalid)
+        );
+        assert_eq!(
+            manager.start_decrypt(session, cert_handle, CKM_RSA_PKCS, None),
+            Err(Error::KeyHandleInvalid)
+        );
+        let sha384_params = Some(OaepParams {
+            hash_algorithm: CKM_SHA384,
+        });
+        assert_eq!(
+            manager.start_decrypt(session, key_handle, CKM_RSA_PKCS_OAEP, sha384_params),
+            Err(Error::MechanismParamInvalid)
+        );
+        assert_eq!(
+            manager.start_decrypt(session, key_handle, CKM_RSA_PKCS_OAEP, None),
+            Err(Error::MechanismParamInvalid)
+        );
+        assert_eq!(
+            manager.decrypt(session, &[0; 256], 256),
+            Err(Error::OperationNotInitialized)
+        );
+        manager
+            .start_decrypt(session, key_handle, CKM_RSA_PKCS, None)
+            .unwrap();
+        assert_eq!(
+            manager.start_decrypt(session, key_handle, CKM_RSA_PKCS, None),
+            Err(Error::OperationActive)
+        );
+        // Decrypt operations are independent of sign operations.
+        manager
+            .start_sign(session, key_handle, CKM_RSA_PKCS, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_decrypt_buffer_too_small() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        let encrypted = rsa_public_key()
+            .encrypt(&mut rsa::rand_core::OsRng, Pkcs1v15Encrypt, b"secret")
+            .unwrap();
+        manager
+            .start_decrypt(session, key_handle, CKM_RSA_PKCS, None)
+            .unwrap();
+        assert_eq!(
+            manager.decrypt(session, &encrypted, 3),
+            Err(Error::BufferTooSmall)
+        );
+        // The operation is still active, and now the exact length is known.
+        assert_eq!(manager.get_decrypted_length(session, &encrypted), Ok(6));
+        assert_eq!(
+            manager.decrypt(session, &encrypted, 6),
+            Ok(b"secret".to_vec())
+        );
+        assert_eq!(
+            manager.decrypt(session, &encrypted, 6),
+            Err(Error::OperationNotInitialized)
+        );
+        // A failed decryption finishes the operation.
+        manager
+            .start_decrypt(session, key_handle, CKM_RSA_PKCS, None)
+            .unwrap();
+        assert!(manager.decrypt(session, &[1; 256], 256).is_err());
+        assert_eq!(
+            manager.decrypt(session, &encrypted, 256),
+            Err(Error::OperationNotInitialized)
+        );
+    }
+
+    /// Verifies the given signature of the given data with the public key of the identity with the
+    /// given certificate on a new session.
+    fn verify_with(
+        manager: &mut Manager,
+        cert_der: &[u8],
+        mechanism: CK_MECHANISM_TYPE,
+        params: Option<CK_RSA_PKCS_PSS_PARAMS>,
+        data: &[u8],
+        signature: &[u8],
+    ) -> Result<(), Error> {
+        let key_handle = find_handle(manager, CKO_PUBLIC_KEY, cert_der);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager
+            .start_verify(session, key_handle, mechanism, params)
+            .unwrap();
+        let result = manager.verify(session, data, signature);
+        // Verifying finishes the operation either way.
+        assert!(!manager.get_session_info(session).unwrap().verify_active);
+        result
+    }
+
+    #[test]
+    fn test_verify() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let pss_params = |hash_algorithm, mgf, salt_len| {
+            Some(CK_RSA_PKCS_PSS_PARAMS {
+                hashAlg: hash_algorithm,
+                mgf,
+                sLen: salt_len,
+            })
+        };
+        let hash = Sha256::digest(b"hello, world").to_vec();
+        for (cert, mechanism, params, data) in [
+            (RSA_CERT, CKM_RSA_PKCS, None, sha256_digest_info(b"data")),
+            (
+                RSA_CERT,
+                CKM_RSA_PKCS_PSS,
+                pss_params(CKM_SHA256, CKG_MGF1_SHA256, 32),
+                hash.clone(),
+            ),
+            (P256_CERT, CKM_ECDSA, None, hash.clone()),
+            (P384_CERT, CKM_ECDSA, None, hash.clone()),
+            (RSA_CERT, CKM_SHA256_RSA_PKCS, None, b"data".to_vec()),
+            (RSA_CERT, CKM_SHA512_RSA_PKCS, None, b"data".to_vec()),
+            (
+                RSA_CERT,
+                CKM_SHA384_RSA_PKCS_PSS,
+                pss_params(CKM_SHA384, CKG_MGF1_SHA384, 48),
+                b"data".to_vec(),
+            ),
+            (P256_CERT, CKM_ECDSA_SHA256, None, b"data".to_vec()),
+            (P384_CERT, CKM_ECDSA_SHA384, None, b"data".to_vec()),
+        ] {
+            let signature = sign_with(&mut manager, cert, mechanism, params, &data);
+            let mut tampered = data.clone();
+            tampered[0] ^= 1;
+            assert_eq!(
+                verify_with(&mut manager, cert, mechanism, params, &data, &signature),
+                Ok(())
+            );
+            assert_eq!(
+                verify_with(&mut manager, cert, mechanism, params, &tampered, &signature),
+                Err(Error::SignatureInvalid)
+            );
+            assert_eq!(
+                verify_with(
+                    &mut manager,
+                    cert,
+                    mechanism,
+                    params,
+                    &data,
+                    &signature[1..]
+                ),
+                Err(Error::SignatureLenRange)
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_in_parts() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let signature = sign_with(
+            &mut manager,
+            P256_CERT,
+            CKM_ECDSA_SHA256,
+            None,
+            b"hello, world",
+        );
+        let key_handle = find_handle(&mut manager, CKO_PUBLIC_KEY, P256_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager
+            .start_verify(session, key_handle, CKM_ECDSA_SHA256, None)
+            .unwrap();
+        manager.verify_update(session, b"hello, ").unwrap();
+        manager.verify_update(session, b"world").unwrap();
+        // An operation that was given data in parts has to be finished with verify_final.
+        assert_eq!(
+            manager.verify(session, b"", &signature),
+            Err(Error::OperationActive)
+        );
+        assert_eq!(manager.verify_final(session, &signature), Ok(()));
+        assert_eq!(
+            manager.verify_final(session, &signature),
+            Err(Error::OperationNotInitialized)
+        );
+        // The single-part mechanisms can't be given data in parts, which finishes the operation.
+        manager
+            .start_verify(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        assert_eq!(
+            manager.verify_update(session, b"hello"),
+            Err(Error::MechanismInvalid)
+        );
+        assert!(!manager.get_session_info(session).unwrap().verify_active);
+        // Nor can they be finished with verify_final.
+        manager
+            .start_verify(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        assert_eq!(
+            manager.verify_final(session, &signature),
+            Err(Error::MechanismInvalid)
+        );
+        assert!(!manager.get_session_info(session).unwrap().verify_active);
+    }
+
+    #[test]
+    fn test_start_verify_checks_key_and_mechanism() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let rsa_public_key_handle = find_handle(&mut manager, CKO_PUBLIC_KEY, RSA_CERT);
+        let rsa_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(
+            manager.start_verify(session, rsa_public_key_handle, CKM_RSA_PKCS_OAEP, None),
+            Err(Error::MechanismInvalid)
+        );
+        // Signatures are verified with public keys, not private keys.
+        assert_eq!(
+            manager.start_verify(session, rsa_key_handle, CKM_RSA_PKCS, None),
+            Err(Error::KeyHandleInvalid)
+        );
+        assert_eq!(
+            manager.start_verify(session, rsa_public_key_handle, CKM_ECDSA, None),
+            Err(Error::KeyTypeInconsistent)
+        );
+        let params = CK_RSA_PKCS_PSS_PARAMS {
+            hashAlg: CKM_SHA512,
+            mgf: CKG_MGF1_SHA512,
+            sLen: 64,
+        };
+        assert_eq!(
+            manager.start_verify(
+                session,
+                rsa_public_key_handle,
+                CKM_SHA256_RSA_PKCS_PSS,
+                Some(params)
+            ),
+            Err(Error::MechanismParamInvalid)
+        );
+        assert!(manager
+            .start_verify(session, rsa_public_key_handle, CKM_RSA_PKCS, None)
+            .is_ok());
+        assert_eq!(
+            manager.start_verify(session, rsa_public_key_handle, CKM_RSA_PKCS, None),
+            Err(Error::OperationActive)
+        );
+        assert_eq!(
+            get_attribute(&mut manager, rsa_public_key_handle, CKA_VERIFY),
+            Some(serialize_uint(CK_TRUE).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_login() {
+        let store = MockStore::new();
+        store.add_identity(RSA_CERT, RSA_KEY);
+        store.add_locked_identity(P256_CERT, P256_KEY, "1234");
+        let mut manager = new_manager(&store);
+        let login_required = |manager: &Manager| {
+            manager
+                .get_token_info(SLOT_ID)
+                .map(|token_info| token_info.login_required)
+        };
+        assert_eq!(login_required(&manager), Ok(true));
+        assert_eq!(find_objects(&mut manager, &[]).len(), 3);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(manager.login(session, "0000"), Err(Error::PinIncorrect));
+        assert_eq!(login_required(&manager), Ok(true));
+        // Logging in makes the newly-available objects visible immediately.
+        assert!(manager.login(session, "1234").is_ok());
+        assert_eq!(login_required(&manager), Ok(false));
+        assert_eq!(find_objects(&mut manager, &[]).len(), 6);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        assert!(manager.logout(session).is_ok());
+        assert_eq!(login_required(&manager), Ok(true));
+        // Logging out makes them unavailable immediately.
+        assert_eq!(find_objects(&mut manager, &[]).len(), 3);
+        assert_eq!(
+            manager.get_attributes(session, key_handle, vec![CKA_CLASS]),
+            Err(Error::ObjectHandleInvalid)
+        );
+    }
+
+    #[test]
+    fn test_login_state() {
+        let store = MockStore::new();
+        store.add_locked_identity(P256_CERT, P256_KEY, "1234");
+        let mut manager = new_manager(&store);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(manager.logout(session), Err(Error::UserNotLoggedIn));
+        assert!(manager.login(session, "1234").is_ok());
+        assert_eq!(
+            manager.login(session, "1234"),
+            Err(Error::UserAlreadyLoggedIn)
+        );
+        assert!(manager.logout(session).is_ok());
+        assert_eq!(manager.logout(session), Err(Error::UserNotLoggedIn));
+    }
+
+    #[test]
+    fn test_closing_last_session_logs_out() {
+        let store = MockStore::new();
+        store.add_identity(RSA_CERT, RSA_KEY);
+        store.add_locked_identity(P256_CERT, P256_KEY, "1234");
+        let mut manager = new_manager(&store);
+        let first_session = manager.open_session(SLOT_ID, false).unwrap();
+        let second_session = manager.open_session(SLOT_ID, false).unwrap();
+        assert!(manager.login(first_session, "1234").is_ok());
+        assert_eq!(find_objects(&mut manager, &[]).len(), 6);
+        // Other sessions keep the slot logged in.
+        assert!(manager.close_session(first_session).is_ok());
+        assert!(manager.get_session_info(second_session).unwrap().logged_in);
+        assert!(manager.close_session(second_session).is_ok());
+        assert!(manager.get_token_info(SLOT_ID).unwrap().login_required);
+        assert_eq!(find_objects(&mut manager, &[]).len(), 3);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert!(!manager.get_session_info(session).unwrap().logged_in);
+        assert!(manager.login(session, "1234").is_ok());
+        assert!(manager.close_all_sessions(SLOT_ID).is_ok());
+        assert!(manager.get_token_info(SLOT_ID).unwrap().login_required);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert!(manager.login(session, "1234").is_ok());
+    }
+
+    #[test]
+    fn test_pin_lockout() {
+        let store = MockStore::new();
+        store.add_locked_identity(P256_CERT, P256_KEY, "1234");
+        let mut manager = new_manager(&store);
+        let pin_attempts_remaining = |manager: &Manager| {
+            manager
+                .get_token_info(SLOT_ID)
+                .map(|token_info| token_info.pin_attempts_remaining)
+        };
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(pin_attempts_remaining(&manager), Ok(MAX_PIN_ATTEMPTS));
+        assert_eq!(manager.login(session, "0000"), Err(Error::PinIncorrect));
+        assert_eq!(pin_attempts_remaining(&manager), Ok(MAX_PIN_ATTEMPTS - 1));
+        // Logging in successfully resets the count.
+        assert!(manager.login(session, "1234").is_ok());
+        assert_eq!(pin_attempts_remaining(&manager), Ok(MAX_PIN_ATTEMPTS));
+        assert!(manager.logout(session).is_ok());
+        for _ in 0..MAX_PIN_ATTEMPTS {
+            assert_eq!(manager.login(session, "0000"), Err(Error::PinIncorrect));
+        }
+        assert_eq!(pin_attempts_remaining(&manager), Ok(0));
+        // Once locked, not even the correct PIN is accepted.
+        assert_eq!(manager.login(session, "1234"), Err(Error::PinLocked));
+        assert!(manager.get_token_info(SLOT_ID).unwrap().login_required);
+    }
+
+    #[test]
+    fn test_context_specific_login() {
+        let store = MockStore::with_fixtures();
+        store.set_always_authenticate_pin(Some("1234"));
+        let mut manager = new_manager(&store);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, P256_CERT);
+        assert_eq!(
+            get_attribute(&mut manager, key_handle, CKA_ALWAYS_AUTHENTICATE),
+            Some(serialize_uint(CK_TRUE).unwrap())
+        );
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        assert_eq!(
+            manager.login_context_specific(session, "1234"),
+            Err(Error::OperationNotInitialized)
+        );
+        manager
+            .start_sign(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        let hash = Sha256::digest(b"hello, world");
+        // Signing fails until the PIN is given, but the operation stays active.
+        assert_eq!(manager.sign(session, &hash), Err(Error::UserNotLoggedIn));
+        assert_eq!(
+            manager.login_context_specific(session, "0000"),
+            Err(Error::PinIncorrect)
+        );
+        assert_eq!(
+            manager
+                .get_token_info(SLOT_ID)
+                .unwrap()
+                .pin_attempts_remaining,
+            MAX_PIN_ATTEMPTS - 1
+        );
+        assert!(manager.login_context_specific(session, "1234").is_ok());
+        let signature = manager.sign(session, &hash).unwrap();
+        let key = p256::ecdsa::SigningKey::from_pkcs8_der(P256_KEY).unwrap();
+        let signature = p256::ecdsa::Signature::from_slice(&signature).unwrap();
+        assert!(key
+            .verifying_key()
+            .verify_prehash(&hash, &signature)
+            .is_ok());
+        // The PIN is only good for one operation.
+        manager
+            .start_sign(session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        assert_eq!(manager.sign(session, &hash), Err(Error::UserNotLoggedIn));
+        // If the backend still needs the PIN, the operation stays active too.
+        assert!(manager.login_context_specific(session, "1234").is_ok());
+        store.set_sign_error(Some(Error::UserNotLoggedIn));
+        assert_eq!(manager.sign(session, &hash), Err(Error::UserNotLoggedIn));
+        store.set_sign_error(None);
+        assert!(manager.login_context_specific(session, "1234").is_ok());
+        assert!(manager.sign(session, &hash).is_ok());
+        // Such keys can't be used to decrypt, since there's no way to give the PIN for that.
+        let rsa_key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        assert_eq!(
+            manager.start_decrypt(session, rsa_key_handle, CKM_RSA_PKCS, None),
+            Err(Error::KeyFunctionNotPermitted)
+        );
+    }
+
+    #[test]
+    fn test_keys_dont_always_authenticate_by_default() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        let key_handle = find_handle(&mut manager, CKO_PRIVATE_KEY, RSA_CERT);
+        assert_eq!(
+            get_attribute(&mut manager, key_handle, CKA_ALWAYS_AUTHENTICATE),
+            Some(serialize_uint(CK_FALSE).unwrap())
+        );
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager
+            .start_sign(session, key_handle, CKM_RSA_PKCS, None)
+            .unwrap();
+        assert_eq!(
+            manager.login_context_specific(session, "1234"),
+            Err(Error::OperationNotInitialized)
+        );
+    }
+
+    #[test]
+    fn test_slot_per_backend() {
+        let first_store = MockStore::new();
+        first_store.add_identity(RSA_CERT, RSA_KEY);
+        let second_store = MockStore::new();
+        second_store.add_identity(P256_CERT, P256_KEY);
+        second_store.add_identity(P384_CERT, P384_KEY);
+        let mut manager = new_multi_slot_manager(&[&first_store, &second_store]);
+        assert_eq!(manager.get_slot_ids(), Ok(vec![1, 2]));
+        assert_eq!(first_store.scan_count(), 1);
+        assert_eq!(second_store.scan_count(), 1);
+        let first_token_info = manager.get_token_info(1).unwrap();
+        let second_token_info = manager.get_token_info(2).unwrap();
+        assert_eq!(first_token_info.serial_number, "0000000000000001");
+        assert_eq!(second_token_info.serial_number, "0000000000000002");
+        assert!(manager.get_token_info(3).is_err());
+        assert!(manager.open_session(3, false).is_err());
+        // Each slot only has the objects of its own backend.
+        let first_handles = find_objects_in_slot(&mut manager, 1, &[]);
+        let second_handles = find_objects_in_slot(&mut manager, 2, &[]);
+        assert_eq!(first_handles.len(), 3);
+        assert_eq!(second_handles.len(), 6);
+        assert!(first_handles
+            .iter()
+            .all(|handle| !second_handles.contains(handle)));
+        let id = Sha256::digest(RSA_CERT).to_vec();
+        assert!(find_objects_in_slot(&mut manager, 2, &[(CKA_ID, id)]).is_empty());
+    }
+
+    #[test]
+    fn test_configured_token_info() {
+        let store = MockStore::new();
+        let configured = ConfiguredBackend::new(
+            Box::new(MockBackend::new(store.clone())),
+            TokenConfig {
+                label: Some(String::from("Configured Token")),
+                serial_number: Some(String::from("serial")),
+                manufacturer: Some(String::from("Example Corp")),
+            },
+            Filter::default(),
+        );
+        let manager = Manager::new(
+            vec![
+                Box::new(configured),
+                Box::new(MockBackend::new(store.clone())),
+            ],
+            DEFAULT_RESCAN_INTERVAL,
+        );
+        let token_info = manager.get_token_info(1).unwrap();
+        assert_eq!(token_info.label, "Configured Token");
+        assert_eq!(token_info.serial_number, "serial");
+        assert_eq!(token_info.manufacturer, "Example Corp");
+        let token_info = manager.get_token_info(2).unwrap();
+        assert_eq!(token_info.label, "mock token");
+        assert_eq!(token_info.serial_number, "0000000000000002");
+        assert_eq!(token_info.manufacturer, DEFAULT_MANUFACTURER);
+    }
+
+    #[test]
+    fn test_configured_rescan_interval() {
+        let store = MockStore::new();
+        let mut manager = Manager::new(
+            vec![Box::new(MockBackend::new(store.clone()))],
+            Duration::from_secs(3600),
+        );
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager.close_session(session).unwrap();
+        assert_eq!(store.scan_count(), 1);
+        expire_last_scan(&mut manager);
+        let session = manager.open_session(SLOT_ID, false).unwrap();
+        manager.close_session(session).unwrap();
+        assert_eq!(store.scan_count(), 2);
+    }
+
+    #[test]
+    fn test_same_identity_in_two_slots() {
+        let store = MockStore::new();
+        store.add_identity(P256_CERT, P256_KEY);
+        let mut manager = new_multi_slot_manager(&[&store, &store]);
+        let first_handles = find_objects_in_slot(&mut manager, 1, &[]);
+        let second_handles = find_objects_in_slot(&mut manager, 2, &[]);
+        assert_eq!(first_handles.len(), 3);
+        assert_eq!(second_handles.len(), 3);
+        assert!(first_handles
+            .iter()
+            .all(|handle| !second_handles.contains(handle)));
+    }
+
+    #[test]
+    fn test_sessions_only_see_their_slot() {
+        let first_store = MockStore::new();
+        first_store.add_identity(RSA_CERT, RSA_KEY);
+        let second_store = MockStore::new();
+        second_store.add_identity(P256_CERT, P256_KEY);
+        let mut manager = new_multi_slot_manager(&[&first_store, &second_store]);
+        let id = Sha256::digest(P256_CERT).to_vec();
+        let key_handle = find_objects_in_slot(
+            &mut manager,
+            2,
+            &[class_attr(CKO_PRIVATE_KEY), (CKA_ID, id)],
+        )[0];
+        let first_session = manager.open_session(1, false).unwrap();
+        assert!(manager
+            .get_attributes(first_session, key_handle, vec![CKA_CLASS])
+            .is_err());
+        assert!(manager
+            .start_sign(first_session, key_handle, CKM_ECDSA, None)
+            .is_err());
+        let second_session = manager.open_session(2, false).unwrap();
+        assert!(manager
+            .get_attributes(second_session, key_handle, vec![CKA_CLASS])
+            .is_ok());
+        manager
+            .start_sign(second_session, key_handle, CKM_ECDSA, None)
+            .unwrap();
+        assert_eq!(manager.sign(second_session, &[0; 32]).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_close_all_sessions_is_per_slot() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_multi_slot_manager(&[&store, &store]);
+        let first_session = manager.open_session(1, false).unwrap();
+        let second_session = manager.open_session(2, false).unwrap();
+        assert!(first_session != second_session);
+        manager.start_search(first_session, &[]).unwrap();
+        assert!(manager.close_all_sessions(1).is_ok());
+        assert!(manager.close_session(first_session).is_err());
+        // Closing a session ends its operations.
+        assert!(manager.search(first_session, 1).is_err());
+        assert!(manager.close_session(second_session).is_ok());
+        assert!(manager.close_all_sessions(3).is_err());
+    }
+
+    #[test]
+    fn test_login_is_per_slot() {
+        let first_store = MockStore::new();
+        first_store.add_locked_identity(RSA_CERT, RSA_KEY, "1234");
+        let second_store = MockStore::new();
+        second_store.add_locked_identity(P256_CERT, P256_KEY, "5678");
+        let mut manager = new_multi_slot_manager(&[&first_store, &second_store]);
+        assert!(manager.get_token_info(1).unwrap().login_required);
+        assert!(manager.get_token_info(2).unwrap().login_required);
+        let first_session = manager.open_session(1, false).unwrap();
+        assert!(manager.login(first_session, "5678").is_err());
+        assert!(manager.login(first_session, "1234").is_ok());
+        assert!(!manager.get_token_info(1).unwrap().login_required);
+        assert!(manager.get_token_info(2).unwrap().login_required);
+        assert_eq!(find_objects_in_slot(&mut manager, 1, &[]).len(), 3);
+        assert!(find_objects_in_slot(&mut manager, 2, &[]).is_empty());
+        assert!(manager.logout(first_session).is_ok());
+        assert!(manager.get_token_info(1).unwrap().login_required);
+        // Logging in requires a session.
+        assert!(manager.login(first_session + 1, "5678").is_err());
+    }
+
+    #[test]
+    fn test_no_slot_event_without_changes() {
+        let store = MockStore::with_fixtures();
+        let mut manager = new_manager(&store);
+        assert_eq!(manager.get_slot_event(), Ok(None));
+        expire_last_scan(&mut manager);
+        assert_eq!(manager.get_slot_event(), Ok(None));
+        assert_eq!(store.scan_count(), 2);
+    }
+
+    #[test]
+    fn test_slot_event_when_identities_change() {
+        let store = MockStore::new();
+        store.add_identity(RSA_CERT, RSA_KEY);
+        let mut manager = new_manager(&store);
+        store.add_identity(P256_CERT, P256_KEY);
+        // The backend isn't asked for its identities again until the scan interval has elapsed.
+        assert_eq!(manager.get_slot_event(), Ok(None));
+        expire_last_scan(&mut manager);
+        assert_eq!(manager.get_slot_event(), Ok(Some(SLOT_ID)));
+        assert_eq!(manager.get_slot_event(), Ok(None));
+        // The new objects are available right away.
+        assert_eq!(find_objects(&mut manager, &[]).len(), 6);
+    }
+
+    #[test]
+    fn test_slot_events_are_per_slot() {
+        let first_store = MockStore::with_fixtures();
+        let second_store = MockStore::new();
+        let mut manager = new_multi_slot_manager(&[&first_store, &second_store]);
+        second_store.add_identity(RSA_CERT, RSA_KEY);
+        expire_last_scan(&mut manager);
+        assert_eq!(manager.get_slot_event(), Ok(Some(2)));
+        assert_eq!(manager.get_slot_event(), Ok(None));
+        first_store.add_identity(RSA_CERT, RSA_KEY);
+        second_store.add_identity(P256_CERT, P256_KEY);
+        expire_last_scan(&mut manager);
+        // The first store already had this identity, so only the second slot has changed.
+        assert_eq!(manager.get_slot_event(), Ok(Some(2)));
+        assert_eq!(manager.get_slot_event(), Ok(None));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_slot_event_for_watched_directory() {
+        use crate::backend_file::FileBackend;
+
+        let directory = tempfile::tempdir().unwrap();
+        let backend = FileBackend::with_directories("test", vec![directory.path().to_path_buf()]);
+        let mut manager = Manager::new(vec![Box::new(backend)], DEFAULT_RESCAN_INTERVAL);
+        assert!(manager.slots[&SLOT_ID].watcher.is_some());
+        assert_eq!(manager.get_slot_event(), Ok(None));
+        std::fs::write(directory.path().join("rsa.crt"), RSA_CERT).unwrap();
+        std::fs::write(directory.path().join("rsa.key"), RSA_KEY).unwrap();
+        // The scan interval hasn't elapsed, but the directory is known to have changed.
+        assert_eq!(manager.get_slot_event(), Ok(Some(SLOT_ID)));
+        assert_eq!(manager.get_slot_event(), Ok(None));
+        assert_eq!(find_objects(&mut manager, &[]).len(), 3);
+        // If the directory goes away, the slot falls back to being scanned periodically.
+        std::fs::remove_file(directory.path().join("rsa.crt")).unwrap();
+        std::fs::remove_file(directory.path().join("rsa.key")).unwrap();
+        std::fs::remove_dir(directory.path()).unwrap();
+        assert_eq!(manager.get_slot_event(), Ok(Some(SLOT_ID)));
+        assert!(manager.slots[&SLOT_ID].watcher.is_none());
+        assert_eq!(manager.get_slot_event(), Ok(None));
+    }
+
+    #[test]
+    fn test_manager_proxy() {
+        let store = MockStore::with_fixtures();
+        let backend_store = store.clone();
+        let mut manager_proxy = ManagerProxy::new(
+            move || vec![Box::new(MockBackend::new(backend_store)) as Box<dyn Backend>],
+            DEFAULT_RESCAN_INTERVAL,
+        );
+        assert_eq!(manager_proxy.get_slot_ids(), Ok(vec![SLOT_ID]));
+        assert_eq!(
+            manager_proxy.get_token_info(SLOT_ID),
+            Ok(TokenInfo {
+                label: String::from("mock token"),
+                serial_number: String::from("0000000000000001"),
+                manufacturer: String::from("Mozilla Corporation"),
+                login_required: false,
+                pin_attempts_remaining: MAX_PIN_ATTEMPTS,
+            })
+        );
+        let session = manager_proxy.open_session(SLOT_ID, false).unwrap();
+        let id = Sha256::digest(RSA_CERT).to_vec();
+        manager_proxy
+            .start_search(session, vec![class_attr(CKO_PRIVATE_KEY), (CKA_ID, id)])
+            .unwrap();
+        let handles = manager_proxy.search(session, 10).unwrap();
+        assert_eq!(handles.len(), 1);
+        assert!(manager_proxy.search(session, 10).unwrap().is_empty());
+        manager_proxy.clear_search(session).unwrap();
+        let key_handle = handles[0];
+        assert_eq!(
+            manager_proxy
+                .get_attributes(session, key_handle, vec![CKA_KEY_TYPE, CKA_LABEL])
+                .unwrap(),
+            vec![
+                Ok(serialize_uint(CKK_RSA).unwrap()),
+                Err(Error::AttributeTypeInvalid)
+            ]
+        );
+        manager_proxy
+            .start_sign(session, key_handle, CKM_RSA_PKCS, None)
+            .unwrap();
+        let digest_info = sha256_digest_info(b"hello, world");
+        assert_eq!(
+            manager_proxy.get_signature_length(session, digest_info.clone()),
+            Ok(256)
+        );
+        let signature = manager_proxy.sign(session, digest_info.clone()).unwrap();
+        assert!(rsa_public_key()
+            .verify(Pkcs1v15Sign::new_unprefixed(), &digest_info, &signature)
+            .is_ok());
+        assert!(manager_proxy.sign(session, vec![0; 32]).is_err());
+        manager_proxy.close_session(session).unwrap();
+        assert!(manager_proxy.close_session(session).is_err());
+        assert!(manager_proxy.close_all_sessions(SLOT_ID).is_ok());
+        assert_eq!(store.scan_count(), 1);
+        assert_eq!(manager_proxy.get_slot_event(), Ok(None));
+        assert!(manager_proxy.stop().is_ok());
+        // The manager thread is gone, so nothing else works.
+        assert!(manager_proxy.open_session(SLOT_ID, false).is_err());
+        assert!(manager_proxy.stop().is_err());
+    }
+
+    #[test]
+    fn test_inline_manager_proxy() {
+        let store = MockStore::with_fixtures();
+        let mut manager_proxy = ManagerProxy::new_inline(
+            vec![Box::new(MockBackend::new(store.clone()))],
+            DEFAULT_RESCAN_INTERVAL,
+        );
+        // The backends are scanned right away, on this thread.
+        assert_eq!(store.scan_count(), 1);
+        assert_eq!(manager_proxy.get_slot_ids(), Ok(vec![SLOT_ID]));
+        let session = manager_proxy.open_session(SLOT_ID, false).unwrap();
+        manager_proxy
+            .start_search(session, vec![class_attr(CKO_PRIVATE_KEY)])
+            .unwrap();
+        let handles = manager_proxy.search(session, 10).unwrap();
+        assert_eq!(handles.len(), 3);
+        manager_proxy.clear_search(session).unwrap();
+        assert!(manager_proxy.stop().is_ok());
+        // The manager is gone, so nothing else works.
+        assert!(manager_proxy.open_session(SLOT_ID, false).is_err());
+        assert!(manager_proxy.stop().is_err());
+    }
+
+    #[test]
+    fn test_manager_proxy_errors() {
+        let store = MockStore::new();
+        let mut manager_proxy = ManagerProxy::new(
+            move || vec![Box::new(MockBackend::new(store)) as Box<dyn Backend>],
+            DEFAULT_RESCAN_INTERVAL,
+        );
+        assert!(manager_proxy.open_session(SLOT_ID + 1, false).is_err());
+        assert!(manager_proxy.get_token_info(SLOT_ID + 1).is_err());
+        let session = manager_proxy.open_session(SLOT_ID, false).unwrap();
+        assert!(manager_proxy.search(session, 1).is_err());
+        manager_proxy.start_search(session, Vec::new()).unwrap();
+        assert!(manager_proxy.start_search(session, Vec::new()).is_err());
+        assert!(manager_proxy.search(session, 0).is_err());
+        assert!(manager_proxy.search(session, 1).unwrap().is_empty());
+        assert!(manager_proxy
+            .get_attributes(session, 1, vec![CKA_CLASS])
+            .is_err());
+        assert!(manager_proxy
+            .start_sign(session, 1, CKM_ECDSA, None)
+            .is_err());
+        assert!(manager_proxy.sign(session, vec![0; 32]).is_err());
+        assert!(manager_proxy.stop().is_ok());
     }
 }
@@ -0,0 +1,532 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use aes_gcm::aead::consts::{U12, U13, U14, U15, U16};
+use aes_gcm::aead::{Aead, Nonce};
+use aes_gcm::AesGcm;
+use cbc::cipher::block_padding::Pkcs7;
+use cbc::cipher::{BlockCipher, BlockDecrypt, BlockDecryptMut, BlockEncrypt, InnerIvInit, KeyInit};
+use hmac::digest::core_api::BlockSizeUser;
+use hmac::digest::Digest;
+use hmac::{Mac, SimpleHmac};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Sha256, Sha384, Sha512};
+use std::convert::TryFrom;
+
+use crate::util::pkcs::*;
+
+/// The most iterations of PBKDF2 or of the PKCS #12 key derivation function that will be done.
+/// The parameters come from the file being decrypted, so without a limit, one file could make
+/// logging in take practically forever. Tools use far fewer by default (OpenSSL uses 2048, and
+/// OWASP recommends 1,300,000 for PBKDF2 with HMAC-SHA1, the slowest case).
+const MAX_ITERATIONS: u64 = 2_000_000;
+
+/// The most memory (128 * N * r bytes) scrypt will use. OpenSSL refuses more than 32MB by default;
+/// this allows for keys encrypted with more demanding parameters.
+const MAX_SCRYPT_MEMORY: u64 = 256 * 1024 * 1024;
+
+/// The most work scrypt will do, in the same units as `MAX_SCRYPT_MEMORY` (the memory needed for
+/// each of the p parallel computations, which are done one after the other).
+const MAX_SCRYPT_WORK: u64 = 4 * MAX_SCRYPT_MEMORY;
+
+/// Fails if the given number of iterations is more than `MAX_ITERATIONS` (or 0).
+fn check_iterations(iterations: u64) -> Result<(), ()> {
+    if iterations == 0 || iterations > MAX_ITERATIONS {
+        error!("unsupported iteration count {}", iterations);
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Decrypts data that was encrypted with a password-based encryption scheme. Supported schemes are
+/// PBES2 (with PBKDF2 or scrypt and AES-CBC, AES-GCM, or 3DES-CBC) and the legacy PKCS #12 schemes
+/// (3DES and RC2 with a key derived from the password using SHA-1).
+pub fn decrypt(
+    algorithm: &AlgorithmIdentifier,
+    password: &str,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let parameters = algorithm.parameters.ok_or(())?;
+    if algorithm.algorithm == OID_BYTES_PBES2 {
+        return decrypt_pbes2(&read_pbes2_parameters(parameters)?, password, ciphertext);
+    }
+    let parameters = read_pkcs12_pbe_parameters(parameters)?;
+    let password = bmp_password(password);
+    let derive = |id, length| {
+        pkcs12_kdf::<Sha1>(
+            &password,
+            parameters.salt,
+            id,
+            parameters.iterations,
+            length,
+        )
+    };
+    match algorithm.algorithm {
+        OID_BYTES_PBE_SHA1_3DES => cbc_decrypt::<des::TdesEde3>(
+            des::TdesEde3::new_from_slice(&derive(PKCS12_KDF_KEY, 24)?).map_err(|_| ())?,
+            &derive(PKCS12_KDF_IV, 8)?,
+            ciphertext,
+        ),
+        OID_BYTES_PBE_SHA1_2DES => cbc_decrypt::<des::TdesEde2>(
+            des::TdesEde2::new_from_slice(&derive(PKCS12_KDF_KEY, 16)?).map_err(|_| ())?,
+            &derive(PKCS12_KDF_IV, 8)?,
+            ciphertext,
+        ),
+        OID_BYTES_PBE_SHA1_RC2_128 => cbc_decrypt::<rc2::Rc2>(
+            rc2::Rc2::new_with_eff_key_len(&derive(PKCS12_KDF_KEY, 16)?, 128),
+            &derive(PKCS12_KDF_IV, 8)?,
+            ciphertext,
+        ),
+        OID_BYTES_PBE_SHA1_RC2_40 => cbc_decrypt::<rc2::Rc2>(
+            rc2::Rc2::new_with_eff_key_len(&derive(PKCS12_KDF_KEY, 5)?, 40),
+            &derive(PKCS12_KDF_IV, 8)?,
+            ciphertext,
+        ),
+        _ => {
+            error!("unsupported password-based encryption scheme");
+            Err(())
+        }
+    }
+}
+
+fn decrypt_pbes2(
+    parameters: &Pbes2Parameters,
+    password: &str,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let encryption_scheme = &parameters.encryption_scheme;
+    let key_length = match encryption_scheme.algorithm {
+        OID_BYTES_AES_128_CBC | OID_BYTES_AES_128_GCM => 16,
+        OID_BYTES_AES_192_CBC | OID_BYTES_AES_192_GCM => 24,
+        OID_BYTES_AES_256_CBC | OID_BYTES_AES_256_GCM => 32,
+        OID_BYTES_DES_EDE3_CBC => 24,
+        _ => {
+            error!("unsupported PBES2 encryption scheme");
+            return Err(());
+        }
+    };
+    let key = match &parameters.kdf {
+        KeyDerivationFunction::Pbkdf2(kdf) => pbkdf2(kdf, password.as_bytes(), key_length)?,
+        KeyDerivationFunction::Scrypt(kdf) => scrypt(kdf, password.as_bytes(), key_length)?,
+    };
+    let parameters = encryption_scheme.parameters.ok_or(())?;
+    match encryption_scheme.algorithm {
+        OID_BYTES_AES_128_GCM => {
+            gcm_decrypt::<aes::Aes128>(&key, &read_gcm_parameters(parameters)?, ciphertext)
+        }
+        OID_BYTES_AES_192_GCM => {
+            gcm_decrypt::<aes::Aes192>(&key, &read_gcm_parameters(parameters)?, ciphertext)
+        }
+        OID_BYTES_AES_256_GCM => {
+            gcm_decrypt::<aes::Aes256>(&key, &read_gcm_parameters(parameters)?, ciphertext)
+        }
+        OID_BYTES_AES_128_CBC => cbc_decrypt::<aes::Aes128>(
+            aes::Aes128::new_from_slice(&key).map_err(|_| ())?,
+            read_octet_string(parameters)?,
+            ciphertext,
+        ),
+        OID_BYTES_AES_192_CBC => cbc_decrypt::<aes::Aes192>(
+            aes::Aes192::new_from_slice(&key).map_err(|_| ())?,
+            read_octet_string(parameters)?,
+            ciphertext,
+        ),
+        OID_BYTES_AES_256_CBC => cbc_decrypt::<aes::Aes256>(
+            aes::Aes256::new_from_slice(&key).map_err(|_| ())?,
+            read_octet_string(parameters)?,
+            ciphertext,
+        ),
+        _ => cbc_decrypt::<des::TdesEde3>(
+            des::TdesEde3::new_from_slice(&key).map_err(|_| ())?,
+            read_octet_string(parameters)?,
+            ciphertext,
+        ),
+    }
+}
+
+/// If the parameters of a key derivation function specify the length of the key, checks that it
+/// is the length the encryption scheme needs.
+fn check_key_length(specified_key_length: Option<u64>, key_length: usize) -> Result<(), ()> {
+    match specified_key_length {
+        Some(specified_key_length) if specified_key_length != key_length as u64 => {
+            error!("PBES2 key length doesn't match the encryption scheme");
+            Err(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn pbkdf2(parameters: &Pbkdf2Parameters, password: &[u8], length: usize) -> Result<Vec<u8>, ()> {
+    check_key_length(parameters.key_length, length)?;
+    check_iterations(parameters.iterations)?;
+    let iterations = u32::try_from(parameters.iterations).map_err(|_| ())?;
+    let mut key = vec![0; length];
+    match parameters.prf {
+        OID_BYTES_HMAC_SHA1 => {
+            pbkdf2::pbkdf2_hmac::<Sha1>(password, parameters.salt, iterations, &mut key)
+        }
+        OID_BYTES_HMAC_SHA256 => {
+            pbkdf2::pbkdf2_hmac::<Sha256>(password, parameters.salt, iterations, &mut key)
+        }
+        OID_BYTES_HMAC_SHA384 => {
+            pbkdf2::pbkdf2_hmac::<Sha384>(password, parameters.salt, iterations, &mut key)
+        }
+        OID_BYTES_HMAC_SHA512 => {
+            pbkdf2::pbkdf2_hmac::<Sha512>(password, parameters.salt, iterations, &mut key)
+        }
+        _ => {
+            error!("unsupported PBKDF2 pseudorandom function");
+            return Err(());
+        }
+    }
+    Ok(key)
+}
+
+fn scrypt(parameters: &ScryptParameters, password: &[u8], length: usize) -> Result<Vec<u8>, ()> {
+    check_key_length(parameters.key_length, length)?;
+    if !parameters.cost.is_power_of_two() {
+        return Err(());
+    }
+    let memory = 128u64
+        .checked_mul(parameters.cost)
+        .and_then(|memory| memory.checked_mul(parameters.block_size))
+        .ok_or(())?;
+    let work = memory.checked_mul(parameters.parallelization).ok_or(())?;
+    if memory > MAX_SCRYPT_MEMORY || work > MAX_SCRYPT_WORK {
+        error!(
+            "unsupported scrypt parameters (N = {}, r = {}, p = {})",
+            parameters.cost, parameters.block_size, parameters.parallelization
+        );
+        return Err(());
+    }
+    let log_n = u8::try_from(parameters.cost.trailing_zeros()).map_err(|_| ())?;
+    let r = u32::try_from(parameters.block_size).map_err(|_| ())?;
+    let p = u32::try_from(parameters.parallelization).map_err(|_| ())?;
+    let params = scrypt::Params::new(log_n, r, p, length)
+        .map_err(|e| error!("invalid scrypt parameters: {}", e))?;
+    let mut key = vec![0; length];
+    scrypt::scrypt(password, parameters.salt, &params, &mut key).map_err(|_| ())?;
+    Ok(key)
+}
+
+/// Decrypts the given data (with the authentication tag appended) with AES in GCM mode. Only
+/// 12-byte nonces are supported.
+fn gcm_decrypt<C>(key: &[u8], parameters: &GcmParameters, ciphertext: &[u8]) -> Result<Vec<u8>, ()>
+where
+    C: BlockCipher + BlockSizeUser<BlockSize = U16> + BlockEncrypt + KeyInit,
+{
+    match parameters.icv_length {
+        12 => aead_decrypt::<AesGcm<C, U12, U12>>(key, parameters.nonce, ciphertext),
+        13 => aead_decrypt::<AesGcm<C, U12, U13>>(key, parameters.nonce, ciphertext),
+        14 => aead_decrypt::<AesGcm<C, U12, U14>>(key, parameters.nonce, ciphertext),
+        15 => aead_decrypt::<AesGcm<C, U12, U15>>(key, parameters.nonce, ciphertext),
+        16 => aead_decrypt::<AesGcm<C, U12, U16>>(key, parameters.nonce, ciphertext),
+        _ => {
+            error!("unsupported AES-GCM tag length");
+            Err(())
+        }
+    }
+}
+
+fn aead_decrypt<A: Aead + KeyInit>(
+    key: &[u8],
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let mut nonce_array = Nonce::<A>::default();
+    if nonce.len() != nonce_array.len() {
+        error!("unsupported AES-GCM nonce length");
+        return Err(());
+    }
+    nonce_array.copy_from_slice(nonce);
+    let cipher = A::new_from_slice(key).map_err(|_| ())?;
+    cipher.decrypt(&nonce_array, ciphertext).map_err(|_| ())
+}
+
+/// Decrypts a key from a PEM block with OpenSSL's legacy encryption headers ("Proc-Type:
+/// 4,ENCRYPTED" and "DEK-Info"). `dek_info` is the value of the DEK-Info header, which consists of
+/// the name of the cipher and the hex-encoded initialization vector. The key is derived from the
+/// password with OpenSSL's EVP_BytesToKey (MD5, one iteration), using the first 8 bytes of the
+/// initialization vector as the salt.
+pub fn decrypt_legacy_pem(
+    dek_info: &str,
+    password: &str,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let (cipher, iv) = dek_info.split_once(',').ok_or(())?;
+    let iv = decode_hex(iv.trim())?;
+    let key_length = match cipher.trim() {
+        "AES-128-CBC" => 16,
+        "AES-192-CBC" => 24,
+        "AES-256-CBC" => 32,
+        "DES-EDE3-CBC" => 24,
+        "DES-CBC" => 8,
+        cipher => {
+            error!("unsupported legacy PEM encryption '{}'", cipher);
+            return Err(());
+        }
+    };
+    if iv.len() < 8 {
+        return Err(());
+    }
+    let key = evp_bytes_to_key(password.as_bytes(), &iv[..8], key_length);
+    match cipher.trim() {
+        "AES-128-CBC" => cbc_decrypt::<aes::Aes128>(
+            aes::Aes128::new_from_slice(&key).map_err(|_| ())?,
+            &iv,
+            ciphertext,
+        ),
+        "AES-192-CBC" => cbc_decrypt::<aes::Aes192>(
+            aes::Aes192::new_from_slice(&key).map_err(|_| ())?,
+            &iv,
+            ciphertext,
+        ),
+        "AES-256-CBC" => cbc_decrypt::<aes::Aes256>(
+            aes::Aes256::new_from_slice(&key).map_err(|_| ())?,
+            &iv,
+            ciphertext,
+        ),
+        "DES-EDE3-CBC" => cbc_decrypt::<des::TdesEde3>(
+            des::TdesEde3::new_from_slice(&key).map_err(|_| ())?,
+            &iv,
+            ciphertext,
+        ),
+        _ => cbc_decrypt::<des::Des>(
+            des::Des::new_from_slice(&key).map_err(|_| ())?,
+            &iv,
+            ciphertext,
+        ),
+    }
+}
+
+/// OpenSSL's EVP_BytesToKey with MD5 as the hash function and an iteration count of 1.
+fn evp_bytes_to_key(password: &[u8], salt: &[u8], length: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(length + 16);
+    let mut previous: Vec<u8> = Vec::new();
+    while key.len() < length {
+        previous = Md5::new()
+            .chain_update(&previous)
+            .chain_update(password)
+            .chain_update(salt)
+            .finalize()
+            .to_vec();
+        key.extend_from_slice(&previous);
+    }
+    key.truncate(length);
+    key
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, ()> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2).ok_or(())?, 16).map_err(|_| ()))
+        .collect()
+}
+
+/// Decrypts the given data with the given block cipher in CBC mode and removes the PKCS #7 padding.
+/// Because the padding is checked, decrypting with the wrong key will usually fail.
+fn cbc_decrypt<C: BlockCipher + BlockDecrypt>(
+    cipher: C,
+    iv: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let decryptor = cbc::Decryptor::<C>::inner_iv_slice_init(cipher, iv).map_err(|_| ())?;
+    let mut buffer = ciphertext.to_vec();
+    let plaintext_length = decryptor
+        .decrypt_padded_mut::<Pkcs7>(&mut buffer)
+        .map_err(|_| ())?
+        .len();
+    buffer.truncate(plaintext_length);
+    Ok(buffer)
+}
+
+/// The purposes a PKCS #12 key derivation can be for (these are the "ID" bytes of RFC 7292
+/// appendix B.3).
+const PKCS12_KDF_KEY: u8 = 1;
+const PKCS12_KDF_IV: u8 = 2;
+const PKCS12_KDF_MAC: u8 = 3;
+
+/// Encodes a password the way the PKCS #12 key derivation function expects: as a null-terminated
+/// BMPString (big-endian UTF-16).
+fn bmp_password(password: &str) -> Vec<u8> {
+    let mut bmp_password = Vec::with_capacity(2 * (password.len() + 1));
+    for code_unit in password.encode_utf16().chain(std::iter::once(0)) {
+        bmp_password.extend_from_slice(&code_unit.to_be_bytes());
+    }
+    bmp_password
+}
+
+/// The key derivation function from RFC 7292 appendix B.2. `password` must already be encoded as
+/// a BMPString.
+fn pkcs12_kdf<D: Digest + BlockSizeUser>(
+    password: &[u8],
+    salt: &[u8],
+    id: u8,
+    iterations: u64,
+    length: usize,
+) -> Result<Vec<u8>, ()> {
+    check_iterations(iterations)?;
+    let u = <D as Digest>::output_size();
+    let v = D::block_size();
+    // Concatenates copies of `input` to make a string of length v * ceil(len(input) / v).
+    let extend = |input: &[u8]| -> Vec<u8> {
+        let length = v * input.len().div_ceil(v);
+        input.iter().cycle().take(length).cloned().collect()
+    };
+    let d = vec![id; v];
+    let mut i = extend(salt);
+    i.extend(extend(password));
+    let mut output = Vec::with_capacity(length + u);
+    while output.len() < length {
+        let mut a = D::new().chain_update(&d).chain_update(&i).finalize();
+        for _ in 1..iterations {
+            a = D::digest(&a);
+        }
+        output.extend_from_slice(&a);
+        let b: Vec<u8> = a.iter().cycle().take(v).cloned().collect();
+        // Treating each v-byte block of I and B as big-endian integers, set each block of I to
+        // (block + B + 1) mod 2^(8v).
+        for block in i.chunks_mut(v) {
+            let mut carry = 1u16;
+            for (i_byte, b_byte) in block.iter_mut().zip(b.iter()).rev() {
+                let sum = u16::from(*i_byte) + u16::from(*b_byte) + carry;
+                *i_byte = sum as u8;
+                carry = sum >> 8;
+            }
+        }
+    }
+    output.truncate(length);
+    Ok(output)
+}
+
+/// Verifies the MAC of a PKCS #12 PFX, which is an HMAC of the contents of the AuthenticatedSafe
+/// using a key derived from the password with the PKCS #12 key derivation function.
+pub fn verify_pkcs12_mac(mac_data: &MacData, password: &str, data: &[u8]) -> Result<(), ()> {
+    let password = bmp_password(password);
+    match mac_data.digest_algorithm {
+        OID_BYTES_SHA1 => verify_hmac::<Sha1>(mac_data, &password, data),
+        OID_BYTES_SHA256 => verify_hmac::<Sha256>(mac_data, &password, data),
+        OID_BYTES_SHA384 => verify_hmac::<Sha384>(mac_data, &password, data),
+        OID_BYTES_SHA512 => verify_hmac::<Sha512>(mac_data, &password, data),
+        _ => {
+            error!("unsupported PKCS #12 MAC algorithm");
+            Err(())
+        }
+    }
+}
+
+fn verify_hmac<D: Digest + BlockSizeUser>(
+    mac_data: &MacData,
+    password: &[u8],
+    data: &[u8],
+) -> Result<(), ()> {
+    let key = pkcs12_kdf::<D>(
+        password,
+        mac_data.salt,
+        PKCS12_KDF_MAC,
+        mac_data.iterations,
+        <D as Digest>::output_size(),
+    )?;
+    let mut hmac = <SimpleHmac<D> as KeyInit>::new_from_slice(&key).map_err(|_| ())?;
+    hmac.update(data);
+    hmac.verify_slice(mac_data.digest).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bmp_password() {
+        assert_eq!(bmp_password(""), vec![0, 0]);
+        assert_eq!(bmp_password("ab"), vec![0, b'a', 0, b'b', 0, 0]);
+        assert_eq!(bmp_password("\u{e9}"), vec![0, 0xe9, 0, 0]);
+    }
+
+    // A well-known PKCS #12 key derivation test vector (also used by OpenSSL and Bouncy Castle).
+    #[test]
+    fn test_pkcs12_kdf() {
+        let password = bmp_password("smeg");
+        let salt = [0x0a, 0x58, 0xcf, 0x64, 0x53, 0x0d, 0x82, 0x3f];
+        let key = pkcs12_kdf::<Sha1>(&password, &salt, PKCS12_KDF_KEY, 1, 24).unwrap();
+        assert_eq!(
+            key,
+            vec![
+                0x8a, 0xaa, 0xe6, 0x29, 0x7b, 0x6c, 0xb0, 0x46, 0x42, 0xab, 0x5b, 0x07, 0x78, 0x51,
+                0x28, 0x4e, 0xb7, 0x12, 0x8f, 0x1a, 0x2a, 0x7f, 0xbc, 0xa3
+            ]
+        );
+        let iv = pkcs12_kdf::<Sha1>(&password, &salt, PKCS12_KDF_IV, 1, 8).unwrap();
+        assert_eq!(iv, vec![0x79, 0x99, 0x3d, 0xfe, 0x04, 0x8d, 0x3b, 0x76]);
+        assert!(pkcs12_kdf::<Sha1>(&password, &salt, PKCS12_KDF_KEY, 0, 24).is_err());
+        assert!(
+            pkcs12_kdf::<Sha1>(&password, &salt, PKCS12_KDF_KEY, MAX_ITERATIONS + 1, 24).is_err()
+        );
+    }
+
+    #[test]
+    fn test_kdf_parameters_are_limited() {
+        let pbkdf2_parameters = |iterations| Pbkdf2Parameters {
+            salt: b"salt",
+            iterations,
+            key_length: None,
+            prf: OID_BYTES_HMAC_SHA256,
+        };
+        assert!(pbkdf2(&pbkdf2_parameters(1), b"password", 16).is_ok());
+        assert!(pbkdf2(&pbkdf2_parameters(0), b"password", 16).is_err());
+        assert!(pbkdf2(&pbkdf2_parameters(MAX_ITERATIONS + 1), b"password", 16).is_err());
+        assert!(pbkdf2(&pbkdf2_parameters(u64::MAX), b"password", 16).is_err());
+
+        let scrypt_parameters = |cost, block_size, parallelization| ScryptParameters {
+            salt: b"salt",
+            cost,
+            block_size,
+            parallelization,
+            key_length: None,
+        };
+        assert!(scrypt(&scrypt_parameters(16, 1, 1), b"password", 16).is_ok());
+        // 128 * 2^20 * 8 bytes is 1GB.
+        assert!(scrypt(&scrypt_parameters(1 << 20, 8, 1), b"password", 16).is_err());
+        assert!(scrypt(&scrypt_parameters(16, 1, u64::MAX), b"password", 16).is_err());
+        assert!(scrypt(&scrypt_parameters(1 << 14, 8, 65), b"password", 16).is_err());
+        assert!(scrypt(&scrypt_parameters(1 << 62, 1 << 62, 1), b"password", 16).is_err());
+    }
+
+    #[test]
+    fn test_evp_bytes_to_key() {
+        // Generated with `openssl enc -aes-256-cbc -md md5 -pass pass:password -S 0102030405060708 -P`
+        // (which derives both the key and the IV this way).
+        let key_and_iv = evp_bytes_to_key(b"password", &[1, 2, 3, 4, 5, 6, 7, 8], 48);
+        assert_eq!(
+            key_and_iv,
+            decode_hex(concat!(
+                "E7B0971E52CA5CC8D0539FB3412F6316F7BA2E6EE293D9F3457B99436B51CE02",
+                "8D450E2ED75A84A923D4EAC9FE49226B"
+            ))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("00ff7A"), Ok(vec![0x00, 0xff, 0x7a]));
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_decrypt_legacy_pem() {
+        let pem = pem::parse(&include_bytes!("../test/rsa-key-legacy.pem")[..]).unwrap();
+        let dek_info = pem.headers().get("DEK-Info").unwrap();
+        let der = decrypt_legacy_pem(dek_info, "password", pem.contents()).unwrap();
+        assert!(decrypt_legacy_pem(dek_info, "wrong", pem.contents()).is_err());
+        assert!(decrypt_legacy_pem("AES-256-CTR,00", "password", pem.contents()).is_err());
+        assert!(decrypt_legacy_pem("AES-192-CBC", "password", pem.contents()).is_err());
+        assert_eq!(der[0], 0x30);
+    }
+}
@@ -0,0 +1,172 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::pbe;
+use crate::software_key::SoftwareKey;
+use crate::util::pkcs::*;
+
+/// The certificates and keys found in a PKCS #12 file. Each certificate is accompanied by its
+/// friendly name, if it has one.
+pub struct Pkcs12Contents {
+    pub certs: Vec<(Vec<u8>, Option<String>)>,
+    pub keys: Vec<SoftwareKey>,
+}
+
+/// Returns whether or not the given PKCS #12 file can't be decoded without a password. This is the
+/// case if any of its contents are encrypted.
+pub fn needs_password(der: &[u8]) -> Result<bool, ()> {
+    let pfx = read_pfx(der)?;
+    for content_info in read_authenticated_safe(pfx.auth_safe)? {
+        match content_info {
+            ContentInfo::Data(safe_contents) => {
+                for safe_bag in read_safe_contents(safe_contents)? {
+                    if let SafeBagValue::ShroudedKey(_) = safe_bag.value {
+                        return Ok(true);
+                    }
+                }
+            }
+            ContentInfo::EncryptedData(_) => return Ok(true),
+            ContentInfo::Unsupported => {}
+        }
+    }
+    Ok(false)
+}
+
+/// Decodes the given PKCS #12 file. If `password` is given, it is used to verify the integrity of
+/// the file and to decrypt its contents. If not, anything encrypted is skipped (and the integrity
+/// of the file is not verified). This fails if the password is incorrect.
+pub fn decode(der: &[u8], password: Option<&str>) -> Result<Pkcs12Contents, ()> {
+    let pfx = read_pfx(der)?;
+    if let (Some(mac_data), Some(password)) = (&pfx.mac_data, password) {
+        if pbe::verify_pkcs12_mac(mac_data, password, pfx.auth_safe).is_err() {
+            error!("PKCS #12 integrity check failed (incorrect password?)");
+            return Err(());
+        }
+    }
+    let mut contents = Pkcs12Contents {
+        certs: Vec::new(),
+        keys: Vec::new(),
+    };
+    for content_info in read_authenticated_safe(pfx.auth_safe)? {
+        match content_info {
+            ContentInfo::Data(safe_contents) => {
+                read_safe_bags(safe_contents, password, &mut contents)?;
+            }
+            ContentInfo::EncryptedData(encrypted_content) => {
+                if let Some(password) = password {
+                    let safe_contents = pbe::decrypt(
+                        &encrypted_content.algorithm,
+                        password,
+                        encrypted_content.encrypted_data,
+                    )?;
+                    read_safe_bags(&safe_contents, Some(password), &mut contents)?;
+                }
+            }
+            ContentInfo::Unsupported => debug!("ignoring unsupported PKCS #12 content"),
+        }
+    }
+    Ok(contents)
+}
+
+fn read_safe_bags(
+    safe_contents: &[u8],
+    password: Option<&str>,
+    contents: &mut Pkcs12Contents,
+) -> Result<(), ()> {
+    for safe_bag in read_safe_contents(safe_contents)? {
+        match safe_bag.value {
+            SafeBagValue::Cert(cert) => {
+                contents.certs.push((cert.to_vec(), safe_bag.friendly_name))
+            }
+            SafeBagValue::Key(key) => contents.keys.push(SoftwareKey::from_pkcs8_der(key)?),
+            SafeBagValue::ShroudedKey(encrypted_key) => {
+                if let Some(password) = password {
+                    let key = pbe::decrypt(
+                        &encrypted_key.algorithm,
+                        password,
+                        encrypted_key.encrypted_data,
+                    )?;
+                    contents.keys.push(SoftwareKey::from_pkcs8_der(&key)?);
+                }
+            }
+            SafeBagValue::Unsupported => debug!("ignoring unsupported PKCS #12 safe bag"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_mock::*;
+
+    const RSA_AES_P12: &[u8] = include_bytes!("../test/rsa-aes.p12");
+    const P256_LEGACY_P12: &[u8] = include_bytes!("../test/p256-legacy.p12");
+    const P384_3DES_P12: &[u8] = include_bytes!("../test/p384-3des.p12");
+    const P256_UNENCRYPTED_P12: &[u8] = include_bytes!("../test/p256-unencrypted.p12");
+
+    fn assert_decodes(der: &[u8], cert_der: &[u8], key_der: &[u8], friendly_name: Option<&str>) {
+        let contents = decode(der, Some("password")).unwrap();
+        assert_eq!(contents.certs.len(), 1);
+        assert_eq!(contents.certs[0].0, cert_der);
+        assert_eq!(contents.certs[0].1.as_deref(), friendly_name);
+        assert_eq!(contents.keys.len(), 1);
+        assert_eq!(
+            contents.keys[0].subject_public_key_info(),
+            SoftwareKey::from_pkcs8_der(key_der)
+                .unwrap()
+                .subject_public_key_info()
+        );
+    }
+
+    #[test]
+    fn test_pbes2_aes() {
+        assert_eq!(needs_password(RSA_AES_P12), Ok(true));
+        assert_decodes(RSA_AES_P12, RSA_CERT, RSA_KEY, Some("Test RSA"));
+    }
+
+    #[test]
+    fn test_legacy_rc2_and_3des() {
+        assert_eq!(needs_password(P256_LEGACY_P12), Ok(true));
+        assert_decodes(P256_LEGACY_P12, P256_CERT, P256_KEY, Some("Test P-256"));
+    }
+
+    #[test]
+    fn test_unencrypted_certs_and_3des_key() {
+        assert_eq!(needs_password(P384_3DES_P12), Ok(true));
+        assert_decodes(P384_3DES_P12, P384_CERT, P384_KEY, None);
+        // Without the password, only the certificate is available.
+        let contents = decode(P384_3DES_P12, None).unwrap();
+        assert_eq!(contents.certs.len(), 1);
+        assert!(contents.keys.is_empty());
+    }
+
+    #[test]
+    fn test_unencrypted() {
+        assert_eq!(needs_password(P256_UNENCRYPTED_P12), Ok(false));
+        let contents = decode(P256_UNENCRYPTED_P12, None).unwrap();
+        assert_eq!(contents.certs.len(), 1);
+        assert_eq!(contents.keys.len(), 1);
+    }
+
+    #[test]
+    fn test_locked_without_password() {
+        let contents = decode(RSA_AES_P12, None).unwrap();
+        assert!(contents.certs.is_empty());
+        assert!(contents.keys.is_empty());
+    }
+
+    #[test]
+    fn test_incorrect_password() {
+        assert!(decode(RSA_AES_P12, Some("wrong")).is_err());
+        assert!(decode(P256_LEGACY_P12, Some("")).is_err());
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(decode(&RSA_AES_P12[..RSA_AES_P12.len() - 1], Some("password")).is_err());
+        assert!(needs_password(RSA_CERT).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use p256::ecdsa::signature::hazmat::PrehashSigner;
+use pkcs11::types::*;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::{DecodePrivateKey, EncodePublicKey};
+use rsa::rand_core::OsRng;
+use rsa::traits::PublicKeyParts;
+use rsa::{Oaep, Pkcs1v15Sign, Pss, RsaPrivateKey};
+use sha1::Sha1;
+use sha2::{Sha256, Sha384, Sha512};
+
+use crate::backend::*;
+use crate::error::Error;
+use crate::pbe;
+use crate::util::pkcs::read_encrypted_private_key_info;
+use crate::util::*;
+
+/// A private key that is held in memory and used to sign data in software (as opposed to a key
+/// held by the OS or by some other device).
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
+pub enum SoftwareKey {
+    RSA(RsaPrivateKey),
+    P256(p256::ecdsa::SigningKey),
+    P384(p384::ecdsa::SigningKey),
+}
+
+impl SoftwareKey {
+    /// Given the DER bytes of an unencrypted PKCS #8 PrivateKeyInfo, tries to decode an RSA,
+    /// secp256r1, or secp384r1 key.
+    pub fn from_pkcs8_der(der: &[u8]) -> Result<SoftwareKey, ()> {
+        decode_pkcs8(der).ok_or_else(|| error!("unsupported or malformed PKCS #8 key"))
+    }
+
+    /// Given the DER bytes of a PKCS #8 EncryptedPrivateKeyInfo, decrypts it with the given
+    /// password and tries to decode the result like `from_pkcs8_der`.
+    pub fn from_encrypted_pkcs8_der(der: &[u8], password: &str) -> Result<SoftwareKey, ()> {
+        let encrypted_private_key_info = read_encrypted_private_key_info(der)?;
+        let der = pbe::decrypt(
+            &encrypted_private_key_info.algorithm,
+            password,
+            encrypted_private_key_info.encrypted_data,
+        )?;
+        SoftwareKey::from_pkcs8_der(&der)
+    }
+
+    /// Given the DER bytes of a PKCS #1 RSAPrivateKey (the "traditional" OpenSSL format for RSA
+    /// keys), tries to decode an RSA key.
+    pub fn from_pkcs1_der(der: &[u8]) -> Result<SoftwareKey, ()> {
+        decode_pkcs1(der).ok_or_else(|| error!("malformed PKCS #1 RSA key"))
+    }
+
+    /// Given the DER bytes of a SEC1 ECPrivateKey, tries to decode a secp256r1 or secp384r1 key.
+    pub fn from_sec1_der(der: &[u8]) -> Result<SoftwareKey, ()> {
+        decode_sec1(der).ok_or_else(|| error!("unsupported or malformed SEC1 EC key"))
+    }
+
+    /// Given DER bytes of unknown format, tries to decode them as a PKCS #8, PKCS #1, or SEC1 key
+    /// (in that order).
+    pub fn from_der(der: &[u8]) -> Result<SoftwareKey, ()> {
+        decode_pkcs8(der)
+            .or_else(|| decode_pkcs1(der))
+            .or_else(|| decode_sec1(der))
+            .ok_or_else(|| error!("unsupported or malformed private key"))
+    }
+
+    /// Returns the DER bytes of the SubjectPublicKeyInfo of the public key corresponding to this
+    /// key. This is used to find the certificate (if any) that this key belongs to.
+    pub fn subject_public_key_info(&self) -> Result<Vec<u8>, ()> {
+        let result = match self {
+            SoftwareKey::RSA(key) => key.to_public_key().to_public_key_der(),
+            SoftwareKey::P256(key) => key.verifying_key().to_public_key_der(),
+            SoftwareKey::P384(key) => key.verifying_key().to_public_key_der(),
+        };
+        match result {
+            Ok(spki) => Ok(spki.into_vec()),
+            Err(e) => {
+                error!("couldn't encode public key: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    /// Creates a `Key` representing this key, which corresponds to the certificate with the given
+    /// DER bytes.
+    pub fn new_key(&self, cert_der: &[u8]) -> Result<Key, ()> {
+        match self {
+            SoftwareKey::RSA(key) => {
+                Key::new(cert_der, KeyType::RSA, Some(key.n().to_bytes_be()), None)
+            }
+            SoftwareKey::P256(_) => Key::new(
+                cert_der,
+                KeyType::EC(32),
+                None,
+                Some(OID_BYTES_SECP256R1.to_vec()),
+            ),
+            SoftwareKey::P384(_) => Key::new(
+                cert_der,
+                KeyType::EC(48),
+                None,
+                Some(OID_BYTES_SECP384R1.to_vec()),
+            ),
+        }
+    }
+
+    pub fn get_signature_length(
+        &self,
+        _data: &[u8],
+        _params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<usize, Error> {
+        match self {
+            SoftwareKey::RSA(key) => Ok(key.size()),
+            SoftwareKey::P256(_) => Ok(64),
+            SoftwareKey::P384(_) => Ok(96),
+        }
+    }
+
+    /// Signs the given data. For RSA keys, if `params` is `None`, `data` is a DigestInfo to sign
+    /// with PKCS #1 v1.5 padding. Otherwise, `data` is a hash to sign with PSS padding. For EC keys,
+    /// `data` is a hash, and the signature is returned as the concatenation of r and s. Other than
+    /// with unsupported PSS params, this only fails if the data has the wrong length.
+    pub fn sign(
+        &self,
+        data: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<Vec<u8>, Error> {
+        match self {
+            SoftwareKey::RSA(key) => match params {
+                None => key.sign(Pkcs1v15Sign::new_unprefixed(), data).map_err(|e| {
+                    error!("RSA PKCS #1 v1.5 signing failed: {}", e);
+                    Error::DataLenRange
+                }),
+                Some(pss_params) => {
+                    let salt_len = pss_params.sLen as usize;
+                    let padding = match (pss_params.hashAlg, pss_params.mgf) {
+                        (CKM_SHA256, CKG_MGF1_SHA256) => Pss::new_with_salt::<Sha256>(salt_len),
+                        (CKM_SHA384, CKG_MGF1_SHA384) => Pss::new_with_salt::<Sha384>(salt_len),
+                        (CKM_SHA512, CKG_MGF1_SHA512) => Pss::new_with_salt::<Sha512>(salt_len),
+                        _ => {
+                            error!(
+                                "unsupported algorithm to use with RSA-PSS: {}",
+                                unsafe_packed_field_access!(pss_params.hashAlg)
+                            );
+                            return Err(Error::MechanismParamInvalid);
+                        }
+                    };
+                    key.sign_with_rng(&mut OsRng, padding, data).map_err(|e| {
+                        error!("RSA PSS signing failed: {}", e);
+                        Error::DataLenRange
+                    })
+                }
+            },
+            SoftwareKey::P256(key) => {
+                let signature: p256::ecdsa::Signature = key.sign_prehash(data).map_err(|e| {
+                    error!("ECDSA signing failed: {}", e);
+                    Error::DataLenRange
+                })?;
+                Ok(signature.to_bytes().to_vec())
+            }
+            SoftwareKey::P384(key) => {
+                let signature: p384::ecdsa::Signature = key.sign_prehash(data).map_err(|e| {
+                    error!("ECDSA signing failed: {}", e);
+                    Error::DataLenRange
+                })?;
+                Ok(signature.to_bytes().to_vec())
+            }
+        }
+    }
+
+    /// Decrypts the given data with an RSA key that was padded with OAEP. PKCS #1 v1.5 padding
+    /// (`params` is `None`) isn't supported: the `rsa` crate doesn't decrypt it in constant time
+    /// (RUSTSEC-2023-0071, the "Marvin attack"), so anything that can ask for decryptions could
+    /// use it as a padding oracle.
+    pub fn decrypt(&self, data: &[u8], params: &Option<OaepParams>) -> Result<Vec<u8>, Error> {
+        let key = match self {
+            SoftwareKey::RSA(key) => key,
+            _ => return Err(Error::KeyTypeInconsistent),
+        };
+        if data.len() != key.size() {
+            return Err(Error::EncryptedDataLenRange);
+        }
+        let result = match params {
+            None => {
+                error!("PKCS #1 v1.5 decryption isn't supported (RUSTSEC-2023-0071)");
+                return Err(Error::MechanismInvalid);
+            }
+            Some(OaepParams {
+                hash_algorithm: CKM_SHA_1,
+            }) => key.decrypt(Oaep::new::<Sha1>(), data),
+            Some(OaepParams {
+                hash_algorithm: CKM_SHA256,
+            }) => key.decrypt(Oaep::new::<Sha256>(), data),
+            Some(params) => {
+                error!(
+                    "unsupported algorithm to use with RSA-OAEP: {}",
+                    params.hash_algorithm
+                );
+                return Err(Error::MechanismParamInvalid);
+            }
+        };
+        result.map_err(|e| {
+            error!("RSA decryption failed: {}", e);
+            Error::EncryptedDataInvalid
+        })
+    }
+}
+
+fn decode_pkcs8(der: &[u8]) -> Option<SoftwareKey> {
+    if let Ok(key) = RsaPrivateKey::from_pkcs8_der(der) {
+        return Some(SoftwareKey::RSA(key));
+    }
+    if let Ok(key) = p256::ecdsa::SigningKey::from_pkcs8_der(der) {
+        return Some(SoftwareKey::P256(key));
+    }
+    if let Ok(key) = p384::ecdsa::SigningKey::from_pkcs8_der(der) {
+        return Some(SoftwareKey::P384(key));
+    }
+    None
+}
+
+fn decode_pkcs1(der: &[u8]) -> Option<SoftwareKey> {
+    RsaPrivateKey::from_pkcs1_der(der)
+        .ok()
+        .map(SoftwareKey::RSA)
+}
+
+fn decode_sec1(der: &[u8]) -> Option<SoftwareKey> {
+    if let Ok(key) = p256::SecretKey::from_sec1_der(der) {
+        return Some(SoftwareKey::P256(key.into()));
+    }
+    if let Ok(key) = p384::SecretKey::from_sec1_der(der) {
+        return Some(SoftwareKey::P384(key.into()));
+    }
+    None
+}
@@ -6,6 +6,9 @@
 use byteorder::{BigEndian, NativeEndian, ReadBytesExt, WriteBytesExt};
 use std::convert::TryInto;
 
+#[cfg(any(test, not(any(target_os = "macos", target_os = "windows"))))]
+pub mod pkcs;
+
 /// Accessing fields of packed structs is unsafe (it may be undefined behavior if the field isn't
 /// aligned). Since we're implementing a PKCS#11 module, we already have to trust the caller not to
 /// give us bad data, so normally we would deal with this by adding an unsafe block. If we do that,
@@ -19,14 +22,27 @@ macro_rules! unsafe_packed_field_access {
     }};
 }
 
-#[cfg(target_os = "macos")]
 pub const OID_BYTES_SECP256R1: &[u8] =
     &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
-#[cfg(target_os = "macos")]
 pub const OID_BYTES_SECP384R1: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
-#[cfg(target_os = "macos")]
 pub const OID_BYTES_SECP521R1: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x23];
 
+/// Given the DER bytes of the OID identifying an EC curve, returns the width in bytes of each
+/// coordinate of a point on that curve. Only secp256r1, secp384r1, and secp521r1 are supported.
+#[cfg(not(target_os = "macos"))]
+pub fn get_ec_coordinate_width(ec_params: &[u8]) -> Result<usize, ()> {
+    if ec_params == OID_BYTES_SECP256R1 {
+        Ok(32)
+    } else if ec_params == OID_BYTES_SECP384R1 {
+        Ok(48)
+    } else if ec_params == OID_BYTES_SECP521R1 {
+        Ok(66)
+    } else {
+        error!("unsupported EC curve");
+        Err(())
+    }
+}
+
 // This is a helper function to take a value and lay it out in memory how
 // PKCS#11 is expecting it.
 pub fn serialize_uint<T: TryInto<u64>>(value: T) -> Result<Vec<u8>, ()> {
@@ -47,6 +63,7 @@ pub fn serialize_uint<T: TryInto<u64>>(value: T) -> Result<Vec<u8>, ()> {
 ///     modulus           INTEGER,  -- n
 ///     publicExponent    INTEGER   -- e
 /// }
+#[cfg(any(target_os = "macos", target_os = "windows", test))]
 pub fn read_rsa_modulus(public_key: &[u8]) -> Result<Vec<u8>, ()> {
     let mut sequence = Sequence::new(public_key)?;
     let modulus_value = sequence.read_unsigned_integer()?;
@@ -62,8 +79,8 @@ pub fn read_rsa_modulus(public_key: &[u8]) -> Result<Vec<u8>, ()> {
 ///   Ecdsa-Sig-Value  ::=  SEQUENCE  {
 ///        r     INTEGER,
 ///        s     INTEGER  }
-#[cfg(target_os = "macos")]
-pub fn read_ec_sig_point<'a>(signature: &'a [u8]) -> Result<(&'a [u8], &'a [u8]), ()> {
+#[cfg(any(target_os = "macos", target_os = "linux", test))]
+pub fn read_ec_sig_point(signature: &[u8]) -> Result<(&[u8], &[u8]), ()> {
     let mut sequence = Sequence::new(signature)?;
     let r = sequence.read_unsigned_integer()?;
     let s = sequence.read_unsigned_integer()?;
@@ -73,6 +90,254 @@ pub fn read_ec_sig_point<'a>(signature: &'a [u8]) -> Result<(&'a [u8], &'a [u8])
     Ok((r, s))
 }
 
+/// The fields of a certificate that are needed to expose it as a PKCS #11 object. Each field is the
+/// complete DER encoding (i.e. including the tag and length) of the corresponding item.
+pub struct CertificateFields<'a> {
+    pub serial_number: &'a [u8],
+    pub issuer: &'a [u8],
+    pub subject: &'a [u8],
+    pub subject_public_key_info: &'a [u8],
+}
+
+/// Given a slice of DER bytes representing a certificate, extracts the DER encodings of the serial
+/// number, issuer, subject, and subject public key info. The contents of these fields are not
+/// validated.
+///   Certificate  ::=  SEQUENCE  {
+///        tbsCertificate       TBSCertificate,
+///        signatureAlgorithm   AlgorithmIdentifier,
+///        signatureValue       BIT STRING  }
+///
+///   TBSCertificate  ::=  SEQUENCE  {
+///        version         [0]  EXPLICIT Version DEFAULT v1,
+///        serialNumber         CertificateSerialNumber,
+///        signature            AlgorithmIdentifier,
+///        issuer               Name,
+///        validity             Validity,
+///        subject              Name,
+///        subjectPublicKeyInfo SubjectPublicKeyInfo,
+///        ... }
+pub fn read_certificate_fields(certificate: &[u8]) -> Result<CertificateFields<'_>, ()> {
+    let (fields, _) = read_tbs_certificate(certificate)?;
+    Ok(fields)
+}
+
+/// The public key of a certificate, as the attributes of a PKCS #11 public key object hold it.
+#[derive(Debug, PartialEq)]
+pub enum PublicKeyInfo<'a> {
+    /// The modulus and public exponent of an RSA key, as unsigned integers.
+    Rsa {
+        modulus: &'a [u8],
+        public_exponent: &'a [u8],
+    },
+    /// The DER encoding of the OID of the curve of an EC key and the encoded point that is the
+    /// key (not wrapped in an OCTET STRING).
+    Ec {
+        ec_params: &'a [u8],
+        point: &'a [u8],
+    },
+}
+
+/// Given a slice of DER bytes representing a certificate, extracts its RSA or EC public key. Only
+/// named curves are supported for EC keys.
+///   SubjectPublicKeyInfo  ::=  SEQUENCE  {
+///        algorithm            AlgorithmIdentifier,
+///        subjectPublicKey     BIT STRING  }
+///
+///   AlgorithmIdentifier  ::=  SEQUENCE  {
+///        algorithm               OBJECT IDENTIFIER,
+///        parameters              ANY DEFINED BY algorithm OPTIONAL  }
+pub fn read_public_key_info(certificate: &[u8]) -> Result<PublicKeyInfo<'_>, ()> {
+    let fields = read_certificate_fields(certificate)?;
+    let mut subject_public_key_info = Sequence::new(fields.subject_public_key_info)?;
+    let mut algorithm =
+        Sequence::new(subject_public_key_info.read_element(SEQUENCE | CONSTRUCTED)?)?;
+    let algorithm_id = algorithm.read_element(OBJECT_IDENTIFIER)?;
+    let subject_public_key = subject_public_key_info.contents.read(BIT_STRING)?;
+    if !subject_public_key_info.at_end() {
+        return Err(());
+    }
+    // The first byte of a BIT STRING is the number of unused bits at the end, which must be 0 for
+    // a key.
+    let subject_public_key = match subject_public_key.split_first() {
+        Some((0, subject_public_key)) => subject_public_key,
+        _ => return Err(()),
+    };
+    if algorithm_id == OID_BYTES_RSA_ENCRYPTION {
+        if algorithm.peek_tag() == Some(NULL) {
+            let _parameters = algorithm.read_element(NULL)?;
+        }
+        if !algorithm.at_end() {
+            return Err(());
+        }
+        let mut rsa_public_key = Sequence::new(subject_public_key)?;
+        let modulus = rsa_public_key.read_unsigned_integer()?;
+        let public_exponent = rsa_public_key.read_unsigned_integer()?;
+        if !rsa_public_key.at_end() {
+            return Err(());
+        }
+        Ok(PublicKeyInfo::Rsa {
+            modulus,
+            public_exponent,
+        })
+    } else if algorithm_id == OID_BYTES_EC_PUBLIC_KEY {
+        let ec_params = algorithm.read_element(OBJECT_IDENTIFIER)?;
+        if !algorithm.at_end() {
+            return Err(());
+        }
+        Ok(PublicKeyInfo::Ec {
+            ec_params,
+            point: subject_public_key,
+        })
+    } else {
+        error!("unsupported public key algorithm");
+        Err(())
+    }
+}
+
+/// Returns the DER encoding of an OCTET STRING with the given contents.
+pub fn encode_octet_string(contents: &[u8]) -> Result<Vec<u8>, ()> {
+    let mut encoded = vec![OCTET_STRING];
+    if contents.len() < 0x80 {
+        encoded.push(contents.len() as u8);
+    } else if contents.len() < 0x100 {
+        encoded.extend_from_slice(&[0x81, contents.len() as u8]);
+    } else if contents.len() < 0x10000 {
+        encoded.push(0x82);
+        encoded
+            .write_u16::<BigEndian>(contents.len() as u16)
+            .map_err(|_| ())?;
+    } else {
+        return Err(());
+    }
+    encoded.extend_from_slice(contents);
+    Ok(encoded)
+}
+
+/// Given a slice of DER bytes representing a certificate, returns the key identifier from its
+/// subject key identifier extension, if it has one.
+///   SubjectKeyIdentifier ::= KeyIdentifier
+///   KeyIdentifier ::= OCTET STRING
+#[cfg(any(test, target_os = "linux"))]
+pub fn read_subject_key_identifier(certificate: &[u8]) -> Result<Option<&[u8]>, ()> {
+    let extension_value = match read_extension(certificate, OID_BYTES_SUBJECT_KEY_IDENTIFIER)? {
+        Some(extension_value) => extension_value,
+        None => return Ok(None),
+    };
+    let mut key_identifier = Der::new(extension_value);
+    let key_identifier_value = key_identifier.read(OCTET_STRING)?;
+    if !key_identifier.at_end() {
+        return Err(());
+    }
+    Ok(Some(key_identifier_value))
+}
+
+/// Given a slice of DER bytes representing a certificate, returns whether or not the certificate's
+/// key may be used to encrypt keys (e.g. the content-encryption key of an S/MIME message). This is
+/// the case if the certificate has no key usage extension or if its key usage includes
+/// keyEncipherment.
+///   KeyUsage ::= BIT STRING {
+///        digitalSignature        (0),
+///        nonRepudiation          (1),
+///        keyEncipherment         (2),
+///        ... }
+pub fn allows_key_encipherment(certificate: &[u8]) -> Result<bool, ()> {
+    let extension_value = match read_extension(certificate, OID_BYTES_KEY_USAGE)? {
+        Some(extension_value) => extension_value,
+        None => return Ok(true),
+    };
+    let mut key_usage = Der::new(extension_value);
+    let key_usage_bits = key_usage.read(BIT_STRING)?;
+    if !key_usage.at_end() {
+        return Err(());
+    }
+    // The first byte of a BIT STRING is the number of unused bits at the end. Named bits are
+    // numbered from the most significant bit of the first byte after that.
+    match key_usage_bits {
+        [] => Err(()),
+        [_] => Ok(false),
+        [_, first, ..] => Ok(first & 0x20 != 0),
+    }
+}
+
+/// Given a slice of DER bytes representing a certificate, returns the contents of the extnValue of
+/// its extension with the given OID (the complete DER encoding), if it has one.
+///   TBSCertificate  ::=  SEQUENCE  {
+///        ...
+///        subjectPublicKeyInfo SubjectPublicKeyInfo,
+///        issuerUniqueID  [1]  IMPLICIT UniqueIdentifier OPTIONAL,
+///        subjectUniqueID [2]  IMPLICIT UniqueIdentifier OPTIONAL,
+///        extensions      [3]  EXPLICIT Extensions OPTIONAL }
+///
+///   Extension  ::=  SEQUENCE  {
+///        extnID      OBJECT IDENTIFIER,
+///        critical    BOOLEAN DEFAULT FALSE,
+///        extnValue   OCTET STRING }
+fn read_extension<'a>(certificate: &'a [u8], extension_id: &[u8]) -> Result<Option<&'a [u8]>, ()> {
+    let mut tbs_certificate = read_tbs_certificate_contents(certificate)?;
+    let _serial_number = tbs_certificate.read_element(INTEGER)?;
+    for _ in 0..5 {
+        // signature, issuer, validity, subject, and subjectPublicKeyInfo
+        let _field = tbs_certificate.read_element(SEQUENCE | CONSTRUCTED)?;
+    }
+    for unique_identifier in [CONTEXT_SPECIFIC | 1, CONTEXT_SPECIFIC | 2] {
+        if tbs_certificate.peek_tag() == Some(unique_identifier) {
+            let _unique_identifier = tbs_certificate.read_element(unique_identifier)?;
+        }
+    }
+    if tbs_certificate.at_end() {
+        return Ok(None);
+    }
+    let mut extensions = Sequence::new(tbs_certificate.contents.read(EXTENSIONS)?)?;
+    while !extensions.at_end() {
+        let mut extension = Sequence::new(extensions.read_element(SEQUENCE | CONSTRUCTED)?)?;
+        let id = extension.read_element(OBJECT_IDENTIFIER)?;
+        if extension.peek_tag() == Some(BOOLEAN) {
+            let _critical = extension.read_element(BOOLEAN)?;
+        }
+        let extension_value = extension.contents.read(OCTET_STRING)?;
+        if id == extension_id {
+            return Ok(Some(extension_value));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the fields of a certificate (see `read_certificate_fields`), returning them along with a
+/// reader for the rest of the TBSCertificate.
+fn read_tbs_certificate(certificate: &[u8]) -> Result<(CertificateFields<'_>, Sequence<'_>), ()> {
+    let mut tbs_certificate = read_tbs_certificate_contents(certificate)?;
+    let serial_number = tbs_certificate.read_element(INTEGER)?;
+    let _signature = tbs_certificate.read_element(SEQUENCE | CONSTRUCTED)?;
+    let issuer = tbs_certificate.read_element(SEQUENCE | CONSTRUCTED)?;
+    let _validity = tbs_certificate.read_element(SEQUENCE | CONSTRUCTED)?;
+    let subject = tbs_certificate.read_element(SEQUENCE | CONSTRUCTED)?;
+    let subject_public_key_info = tbs_certificate.read_element(SEQUENCE | CONSTRUCTED)?;
+    Ok((
+        CertificateFields {
+            serial_number,
+            issuer,
+            subject,
+            subject_public_key_info,
+        },
+        tbs_certificate,
+    ))
+}
+
+/// Returns a reader for the TBSCertificate of a certificate, positioned after its version.
+fn read_tbs_certificate_contents(certificate: &[u8]) -> Result<Sequence<'_>, ()> {
+    let mut certificate = Sequence::new(certificate)?;
+    let mut tbs_certificate = Sequence::new(certificate.read_element(SEQUENCE | CONSTRUCTED)?)?;
+    let _signature_algorithm = certificate.read_element(SEQUENCE | CONSTRUCTED)?;
+    let _signature_value = certificate.read_element(BIT_STRING)?;
+    if !certificate.at_end() {
+        return Err(());
+    }
+    if tbs_certificate.peek_tag() == Some(VERSION) {
+        let _version = tbs_certificate.read_element(VERSION)?;
+    }
+    Ok(tbs_certificate)
+}
+
 /// Helper macro for reading some bytes from a slice while checking the slice is long enough.
 /// Returns a pair consisting of a slice of the bytes read and a slice of the rest of the bytes
 /// from the original slice.
@@ -85,12 +350,39 @@ macro_rules! try_read_bytes {
     }};
 }
 
+/// ASN.1 tag identifying a boolean.
+const BOOLEAN: u8 = 0x01;
 /// ASN.1 tag identifying an integer.
 const INTEGER: u8 = 0x02;
+/// ASN.1 tag identifying a bit string.
+const BIT_STRING: u8 = 0x03;
+/// ASN.1 tag identifying an octet string.
+const OCTET_STRING: u8 = 0x04;
+/// ASN.1 tag identifying a null value.
+const NULL: u8 = 0x05;
+/// ASN.1 tag identifying an object identifier.
+const OBJECT_IDENTIFIER: u8 = 0x06;
 /// ASN.1 tag identifying a sequence.
 const SEQUENCE: u8 = 0x10;
 /// ASN.1 tag modifier identifying an item as constructed.
 const CONSTRUCTED: u8 = 0x20;
+/// ASN.1 tag modifier identifying an item as context-specific.
+const CONTEXT_SPECIFIC: u8 = 0x80;
+/// ASN.1 tag identifying the explicitly-tagged version of a certificate.
+const VERSION: u8 = CONTEXT_SPECIFIC | CONSTRUCTED;
+/// ASN.1 tag identifying the explicitly-tagged extensions of a certificate.
+const EXTENSIONS: u8 = CONTEXT_SPECIFIC | CONSTRUCTED | 3;
+/// 2.5.29.14
+#[cfg(any(test, target_os = "linux"))]
+const OID_BYTES_SUBJECT_KEY_IDENTIFIER: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x0e];
+/// 2.5.29.15
+const OID_BYTES_KEY_USAGE: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x0f];
+/// 1.2.840.113549.1.1.1
+const OID_BYTES_RSA_ENCRYPTION: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01,
+];
+/// 1.2.840.10045.2.1
+const OID_BYTES_EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
 
 /// A helper struct for reading items from a DER SEQUENCE (in this case, all sequences are
 /// assumed to be CONSTRUCTED).
@@ -128,6 +420,16 @@ impl<'a> Sequence<'a> {
         }
     }
 
+    /// Reads the next item, which must have the given tag, and returns its complete DER encoding
+    /// (including the tag and length).
+    fn read_element(&mut self, tag: u8) -> Result<&'a [u8], ()> {
+        self.contents.read_element(tag)
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.contents.peek_tag()
+    }
+
     fn at_end(&self) -> bool {
         self.contents.at_end()
     }
@@ -149,11 +451,17 @@ impl<'a> Der<'a> {
     // use it incorrectly (i.e. it stays in this module and we only expose a stateless API), it
     // should be safe.
     fn read(&mut self, tag: u8) -> Result<&'a [u8], ()> {
-        let contents = self.contents;
-        let (tag_read, rest) = try_read_bytes!(contents, 1);
-        if tag_read[0] != tag {
+        if self.peek_tag() != Some(tag) {
             return Err(());
         }
+        let (_, contents) = self.read_any()?;
+        Ok(contents)
+    }
+
+    /// Reads the next item, whatever its tag. Returns the tag and the contents of the item.
+    fn read_any(&mut self) -> Result<(u8, &'a [u8]), ()> {
+        let contents = self.contents;
+        let (tag_read, rest) = try_read_bytes!(contents, 1);
         let (length1, rest) = try_read_bytes!(rest, 1);
         let (length, to_read_from) = if length1[0] < 0x80 {
             (length1[0] as usize, rest)
@@ -177,7 +485,18 @@ impl<'a> Der<'a> {
         };
         let (contents, rest) = try_read_bytes!(to_read_from, length);
         self.contents = rest;
-        Ok(contents)
+        Ok((tag_read[0], contents))
+    }
+
+    fn read_element(&mut self, tag: u8) -> Result<&'a [u8], ()> {
+        let element_start = self.contents;
+        let _ = self.read(tag)?;
+        let (element, _) = element_start.split_at(element_start.len() - self.contents.len());
+        Ok(element)
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.contents.first().copied()
     }
 
     fn at_end(&self) -> bool {
@@ -188,6 +507,9 @@ impl<'a> Der<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rsa::pkcs8::DecodePrivateKey;
+    use rsa::traits::PublicKeyParts;
+    use rsa::RsaPrivateKey;
 
     #[test]
     fn der_test_empty_input() {
@@ -321,4 +643,74 @@ mod tests {
         let modulus = result.unwrap();
         assert_eq!(modulus, include_bytes!("../test/modulus.bin").to_vec());
     }
+
+    #[test]
+    fn test_read_subject_key_identifier() {
+        let cert = include_bytes!("../test/rsa-cert.der");
+        assert_eq!(
+            read_subject_key_identifier(cert),
+            Ok(Some(
+                &[
+                    0xb1, 0x61, 0xba, 0xe6, 0xdf, 0xa9, 0x52, 0x93, 0x2b, 0x36, 0x7a, 0x9d, 0x95,
+                    0x0c, 0x20, 0x31, 0x24, 0xb5, 0xcc, 0x7f
+                ][..]
+            ))
+        );
+        assert!(read_subject_key_identifier(&cert[..cert.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_read_public_key_info() {
+        let modulus = RsaPrivateKey::from_pkcs8_der(include_bytes!("../test/rsa-key.der"))
+            .unwrap()
+            .n()
+            .to_bytes_be();
+        assert_eq!(
+            read_public_key_info(include_bytes!("../test/rsa-cert.der")),
+            Ok(PublicKeyInfo::Rsa {
+                modulus: &modulus,
+                public_exponent: &[0x01, 0x00, 0x01][..],
+            })
+        );
+        match read_public_key_info(include_bytes!("../test/p256-cert.der")) {
+            Ok(PublicKeyInfo::Ec { ec_params, point }) => {
+                assert_eq!(ec_params, OID_BYTES_SECP256R1);
+                // The point is uncompressed.
+                assert_eq!(point.len(), 65);
+                assert_eq!(point[0], 0x04);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_encode_octet_string() {
+        assert_eq!(
+            encode_octet_string(&[1, 2]),
+            Ok(vec![OCTET_STRING, 2, 1, 2])
+        );
+        let encoded = encode_octet_string(&[0; 133]).unwrap();
+        assert_eq!(&encoded[..3], &[OCTET_STRING, 0x81, 133]);
+        assert_eq!(encoded.len(), 136);
+        let encoded = encode_octet_string(&[0; 300]).unwrap();
+        assert_eq!(&encoded[..4], &[OCTET_STRING, 0x82, 0x01, 0x2c]);
+        let mut der = Der::new(&encoded);
+        assert_eq!(der.read(OCTET_STRING), Ok(&[0; 300][..]));
+    }
+
+    #[test]
+    fn test_allows_key_encipherment() {
+        // Certificates without a key usage extension can be used for anything.
+        assert_eq!(
+            allows_key_encipherment(include_bytes!("../test/rsa-cert.der")),
+            Ok(true)
+        );
+        assert_eq!(
+            allows_key_encipherment(include_bytes!("../test/p256-cert.der")),
+            Ok(true)
+        );
+        let signing_cert = include_bytes!("../test/rsa-signing-cert.der");
+        assert_eq!(allows_key_encipherment(signing_cert), Ok(false));
+        assert!(allows_key_encipherment(&signing_cert[..signing_cert.len() - 1]).is_err());
+    }
 }
@@ -0,0 +1,880 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Parsing of the PKCS #5, #8, and #12 structures used by password-protected key files (and of the
+//! PKCS #1 DigestInfo structures that are signed with RSA keys). Like the rest of `util`, this only
+//! exposes stateless functions that return slices of the input.
+
+use super::*;
+
+/// ASN.1 tag identifying a set.
+const SET: u8 = 0x11;
+/// ASN.1 tag identifying a BMPString (a big-endian UTF-16 string).
+const BMP_STRING: u8 = 0x1e;
+/// ASN.1 tag identifying the first explicitly-tagged context-specific item.
+const EXPLICIT_TAG_0: u8 = CONTEXT_SPECIFIC | CONSTRUCTED;
+/// ASN.1 tag identifying the first implicitly-tagged context-specific item (when primitive).
+const IMPLICIT_TAG_0: u8 = CONTEXT_SPECIFIC;
+
+// The DER encodings of the object identifiers this module deals with (including the tag and
+// length, like the curve OIDs above).
+/// 1.2.840.113549.1.7.1
+const OID_BYTES_DATA: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01,
+];
+/// 1.2.840.113549.1.7.6
+const OID_BYTES_ENCRYPTED_DATA: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x06,
+];
+/// 1.2.840.113549.1.12.10.1.1
+const OID_BYTES_KEY_BAG: &[u8] = &[
+    0x06, 0x0b, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x0a, 0x01, 0x01,
+];
+/// 1.2.840.113549.1.12.10.1.2
+const OID_BYTES_PKCS8_SHROUDED_KEY_BAG: &[u8] = &[
+    0x06, 0x0b, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x0a, 0x01, 0x02,
+];
+/// 1.2.840.113549.1.12.10.1.3
+const OID_BYTES_CERT_BAG: &[u8] = &[
+    0x06, 0x0b, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x0a, 0x01, 0x03,
+];
+/// 1.2.840.113549.1.9.22.1
+const OID_BYTES_X509_CERTIFICATE: &[u8] = &[
+    0x06, 0x0a, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x16, 0x01,
+];
+/// 1.2.840.113549.1.9.20
+const OID_BYTES_FRIENDLY_NAME: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x14,
+];
+/// 1.2.840.113549.1.5.12
+const OID_BYTES_PBKDF2: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0c,
+];
+/// 1.3.6.1.4.1.11591.4.11
+const OID_BYTES_SCRYPT: &[u8] = &[
+    0x06, 0x09, 0x2b, 0x06, 0x01, 0x04, 0x01, 0xda, 0x47, 0x04, 0x0b,
+];
+/// 1.2.840.113549.1.5.13
+pub const OID_BYTES_PBES2: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0d,
+];
+/// 1.2.840.113549.1.12.1.3
+pub const OID_BYTES_PBE_SHA1_3DES: &[u8] = &[
+    0x06, 0x0a, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x01, 0x03,
+];
+/// 1.2.840.113549.1.12.1.4
+pub const OID_BYTES_PBE_SHA1_2DES: &[u8] = &[
+    0x06, 0x0a, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x01, 0x04,
+];
+/// 1.2.840.113549.1.12.1.5
+pub const OID_BYTES_PBE_SHA1_RC2_128: &[u8] = &[
+    0x06, 0x0a, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x01, 0x05,
+];
+/// 1.2.840.113549.1.12.1.6
+pub const OID_BYTES_PBE_SHA1_RC2_40: &[u8] = &[
+    0x06, 0x0a, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x01, 0x06,
+];
+/// 1.2.840.113549.2.7
+pub const OID_BYTES_HMAC_SHA1: &[u8] =
+    &[0x06, 0x08, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x07];
+/// 1.2.840.113549.2.9
+pub const OID_BYTES_HMAC_SHA256: &[u8] =
+    &[0x06, 0x08, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x09];
+/// 1.2.840.113549.2.10
+pub const OID_BYTES_HMAC_SHA384: &[u8] =
+    &[0x06, 0x08, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x0a];
+/// 1.2.840.113549.2.11
+pub const OID_BYTES_HMAC_SHA512: &[u8] =
+    &[0x06, 0x08, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x0b];
+/// 2.16.840.1.101.3.4.1.2
+pub const OID_BYTES_AES_128_CBC: &[u8] = &[
+    0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x02,
+];
+/// 2.16.840.1.101.3.4.1.22
+pub const OID_BYTES_AES_192_CBC: &[u8] = &[
+    0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x16,
+];
+/// 2.16.840.1.101.3.4.1.42
+pub const OID_BYTES_AES_256_CBC: &[u8] = &[
+    0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a,
+];
+/// 2.16.840.1.101.3.4.1.6
+pub const OID_BYTES_AES_128_GCM: &[u8] = &[
+    0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x06,
+];
+/// 2.16.840.1.101.3.4.1.26
+pub const OID_BYTES_AES_192_GCM: &[u8] = &[
+    0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x1a,
+];
+/// 2.16.840.1.101.3.4.1.46
+pub const OID_BYTES_AES_256_GCM: &[u8] = &[
+    0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2e,
+];
+/// 1.2.840.113549.3.7
+pub const OID_BYTES_DES_EDE3_CBC: &[u8] =
+    &[0x06, 0x08, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x03, 0x07];
+/// 1.3.14.3.2.26
+pub const OID_BYTES_SHA1: &[u8] = &[0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a];
+/// 2.16.840.1.101.3.4.2.1
+pub const OID_BYTES_SHA256: &[u8] = &[
+    0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01,
+];
+/// 2.16.840.1.101.3.4.2.2
+pub const OID_BYTES_SHA384: &[u8] = &[
+    0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02,
+];
+/// 2.16.840.1.101.3.4.2.3
+pub const OID_BYTES_SHA512: &[u8] = &[
+    0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03,
+];
+
+/// An algorithm and its (optional) parameters. `algorithm` is the complete DER encoding of the
+/// algorithm's OID and `parameters` is the complete DER encoding of the parameters.
+///   AlgorithmIdentifier  ::=  SEQUENCE  {
+///        algorithm               OBJECT IDENTIFIER,
+///        parameters              ANY DEFINED BY algorithm OPTIONAL  }
+pub struct AlgorithmIdentifier<'a> {
+    pub algorithm: &'a [u8],
+    pub parameters: Option<&'a [u8]>,
+}
+
+/// Some data encrypted with the given algorithm. This represents both the EncryptedContentInfo of
+/// a PKCS #7 EncryptedData and a PKCS #8 EncryptedPrivateKeyInfo.
+pub struct EncryptedContent<'a> {
+    pub algorithm: AlgorithmIdentifier<'a>,
+    pub encrypted_data: &'a [u8],
+}
+
+/// The relevant parts of a PKCS #12 PFX.
+pub struct Pfx<'a> {
+    /// The contents of the AuthenticatedSafe (which has yet to be parsed).
+    pub auth_safe: &'a [u8],
+    pub mac_data: Option<MacData<'a>>,
+}
+
+/// The information needed to verify the integrity of a PFX. `digest_algorithm` is the DER encoding
+/// of the OID of the hash algorithm used.
+pub struct MacData<'a> {
+    pub digest_algorithm: &'a [u8],
+    pub digest: &'a [u8],
+    pub salt: &'a [u8],
+    pub iterations: u64,
+}
+
+/// A PKCS #7 ContentInfo from an AuthenticatedSafe. Only plain data (which contains DER-encoded
+/// SafeContents) and encrypted data (which contains encrypted DER-encoded SafeContents) are
+/// supported.
+pub enum ContentInfo<'a> {
+    Data(&'a [u8]),
+    EncryptedData(EncryptedContent<'a>),
+    Unsupported,
+}
+
+/// The value of a SafeBag. `Key` is an unencrypted PKCS #8 PrivateKeyInfo, `ShroudedKey` is an
+/// encrypted PKCS #8 PrivateKeyInfo, and `Cert` is a DER-encoded X.509 certificate.
+pub enum SafeBagValue<'a> {
+    Key(&'a [u8]),
+    ShroudedKey(EncryptedContent<'a>),
+    Cert(&'a [u8]),
+    Unsupported,
+}
+
+pub struct SafeBag<'a> {
+    pub value: SafeBagValue<'a>,
+    /// The friendlyName attribute of the bag, if present.
+    pub friendly_name: Option<String>,
+}
+
+/// The parameters of the password-based encryption schemes defined in PKCS #12.
+///   pkcs-12PbeParams ::= SEQUENCE {
+///       salt        OCTET STRING,
+///       iterations  INTEGER }
+pub struct Pkcs12PbeParameters<'a> {
+    pub salt: &'a [u8],
+    pub iterations: u64,
+}
+
+/// The parameters of PBES2 (from PKCS #5).
+///   PBES2-params ::= SEQUENCE {
+///       keyDerivationFunc AlgorithmIdentifier {{PBES2-KDFs}},
+///       encryptionScheme AlgorithmIdentifier {{PBES2-Encs}} }
+pub struct Pbes2Parameters<'a> {
+    pub kdf: KeyDerivationFunction<'a>,
+    pub encryption_scheme: AlgorithmIdentifier<'a>,
+}
+
+/// The key derivation functions supported with PBES2: PBKDF2 (from PKCS #5) and scrypt (from
+/// RFC 7914).
+pub enum KeyDerivationFunction<'a> {
+    Pbkdf2(Pbkdf2Parameters<'a>),
+    Scrypt(ScryptParameters<'a>),
+}
+
+/// The parameters of PBKDF2. `prf` is the DER encoding of the OID of the pseudorandom function.
+///   PBKDF2-params ::= SEQUENCE {
+///       salt CHOICE {
+///           specified OCTET STRING,
+///           otherSource AlgorithmIdentifier {{PBKDF2-SaltSources}} },
+///       iterationCount INTEGER (1..MAX),
+///       keyLength INTEGER (1..MAX) OPTIONAL,
+///       prf AlgorithmIdentifier {{PBKDF2-PRFs}} DEFAULT algid-hmacWithSHA1 }
+pub struct Pbkdf2Parameters<'a> {
+    pub salt: &'a [u8],
+    pub iterations: u64,
+    pub key_length: Option<u64>,
+    pub prf: &'a [u8],
+}
+
+/// The parameters of scrypt.
+///   scrypt-params ::= SEQUENCE {
+///       salt OCTET STRING,
+///       costParameter INTEGER (1..MAX),
+///       blockSize INTEGER (1..MAX),
+///       parallelizationParameter INTEGER (1..MAX),
+///       keyLength INTEGER (1..MAX) OPTIONAL }
+pub struct ScryptParameters<'a> {
+    pub salt: &'a [u8],
+    pub cost: u64,
+    pub block_size: u64,
+    pub parallelization: u64,
+    pub key_length: Option<u64>,
+}
+
+/// The parameters of AES-GCM (from RFC 5084). `icv_length` is the length of the authentication
+/// tag, which is appended to the ciphertext.
+///   GCMParameters ::= SEQUENCE {
+///       aes-nonce        OCTET STRING,
+///       aes-ICVlen       AES-GCM-ICVlen DEFAULT 12 }
+pub struct GcmParameters<'a> {
+    pub nonce: &'a [u8],
+    pub icv_length: u64,
+}
+
+impl<'a> Sequence<'a> {
+    fn read_sequence(&mut self) -> Result<Sequence<'a>, ()> {
+        Ok(Sequence {
+            contents: Der::new(self.contents.read(SEQUENCE | CONSTRUCTED)?),
+        })
+    }
+
+    /// Reads a SET. The items of the returned value can be read in order like a SEQUENCE.
+    fn read_set(&mut self) -> Result<Sequence<'a>, ()> {
+        Ok(Sequence {
+            contents: Der::new(self.contents.read(SET | CONSTRUCTED)?),
+        })
+    }
+
+    /// Reads an explicitly-tagged item with tag number 0, returning a reader for its contents.
+    fn read_explicit_tag_0(&mut self) -> Result<Sequence<'a>, ()> {
+        Ok(Sequence {
+            contents: Der::new(self.contents.read(EXPLICIT_TAG_0)?),
+        })
+    }
+
+    /// Reads an OBJECT IDENTIFIER and returns its complete DER encoding.
+    fn read_oid(&mut self) -> Result<&'a [u8], ()> {
+        self.contents.read_element(OBJECT_IDENTIFIER)
+    }
+
+    fn read_octet_string(&mut self) -> Result<&'a [u8], ()> {
+        self.contents.read(OCTET_STRING)
+    }
+
+    /// Reads an INTEGER that must be non-negative and fit in 64 bits.
+    fn read_small_unsigned_integer(&mut self) -> Result<u64, ()> {
+        let bytes = self.read_unsigned_integer()?;
+        if bytes.len() > 8 {
+            return Err(());
+        }
+        Ok(bytes
+            .iter()
+            .fold(0, |value, byte| (value << 8) | u64::from(*byte)))
+    }
+
+    /// Reads the next item, whatever it is, and returns its complete DER encoding.
+    fn read_any_element(&mut self) -> Result<&'a [u8], ()> {
+        let element_start = self.contents.contents;
+        let _ = self.contents.read_any()?;
+        let (element, _) =
+            element_start.split_at(element_start.len() - self.contents.contents.len());
+        Ok(element)
+    }
+
+    fn read_algorithm_identifier(&mut self) -> Result<AlgorithmIdentifier<'a>, ()> {
+        let mut sequence = self.read_sequence()?;
+        let algorithm = sequence.read_oid()?;
+        let parameters = if sequence.at_end() {
+            None
+        } else {
+            Some(sequence.read_any_element()?)
+        };
+        if !sequence.at_end() {
+            return Err(());
+        }
+        Ok(AlgorithmIdentifier {
+            algorithm,
+            parameters,
+        })
+    }
+
+    fn read_encrypted_content(&mut self) -> Result<EncryptedContent<'a>, ()> {
+        let algorithm = self.read_algorithm_identifier()?;
+        let encrypted_data = self.read_octet_string()?;
+        Ok(EncryptedContent {
+            algorithm,
+            encrypted_data,
+        })
+    }
+
+    fn read_encrypted_private_key_info(&mut self) -> Result<EncryptedContent<'a>, ()> {
+        let mut encrypted_private_key_info = self.read_sequence()?;
+        let encrypted_content = encrypted_private_key_info.read_encrypted_content()?;
+        if !encrypted_private_key_info.at_end() {
+            return Err(());
+        }
+        Ok(encrypted_content)
+    }
+
+    /// Reads an optional trailing INTEGER (e.g. a key length), which must be followed by nothing or
+    /// by an item that isn't an INTEGER.
+    fn read_optional_small_unsigned_integer(&mut self) -> Result<Option<u64>, ()> {
+        if self.peek_tag() == Some(INTEGER) {
+            Ok(Some(self.read_small_unsigned_integer()?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Given the DER encoding of a PKCS #8 EncryptedPrivateKeyInfo (e.g. the contents of an
+/// "ENCRYPTED PRIVATE KEY" PEM block), reads the encryption algorithm and the encrypted key.
+///   EncryptedPrivateKeyInfo ::= SEQUENCE {
+///       encryptionAlgorithm  EncryptionAlgorithmIdentifier,
+///       encryptedData        EncryptedData }
+pub fn read_encrypted_private_key_info(der: &[u8]) -> Result<EncryptedContent<'_>, ()> {
+    let mut encrypted_private_key_info = Sequence::new(der)?;
+    let encrypted_content = encrypted_private_key_info.read_encrypted_content()?;
+    if !encrypted_private_key_info.at_end() {
+        return Err(());
+    }
+    Ok(encrypted_content)
+}
+
+/// Given the DER encoding of a PKCS #12 PFX, extracts the AuthenticatedSafe and the MacData (if
+/// present). Only PFXs protected with a password-based MAC (or not protected at all) are supported,
+/// so the authSafe ContentInfo must be of type data.
+///   PFX ::= SEQUENCE {
+///       version     INTEGER {v3(3)}(v3,...),
+///       authSafe    ContentInfo,
+///       macData     MacData OPTIONAL }
+///
+///   MacData ::= SEQUENCE {
+///       mac         DigestInfo,
+///       macSalt     OCTET STRING,
+///       iterations  INTEGER DEFAULT 1 }
+pub fn read_pfx(pfx: &[u8]) -> Result<Pfx<'_>, ()> {
+    let mut pfx = Sequence::new(pfx)?;
+    if pfx.read_small_unsigned_integer()? != 3 {
+        return Err(());
+    }
+    let auth_safe = match read_content_info(&mut pfx)? {
+        ContentInfo::Data(data) => data,
+        _ => return Err(()),
+    };
+    let mac_data = if pfx.at_end() {
+        None
+    } else {
+        let mut mac_data = pfx.read_sequence()?;
+        let mut digest_info = mac_data.read_sequence()?;
+        let digest_algorithm = digest_info.read_algorithm_identifier()?.algorithm;
+        let digest = digest_info.read_octet_string()?;
+        if !digest_info.at_end() {
+            return Err(());
+        }
+        let salt = mac_data.read_octet_string()?;
+        let iterations = if mac_data.at_end() {
+            1
+        } else {
+            mac_data.read_small_unsigned_integer()?
+        };
+        if !mac_data.at_end() {
+            return Err(());
+        }
+        Some(MacData {
+            digest_algorithm,
+            digest,
+            salt,
+            iterations,
+        })
+    };
+    if !pfx.at_end() {
+        return Err(());
+    }
+    Ok(Pfx {
+        auth_safe,
+        mac_data,
+    })
+}
+
+/// Reads a PKCS #7 ContentInfo.
+///   ContentInfo ::= SEQUENCE {
+///       contentType ContentType,
+///       content [0] EXPLICIT ANY DEFINED BY contentType OPTIONAL }
+///
+///   EncryptedData ::= SEQUENCE {
+///       version Version,
+///       encryptedContentInfo EncryptedContentInfo }
+///
+///   EncryptedContentInfo ::= SEQUENCE {
+///       contentType ContentType,
+///       contentEncryptionAlgorithm ContentEncryptionAlgorithmIdentifier,
+///       encryptedContent [0] IMPLICIT EncryptedContent OPTIONAL }
+fn read_content_info<'a>(sequence: &mut Sequence<'a>) -> Result<ContentInfo<'a>, ()> {
+    let mut content_info = sequence.read_sequence()?;
+    let content_type = content_info.read_oid()?;
+    let mut content = content_info.read_explicit_tag_0()?;
+    if !content_info.at_end() {
+        return Err(());
+    }
+    let result = if content_type == OID_BYTES_DATA {
+        ContentInfo::Data(content.read_octet_string()?)
+    } else if content_type == OID_BYTES_ENCRYPTED_DATA {
+        let mut encrypted_data = content.read_sequence()?;
+        let _version = encrypted_data.read_small_unsigned_integer()?;
+        let mut encrypted_content_info = encrypted_data.read_sequence()?;
+        if encrypted_content_info.read_oid()? != OID_BYTES_DATA {
+            return Err(());
+        }
+        let algorithm = encrypted_content_info.read_algorithm_identifier()?;
+        let encrypted_data = encrypted_content_info.contents.read(IMPLICIT_TAG_0)?;
+        ContentInfo::EncryptedData(EncryptedContent {
+            algorithm,
+            encrypted_data,
+        })
+    } else {
+        let _ = content.read_any_element()?;
+        ContentInfo::Unsupported
+    };
+    if !content.at_end() {
+        return Err(());
+    }
+    Ok(result)
+}
+
+/// Given the DER encoding of a PKCS #12 AuthenticatedSafe, reads each ContentInfo in it.
+///   AuthenticatedSafe ::= SEQUENCE OF ContentInfo
+pub fn read_authenticated_safe(auth_safe: &[u8]) -> Result<Vec<ContentInfo<'_>>, ()> {
+    let mut auth_safe = Sequence::new(auth_safe)?;
+    let mut content_infos = Vec::new();
+    while !auth_safe.at_end() {
+        content_infos.push(read_content_info(&mut auth_safe)?);
+    }
+    Ok(content_infos)
+}
+
+/// Given the DER encoding of a PKCS #12 SafeContents, reads each SafeBag in it.
+///   SafeContents ::= SEQUENCE OF SafeBag
+///
+///   SafeBag ::= SEQUENCE {
+///       bagId         BAG-TYPE.&id ({PKCS12BagSet}),
+///       bagValue      [0] EXPLICIT BAG-TYPE.&Type({PKCS12BagSet}{@bagId}),
+///       bagAttributes SET OF PKCS12Attribute OPTIONAL }
+///
+///   CertBag ::= SEQUENCE {
+///       certId    BAG-TYPE.&id   ({CertTypes}),
+///       certValue [0] EXPLICIT BAG-TYPE.&Type ({CertTypes}{@certId}) }
+///
+///   PKCS12Attribute ::= SEQUENCE {
+///       attrId      ATTRIBUTE.&id ({PKCS12AttrSet}),
+///       attrValues  SET OF ATTRIBUTE.&Type ({PKCS12AttrSet}{@attrId}) }
+pub fn read_safe_contents(safe_contents: &[u8]) -> Result<Vec<SafeBag<'_>>, ()> {
+    let mut safe_contents = Sequence::new(safe_contents)?;
+    let mut safe_bags = Vec::new();
+    while !safe_contents.at_end() {
+        let mut safe_bag = safe_contents.read_sequence()?;
+        let bag_id = safe_bag.read_oid()?;
+        let mut bag_value = safe_bag.read_explicit_tag_0()?;
+        let value = if bag_id == OID_BYTES_KEY_BAG {
+            SafeBagValue::Key(bag_value.read_any_element()?)
+        } else if bag_id == OID_BYTES_PKCS8_SHROUDED_KEY_BAG {
+            SafeBagValue::ShroudedKey(bag_value.read_encrypted_private_key_info()?)
+        } else if bag_id == OID_BYTES_CERT_BAG {
+            let mut cert_bag = bag_value.read_sequence()?;
+            let cert_id = cert_bag.read_oid()?;
+            let mut cert_value = cert_bag.read_explicit_tag_0()?;
+            if cert_id == OID_BYTES_X509_CERTIFICATE {
+                SafeBagValue::Cert(cert_value.read_octet_string()?)
+            } else {
+                SafeBagValue::Unsupported
+            }
+        } else {
+            let _ = bag_value.read_any_element()?;
+            SafeBagValue::Unsupported
+        };
+        if !bag_value.at_end() {
+            return Err(());
+        }
+        let mut friendly_name = None;
+        if !safe_bag.at_end() {
+            let mut bag_attributes = safe_bag.read_set()?;
+            while !bag_attributes.at_end() {
+                let mut attribute = bag_attributes.read_sequence()?;
+                let attr_id = attribute.read_oid()?;
+                let mut attr_values = attribute.read_set()?;
+                if attr_id == OID_BYTES_FRIENDLY_NAME {
+                    friendly_name = Some(read_bmp_string(attr_values.contents.read(BMP_STRING)?)?);
+                }
+            }
+        }
+        if !safe_bag.at_end() {
+            return Err(());
+        }
+        safe_bags.push(SafeBag {
+            value,
+            friendly_name,
+        });
+    }
+    Ok(safe_bags)
+}
+
+/// Decodes the contents of a BMPString.
+fn read_bmp_string(bmp_string: &[u8]) -> Result<String, ()> {
+    if !bmp_string.len().is_multiple_of(2) {
+        return Err(());
+    }
+    let code_units: Vec<u16> = bmp_string
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16(&code_units).map_err(|_| ())
+}
+
+/// Given the DER encoding of the parameters of a PKCS #12 password-based encryption scheme, reads
+/// the salt and iteration count.
+pub fn read_pkcs12_pbe_parameters(parameters: &[u8]) -> Result<Pkcs12PbeParameters<'_>, ()> {
+    let mut parameters = Sequence::new(parameters)?;
+    let salt = parameters.read_octet_string()?;
+    let iterations = parameters.read_small_unsigned_integer()?;
+    if !parameters.at_end() {
+        return Err(());
+    }
+    Ok(Pkcs12PbeParameters { salt, iterations })
+}
+
+/// Given the DER encoding of the parameters of PBES2, reads the key derivation function parameters
+/// and the encryption scheme.
+pub fn read_pbes2_parameters(parameters: &[u8]) -> Result<Pbes2Parameters<'_>, ()> {
+    let mut parameters = Sequence::new(parameters)?;
+    let key_derivation_func = parameters.read_algorithm_identifier()?;
+    let encryption_scheme = parameters.read_algorithm_identifier()?;
+    if !parameters.at_end() {
+        return Err(());
+    }
+    let kdf_parameters = key_derivation_func.parameters.ok_or(())?;
+    let kdf = if key_derivation_func.algorithm == OID_BYTES_PBKDF2 {
+        KeyDerivationFunction::Pbkdf2(read_pbkdf2_parameters(kdf_parameters)?)
+    } else if key_derivation_func.algorithm == OID_BYTES_SCRYPT {
+        KeyDerivationFunction::Scrypt(read_scrypt_parameters(kdf_parameters)?)
+    } else {
+        error!("unsupported PBES2 key derivation function");
+        return Err(());
+    };
+    Ok(Pbes2Parameters {
+        kdf,
+        encryption_scheme,
+    })
+}
+
+fn read_pbkdf2_parameters(parameters: &[u8]) -> Result<Pbkdf2Parameters<'_>, ()> {
+    let mut parameters = Sequence::new(parameters)?;
+    let salt = parameters.read_octet_string()?;
+    let iterations = parameters.read_small_unsigned_integer()?;
+    let key_length = parameters.read_optional_small_unsigned_integer()?;
+    let prf = if parameters.at_end() {
+        OID_BYTES_HMAC_SHA1
+    } else {
+        parameters.read_algorithm_identifier()?.algorithm
+    };
+    if !parameters.at_end() {
+        return Err(());
+    }
+    Ok(Pbkdf2Parameters {
+        salt,
+        iterations,
+        key_length,
+        prf,
+    })
+}
+
+fn read_scrypt_parameters(parameters: &[u8]) -> Result<ScryptParameters<'_>, ()> {
+    let mut parameters = Sequence::new(parameters)?;
+    let salt = parameters.read_octet_string()?;
+    let cost = parameters.read_small_unsigned_integer()?;
+    let block_size = parameters.read_small_unsigned_integer()?;
+    let parallelization = parameters.read_small_unsigned_integer()?;
+    let key_length = parameters.read_optional_small_unsigned_integer()?;
+    if !parameters.at_end() {
+        return Err(());
+    }
+    Ok(ScryptParameters {
+        salt,
+        cost,
+        block_size,
+        parallelization,
+        key_length,
+    })
+}
+
+/// Given the DER encoding of the parameters of AES-GCM, reads the nonce and the tag length.
+pub fn read_gcm_parameters(parameters: &[u8]) -> Result<GcmParameters<'_>, ()> {
+    let mut parameters = Sequence::new(parameters)?;
+    let nonce = parameters.read_octet_string()?;
+    let icv_length = parameters
+        .read_optional_small_unsigned_integer()?
+        .unwrap_or(12);
+    if !parameters.at_end() {
+        return Err(());
+    }
+    Ok(GcmParameters { nonce, icv_length })
+}
+
+/// Given the DER encoding of a DigestInfo (what gets signed with RSA PKCS #1 v1.5), returns the DER
+/// encoding of the OID of the hash algorithm and the hash.
+///   DigestInfo ::= SEQUENCE {
+///       digestAlgorithm DigestAlgorithmIdentifier,
+///       digest Digest }
+pub fn read_digest_info(der: &[u8]) -> Result<(&[u8], &[u8]), ()> {
+    let mut sequence = Sequence::new(der)?;
+    let digest_algorithm = sequence.read_algorithm_identifier()?;
+    let digest = sequence.read_octet_string()?;
+    if !sequence.at_end() {
+        return Err(());
+    }
+    Ok((digest_algorithm.algorithm, digest))
+}
+
+/// Given the DER encoding of an OCTET STRING (e.g. the initialization vector parameter of a CBC
+/// mode cipher), returns its contents.
+pub fn read_octet_string(octet_string: &[u8]) -> Result<&[u8], ()> {
+    let mut der = Der::new(octet_string);
+    let contents = der.read(OCTET_STRING)?;
+    if !der.at_end() {
+        return Err(());
+    }
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_small_unsigned_integer() {
+        let input = [
+            SEQUENCE | CONSTRUCTED,
+            7,
+            INTEGER,
+            2,
+            0x08,
+            0x00,
+            INTEGER,
+            1,
+            0x03,
+        ];
+        let mut sequence = Sequence::new(&input).unwrap();
+        assert_eq!(sequence.read_small_unsigned_integer(), Ok(2048));
+        assert_eq!(sequence.read_small_unsigned_integer(), Ok(3));
+        assert!(sequence.at_end());
+        let too_big = [
+            SEQUENCE | CONSTRUCTED,
+            11,
+            INTEGER,
+            9,
+            1,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let mut sequence = Sequence::new(&too_big).unwrap();
+        assert!(sequence.read_small_unsigned_integer().is_err());
+    }
+
+    #[test]
+    fn test_read_pkcs12_pbe_parameters() {
+        let input = [
+            SEQUENCE | CONSTRUCTED,
+            8,
+            OCTET_STRING,
+            2,
+            0xab,
+            0xcd,
+            INTEGER,
+            2,
+            0x08,
+            0x00,
+        ];
+        let parameters = read_pkcs12_pbe_parameters(&input).unwrap();
+        assert_eq!(parameters.salt, [0xab, 0xcd]);
+        assert_eq!(parameters.iterations, 2048);
+        assert!(read_pkcs12_pbe_parameters(&input[..input.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_read_bmp_string() {
+        assert_eq!(
+            read_bmp_string(&[0, b'T', 0, b'e', 0, b's', 0, b't']),
+            Ok("Test".to_owned())
+        );
+        assert!(read_bmp_string(&[0, b'T', 0]).is_err());
+    }
+
+    #[test]
+    fn test_read_pfx() {
+        let pfx = read_pfx(include_bytes!("../../test/rsa-aes.p12")).unwrap();
+        let mac_data = pfx.mac_data.unwrap();
+        assert_eq!(mac_data.digest_algorithm, OID_BYTES_SHA256);
+        assert_eq!(mac_data.iterations, 2048);
+        let content_infos = read_authenticated_safe(pfx.auth_safe).unwrap();
+        assert_eq!(content_infos.len(), 2);
+        match &content_infos[0] {
+            ContentInfo::EncryptedData(encrypted_content) => {
+                assert_eq!(encrypted_content.algorithm.algorithm, OID_BYTES_PBES2);
+                let parameters =
+                    read_pbes2_parameters(encrypted_content.algorithm.parameters.unwrap()).unwrap();
+                match parameters.kdf {
+                    KeyDerivationFunction::Pbkdf2(kdf) => {
+                        assert_eq!(kdf.iterations, 2048);
+                        assert_eq!(kdf.prf, OID_BYTES_HMAC_SHA256);
+                    }
+                    _ => panic!("expected PBKDF2"),
+                }
+                assert_eq!(
+                    parameters.encryption_scheme.algorithm,
+                    OID_BYTES_AES_256_CBC
+                );
+            }
+            _ => panic!("expected encrypted certificates"),
+        }
+        match &content_infos[1] {
+            ContentInfo::Data(safe_contents) => {
+                let safe_bags = read_safe_contents(safe_contents).unwrap();
+                assert_eq!(safe_bags.len(), 1);
+                assert!(matches!(safe_bags[0].value, SafeBagValue::ShroudedKey(_)));
+            }
+            _ => panic!("expected unencrypted key bags"),
+        }
+    }
+
+    #[test]
+    fn test_read_unencrypted_pfx() {
+        let pfx = read_pfx(include_bytes!("../../test/p256-unencrypted.p12")).unwrap();
+        assert!(pfx.mac_data.is_none());
+        let mut certs = 0;
+        let mut keys = 0;
+        for content_info in read_authenticated_safe(pfx.auth_safe).unwrap() {
+            let safe_contents = match content_info {
+                ContentInfo::Data(safe_contents) => safe_contents,
+                _ => panic!("expected only unencrypted data"),
+            };
+            for safe_bag in read_safe_contents(safe_contents).unwrap() {
+                match safe_bag.value {
+                    SafeBagValue::Cert(cert) => {
+                        assert_eq!(cert, include_bytes!("../../test/p256-cert.der"));
+                        certs += 1;
+                    }
+                    SafeBagValue::Key(key) => {
+                        assert_eq!(key, include_bytes!("../../test/p256-key.der"));
+                        keys += 1;
+                    }
+                    _ => panic!("unexpected safe bag"),
+                }
+            }
+        }
+        assert_eq!(certs, 1);
+        assert_eq!(keys, 1);
+    }
+
+    fn read_pem_encrypted_private_key_info(pem: &[u8]) -> Vec<u8> {
+        pem::parse(pem).unwrap().into_contents()
+    }
+
+    #[test]
+    fn test_read_scrypt_parameters() {
+        let der =
+            read_pem_encrypted_private_key_info(include_bytes!("../../test/rsa-key-scrypt.pem"));
+        let encrypted_private_key_info = read_encrypted_private_key_info(&der).unwrap();
+        assert_eq!(
+            encrypted_private_key_info.algorithm.algorithm,
+            OID_BYTES_PBES2
+        );
+        let parameters =
+            read_pbes2_parameters(encrypted_private_key_info.algorithm.parameters.unwrap())
+                .unwrap();
+        match parameters.kdf {
+            KeyDerivationFunction::Scrypt(kdf) => {
+                assert_eq!(kdf.salt.len(), 16);
+                assert_eq!(kdf.cost, 1024);
+                assert_eq!(kdf.block_size, 8);
+                assert_eq!(kdf.parallelization, 1);
+                assert_eq!(kdf.key_length, None);
+            }
+            _ => panic!("expected scrypt"),
+        }
+        assert_eq!(
+            parameters.encryption_scheme.algorithm,
+            OID_BYTES_AES_256_CBC
+        );
+    }
+
+    #[test]
+    fn test_read_gcm_parameters() {
+        let der =
+            read_pem_encrypted_private_key_info(include_bytes!("../../test/p384-key-gcm.pem"));
+        let encrypted_private_key_info = read_encrypted_private_key_info(&der).unwrap();
+        let parameters =
+            read_pbes2_parameters(encrypted_private_key_info.algorithm.parameters.unwrap())
+                .unwrap();
+        assert!(matches!(parameters.kdf, KeyDerivationFunction::Pbkdf2(_)));
+        assert_eq!(
+            parameters.encryption_scheme.algorithm,
+            OID_BYTES_AES_256_GCM
+        );
+        let gcm_parameters =
+            read_gcm_parameters(parameters.encryption_scheme.parameters.unwrap()).unwrap();
+        assert_eq!(gcm_parameters.nonce.len(), 12);
+        assert_eq!(gcm_parameters.icv_length, 16);
+        // The tag length defaults to 12.
+        let input = [SEQUENCE | CONSTRUCTED, 4, OCTET_STRING, 2, 0xab, 0xcd];
+        let gcm_parameters = read_gcm_parameters(&input).unwrap();
+        assert_eq!(gcm_parameters.nonce, [0xab, 0xcd]);
+        assert_eq!(gcm_parameters.icv_length, 12);
+    }
+
+    #[test]
+    fn test_read_encrypted_private_key_info_rejects_private_key_info() {
+        assert!(
+            read_encrypted_private_key_info(include_bytes!("../../test/p256-key.der")).is_err()
+        );
+    }
+
+    #[test]
+    fn test_read_digest_info() {
+        let mut input = vec![
+            0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+            0x01, 0x05, 0x00, 0x04, 0x20,
+        ];
+        input.extend_from_slice(&[0x11; 32]);
+        let (algorithm, digest) = read_digest_info(&input).unwrap();
+        assert_eq!(algorithm, OID_BYTES_SHA256);
+        assert_eq!(digest, [0x11; 32]);
+        assert!(read_digest_info(&input[..input.len() - 1]).is_err());
+        assert!(read_digest_info(&[0x11; 36]).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+/* -*- Mode: rust; rust-indent-offset: 4 -*- */
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+use pkcs11::types::*;
+use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, Pkcs1v15Sign, Pss, RsaPublicKey};
+use sha2::{Sha256, Sha384, Sha512};
+
+use crate::error::Error;
+use crate::util::*;
+
+/// The public key of a certificate, which is used to verify signatures in software. Verifying
+/// doesn't involve the backends, so it works the same way for every identity.
+#[allow(clippy::upper_case_acronyms)]
+pub enum VerifyingKey {
+    RSA(RsaPublicKey),
+    P256(p256::ecdsa::VerifyingKey),
+    P384(p384::ecdsa::VerifyingKey),
+}
+
+impl VerifyingKey {
+    /// Creates a `VerifyingKey` from the given public key, if it is an RSA key or an EC key on one
+    /// of the supported curves (P-256 and P-384).
+    pub fn new(public_key_info: &PublicKeyInfo) -> Result<VerifyingKey, ()> {
+        match public_key_info {
+            PublicKeyInfo::Rsa {
+                modulus,
+                public_exponent,
+            } => RsaPublicKey::new(
+                BigUint::from_bytes_be(modulus),
+                BigUint::from_bytes_be(public_exponent),
+            )
+            .map(VerifyingKey::RSA)
+            .map_err(|e| error!("invalid RSA public key: {}", e)),
+            PublicKeyInfo::Ec { ec_params, point } => {
+                if *ec_params == OID_BYTES_SECP256R1 {
+                    p256::ecdsa::VerifyingKey::from_sec1_bytes(point)
+                        .map(VerifyingKey::P256)
+                        .map_err(|e| error!("invalid P-256 public key: {}", e))
+                } else if *ec_params == OID_BYTES_SECP384R1 {
+                    p384::ecdsa::VerifyingKey::from_sec1_bytes(point)
+                        .map(VerifyingKey::P384)
+                        .map_err(|e| error!("invalid P-384 public key: {}", e))
+                } else {
+                    debug!("can't verify signatures with keys on this curve");
+                    Err(())
+                }
+            }
+        }
+    }
+
+    /// Returns the length that signatures made with the corresponding private key have.
+    pub fn signature_length(&self) -> usize {
+        match self {
+            VerifyingKey::RSA(key) => key.size(),
+            VerifyingKey::P256(_) => 64,
+            VerifyingKey::P384(_) => 96,
+        }
+    }
+
+    /// Verifies the given signature of the given data. For RSA keys, if `params` is `None`, `data`
+    /// is a DigestInfo that was signed with PKCS #1 v1.5 padding. Otherwise, `data` is a hash that
+    /// was signed with PSS padding. For EC keys, `data` is a hash, and the signature is the
+    /// concatenation of r and s.
+    pub fn verify(
+        &self,
+        data: &[u8],
+        signature: &[u8],
+        params: &Option<CK_RSA_PKCS_PSS_PARAMS>,
+    ) -> Result<(), Error> {
+        if signature.len() != self.signature_length() {
+            return Err(Error::SignatureLenRange);
+        }
+        let verified = match self {
+            VerifyingKey::RSA(key) => match params {
+                None => key
+                    .verify(Pkcs1v15Sign::new_unprefixed(), data, signature)
+                    .is_ok(),
+                Some(pss_params) => {
+                    let salt_len = pss_params.sLen as usize;
+                    let (padding, hash_len) = match (pss_params.hashAlg, pss_params.mgf) {
+                        (CKM_SHA256, CKG_MGF1_SHA256) => {
+                            (Pss::new_with_salt::<Sha256>(salt_len), 32)
+                        }
+                        (CKM_SHA384, CKG_MGF1_SHA384) => {
+                            (Pss::new_with_salt::<Sha384>(salt_len), 48)
+                        }
+                        (CKM_SHA512, CKG_MGF1_SHA512) => {
+                            (Pss::new_with_salt::<Sha512>(salt_len), 64)
+                        }
+                        _ => {
+                            error!(
+                                "unsupported algorithm to use with RSA-PSS: {}",
+                                unsafe_packed_field_access!(pss_params.hashAlg)
+                            );
+                            return Err(Error::MechanismParamInvalid);
+                        }
+                    };
+                    if data.len() != hash_len {
+                        return Err(Error::DataLenRange);
+                    }
+                    key.verify(padding, data, signature).is_ok()
+                }
+            },
+            VerifyingKey::P256(key) => match p256::ecdsa::Signature::from_slice(signature) {
+                Ok(signature) => key.verify_prehash(data, &signature).is_ok(),
+                Err(_) => false,
+            },
+            VerifyingKey::P384(key) => match p384::ecdsa::Signature::from_slice(signature) {
+                Ok(signature) => key.verify_prehash(data, &signature).is_ok(),
+                Err(_) => false,
+            },
+        };
+        if verified {
+            Ok(())
+        } else {
+            Err(Error::SignatureInvalid)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_mock::*;
+    use crate::software_key::SoftwareKey;
+    use sha2::Digest;
+
+    fn verifying_key(cert: &[u8]) -> VerifyingKey {
+        VerifyingKey::new(&read_public_key_info(cert).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_verify_rsa() {
+        let key = verifying_key(RSA_CERT);
+        assert_eq!(key.signature_length(), 256);
+        let software_key = SoftwareKey::from_pkcs8_der(RSA_KEY).unwrap();
+        let digest_info =
+            crate::digest::encode_digest_info(CKM_SHA256, &Sha256::digest(b"hello, world"))
+                .unwrap();
+        let mut signature = software_key.sign(&digest_info, &None).unwrap();
+        assert_eq!(key.verify(&digest_info, &signature, &None), Ok(()));
+        signature[0] ^= 1;
+        assert_eq!(
+            key.verify(&digest_info, &signature, &None),
+            Err(Error::SignatureInvalid)
+        );
+        assert_eq!(
+            key.verify(&digest_info, &signature[1..], &None),
+            Err(Error::SignatureLenRange)
+        );
+
+        let params = Some(CK_RSA_PKCS_PSS_PARAMS {
+            hashAlg: CKM_SHA256,
+            mgf: CKG_MGF1_SHA256,
+            sLen: 32,
+        });
+        let hash = Sha256::digest(b"hello, world");
+        let signature = software_key.sign(&hash, &params).unwrap();
+        assert_eq!(key.verify(&hash, &signature, &params), Ok(()));
+        assert_eq!(
+            key.verify(&hash, &signature, &None),
+            Err(Error::SignatureInvalid)
+        );
+        assert_eq!(
+            key.verify(&hash[1..], &signature, &params),
+            Err(Error::DataLenRange)
+        );
+        let mismatched_params = Some(CK_RSA_PKCS_PSS_PARAMS {
+            hashAlg: CKM_SHA256,
+            mgf: CKG_MGF1_SHA384,
+            sLen: 32,
+        });
+        assert_eq!(
+            key.verify(&hash, &signature, &mismatched_params),
+            Err(Error::MechanismParamInvalid)
+        );
+    }
+
+    #[test]
+    fn test_verify_ec() {
+        for (cert, key_der, signature_length) in
+            [(P256_CERT, P256_KEY, 64), (P384_CERT, P384_KEY, 96)]
+        {
+            let key = verifying_key(cert);
+            assert_eq!(key.signature_length(), signature_length);
+            let software_key = SoftwareKey::from_pkcs8_der(key_der).unwrap();
+            let hash = Sha256::digest(b"hello, world");
+            let mut signature = software_key.sign(&hash, &None).unwrap();
+            assert_eq!(key.verify(&hash, &signature, &None), Ok(()));
+            assert_eq!(
+                key.verify(&Sha256::digest(b"goodbye"), &signature, &None),
+                Err(Error::SignatureInvalid)
+            );
+            signature.push(0);
+            assert_eq!(
+                key.verify(&hash, &signature, &None),
+                Err(Error::SignatureLenRange)
+            );
+        }
+    }
+}